@@ -0,0 +1,571 @@
+//! Frontend input bindings.
+//!
+//! The mapping between the host inputs and the emulated pads can be
+//! configured with an INI file. Each `[padN]` section configures the
+//! pad of player N:
+//!
+//! ```ini
+//! [pad1]
+//! # Type of pad, same names as the --padN option
+//! device = dualshock
+//! # Host controller driving this pad (1 for the first one) or "none"
+//! controller = 2
+//! # Controller axis deadzone in percents
+//! deadzone = 15
+//! # <PSX input> = <host input>[, <host input>...]
+//! cross = key:Keypad 2, button:a
+//! start = key:Return, button:start
+//! leftx = axis:leftx
+//! lefty- = key:W
+//! l2 = axis:lefttrigger
+//! mouseleft = mouse:left
+//! ```
+//!
+//! PSX inputs are the pad buttons (`select`, `l3`, `r3`, `start`,
+//! `up`, `right`, `down`, `left`, `l2`, `r2`, `l1`, `r1`,
+//! `triangle`, `circle`, `cross`, `square`, `analog`, `mouseleft`
+//! and `mouseright`) and the analog axes (`leftx`, `lefty`,
+//! `rightx`, `righty`, `lefttrigger` and `righttrigger`). Stick axes
+//! can be suffixed with `-` or `+` to bind a digital input to one
+//! direction.
+//!
+//! Host inputs are `key:<name>` using the SDL key names,
+//! `button:<name>` and `axis:<name>` using the SDL game controller
+//! names and `mouse:<left|middle|right|x1|x2>`. Controller axes can
+//! be suffixed with `-` or `+` to only use one half of the axis.
+//!
+//! If a section contains any binding the default bindings for this
+//! pad are discarded.
+
+use std::io::{self, Read};
+use std::cmp;
+use std::fs::File;
+use std::path::Path;
+
+use sdl2::keyboard::Keycode;
+use sdl2::controller;
+use sdl2::mouse::Mouse as MouseButton;
+
+use padmemcard::gamepad::{self, Profile, Button, ButtonState, Axis};
+
+/// Maximum number of players, using two multitaps
+pub const MAX_PLAYERS: usize = 8;
+
+pub struct Bindings {
+    pads: Vec<PadBindings>,
+}
+
+impl Bindings {
+    /// Default bindings: the keyboard controls the first player and
+    /// the Nth host controller player N.
+    pub fn new() -> Bindings {
+        let mut pads: Vec<_> =
+            (0..MAX_PLAYERS).map(PadBindings::new).collect();
+
+        pads[0].map.extend_from_slice(&DEFAULT_KEYBOARD);
+
+        Bindings {
+            pads: pads,
+        }
+    }
+
+    /// Load the bindings from an INI file
+    pub fn from_path(path: &Path) -> io::Result<Bindings> {
+        let mut file = try!(File::open(path));
+
+        let mut config = String::new();
+
+        try!(file.read_to_string(&mut config));
+
+        Bindings::parse(&config).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })
+    }
+
+    /// Parse the bindings from the contents of an INI file
+    pub fn parse(config: &str) -> Result<Bindings, String> {
+        let mut bindings = Bindings::new();
+        // Pad configured by the current section
+        let mut player = None;
+        // Players whose default bindings have been discarded
+        let mut custom = [false; MAX_PLAYERS];
+
+        for (n, line) in config.lines().enumerate() {
+            let error = |msg: String| format!("line {}: {}", n + 1, msg);
+
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') ||
+                line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let section = &line[1..line.len() - 1];
+
+                player =
+                    match parse_section(section) {
+                        Some(p) => Some(p),
+                        None => return Err(error(
+                            format!("invalid section [{}]", section))),
+                    };
+
+                continue;
+            }
+
+            let (key, value) =
+                match line.find('=') {
+                    Some(p) => (line[..p].trim(), line[p + 1..].trim()),
+                    None => return Err(error(
+                        format!("expected \"key = value\": {}", line))),
+                };
+
+            let player =
+                match player {
+                    Some(p) => p,
+                    None => return Err(error(
+                        format!("{} outside of a [padN] section", key))),
+                };
+
+            let pad = &mut bindings.pads[player];
+
+            match key {
+                "device" => {
+                    pad.device =
+                        match parse_device(value) {
+                            Some(t) => Some(t),
+                            None => return Err(error(
+                                format!("invalid device {}", value))),
+                        };
+                }
+                "controller" => {
+                    pad.controller =
+                        match (value, value.parse::<usize>()) {
+                            ("none", _) => None,
+                            (_, Ok(n)) if n >= 1 && n <= MAX_PLAYERS =>
+                                Some(n - 1),
+                            _ => return Err(error(
+                                format!("invalid controller {}", value))),
+                        };
+                }
+                "deadzone" => {
+                    pad.deadzone =
+                        match value.parse::<u32>() {
+                            Ok(p) if p <= 100 => (p * 0x7fff / 100) as i16,
+                            _ => return Err(error(
+                                format!("invalid deadzone {}", value))),
+                        };
+                }
+                _ => {
+                    let target =
+                        match parse_target(key) {
+                            Some(t) => t,
+                            None => return Err(error(
+                                format!("unknown setting {}", key))),
+                        };
+
+                    if !custom[player] {
+                        pad.map.clear();
+                        custom[player] = true;
+                    }
+
+                    for input in value.split(',') {
+                        let input = input.trim();
+
+                        match parse_input(input) {
+                            Some(i) => pad.map.push((i, target)),
+                            None => return Err(error(
+                                format!("invalid input {}", input))),
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(bindings)
+    }
+
+    /// Return the bindings for `player`
+    pub fn pad(&self, player: usize) -> &PadBindings {
+        &self.pads[player]
+    }
+
+    /// Override the type of pad connected for `player`
+    pub fn set_device(&mut self, player: usize, device: gamepad::Type) {
+        self.pads[player].device = Some(device);
+    }
+}
+
+/// Bindings for a single pad
+pub struct PadBindings {
+    /// Type of pad connected, `None` to use the default
+    pub device: Option<gamepad::Type>,
+    /// Index of the host controller driving this pad
+    pub controller: Option<usize>,
+    /// Controller axis values closer to the center are ignored
+    deadzone: i16,
+    /// Host inputs and the pad input they control
+    map: Vec<(Input, Target)>,
+}
+
+impl PadBindings {
+    fn new(player: usize) -> PadBindings {
+        PadBindings {
+            device: None,
+            controller: Some(player),
+            deadzone: 0,
+            map: DEFAULT_CONTROLLER.to_vec(),
+        }
+    }
+
+    /// Handle a key, controller button or mouse button `input`
+    pub fn digital(&self,
+                   pad: &mut Profile,
+                   input: Input,
+                   state: ButtonState) {
+        for &(_, target) in self.map.iter().filter(|m| m.0 == input) {
+            match target {
+                Target::Button(b) => pad.set_button_state(b, state),
+                Target::Axis(a, half) => {
+                    let val =
+                        match (state, half) {
+                            (ButtonState::Released, _) => axis_rest(a),
+                            (ButtonState::Pressed, Half::Negative) => 0x00,
+                            (ButtonState::Pressed, _) => 0xff,
+                        };
+
+                    pad.set_axis_state(a, val);
+                }
+            }
+        }
+    }
+
+    /// Handle a host controller axis motion
+    pub fn axis(&self, pad: &mut Profile, axis: controller::Axis, val: i16) {
+        // SDL axes go from -0x8000 to 0x7fff, triggers from 0 to
+        // 0x7fff
+        let val =
+            if (val as i32).abs() < self.deadzone as i32 {
+                0
+            } else {
+                val as i32
+            };
+
+        for &(input, target) in self.map.iter() {
+            let val =
+                match input {
+                    Input::Axis(a, Half::Full) if a == axis => val,
+                    Input::Axis(a, Half::Positive) if a == axis =>
+                        if val > 0 { val } else { 0 },
+                    Input::Axis(a, Half::Negative) if a == axis =>
+                        if val < 0 { -val } else { 0 },
+                    _ => continue,
+                };
+
+            match target {
+                Target::Button(b) => {
+                    let state =
+                        if val < 0x4000 {
+                            ButtonState::Released
+                        } else {
+                            ButtonState::Pressed
+                        };
+
+                    pad.set_button_state(b, state);
+                }
+                Target::Axis(a @ Axis::LeftTrigger, _) |
+                Target::Axis(a @ Axis::RightTrigger, _) => {
+                    let pressure =
+                        if val < 0 {
+                            0
+                        } else {
+                            (cmp::min(val, 0x7fff) >> 7) as u8
+                        };
+
+                    pad.set_axis_state(a, pressure);
+                }
+                Target::Axis(a, half) => {
+                    let val =
+                        match half {
+                            Half::Negative => -val,
+                            _ => val,
+                        };
+
+                    // The PlayStation uses 0x00 to 0xff with 0x80 at
+                    // the center
+                    let val = (cmp::min(val, 0x7fff) >> 8) + 0x80;
+
+                    pad.set_axis_state(a, val as u8);
+                }
+            }
+        }
+    }
+}
+
+/// Value of an axis when it's not used
+fn axis_rest(axis: Axis) -> u8 {
+    match axis {
+        Axis::LeftTrigger | Axis::RightTrigger => 0x00,
+        _ => 0x80,
+    }
+}
+
+/// Host input
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Input {
+    Key(Keycode),
+    Button(controller::Button),
+    Axis(controller::Axis, Half),
+    Mouse(MouseButton),
+}
+
+/// Pad input controlled by a host input
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    Button(Button),
+    Axis(Axis, Half),
+}
+
+/// Part of an axis used by a binding
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Half {
+    Full,
+    Negative,
+    Positive,
+}
+
+/// Parse a pad type name, same names as the `--padN` option
+pub fn parse_device(name: &str) -> Option<gamepad::Type> {
+    let t =
+        match name {
+            "digital" => gamepad::Type::Digital,
+            "dualshock" => gamepad::Type::DualShock,
+            "mouse" => gamepad::Type::Mouse,
+            "negcon" => gamepad::Type::NeGcon,
+            "jogcon" => gamepad::Type::Jogcon,
+            "guncon" => gamepad::Type::GunCon,
+            "justifier" => gamepad::Type::Justifier,
+            "none" => gamepad::Type::Disconnected,
+            _ => return None,
+        };
+
+    Some(t)
+}
+
+/// Parse a `padN` section name, returns the player index
+fn parse_section(section: &str) -> Option<usize> {
+    if !section.starts_with("pad") {
+        return None;
+    }
+
+    match section[3..].parse::<usize>() {
+        Ok(n) if n >= 1 && n <= MAX_PLAYERS => Some(n - 1),
+        _ => None,
+    }
+}
+
+/// Split the optional `-` or `+` suffix of an axis name
+fn split_half(name: &str) -> (&str, Half) {
+    if name.ends_with('-') {
+        (&name[..name.len() - 1], Half::Negative)
+    } else if name.ends_with('+') {
+        (&name[..name.len() - 1], Half::Positive)
+    } else {
+        (name, Half::Full)
+    }
+}
+
+fn parse_target(name: &str) -> Option<Target> {
+    let (axis, half) = split_half(name);
+
+    let axis =
+        match axis {
+            "leftx" => Some(Axis::LeftX),
+            "lefty" => Some(Axis::LeftY),
+            "rightx" => Some(Axis::RightX),
+            "righty" => Some(Axis::RightY),
+            "lefttrigger" if half == Half::Full => Some(Axis::LeftTrigger),
+            "righttrigger" if half == Half::Full =>
+                Some(Axis::RightTrigger),
+            _ => None,
+        };
+
+    if let Some(axis) = axis {
+        return Some(Target::Axis(axis, half));
+    }
+
+    let button =
+        match name {
+            "select" => Button::Select,
+            "l3" => Button::L3,
+            "r3" => Button::R3,
+            "start" => Button::Start,
+            "up" => Button::DUp,
+            "right" => Button::DRight,
+            "down" => Button::DDown,
+            "left" => Button::DLeft,
+            "l2" => Button::L2,
+            "r2" => Button::R2,
+            "l1" => Button::L1,
+            "r1" => Button::R1,
+            "triangle" => Button::Triangle,
+            "circle" => Button::Circle,
+            "cross" => Button::Cross,
+            "square" => Button::Square,
+            "analog" => Button::Analog,
+            "mouseleft" => Button::MouseLeft,
+            "mouseright" => Button::MouseRight,
+            _ => return None,
+        };
+
+    Some(Target::Button(button))
+}
+
+fn parse_input(input: &str) -> Option<Input> {
+    let (kind, name) =
+        match input.find(':') {
+            Some(p) => (&input[..p], &input[p + 1..]),
+            None => return None,
+        };
+
+    match kind {
+        "key" => Keycode::from_name(name).map(Input::Key),
+        "button" =>
+            controller::Button::from_string(name).map(Input::Button),
+        "axis" => {
+            let (axis, half) = split_half(name);
+
+            controller::Axis::from_string(axis)
+                .map(|a| Input::Axis(a, half))
+        }
+        "mouse" => {
+            let button =
+                match name {
+                    "left" => MouseButton::Left,
+                    "middle" => MouseButton::Middle,
+                    "right" => MouseButton::Right,
+                    "x1" => MouseButton::X1,
+                    "x2" => MouseButton::X2,
+                    _ => return None,
+                };
+
+            Some(Input::Mouse(button))
+        }
+        _ => None,
+    }
+}
+
+/// Default keyboard bindings for the first player
+const DEFAULT_KEYBOARD: [(Input, Target); 14] = [
+    (Input::Key(Keycode::Return), Target::Button(Button::Start)),
+    (Input::Key(Keycode::RShift), Target::Button(Button::Select)),
+    (Input::Key(Keycode::Up), Target::Button(Button::DUp)),
+    (Input::Key(Keycode::Down), Target::Button(Button::DDown)),
+    (Input::Key(Keycode::Left), Target::Button(Button::DLeft)),
+    (Input::Key(Keycode::Right), Target::Button(Button::DRight)),
+    (Input::Key(Keycode::Kp2), Target::Button(Button::Cross)),
+    (Input::Key(Keycode::Kp4), Target::Button(Button::Square)),
+    (Input::Key(Keycode::Kp6), Target::Button(Button::Circle)),
+    (Input::Key(Keycode::Kp8), Target::Button(Button::Triangle)),
+    (Input::Key(Keycode::Kp7), Target::Button(Button::L1)),
+    (Input::Key(Keycode::NumLockClear), Target::Button(Button::L2)),
+    (Input::Key(Keycode::Kp9), Target::Button(Button::R1)),
+    (Input::Key(Keycode::KpMultiply), Target::Button(Button::R2)),
+];
+
+/// Default controller and mouse bindings. Map the original
+/// playstation controller as closely as possible on an XBox 360
+/// controller.
+const DEFAULT_CONTROLLER: [(Input, Target); 25] = [
+    (Input::Button(controller::Button::Start),
+     Target::Button(Button::Start)),
+    (Input::Button(controller::Button::Back),
+     Target::Button(Button::Select)),
+    (Input::Button(controller::Button::DPadLeft),
+     Target::Button(Button::DLeft)),
+    (Input::Button(controller::Button::DPadRight),
+     Target::Button(Button::DRight)),
+    (Input::Button(controller::Button::DPadUp),
+     Target::Button(Button::DUp)),
+    (Input::Button(controller::Button::DPadDown),
+     Target::Button(Button::DDown)),
+    (Input::Button(controller::Button::A),
+     Target::Button(Button::Cross)),
+    (Input::Button(controller::Button::B),
+     Target::Button(Button::Circle)),
+    (Input::Button(controller::Button::X),
+     Target::Button(Button::Square)),
+    (Input::Button(controller::Button::Y),
+     Target::Button(Button::Triangle)),
+    (Input::Button(controller::Button::LeftShoulder),
+     Target::Button(Button::L1)),
+    (Input::Button(controller::Button::RightShoulder),
+     Target::Button(Button::R1)),
+    (Input::Button(controller::Button::LeftStick),
+     Target::Button(Button::L3)),
+    (Input::Button(controller::Button::RightStick),
+     Target::Button(Button::R3)),
+    (Input::Button(controller::Button::Guide),
+     Target::Button(Button::Analog)),
+    (Input::Axis(controller::Axis::LeftX, Half::Full),
+     Target::Axis(Axis::LeftX, Half::Full)),
+    (Input::Axis(controller::Axis::LeftY, Half::Full),
+     Target::Axis(Axis::LeftY, Half::Full)),
+    (Input::Axis(controller::Axis::RightX, Half::Full),
+     Target::Axis(Axis::RightX, Half::Full)),
+    (Input::Axis(controller::Axis::RightY, Half::Full),
+     Target::Axis(Axis::RightY, Half::Full)),
+    // The triggers control both the digital L2/R2 buttons and the
+    // analog pressure used by the NeGcon
+    (Input::Axis(controller::Axis::TriggerLeft, Half::Full),
+     Target::Button(Button::L2)),
+    (Input::Axis(controller::Axis::TriggerLeft, Half::Full),
+     Target::Axis(Axis::LeftTrigger, Half::Full)),
+    (Input::Axis(controller::Axis::TriggerRight, Half::Full),
+     Target::Button(Button::R2)),
+    (Input::Axis(controller::Axis::TriggerRight, Half::Full),
+     Target::Axis(Axis::RightTrigger, Half::Full)),
+    (Input::Mouse(MouseButton::Left), Target::Button(Button::MouseLeft)),
+    (Input::Mouse(MouseButton::Right), Target::Button(Button::MouseRight)),
+];
+
+#[test]
+fn parse_bindings() {
+    let config = "
+# Player 2 uses the keyboard and the first controller
+[pad2]
+device = negcon
+controller = 1
+deadzone = 50
+cross = key:Space, button:a
+leftx- = key:Left
+righty = axis:lefty+
+
+[pad1]
+controller = none
+";
+
+    let bindings = Bindings::parse(config).unwrap();
+
+    let pad1 = bindings.pad(0);
+    let pad2 = bindings.pad(1);
+
+    // Pad 1 keeps its default bindings
+    assert!(pad1.device.is_none());
+    assert!(pad1.controller.is_none());
+    assert!(pad1.map.len() == DEFAULT_KEYBOARD.len() +
+            DEFAULT_CONTROLLER.len());
+
+    assert!(pad2.device == Some(gamepad::Type::NeGcon));
+    assert!(pad2.controller == Some(0));
+    assert!(pad2.deadzone == 0x3fff);
+    assert!(pad2.map.iter().map(|m| m.0).collect::<Vec<_>>() ==
+            [Input::Key(Keycode::Space),
+             Input::Button(controller::Button::A),
+             Input::Key(Keycode::Left),
+             Input::Axis(controller::Axis::LeftY, Half::Positive)]);
+
+    assert!(Bindings::parse("cross = key:Space").is_err());
+    assert!(Bindings::parse("[pad9]").is_err());
+    assert!(Bindings::parse("[pad1]\ncross = joystick:0").is_err());
+}
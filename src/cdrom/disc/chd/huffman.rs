@@ -0,0 +1,138 @@
+//! Huffman decoder used by CHD v5 to compress the hunk map. This is
+//! MAME's own canonical Huffman scheme, it's not compatible with the
+//! one used by DEFLATE.
+
+use std::io;
+
+use super::super::decompress::{BitReader, error};
+
+pub struct Huffman {
+    /// Maximum code length
+    max_bits: u32,
+    /// Lookup table indexed by the next `max_bits` bits of the
+    /// stream. Each entry contains `(symbol, code_length)`.
+    lookup: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    /// Import a tree from `reader` using MAME's RLE encoding of the
+    /// code lengths
+    pub fn import_rle(reader: &mut BitReader,
+                      num_codes: usize,
+                      max_bits: u32) -> io::Result<Huffman> {
+        let length_bits =
+            if max_bits >= 16 {
+                5
+            } else if max_bits >= 8 {
+                4
+            } else {
+                3
+            };
+
+        let mut lengths = vec![0u8; num_codes];
+
+        let mut code = 0;
+
+        while code < num_codes {
+            let len = try!(reader.read(length_bits));
+
+            if len != 1 {
+                lengths[code] = len as u8;
+                code += 1;
+                continue;
+            }
+
+            // 1 is an escape code
+            let len = try!(reader.read(length_bits));
+
+            if len == 1 {
+                // Double 1 is just a single 1
+                lengths[code] = 1;
+                code += 1;
+            } else {
+                let repeat = try!(reader.read(length_bits)) as usize + 3;
+
+                if code + repeat > num_codes {
+                    return error("invalid CHD huffman tree");
+                }
+
+                for l in &mut lengths[code..code + repeat] {
+                    *l = len as u8;
+                }
+
+                code += repeat;
+            }
+        }
+
+        Huffman::from_lengths(&lengths, max_bits)
+    }
+
+    /// Assign the canonical codes and build the lookup table
+    fn from_lengths(lengths: &[u8], max_bits: u32) -> io::Result<Huffman> {
+        // Number of codes of each length
+        let mut histogram = [0u32; 33];
+
+        for &l in lengths {
+            if l as u32 > max_bits {
+                return error("invalid CHD huffman code length");
+            }
+
+            histogram[l as usize] += 1;
+        }
+
+        // Determine the first code of each length, starting with the
+        // longest codes
+        let mut start = 0;
+
+        for len in (1..33).rev() {
+            let next = (start + histogram[len]) >> 1;
+
+            if len != 1 && next * 2 != start + histogram[len] {
+                return error("inconsistent CHD huffman tree");
+            }
+
+            histogram[len] = start;
+            start = next;
+        }
+
+        let mut lookup = vec![(0, 0); 1 << max_bits];
+
+        for (symbol, &l) in lengths.iter().enumerate() {
+            if l == 0 {
+                continue;
+            }
+
+            let code = histogram[l as usize];
+            histogram[l as usize] += 1;
+
+            // Fill all the entries starting with this code
+            let shift = max_bits - l as u32;
+            let first = (code << shift) as usize;
+            let last = ((code + 1) << shift) as usize;
+
+            if last > lookup.len() {
+                return error("invalid CHD huffman code");
+            }
+
+            for e in &mut lookup[first..last] {
+                *e = (symbol as u16, l);
+            }
+        }
+
+        Ok(Huffman {
+            max_bits: max_bits,
+            lookup: lookup,
+        })
+    }
+
+    /// Decode a single symbol from `reader`
+    pub fn decode(&self, reader: &mut BitReader) -> u16 {
+        let bits = reader.peek(self.max_bits);
+
+        let (symbol, len) = self.lookup[bits as usize];
+
+        reader.skip(len as u32);
+
+        symbol
+    }
+}
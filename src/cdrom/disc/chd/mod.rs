@@ -0,0 +1,851 @@
+//! MAME "Compressed Hunks of Data" (CHD) v5 disc images.
+//!
+//! The image is split in "hunks" (8 CD frames for CD images) which
+//! are compressed independently. The hunk map tells us where each
+//! hunk is stored and how it's compressed. The track layout is stored
+//! as text metadata.
+//!
+//! Each CD frame is made of a 2352 byte sector followed by 96 bytes
+//! of subchannel data.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, Read};
+
+use super::image::{Image, Toc, Track, TrackFormat};
+use super::msf::Msf;
+use super::{XaSector, SECTOR_SIZE, SECTOR_SYNC_PATTERN};
+use super::decompress::{self, BitReader, inflate, lzma, flac};
+use super::ecc;
+
+use self::huffman::Huffman;
+
+mod huffman;
+
+/// CHD disc image
+pub struct Chd {
+    file: File,
+    header: Header,
+    /// Location of every hunk in the file
+    map: Vec<MapEntry>,
+    /// Location of each track in the image
+    tracks: Vec<ChdTrack>,
+    toc: Toc,
+    /// Cache of recently decompressed hunks, indexed by `hunk %
+    /// HUNK_CACHE_SIZE`
+    cache: Vec<Option<(u32, Vec<u8>)>>,
+}
+
+impl Chd {
+    pub fn open(path: &Path) -> io::Result<Chd> {
+        let mut file = try!(File::open(path));
+
+        let header = try!(Header::parse(&mut file));
+
+        let map =
+            if header.is_compressed() {
+                try!(read_compressed_map(&mut file, &header))
+            } else {
+                try!(read_uncompressed_map(&mut file, &header))
+            };
+
+        let metadata = try!(read_track_metadata(&mut file, &header));
+
+        let (tracks, toc) = try!(build_layout(&metadata));
+
+        Ok(Chd {
+            file: file,
+            header: header,
+            map: map,
+            tracks: tracks,
+            toc: toc,
+            cache: vec![None; HUNK_CACHE_SIZE],
+        })
+    }
+
+    /// Return the uncompressed contents of `hunk`, decompressing it
+    /// if it's not already in the cache
+    fn hunk(&mut self, hunk: u32) -> io::Result<&[u8]> {
+        let slot = hunk as usize % HUNK_CACHE_SIZE;
+
+        let cached =
+            match self.cache[slot] {
+                Some((h, _)) => h == hunk,
+                None => false,
+            };
+
+        if !cached {
+            let mut data = vec![0; self.header.hunk_bytes as usize];
+
+            try!(self.decompress_hunk(hunk, &mut data));
+
+            self.cache[slot] = Some((hunk, data));
+        }
+
+        match self.cache[slot] {
+            Some((_, ref data)) => Ok(data),
+            None => unreachable!(),
+        }
+    }
+
+    fn decompress_hunk(&mut self, hunk: u32, out: &mut [u8]) -> io::Result<()> {
+        let entry =
+            match self.map.get(hunk as usize) {
+                Some(&e) => e,
+                None => return error("CHD hunk out of range"),
+            };
+
+        if !self.header.is_compressed() {
+            if entry.offset == 0 {
+                // Hunk is not present, fill with zeroes
+                for b in out.iter_mut() {
+                    *b = 0;
+                }
+
+                return Ok(());
+            }
+
+            return read_at(&mut self.file, entry.offset, out);
+        }
+
+        match entry.compression {
+            c @ COMPRESSION_TYPE_0...COMPRESSION_TYPE_3 => {
+                let mut compressed = vec![0; entry.length as usize];
+
+                try!(read_at(&mut self.file, entry.offset, &mut compressed));
+
+                let codec = self.header.compressors[c as usize];
+
+                try!(decompress_cd(codec, &compressed, out));
+            }
+            COMPRESSION_NONE => {
+                try!(read_at(&mut self.file, entry.offset, out));
+            }
+            COMPRESSION_SELF => {
+                // Copy of an other hunk
+                if entry.offset >= hunk as u64 {
+                    return error("invalid CHD self-reference");
+                }
+
+                let data = try!(self.hunk(entry.offset as u32));
+
+                out.copy_from_slice(data);
+
+                return Ok(());
+            }
+            COMPRESSION_PARENT =>
+                return error("CHD parent images are not supported"),
+            _ => return error("invalid CHD hunk compression"),
+        }
+
+        if crc16(out) != entry.crc {
+            return error("CHD hunk CRC mismatch");
+        }
+
+        Ok(())
+    }
+
+    /// Read the sector in CD frame `frame` of the image
+    fn read_frame(&mut self,
+                  frame: u32,
+                  sector: &mut XaSector) -> io::Result<()> {
+        let frames_per_hunk = self.header.hunk_bytes / CD_FRAME_SIZE as u32;
+
+        let hunk = frame / frames_per_hunk;
+        let offset = (frame % frames_per_hunk) as usize * CD_FRAME_SIZE;
+
+        let data = try!(self.hunk(hunk));
+
+        sector.raw.copy_from_slice(&data[offset..offset + SECTOR_SIZE]);
+
+        Ok(())
+    }
+}
+
+impl Image for Chd {
+    fn image_format(&self) -> &'static str {
+        "CHD"
+    }
+
+    fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    fn read_sector(&mut self, msf: Msf) -> io::Result<XaSector> {
+        let index = msf.sector_index();
+
+        let track =
+            match self.tracks.iter().find(|t| t.contains(index)) {
+                Some(&t) => t,
+                None => {
+                    let msg = format!("sector {} is outside of the disc", msf);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              msg));
+                }
+            };
+
+        let mut sector = XaSector::new();
+
+        if index < track.stored_start
+            || index >= track.stored_start + track.stored_frames {
+            // Pregap or postgap not stored in the image
+            let empty = [0; 2048];
+
+            let sector =
+                match track.track.format {
+                    TrackFormat::Audio => sector,
+                    TrackFormat::Mode1 => XaSector::new_mode1(msf, &empty),
+                    TrackFormat::Mode2Xa =>
+                        XaSector::new_mode2_form1(msf, &[0; 4], &empty),
+                };
+
+            return Ok(sector);
+        }
+
+        let frame = track.first_frame + (index - track.stored_start);
+
+        try!(self.read_frame(frame, &mut sector));
+
+        if track.track.format == TrackFormat::Audio {
+            // Audio samples are stored big endian
+            for s in sector.raw.chunks_mut(2) {
+                s.swap(0, 1);
+            }
+        }
+
+        Ok(sector)
+    }
+}
+
+/// Number of hunks kept in the decompression cache
+const HUNK_CACHE_SIZE: usize = 16;
+
+/// Size of a CD frame in a CHD image: a full sector followed by
+/// subchannel data
+const CD_FRAME_SIZE: usize = SECTOR_SIZE + 96;
+
+/// Tracks are padded to a multiple of this many frames in the image
+const CD_TRACK_PADDING: u32 = 4;
+
+/// Compression types used in the hunk map
+const COMPRESSION_TYPE_0: u8 = 0;
+const COMPRESSION_TYPE_3: u8 = 3;
+const COMPRESSION_NONE: u8 = 4;
+const COMPRESSION_SELF: u8 = 5;
+const COMPRESSION_PARENT: u8 = 6;
+/// Pseudo-types only used in the compressed map
+const COMPRESSION_RLE_SMALL: u8 = 7;
+const COMPRESSION_RLE_LARGE: u8 = 8;
+const COMPRESSION_SELF_0: u8 = 9;
+const COMPRESSION_SELF_1: u8 = 10;
+const COMPRESSION_PARENT_SELF: u8 = 11;
+const COMPRESSION_PARENT_0: u8 = 12;
+const COMPRESSION_PARENT_1: u8 = 13;
+
+/// CD codec using LZMA for the sector data and DEFLATE for the
+/// subchannel data
+const CODEC_CD_LZMA: u32 = 0x63646c7a; // "cdlz"
+/// CD codec using DEFLATE for both sector and subchannel data
+const CODEC_CD_ZLIB: u32 = 0x63647a6c; // "cdzl"
+/// CD codec using FLAC for the sector data (for audio tracks) and
+/// DEFLATE for the subchannel data
+const CODEC_CD_FLAC: u32 = 0x6364666c; // "cdfl"
+
+/// Track metadata (current format)
+const METADATA_TRACK2: u32 = 0x43485432; // "CHT2"
+/// Track metadata (legacy format)
+const METADATA_TRACK: u32 = 0x43485452; // "CHTR"
+
+/// CHD v5 header
+struct Header {
+    /// Codecs used to compress the hunks. Unused slots are 0.
+    compressors: [u32; 4],
+    /// Uncompressed size of the image
+    logical_bytes: u64,
+    /// Offset of the hunk map in the file
+    map_offset: u64,
+    /// Offset of the first metadata entry in the file
+    meta_offset: u64,
+    /// Size of a hunk in bytes
+    hunk_bytes: u32,
+    /// Size of an "unit" in bytes, for CD images it's one frame
+    unit_bytes: u32,
+}
+
+impl Header {
+    fn parse(file: &mut File) -> io::Result<Header> {
+        let mut raw = [0; 124];
+
+        try!(read_at(file, 0, &mut raw));
+
+        if &raw[0..8] != b"MComprHD" {
+            return error("not a CHD file");
+        }
+
+        let version = be32(&raw[12..]);
+
+        if version != 5 {
+            let msg = format!("unsupported CHD version {}", version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        let mut compressors = [0; 4];
+
+        for (i, c) in compressors.iter_mut().enumerate() {
+            *c = be32(&raw[16 + i * 4..]);
+
+            match *c {
+                0 | CODEC_CD_LZMA | CODEC_CD_ZLIB | CODEC_CD_FLAC => (),
+                _ => {
+                    let msg = format!("unsupported CHD codec {:08x}", *c);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              msg));
+                }
+            }
+        }
+
+        let header = Header {
+            compressors: compressors,
+            logical_bytes: be64(&raw[32..]),
+            map_offset: be64(&raw[40..]),
+            meta_offset: be64(&raw[48..]),
+            hunk_bytes: be32(&raw[56..]),
+            unit_bytes: be32(&raw[60..]),
+        };
+
+        if header.unit_bytes as usize != CD_FRAME_SIZE
+            || header.hunk_bytes == 0
+            || header.hunk_bytes % header.unit_bytes != 0 {
+            return error("CHD file is not a CD image");
+        }
+
+        Ok(header)
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.compressors[0] != 0
+    }
+
+    fn hunk_count(&self) -> u32 {
+        let hunk_bytes = self.hunk_bytes as u64;
+
+        ((self.logical_bytes + hunk_bytes - 1) / hunk_bytes) as u32
+    }
+}
+
+/// Location of a hunk in the file
+#[derive(Clone, Copy)]
+struct MapEntry {
+    compression: u8,
+    /// Compressed length
+    length: u32,
+    /// Offset in the file, or hunk number for self-references
+    offset: u64,
+    /// CRC16 of the uncompressed data
+    crc: u16,
+}
+
+/// Uncompressed images use a simple map of hunk offsets
+fn read_uncompressed_map(file: &mut File,
+                         header: &Header) -> io::Result<Vec<MapEntry>> {
+    let count = header.hunk_count() as usize;
+
+    let mut raw = vec![0; count * 4];
+
+    try!(read_at(file, header.map_offset, &mut raw));
+
+    let map =
+        raw.chunks(4)
+        .map(|e| MapEntry {
+            compression: COMPRESSION_NONE,
+            length: header.hunk_bytes,
+            offset: be32(e) as u64 * header.hunk_bytes as u64,
+            crc: 0,
+        })
+        .collect();
+
+    Ok(map)
+}
+
+/// Decode the Huffman-compressed map of compressed images
+fn read_compressed_map(file: &mut File,
+                       header: &Header) -> io::Result<Vec<MapEntry>> {
+    let mut map_header = [0; 16];
+
+    try!(read_at(file, header.map_offset, &mut map_header));
+
+    let map_bytes = be32(&map_header[0..]);
+    let first_offset = be48(&map_header[4..]);
+    let map_crc = be16(&map_header[10..]);
+    let length_bits = map_header[12] as u32;
+    let self_bits = map_header[13] as u32;
+    let parent_bits = map_header[14] as u32;
+
+    let mut compressed = vec![0; map_bytes as usize];
+
+    try!(read_at(file, header.map_offset + 16, &mut compressed));
+
+    let mut reader = BitReader::new(&compressed);
+
+    let huffman = try!(Huffman::import_rle(&mut reader, 16, 8));
+
+    let count = header.hunk_count() as usize;
+
+    // First pass: decode the compression type of each hunk
+    let mut types = Vec::with_capacity(count);
+
+    let mut last = 0;
+    let mut repeat = 0;
+
+    while types.len() < count {
+        if repeat > 0 {
+            types.push(last);
+            repeat -= 1;
+            continue;
+        }
+
+        let t = huffman.decode(&mut reader) as u8;
+
+        match t {
+            COMPRESSION_RLE_SMALL => {
+                types.push(last);
+                repeat = 2 + huffman.decode(&mut reader) as u32;
+            }
+            COMPRESSION_RLE_LARGE => {
+                types.push(last);
+                repeat = 2 + 16 + ((huffman.decode(&mut reader) as u32) << 4);
+                repeat += huffman.decode(&mut reader) as u32;
+            }
+            _ => {
+                types.push(t);
+                last = t;
+            }
+        }
+    }
+
+    // Second pass: decode the offsets, lengths and CRCs
+    let mut map = Vec::with_capacity(count);
+
+    let mut cur_offset = first_offset;
+    let mut last_self = 0;
+    let mut last_parent = 0;
+
+    // Raw map, used to validate the CRC
+    let mut raw = Vec::with_capacity(count * 12);
+
+    for (hunk, &t) in types.iter().enumerate() {
+        let mut entry = MapEntry {
+            compression: t,
+            length: 0,
+            offset: cur_offset,
+            crc: 0,
+        };
+
+        match t {
+            COMPRESSION_TYPE_0...COMPRESSION_TYPE_3 => {
+                entry.length = try!(reader.read(length_bits));
+                cur_offset += entry.length as u64;
+                entry.crc = try!(reader.read(16)) as u16;
+            }
+            COMPRESSION_NONE => {
+                entry.length = header.hunk_bytes;
+                cur_offset += entry.length as u64;
+                entry.crc = try!(reader.read(16)) as u16;
+            }
+            COMPRESSION_SELF => {
+                last_self = try!(reader.read(self_bits)) as u64;
+                entry.offset = last_self;
+            }
+            COMPRESSION_PARENT => {
+                last_parent = try!(reader.read(parent_bits)) as u64;
+                entry.offset = last_parent;
+            }
+            COMPRESSION_SELF_0 | COMPRESSION_SELF_1 => {
+                if t == COMPRESSION_SELF_1 {
+                    last_self += 1;
+                }
+
+                entry.compression = COMPRESSION_SELF;
+                entry.offset = last_self;
+            }
+            COMPRESSION_PARENT_SELF => {
+                entry.compression = COMPRESSION_PARENT;
+                last_parent = (hunk as u64 * header.hunk_bytes as u64)
+                    / header.unit_bytes as u64;
+                entry.offset = last_parent;
+            }
+            COMPRESSION_PARENT_0 | COMPRESSION_PARENT_1 => {
+                if t == COMPRESSION_PARENT_1 {
+                    last_parent +=
+                        (header.hunk_bytes / header.unit_bytes) as u64;
+                }
+
+                entry.compression = COMPRESSION_PARENT;
+                entry.offset = last_parent;
+            }
+            _ => return error("invalid CHD map entry"),
+        }
+
+        raw.push(entry.compression);
+        raw.extend_from_slice(&[(entry.length >> 16) as u8,
+                                (entry.length >> 8) as u8,
+                                entry.length as u8]);
+        for i in (0..6).rev() {
+            raw.push((entry.offset >> (i * 8)) as u8);
+        }
+        raw.extend_from_slice(&[(entry.crc >> 8) as u8, entry.crc as u8]);
+
+        map.push(entry);
+    }
+
+    if crc16(&raw) != map_crc {
+        return error("CHD map CRC mismatch");
+    }
+
+    Ok(map)
+}
+
+/// Track description from the CHD metadata
+struct TrackMetadata {
+    number: u8,
+    format: TrackFormat,
+    /// Number of frames stored in the image, including the pregap if
+    /// `pregap_stored` is true
+    frames: u32,
+    pregap: u32,
+    /// True if the pregap data is stored in the image
+    pregap_stored: bool,
+    postgap: u32,
+}
+
+fn read_track_metadata(file: &mut File,
+                       header: &Header) -> io::Result<Vec<TrackMetadata>> {
+    let mut tracks = Vec::new();
+
+    let mut offset = header.meta_offset;
+
+    while offset != 0 {
+        let mut entry_header = [0; 16];
+
+        try!(read_at(file, offset, &mut entry_header));
+
+        let meta_tag = be32(&entry_header[0..]);
+        // Top byte contains flags
+        let length = be32(&entry_header[4..]) & 0xffffff;
+        let next = be64(&entry_header[8..]);
+
+        if meta_tag == METADATA_TRACK2 || meta_tag == METADATA_TRACK {
+            let mut data = vec![0; length as usize];
+
+            try!(read_at(file, offset + 16, &mut data));
+
+            tracks.push(try!(parse_track_metadata(&data)));
+        }
+
+        offset = next;
+    }
+
+    if tracks.is_empty() {
+        return error("no track metadata found in CHD");
+    }
+
+    tracks.sort_by_key(|t| t.number);
+
+    Ok(tracks)
+}
+
+/// Parse a track metadata string like "TRACK:1 TYPE:MODE2_RAW
+/// SUBTYPE:NONE FRAMES:1234 PREGAP:0 PGTYPE:MODE2_RAW PGSUB:RW
+/// POSTGAP:0"
+fn parse_track_metadata(data: &[u8]) -> io::Result<TrackMetadata> {
+    // The string is NUL terminated
+    let data = data.split(|&b| b == 0).next().unwrap_or(&[]);
+
+    let text = String::from_utf8_lossy(data);
+
+    let mut number = None;
+    let mut format = None;
+    let mut frames = None;
+    let mut pregap = 0;
+    let mut pregap_stored = false;
+    let mut postgap = 0;
+
+    for field in text.split_whitespace() {
+        let mut kv = field.splitn(2, ':');
+
+        let key = kv.next().unwrap_or("");
+        let value = kv.next().unwrap_or("");
+
+        match key {
+            "TRACK" => number = value.parse().ok(),
+            "TYPE" =>
+                format =
+                match value {
+                    "AUDIO" => Some(TrackFormat::Audio),
+                    "MODE1_RAW" => Some(TrackFormat::Mode1),
+                    "MODE2_RAW" => Some(TrackFormat::Mode2Xa),
+                    _ => {
+                        let msg = format!("unsupported CHD track type {}",
+                                          value);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  msg));
+                    }
+                },
+            "FRAMES" => frames = value.parse().ok(),
+            "PREGAP" => pregap = value.parse().unwrap_or(0),
+            // A 'V' prefix means that the pregap data is stored in
+            // the image
+            "PGTYPE" => pregap_stored = value.starts_with('V'),
+            "POSTGAP" => postgap = value.parse().unwrap_or(0),
+            _ => (),
+        }
+    }
+
+    match (number, format, frames) {
+        (Some(number), Some(format), Some(frames)) =>
+            Ok(TrackMetadata {
+                number: number,
+                format: format,
+                frames: frames,
+                pregap: pregap,
+                pregap_stored: pregap_stored,
+                postgap: postgap,
+            }),
+        _ => {
+            let msg = format!("invalid CHD track metadata: {}", text);
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+    }
+}
+
+/// Location of a track in the image
+#[derive(Clone, Copy)]
+struct ChdTrack {
+    track: Track,
+    /// Absolute sector index of the first sector stored in the image
+    stored_start: u32,
+    /// Number of sectors stored in the image
+    stored_frames: u32,
+    /// Index of the CD frame containing `stored_start`
+    first_frame: u32,
+}
+
+impl ChdTrack {
+    fn contains(&self, index: u32) -> bool {
+        index >= self.track.pregap_start() && index < self.track.end()
+    }
+}
+
+/// Compute the position of each track on the disc and in the image
+fn build_layout(metadata: &[TrackMetadata])
+                -> io::Result<(Vec<ChdTrack>, Toc)> {
+    let mut tracks = Vec::with_capacity(metadata.len());
+
+    // Position of the next track's pregap
+    let mut position: u32 = 0;
+    // Position of the next track in the image
+    let mut frame = 0;
+
+    for (i, m) in metadata.iter().enumerate() {
+        let stored_pregap = if m.pregap_stored { m.pregap } else { 0 };
+
+        if stored_pregap > m.frames {
+            return error("invalid CHD track pregap");
+        }
+
+        // The 2 second lead-in before track 01 is never stored
+        let pregap =
+            if i == 0 {
+                m.pregap.checked_add(150)
+            } else {
+                Some(m.pregap)
+            };
+
+        let start = pregap.and_then(|p| position.checked_add(p));
+        let length = (m.frames - stored_pregap).checked_add(m.postgap);
+        let end = start.and_then(|s| length.and_then(|l| s.checked_add(l)));
+
+        let (pregap, start, length) =
+            match (pregap, start, length, end) {
+                (Some(p), Some(s), Some(l), Some(_)) => (p, s, l),
+                _ => return error("CHD track is out of range"),
+            };
+
+        let track = Track {
+            number: m.number,
+            format: m.format,
+            start: start,
+            pregap: pregap,
+            length: length,
+        };
+
+        tracks.push(ChdTrack {
+            track: track,
+            stored_start: start - stored_pregap,
+            stored_frames: m.frames,
+            first_frame: frame,
+        });
+
+        position = track.end();
+
+        // Tracks are padded to a multiple of CD_TRACK_PADDING frames
+        let padded = m.frames.checked_add(CD_TRACK_PADDING - 1)
+            .map(|f| f / CD_TRACK_PADDING * CD_TRACK_PADDING)
+            .and_then(|f| frame.checked_add(f));
+
+        frame =
+            match padded {
+                Some(f) => f,
+                None => return error("CHD track is out of range"),
+            };
+    }
+
+    let toc = try!(Toc::new(tracks.iter().map(|t| t.track).collect()));
+
+    Ok((tracks, toc))
+}
+
+/// Decompress a hunk compressed with one of the CD codecs
+fn decompress_cd(codec: u32, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+    let frames = dest.len() / CD_FRAME_SIZE;
+
+    let sector_bytes = frames * SECTOR_SIZE;
+    let subcode_bytes = frames * (CD_FRAME_SIZE - SECTOR_SIZE);
+
+    let mut buffer = vec![0; sector_bytes + subcode_bytes];
+
+    // For the LZMA and zlib codecs the data starts with a bitmap of
+    // the frames whose sync pattern and ECC must be regenerated
+    // followed by the compressed length of the sector data.
+    let ecc_bytes = (frames + 7) / 8;
+
+    let subcode_offset =
+        if codec == CODEC_CD_FLAC {
+            let mut samples = vec![0i16; sector_bytes / 2];
+
+            let len = try!(flac::decode(src, 2, 16, &mut samples));
+
+            // Samples are stored big endian
+            for (i, s) in samples.iter().enumerate() {
+                buffer[i * 2] = (*s >> 8) as u8;
+                buffer[i * 2 + 1] = *s as u8;
+            }
+
+            len
+        } else {
+            let complen_bytes = if dest.len() < 65536 { 2 } else { 3 };
+            let header_bytes = ecc_bytes + complen_bytes;
+
+            if src.len() < header_bytes {
+                return error("truncated CHD hunk");
+            }
+
+            let mut complen = 0;
+
+            for &b in &src[ecc_bytes..header_bytes] {
+                complen = (complen << 8) | b as usize;
+            }
+
+            if header_bytes + complen > src.len() {
+                return error("truncated CHD hunk");
+            }
+
+            let base = &src[header_bytes..header_bytes + complen];
+            let out = &mut buffer[..sector_bytes];
+
+            let len =
+                if codec == CODEC_CD_LZMA {
+                    let props = lzma::Properties { lc: 3, lp: 0, pb: 2 };
+
+                    try!(lzma::decompress(props, base, out))
+                } else {
+                    try!(inflate::inflate(base, out))
+                };
+
+            if len != sector_bytes {
+                return error("short CHD hunk");
+            }
+
+            header_bytes + complen
+        };
+
+    if subcode_offset > src.len() {
+        return error("truncated CHD hunk");
+    }
+
+    try!(inflate::inflate(&src[subcode_offset..],
+                          &mut buffer[sector_bytes..]));
+
+    for f in 0..frames {
+        let frame = &mut dest[f * CD_FRAME_SIZE..(f + 1) * CD_FRAME_SIZE];
+
+        frame[..SECTOR_SIZE]
+            .copy_from_slice(&buffer[f * SECTOR_SIZE..(f + 1) * SECTOR_SIZE]);
+
+        let subcode = sector_bytes + f * (CD_FRAME_SIZE - SECTOR_SIZE);
+
+        frame[SECTOR_SIZE..]
+            .copy_from_slice(&buffer[subcode..subcode + 96]);
+
+        if codec != CODEC_CD_FLAC && src[f / 8] & (1 << (f % 8)) != 0 {
+            frame[..12].copy_from_slice(&SECTOR_SYNC_PATTERN);
+            ecc::generate(&mut frame[..SECTOR_SIZE], false);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read exactly `buf.len()` bytes at `offset` in `file`
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    try!(file.seek(io::SeekFrom::Start(offset)));
+
+    file.read_exact(buf)
+}
+
+/// CRC-16-CCITT used to validate the map and hunks
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+
+    for &b in data {
+        crc ^= (b as u16) << 8;
+
+        for _ in 0..8 {
+            crc =
+                if crc & 0x8000 != 0 {
+                    (crc << 1) ^ 0x1021
+                } else {
+                    crc << 1
+                };
+        }
+    }
+
+    crc
+}
+
+fn be16(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | b[1] as u16
+}
+
+fn be32(b: &[u8]) -> u32 {
+    ((be16(b) as u32) << 16) | be16(&b[2..]) as u32
+}
+
+fn be48(b: &[u8]) -> u64 {
+    ((be16(b) as u64) << 32) | be32(&b[2..]) as u64
+}
+
+fn be64(b: &[u8]) -> u64 {
+    ((be32(b) as u64) << 32) | be32(&b[4..]) as u64
+}
+
+fn error<T>(msg: &str) -> io::Result<T> {
+    decompress::error(msg)
+}
+
+#[test]
+fn chd_crc16() {
+    // CRC-16/CCITT-FALSE check value
+    assert!(crc16(b"123456789") == 0x29b1);
+}
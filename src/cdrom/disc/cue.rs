@@ -0,0 +1,57 @@
+//! Minimal CUE sheet support.
+//!
+//! For the time being we only care about finding the BIN file
+//! containing the data track, the rest of the cuesheet is ignored.
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+
+/// Parse the cuesheet at `path` and return the path to the binary
+/// file containing the first track.
+pub fn data_file(path: &Path) -> io::Result<PathBuf> {
+    let file = try!(File::open(path));
+
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+        let line = line.trim();
+
+        if !line.starts_with("FILE ") {
+            continue;
+        }
+
+        let name =
+            match file_name(&line[5..]) {
+                Some(n) => n,
+                None => {
+                    let msg = format!("invalid cuesheet FILE entry: {}", line);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              msg));
+                }
+            };
+
+        // File names are relative to the cuesheet's directory
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        return Ok(dir.join(name));
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData,
+                       "no FILE entry found in cuesheet"))
+}
+
+/// Extract the file name from the parameters of a FILE
+/// command. Names containing spaces are quoted, the file type
+/// (BINARY, WAVE...) follows the name.
+fn file_name(params: &str) -> Option<&str> {
+    let params = params.trim();
+
+    if params.starts_with('"') {
+        let params = &params[1..];
+
+        params.find('"').map(|end| &params[..end])
+    } else {
+        params.split_whitespace().next()
+    }
+}
@@ -0,0 +1,471 @@
+//! FLAC frame decoder.
+//!
+//! Only decodes a sequence of bare FLAC frames (without the "fLaC"
+//! marker and metadata blocks), this is what CHD images use to store
+//! CD audio.
+
+use std::io;
+
+use super::{error, BitReader};
+
+/// Decode FLAC frames from `input` until `output` is full. `output`
+/// receives the interleaved samples of `channels` channels. Frames
+/// which don't specify their sample size in the header use
+/// `bits_per_sample`. Returns the number of input bytes consumed.
+pub fn decode(input: &[u8],
+              channels: usize,
+              bits_per_sample: u32,
+              output: &mut [i16]) -> io::Result<usize> {
+    let mut reader = BitReader::new(input);
+
+    let mut pos = 0;
+
+    let mut buffers = vec![Vec::new(); channels];
+
+    while pos < output.len() {
+        let frame_start = reader.byte_pos();
+
+        let header = try!(FrameHeader::parse(&mut reader, bits_per_sample));
+
+        if crc8(&input[frame_start..reader.byte_pos()]) != 0 {
+            return error("FLAC frame header CRC mismatch");
+        }
+
+        if header.channels() != channels {
+            return error("unexpected FLAC channel count");
+        }
+
+        let block_size = header.block_size;
+
+        if pos + block_size * channels > output.len() {
+            return error("FLAC output overflow");
+        }
+
+        for (c, buf) in buffers.iter_mut().enumerate() {
+            // The side channel needs one more bit
+            let bps =
+                match (header.assignment, c) {
+                    (ChannelAssignment::LeftSide, 1) |
+                    (ChannelAssignment::MidSide, 1) |
+                    (ChannelAssignment::SideRight, 0) =>
+                        header.bits_per_sample + 1,
+                    _ => header.bits_per_sample,
+                };
+
+            buf.clear();
+            buf.resize(block_size, 0);
+
+            try!(decode_subframe(&mut reader, bps, buf));
+        }
+
+        reader.align();
+
+        // Footer CRC covers the entire frame, including itself
+        try!(reader.read(16));
+
+        if crc16(&input[frame_start..reader.byte_pos()]) != 0 {
+            return error("FLAC frame CRC mismatch");
+        }
+
+        for i in 0..block_size {
+            let samples =
+                match header.assignment {
+                    ChannelAssignment::Independent(_) => None,
+                    ChannelAssignment::LeftSide => {
+                        let l = buffers[0][i];
+                        let s = buffers[1][i];
+                        Some((l, l - s))
+                    }
+                    ChannelAssignment::SideRight => {
+                        let s = buffers[0][i];
+                        let r = buffers[1][i];
+                        Some((s + r, r))
+                    }
+                    ChannelAssignment::MidSide => {
+                        let s = buffers[1][i];
+                        let m = (buffers[0][i] << 1) | (s & 1);
+                        Some(((m + s) >> 1, (m - s) >> 1))
+                    }
+                };
+
+            match samples {
+                Some((l, r)) => {
+                    output[pos] = l as i16;
+                    output[pos + 1] = r as i16;
+                }
+                None => {
+                    for (c, buf) in buffers.iter().enumerate() {
+                        output[pos + c] = buf[i] as i16;
+                    }
+                }
+            }
+
+            pos += channels;
+        }
+    }
+
+    Ok(reader.byte_pos())
+}
+
+#[derive(Clone, Copy)]
+enum ChannelAssignment {
+    /// Independent channels
+    Independent(usize),
+    /// Left and side (left - right) channels
+    LeftSide,
+    /// Side and right channels
+    SideRight,
+    /// Mid ((left + right) / 2) and side channels
+    MidSide,
+}
+
+struct FrameHeader {
+    block_size: usize,
+    assignment: ChannelAssignment,
+    bits_per_sample: u32,
+}
+
+impl FrameHeader {
+    fn parse(reader: &mut BitReader,
+             default_bps: u32) -> io::Result<FrameHeader> {
+        // 14bit sync code followed by a reserved bit and the blocking
+        // strategy
+        if try!(reader.read(15)) != 0x7ffc {
+            return error("invalid FLAC frame sync");
+        }
+
+        // Blocking strategy, only changes how the frame number is
+        // coded
+        try!(reader.read(1));
+
+        let block_size_code = try!(reader.read(4));
+        let sample_rate_code = try!(reader.read(4));
+        let assignment =
+            match try!(reader.read(4)) {
+                n @ 0...7 => ChannelAssignment::Independent(n as usize + 1),
+                8 => ChannelAssignment::LeftSide,
+                9 => ChannelAssignment::SideRight,
+                10 => ChannelAssignment::MidSide,
+                _ => return error("invalid FLAC channel assignment"),
+            };
+
+        let bits_per_sample =
+            match try!(reader.read(3)) {
+                0 => default_bps,
+                1 => 8,
+                2 => 12,
+                4 => 16,
+                5 => 20,
+                6 => 24,
+                _ => return error("invalid FLAC sample size"),
+            };
+
+        try!(reader.read(1));
+
+        // Frame or sample number, coded like UTF-8. We don't care
+        // about its value.
+        let first = try!(reader.read(8));
+        let extra = (!(first << 24)).leading_zeros();
+
+        if extra == 1 || extra > 7 {
+            return error("invalid FLAC frame number");
+        }
+
+        for _ in 1..extra {
+            try!(reader.read(8));
+        }
+
+        let block_size =
+            match block_size_code {
+                0 => return error("invalid FLAC block size"),
+                1 => 192,
+                n @ 2...5 => 576 << (n - 2),
+                6 => try!(reader.read(8)) + 1,
+                7 => try!(reader.read(16)) + 1,
+                n => 256 << (n - 8),
+            };
+
+        match sample_rate_code {
+            12 => { try!(reader.read(8)); }
+            13 | 14 => { try!(reader.read(16)); }
+            15 => return error("invalid FLAC sample rate"),
+            _ => (),
+        }
+
+        // CRC-8, checked by the caller
+        try!(reader.read(8));
+
+        Ok(FrameHeader {
+            block_size: block_size as usize,
+            assignment: assignment,
+            bits_per_sample: bits_per_sample,
+        })
+    }
+
+    fn channels(&self) -> usize {
+        match self.assignment {
+            ChannelAssignment::Independent(n) => n,
+            _ => 2,
+        }
+    }
+}
+
+fn decode_subframe(reader: &mut BitReader,
+                   bps: u32,
+                   samples: &mut [i32]) -> io::Result<()> {
+    if try!(reader.read(1)) != 0 {
+        return error("invalid FLAC subframe padding");
+    }
+
+    let subframe_type = try!(reader.read(6));
+
+    let wasted =
+        if try!(reader.read(1)) != 0 {
+            try!(reader.read_unary()) + 1
+        } else {
+            0
+        };
+
+    if wasted >= bps {
+        return error("invalid FLAC wasted bits count");
+    }
+
+    let bps = bps - wasted;
+
+    match subframe_type {
+        0 => {
+            let v = try!(reader.read_signed(bps));
+
+            for s in samples.iter_mut() {
+                *s = v;
+            }
+        }
+        1 => {
+            for s in samples.iter_mut() {
+                *s = try!(reader.read_signed(bps));
+            }
+        }
+        8...12 => {
+            let order = (subframe_type - 8) as usize;
+
+            try!(decode_fixed(reader, bps, order, samples));
+        }
+        32...63 => {
+            let order = (subframe_type - 31) as usize;
+
+            try!(decode_lpc(reader, bps, order, samples));
+        }
+        _ => return error("invalid FLAC subframe type"),
+    }
+
+    if wasted > 0 {
+        for s in samples.iter_mut() {
+            *s <<= wasted;
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_fixed(reader: &mut BitReader,
+                bps: u32,
+                order: usize,
+                samples: &mut [i32]) -> io::Result<()> {
+    if order > samples.len() {
+        return error("invalid FLAC predictor order");
+    }
+
+    for s in &mut samples[..order] {
+        *s = try!(reader.read_signed(bps));
+    }
+
+    try!(decode_residual(reader, order, samples));
+
+    for i in order..samples.len() {
+        let r = samples[i];
+
+        let p =
+            match order {
+                0 => 0,
+                1 => samples[i - 1],
+                2 => 2 * samples[i - 1] - samples[i - 2],
+                3 => 3 * samples[i - 1] - 3 * samples[i - 2]
+                    + samples[i - 3],
+                _ => 4 * samples[i - 1] - 6 * samples[i - 2]
+                    + 4 * samples[i - 3] - samples[i - 4],
+            };
+
+        samples[i] = r + p;
+    }
+
+    Ok(())
+}
+
+fn decode_lpc(reader: &mut BitReader,
+              bps: u32,
+              order: usize,
+              samples: &mut [i32]) -> io::Result<()> {
+    if order > samples.len() {
+        return error("invalid FLAC predictor order");
+    }
+
+    for s in &mut samples[..order] {
+        *s = try!(reader.read_signed(bps));
+    }
+
+    let precision = try!(reader.read(4)) + 1;
+
+    if precision == 16 {
+        return error("invalid FLAC LPC precision");
+    }
+
+    let shift = try!(reader.read_signed(5));
+
+    if shift < 0 {
+        return error("negative FLAC LPC shift");
+    }
+
+    let mut coefs = [0i64; 32];
+
+    for c in &mut coefs[..order] {
+        *c = try!(reader.read_signed(precision)) as i64;
+    }
+
+    try!(decode_residual(reader, order, samples));
+
+    for i in order..samples.len() {
+        let mut sum = 0i64;
+
+        for (j, &c) in coefs[..order].iter().enumerate() {
+            sum += c * samples[i - 1 - j] as i64;
+        }
+
+        samples[i] += (sum >> shift) as i32;
+    }
+
+    Ok(())
+}
+
+/// Decode the Rice coded residual into `samples[order..]`
+fn decode_residual(reader: &mut BitReader,
+                   order: usize,
+                   samples: &mut [i32]) -> io::Result<()> {
+    let (param_bits, escape) =
+        match try!(reader.read(2)) {
+            0 => (4, 0xf),
+            1 => (5, 0x1f),
+            _ => return error("invalid FLAC residual coding method"),
+        };
+
+    let partition_order = try!(reader.read(4));
+    let partitions = 1usize << partition_order;
+
+    let block_size = samples.len();
+
+    if block_size % partitions != 0 || (block_size >> partition_order) < order {
+        return error("invalid FLAC partition order");
+    }
+
+    let mut pos = order;
+
+    for p in 0..partitions {
+        let count =
+            if p == 0 {
+                (block_size >> partition_order) - order
+            } else {
+                block_size >> partition_order
+            };
+
+        let param = try!(reader.read(param_bits));
+
+        if param == escape {
+            let bits = try!(reader.read(5));
+
+            for s in &mut samples[pos..pos + count] {
+                *s = try!(reader.read_signed(bits));
+            }
+        } else {
+            for s in &mut samples[pos..pos + count] {
+                let q = try!(reader.read_unary());
+                let r = try!(reader.read(param));
+
+                let v = (q << param) | r;
+
+                // Zigzag decoding
+                *s = ((v >> 1) as i32) ^ -((v & 1) as i32);
+            }
+        }
+
+        pos += count;
+    }
+
+    Ok(())
+}
+
+/// CRC-8 used in FLAC frame headers (polynomial x^8 + x^2 + x^1 + 1)
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+
+    for &b in data {
+        crc ^= b;
+
+        for _ in 0..8 {
+            crc =
+                if crc & 0x80 != 0 {
+                    (crc << 1) ^ 0x07
+                } else {
+                    crc << 1
+                };
+        }
+    }
+
+    crc
+}
+
+/// CRC-16 used in FLAC frame footers (polynomial x^16 + x^15 + x^2 +
+/// 1)
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+
+    for &b in data {
+        crc ^= (b as u16) << 8;
+
+        for _ in 0..8 {
+            crc =
+                if crc & 0x8000 != 0 {
+                    (crc << 1) ^ 0x8005
+                } else {
+                    crc << 1
+                };
+        }
+    }
+
+    crc
+}
+
+#[test]
+fn flac_decode() {
+    // Two frames of 16 stereo samples: a ramp on the left channel, a
+    // constant on the right. The first frame uses independent
+    // channels with FIXED and CONSTANT subframes, the second uses
+    // left/side stereo with LPC and FIXED subframes.
+    let input = [0xff, 0xf8, 0x60, 0x18, 0x00, 0x0f, 0x34, 0x14,
+                 0xfc, 0x18, 0xfc, 0x7c, 0x00, 0x3f, 0xff, 0x00,
+                 0xfb, 0x2e, 0xb3, 0xd0, 0xff, 0xf8, 0x60, 0x88,
+                 0x01, 0x0f, 0x88, 0x40, 0x02, 0x58, 0x10, 0x20,
+                 0x3b, 0x21, 0x90, 0xc8, 0x64, 0x32, 0x19, 0x0c,
+                 0x86, 0x43, 0x21, 0x90, 0xc8, 0x64, 0x32, 0x19,
+                 0x0c, 0x81, 0x40, 0x39, 0x50, 0x1e, 0x38, 0x00,
+                 0xff, 0xfc, 0xd4, 0xf3];
+
+    let mut output = [0; 64];
+
+    let n = decode(&input, 2, 16, &mut output).unwrap();
+
+    assert!(n == input.len());
+
+    for i in 0..32 {
+        assert!(output[i * 2] == (i as i16) * 100 - 1000);
+        assert!(output[i * 2 + 1] == -1234);
+    }
+}
@@ -0,0 +1,390 @@
+//! Raw DEFLATE (RFC 1951) decoder, without zlib or gzip framing.
+//!
+//! Loosely modeled after zlib's "puff" reference decoder: it decodes
+//! Huffman codes one bit at a time which is not the fastest approach
+//! but it's very simple.
+
+use std::io;
+
+use super::error;
+
+/// Decompress the raw DEFLATE stream `input` into `output`. Returns
+/// the number of bytes written. Decoding stops at the end of the last
+/// block or when the output buffer is full.
+pub fn inflate(input: &[u8], output: &mut [u8]) -> io::Result<usize> {
+    let mut state = Inflater {
+        input: input,
+        pos: 0,
+        bit_buf: 0,
+        bit_count: 0,
+        output: output,
+        out_pos: 0,
+    };
+
+    try!(state.run());
+
+    Ok(state.out_pos)
+}
+
+struct Inflater<'a> {
+    input: &'a [u8],
+    /// Position of the next byte to be loaded in `bit_buf`
+    pos: usize,
+    /// Bits not yet consumed, LSB first
+    bit_buf: u32,
+    /// Number of valid bits in `bit_buf`
+    bit_count: u32,
+    output: &'a mut [u8],
+    out_pos: usize,
+}
+
+impl<'a> Inflater<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let last = try!(self.bits(1)) != 0;
+
+            match try!(self.bits(2)) {
+                0 => try!(self.stored()),
+                1 => {
+                    let (lit, dist) = fixed_tables();
+                    try!(self.codes(&lit, &dist));
+                }
+                2 => {
+                    let (lit, dist) = try!(self.dynamic_tables());
+                    try!(self.codes(&lit, &dist));
+                }
+                _ => return error("invalid deflate block type"),
+            }
+
+            if last || self.out_pos == self.output.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Read `n` bits from the input (`n` <= 16)
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.bit_count < n {
+            if self.pos >= self.input.len() {
+                return error("deflate stream truncated");
+            }
+
+            self.bit_buf |= (self.input[self.pos] as u32) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+
+        let v = self.bit_buf & ((1 << n) - 1);
+
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+
+        Ok(v)
+    }
+
+    fn put(&mut self, b: u8) -> io::Result<()> {
+        if self.out_pos >= self.output.len() {
+            return error("deflate output overflow");
+        }
+
+        self.output[self.out_pos] = b;
+        self.out_pos += 1;
+
+        Ok(())
+    }
+
+    /// Uncompressed block
+    fn stored(&mut self) -> io::Result<()> {
+        // Discard the remaining bits in the current byte
+        self.bit_buf = 0;
+        self.bit_count = 0;
+
+        if self.pos + 4 > self.input.len() {
+            return error("deflate stream truncated");
+        }
+
+        let i = &self.input[self.pos..];
+
+        let len = i[0] as usize | ((i[1] as usize) << 8);
+        let nlen = i[2] as usize | ((i[3] as usize) << 8);
+
+        if len != !nlen & 0xffff {
+            return error("invalid deflate stored block length");
+        }
+
+        self.pos += 4;
+
+        if self.pos + len > self.input.len() {
+            return error("deflate stream truncated");
+        }
+
+        for _ in 0..len {
+            let b = self.input[self.pos];
+            self.pos += 1;
+            try!(self.put(b));
+        }
+
+        Ok(())
+    }
+
+    /// Decode a symbol using `h`
+    fn decode(&mut self, h: &Huffman) -> io::Result<u16> {
+        // Canonical codes of each length are consecutive, we can
+        // decode them by building the code bit by bit and checking if
+        // it fits in the range of the current length.
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+
+        for len in 1..16 {
+            code |= try!(self.bits(1)) as i32;
+
+            let count = h.count[len] as i32;
+
+            if code - count < first {
+                return Ok(h.symbol[(index + (code - first)) as usize]);
+            }
+
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+
+        error("invalid deflate huffman code")
+    }
+
+    /// Decode literal/length and distance codes until the end of
+    /// block
+    fn codes(&mut self, lit: &Huffman, dist: &Huffman) -> io::Result<()> {
+        loop {
+            let symbol = try!(self.decode(lit)) as usize;
+
+            if symbol < 256 {
+                try!(self.put(symbol as u8));
+            } else if symbol == 256 {
+                return Ok(());
+            } else {
+                let symbol = symbol - 257;
+
+                if symbol >= 29 {
+                    return error("invalid deflate length symbol");
+                }
+
+                let len = LENGTH_BASE[symbol] as usize
+                    + try!(self.bits(LENGTH_EXTRA[symbol] as u32)) as usize;
+
+                let symbol = try!(self.decode(dist)) as usize;
+
+                if symbol >= 30 {
+                    return error("invalid deflate distance symbol");
+                }
+
+                let dist = DIST_BASE[symbol] as usize
+                    + try!(self.bits(DIST_EXTRA[symbol] as u32)) as usize;
+
+                if dist > self.out_pos {
+                    return error("deflate distance too far back");
+                }
+
+                for _ in 0..len {
+                    let b = self.output[self.out_pos - dist];
+                    try!(self.put(b));
+                }
+            }
+        }
+    }
+
+    /// Read the Huffman tables of a dynamic block
+    fn dynamic_tables(&mut self) -> io::Result<(Huffman, Huffman)> {
+        let nlen = try!(self.bits(5)) as usize + 257;
+        let ndist = try!(self.bits(5)) as usize + 1;
+        let ncode = try!(self.bits(4)) as usize + 4;
+
+        if nlen > 286 || ndist > 30 {
+            return error("invalid deflate dynamic block header");
+        }
+
+        // Order in which the code length code lengths are stored
+        const ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5,
+                                    11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+        let mut lengths = [0u8; 320];
+
+        for &o in &ORDER[..ncode] {
+            lengths[o] = try!(self.bits(3)) as u8;
+        }
+
+        let lencode = try!(Huffman::new(&lengths[..19]));
+
+        let mut index = 0;
+
+        while index < nlen + ndist {
+            let symbol = try!(self.decode(&lencode));
+
+            let (len, repeat) =
+                match symbol {
+                    0...15 => (symbol as u8, 1),
+                    16 => {
+                        if index == 0 {
+                            return error("deflate repeat with no length");
+                        }
+
+                        (lengths[index - 1], 3 + try!(self.bits(2)))
+                    }
+                    17 => (0, 3 + try!(self.bits(3))),
+                    _ => (0, 11 + try!(self.bits(7))),
+                };
+
+            if index + repeat as usize > nlen + ndist {
+                return error("too many deflate code lengths");
+            }
+
+            for _ in 0..repeat {
+                lengths[index] = len;
+                index += 1;
+            }
+        }
+
+        if lengths[256] == 0 {
+            return error("deflate block has no end code");
+        }
+
+        let lit = try!(Huffman::new(&lengths[..nlen]));
+        let dist = try!(Huffman::new(&lengths[nlen..nlen + ndist]));
+
+        Ok((lit, dist))
+    }
+}
+
+/// Canonical Huffman decoding table
+struct Huffman {
+    /// Number of codes of each length
+    count: [u16; 16],
+    /// Symbols ordered by code
+    symbol: [u16; 288],
+}
+
+impl Huffman {
+    /// Build the decoding table from the code `lengths` of each
+    /// symbol
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut h = Huffman {
+            count: [0; 16],
+            symbol: [0; 288],
+        };
+
+        for &l in lengths {
+            h.count[l as usize] += 1;
+        }
+
+        // Check for an over-subscribed code. Incomplete codes are
+        // allowed (they're used for single distance codes)
+        let mut left = 1i32;
+
+        for len in 1..16 {
+            left <<= 1;
+            left -= h.count[len] as i32;
+
+            if left < 0 {
+                return error("over-subscribed deflate huffman code");
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + h.count[len];
+        }
+
+        for (symbol, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                h.symbol[offsets[l as usize] as usize] = symbol as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+
+        Ok(h)
+    }
+}
+
+/// Build the fixed Huffman tables defined by the spec
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+
+    for (i, l) in lengths.iter_mut().enumerate() {
+        *l =
+            match i {
+                0...143 => 8,
+                144...255 => 9,
+                256...279 => 7,
+                _ => 8,
+            };
+    }
+
+    let lit = Huffman::new(&lengths).unwrap();
+    let dist = Huffman::new(&[5; 30]).unwrap();
+
+    (lit, dist)
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577];
+
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+#[test]
+fn inflate_fixed() {
+    // "Rustation 4ever " repeated 8 times, compressed with the fixed
+    // Huffman codes
+    let input = [0x0b, 0x2a, 0x2d, 0x2e, 0x49, 0x2c, 0xc9, 0xcc,
+                 0xcf, 0x53, 0x30, 0x49, 0x2d, 0x4b, 0x2d, 0x52,
+                 0x08, 0xa2, 0x33, 0x1f, 0x00];
+
+    let mut output = [0; 256];
+
+    let n = inflate(&input, &mut output).unwrap();
+
+    assert!(n == 128);
+
+    for chunk in output[..n].chunks(16) {
+        assert!(chunk == b"Rustation 4ever ");
+    }
+}
+
+#[test]
+fn inflate_dynamic() {
+    // 64 pseudo-random "ACGT" characters, compressed with dynamic
+    // Huffman codes
+    let input = [0x1d, 0x8b, 0xb9, 0x0d, 0x00, 0x30, 0x10, 0xc2,
+                 0x66, 0x43, 0x14, 0x5e, 0xc0, 0xfb, 0xcf, 0x12,
+                 0x2e, 0xa2, 0xc0, 0xe2, 0x81, 0xaa, 0x98, 0x79,
+                 0x52, 0x27, 0xe0, 0x47, 0x65, 0xc1, 0x31, 0x6d,
+                 0xae, 0x1b, 0x08, 0x63, 0x6f, 0xd2, 0x7f, 0x7c];
+
+    let mut output = [0; 64];
+
+    let n = inflate(&input, &mut output).unwrap();
+
+    assert!(n == 64);
+
+    let mut x = 1u32;
+
+    for &b in &output[..] {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fffffff;
+
+        assert!(b == b"ACGT"[((x >> 16) & 3) as usize]);
+    }
+}
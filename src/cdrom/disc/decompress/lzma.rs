@@ -0,0 +1,459 @@
+//! LZMA decoder.
+//!
+//! Based on the reference decoder from the LZMA SDK ("LzmaSpec"). We
+//! only support decoding raw streams (no header) into a buffer of
+//! known size, the properties must be provided by the caller.
+
+use std::io;
+
+use super::error;
+
+/// LZMA literal/position coding properties
+#[derive(Clone, Copy)]
+pub struct Properties {
+    /// Number of "literal context" bits
+    pub lc: u32,
+    /// Number of "literal position" bits
+    pub lp: u32,
+    /// Number of "position" bits
+    pub pb: u32,
+}
+
+/// Decompress the raw LZMA stream `input` into `output`. Decoding
+/// stops once `output` is full, or if an end marker is found in which
+/// case the number of bytes decoded is returned.
+pub fn decompress(props: Properties,
+                  input: &[u8],
+                  output: &mut [u8]) -> io::Result<usize> {
+    if props.lc > 8 || props.lp > 4 || props.pb > 4 {
+        return error("invalid LZMA properties");
+    }
+
+    let mut rc = try!(RangeDecoder::new(input));
+
+    let mut d = Decoder::new(props);
+
+    d.run(&mut rc, output)
+}
+
+/// Number of bits used to model the probabilities
+const NUM_BIT_MODEL_TOTAL_BITS: u32 = 11;
+/// Initial value of all probabilities (0.5)
+const PROB_INIT: u16 = (1 << NUM_BIT_MODEL_TOTAL_BITS) / 2;
+const NUM_MOVE_BITS: u32 = 5;
+const TOP_VALUE: u32 = 1 << 24;
+
+struct RangeDecoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(input: &'a [u8]) -> io::Result<RangeDecoder<'a>> {
+        if input.len() < 5 || input[0] != 0 {
+            return error("invalid LZMA stream");
+        }
+
+        let mut rc = RangeDecoder {
+            input: input,
+            pos: 1,
+            range: 0xffffffff,
+            code: 0,
+        };
+
+        for _ in 0..4 {
+            rc.code = (rc.code << 8) | rc.next_byte() as u32;
+        }
+
+        if rc.code == rc.range {
+            return error("invalid LZMA stream");
+        }
+
+        Ok(rc)
+    }
+
+    /// Return the next input byte. Reading past the end returns 0,
+    /// corrupted streams will be caught by the sanity checks in the
+    /// decoder.
+    fn next_byte(&mut self) -> u8 {
+        let b = self.input.get(self.pos).cloned().unwrap_or(0);
+
+        self.pos += 1;
+
+        b
+    }
+
+    fn normalize(&mut self) {
+        if self.range < TOP_VALUE {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+    }
+
+    /// Decode `num_bits` bits with fixed 0.5 probability
+    fn direct_bits(&mut self, num_bits: u32) -> u32 {
+        let mut res = 0u32;
+
+        for _ in 0..num_bits {
+            self.range >>= 1;
+            self.code = self.code.wrapping_sub(self.range);
+
+            // 0 if code was >= range, 0xffffffff otherwise
+            let t = 0u32.wrapping_sub(self.code >> 31);
+
+            self.code = self.code.wrapping_add(self.range & t);
+            res = (res << 1).wrapping_add(t.wrapping_add(1));
+
+            self.normalize();
+        }
+
+        res
+    }
+
+    /// Decode a single bit using the adaptive probability `prob`
+    fn bit(&mut self, prob: &mut u16) -> u32 {
+        let v = *prob as u32;
+        let bound = (self.range >> NUM_BIT_MODEL_TOTAL_BITS) * v;
+
+        let symbol =
+            if self.code < bound {
+                *prob += ((1 << NUM_BIT_MODEL_TOTAL_BITS) - v as u16)
+                    >> NUM_MOVE_BITS;
+                self.range = bound;
+                0
+            } else {
+                *prob -= (v as u16) >> NUM_MOVE_BITS;
+                self.code -= bound;
+                self.range -= bound;
+                1
+            };
+
+        self.normalize();
+
+        symbol
+    }
+
+    /// Decode a `num_bits` wide symbol MSB first using the
+    /// probability tree `probs`
+    fn bit_tree(&mut self, probs: &mut [u16], num_bits: u32) -> u32 {
+        let mut m = 1;
+
+        for _ in 0..num_bits {
+            m = (m << 1) + self.bit(&mut probs[m as usize]);
+        }
+
+        m - (1 << num_bits)
+    }
+
+    /// Same as `bit_tree` but LSB first
+    fn bit_tree_reverse(&mut self, probs: &mut [u16], num_bits: u32) -> u32 {
+        let mut m = 1;
+        let mut symbol = 0;
+
+        for i in 0..num_bits {
+            let bit = self.bit(&mut probs[m as usize]);
+
+            m = (m << 1) + bit;
+            symbol |= bit << i;
+        }
+
+        symbol
+    }
+}
+
+const NUM_POS_BITS_MAX: usize = 4;
+const NUM_STATES: usize = 12;
+const NUM_LEN_TO_POS_STATES: usize = 4;
+const NUM_ALIGN_BITS: u32 = 4;
+const START_POS_MODEL_INDEX: u32 = 4;
+const END_POS_MODEL_INDEX: u32 = 14;
+const NUM_FULL_DISTANCES: usize = 1 << (END_POS_MODEL_INDEX >> 1);
+const MATCH_MIN_LEN: usize = 2;
+
+struct LenDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 1 << 3]; 1 << NUM_POS_BITS_MAX],
+    mid: [[u16; 1 << 3]; 1 << NUM_POS_BITS_MAX],
+    high: [u16; 1 << 8],
+}
+
+impl LenDecoder {
+    fn new() -> LenDecoder {
+        LenDecoder {
+            choice: PROB_INIT,
+            choice2: PROB_INIT,
+            low: [[PROB_INIT; 1 << 3]; 1 << NUM_POS_BITS_MAX],
+            mid: [[PROB_INIT; 1 << 3]; 1 << NUM_POS_BITS_MAX],
+            high: [PROB_INIT; 1 << 8],
+        }
+    }
+
+    fn decode(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> usize {
+        if rc.bit(&mut self.choice) == 0 {
+            return rc.bit_tree(&mut self.low[pos_state], 3) as usize;
+        }
+
+        if rc.bit(&mut self.choice2) == 0 {
+            return 8 + rc.bit_tree(&mut self.mid[pos_state], 3) as usize;
+        }
+
+        16 + rc.bit_tree(&mut self.high, 8) as usize
+    }
+}
+
+struct Decoder {
+    props: Properties,
+    literal_probs: Vec<u16>,
+    pos_slot: [[u16; 1 << 6]; NUM_LEN_TO_POS_STATES],
+    pos_decoders: [u16; 1 + NUM_FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+    align: [u16; 1 << NUM_ALIGN_BITS],
+    is_match: [u16; NUM_STATES << NUM_POS_BITS_MAX],
+    is_rep: [u16; NUM_STATES],
+    is_rep_g0: [u16; NUM_STATES],
+    is_rep_g1: [u16; NUM_STATES],
+    is_rep_g2: [u16; NUM_STATES],
+    is_rep0_long: [u16; NUM_STATES << NUM_POS_BITS_MAX],
+    len_decoder: LenDecoder,
+    rep_len_decoder: LenDecoder,
+}
+
+impl Decoder {
+    fn new(props: Properties) -> Decoder {
+        Decoder {
+            props: props,
+            literal_probs: vec![PROB_INIT; 0x300 << (props.lc + props.lp)],
+            pos_slot: [[PROB_INIT; 1 << 6]; NUM_LEN_TO_POS_STATES],
+            pos_decoders:
+            [PROB_INIT; 1 + NUM_FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+            align: [PROB_INIT; 1 << NUM_ALIGN_BITS],
+            is_match: [PROB_INIT; NUM_STATES << NUM_POS_BITS_MAX],
+            is_rep: [PROB_INIT; NUM_STATES],
+            is_rep_g0: [PROB_INIT; NUM_STATES],
+            is_rep_g1: [PROB_INIT; NUM_STATES],
+            is_rep_g2: [PROB_INIT; NUM_STATES],
+            is_rep0_long: [PROB_INIT; NUM_STATES << NUM_POS_BITS_MAX],
+            len_decoder: LenDecoder::new(),
+            rep_len_decoder: LenDecoder::new(),
+        }
+    }
+
+    fn decode_literal(&mut self,
+                      rc: &mut RangeDecoder,
+                      output: &[u8],
+                      pos: usize,
+                      state: usize,
+                      rep0: usize) -> u8 {
+        let prev_byte =
+            if pos > 0 {
+                output[pos - 1] as u32
+            } else {
+                0
+            };
+
+        let lc = self.props.lc;
+        let lp = self.props.lp;
+
+        let lit_state =
+            (((pos as u32) & ((1 << lp) - 1)) << lc) + (prev_byte >> (8 - lc));
+
+        let probs = &mut self.literal_probs[0x300 * lit_state as usize..];
+
+        let mut symbol = 1u32;
+
+        if state >= 7 {
+            // After a match we use the byte at the match distance to
+            // help predict the literal
+            let mut match_byte = output[pos - rep0 - 1] as u32;
+
+            while symbol < 0x100 {
+                let match_bit = (match_byte >> 7) & 1;
+                match_byte <<= 1;
+
+                let index = ((1 + match_bit) << 8) + symbol;
+                let bit = rc.bit(&mut probs[index as usize]);
+
+                symbol = (symbol << 1) | bit;
+
+                if match_bit != bit {
+                    break;
+                }
+            }
+        }
+
+        while symbol < 0x100 {
+            symbol = (symbol << 1) | rc.bit(&mut probs[symbol as usize]);
+        }
+
+        symbol as u8
+    }
+
+    fn decode_distance(&mut self, rc: &mut RangeDecoder, len: usize) -> u32 {
+        let len_state = ::std::cmp::min(len, NUM_LEN_TO_POS_STATES - 1);
+
+        let pos_slot = rc.bit_tree(&mut self.pos_slot[len_state], 6);
+
+        if pos_slot < START_POS_MODEL_INDEX {
+            return pos_slot;
+        }
+
+        let num_direct_bits = (pos_slot >> 1) - 1;
+        let mut dist = (2 | (pos_slot & 1)) << num_direct_bits;
+
+        if pos_slot < END_POS_MODEL_INDEX {
+            let offset = (dist - pos_slot) as usize;
+
+            dist += rc.bit_tree_reverse(&mut self.pos_decoders[offset..],
+                                        num_direct_bits);
+        } else {
+            dist = dist.wrapping_add(
+                rc.direct_bits(num_direct_bits - NUM_ALIGN_BITS)
+                    << NUM_ALIGN_BITS);
+            dist = dist.wrapping_add(
+                rc.bit_tree_reverse(&mut self.align, NUM_ALIGN_BITS));
+        }
+
+        dist
+    }
+
+    fn run(&mut self,
+           rc: &mut RangeDecoder,
+           output: &mut [u8]) -> io::Result<usize> {
+        let pb_mask = (1 << self.props.pb) - 1;
+
+        let mut rep0 = 0usize;
+        let mut rep1 = 0usize;
+        let mut rep2 = 0usize;
+        let mut rep3 = 0usize;
+        let mut state = 0usize;
+
+        let mut pos = 0;
+
+        while pos < output.len() {
+            let pos_state = pos & pb_mask;
+
+            let is_match_index = (state << NUM_POS_BITS_MAX) + pos_state;
+
+            if rc.bit(&mut self.is_match[is_match_index]) == 0 {
+                output[pos] =
+                    self.decode_literal(rc, output, pos, state, rep0);
+                pos += 1;
+
+                state =
+                    match state {
+                        0...3 => 0,
+                        4...9 => state - 3,
+                        _ => state - 6,
+                    };
+
+                continue;
+            }
+
+            let len;
+
+            if rc.bit(&mut self.is_rep[state]) != 0 {
+                if pos == 0 {
+                    return error("LZMA repeat match at beginning of stream");
+                }
+
+                if rc.bit(&mut self.is_rep_g0[state]) == 0 {
+                    if rc.bit(&mut self.is_rep0_long[is_match_index]) == 0 {
+                        // "Short rep": single byte at rep0
+                        state = if state < 7 { 9 } else { 11 };
+                        output[pos] = output[pos - rep0 - 1];
+                        pos += 1;
+                        continue;
+                    }
+                } else {
+                    let dist;
+
+                    if rc.bit(&mut self.is_rep_g1[state]) == 0 {
+                        dist = rep1;
+                    } else {
+                        if rc.bit(&mut self.is_rep_g2[state]) == 0 {
+                            dist = rep2;
+                        } else {
+                            dist = rep3;
+                            rep3 = rep2;
+                        }
+
+                        rep2 = rep1;
+                    }
+
+                    rep1 = rep0;
+                    rep0 = dist;
+                }
+
+                len = self.rep_len_decoder.decode(rc, pos_state);
+                state = if state < 7 { 8 } else { 11 };
+            } else {
+                rep3 = rep2;
+                rep2 = rep1;
+                rep1 = rep0;
+
+                len = self.len_decoder.decode(rc, pos_state);
+                state = if state < 7 { 7 } else { 10 };
+
+                let dist = self.decode_distance(rc, len);
+
+                if dist == 0xffffffff {
+                    // End marker
+                    return Ok(pos);
+                }
+
+                rep0 = dist as usize;
+            }
+
+            let len = len + MATCH_MIN_LEN;
+
+            if rep0 >= pos {
+                return error("LZMA match distance out of range");
+            }
+
+            if pos + len > output.len() {
+                return error("LZMA output overflow");
+            }
+
+            for _ in 0..len {
+                output[pos] = output[pos - rep0 - 1];
+                pos += 1;
+            }
+        }
+
+        Ok(pos)
+    }
+}
+
+#[test]
+fn lzma_decompress() {
+    // 64 pseudo-random "ACGT" characters followed by 64 'A'
+    let input = [0x00, 0x23, 0xe1, 0x08, 0xa9, 0xa0, 0x47, 0xcc,
+                 0x3e, 0xca, 0x22, 0x1f, 0xae, 0xff, 0x87, 0x3a,
+                 0xa7, 0x0a, 0x35, 0x17, 0xda, 0xc0, 0x65, 0x63,
+                 0x56, 0x12, 0xb7, 0x53, 0x18, 0x54, 0xfa, 0x6a,
+                 0x4d, 0x29, 0xe6, 0xbb, 0x26, 0x10, 0x20, 0x76,
+                 0xe0, 0x7d, 0x4d, 0x97, 0xca, 0x6f, 0x93, 0xff,
+                 0xa2, 0x42, 0x40, 0x00];
+
+    let props = Properties { lc: 3, lp: 0, pb: 2 };
+
+    let mut output = [0; 128];
+
+    let n = decompress(props, &input, &mut output).unwrap();
+
+    assert!(n == 128);
+
+    let mut x = 1u32;
+
+    for &b in &output[..64] {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fffffff;
+
+        assert!(b == b"ACGT"[((x >> 16) & 3) as usize]);
+    }
+
+    for &b in &output[64..] {
+        assert!(b == b'A');
+    }
+}
@@ -0,0 +1,105 @@
+//! Decompression algorithms used by the compressed disc image
+//! formats.
+//!
+//! These are minimal implementations: they only decode into a
+//! preallocated buffer whose size is known in advance, which is
+//! always the case for the formats we support.
+
+use std::io;
+
+pub mod inflate;
+pub mod lzma;
+pub mod flac;
+
+/// Build an `InvalidData` error with message `msg`
+pub fn error<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// MSB-first bit reader
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position of the next bit to be read
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    /// Return the next `n` bits (`n` <= 32) without consuming
+    /// them. Reading past the end of the data returns zeroes.
+    pub fn peek(&self, n: u32) -> u32 {
+        let byte = self.pos >> 3;
+
+        let mut v = 0u64;
+
+        for i in 0..5 {
+            let b = self.data.get(byte + i).cloned().unwrap_or(0);
+
+            v = (v << 8) | b as u64;
+        }
+
+        let shift = 40 - (self.pos & 7) as u32 - n;
+
+        ((v >> shift) & ((1u64 << n) - 1)) as u32
+    }
+
+    /// Consume `n` bits
+    pub fn skip(&mut self, n: u32) {
+        self.pos += n as usize;
+    }
+
+    /// Read and consume the next `n` bits (`n` <= 32)
+    pub fn read(&mut self, n: u32) -> io::Result<u32> {
+        if self.pos + n as usize > self.data.len() * 8 {
+            return error("unexpected end of bitstream");
+        }
+
+        let v = self.peek(n);
+
+        self.skip(n);
+
+        Ok(v)
+    }
+
+    /// Read a `n` bit two's complement signed integer
+    pub fn read_signed(&mut self, n: u32) -> io::Result<i32> {
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let v = try!(self.read(n));
+
+        // Sign extend
+        let shift = 32 - n;
+
+        Ok(((v << shift) as i32) >> shift)
+    }
+
+    /// Read a unary coded value: the number of 0 bits before the
+    /// next 1
+    pub fn read_unary(&mut self) -> io::Result<u32> {
+        let mut count = 0;
+
+        while try!(self.read(1)) == 0 {
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Skip to the next byte boundary
+    pub fn align(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+
+    /// Return the position of the next byte boundary
+    pub fn byte_pos(&self) -> usize {
+        (self.pos + 7) >> 3
+    }
+}
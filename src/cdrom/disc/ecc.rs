@@ -0,0 +1,117 @@
+//! CD-ROM Reed-Solomon Product Code error correction data
+//! generation.
+//!
+//! Mode 1 and Mode 2 Form 1 sectors end with 172 bytes of "P" parity
+//! followed by 104 bytes of "Q" parity computed over the header and
+//! user data. Some image formats don't store these bytes so we need
+//! to be able to regenerate them.
+
+/// Offset of the P parity bytes in the sector
+const ECC_P_OFFSET: usize = 0x81c;
+/// Offset of the Q parity bytes in the sector
+const ECC_Q_OFFSET: usize = 0x8c8;
+
+/// Compute the P and Q parity bytes of `sector` in place. For Mode 2
+/// sectors the header is not covered by the ECC and must be treated
+/// as zeroes, in this case `zero_address` should be true.
+pub fn generate(sector: &mut [u8], zero_address: bool) {
+    let mut address = [0; 4];
+
+    if zero_address {
+        address.copy_from_slice(&sector[12..16]);
+
+        for b in &mut sector[12..16] {
+            *b = 0;
+        }
+    }
+
+    let div3 = div3_table();
+
+    let mut p = [0; 172];
+    let mut q = [0; 104];
+
+    compute_block(&sector[0xc..], &div3, 86, 24, 2, 86, &mut p);
+    sector[ECC_P_OFFSET..ECC_P_OFFSET + 172].copy_from_slice(&p);
+
+    // Q parity also covers the P parity bytes
+    compute_block(&sector[0xc..], &div3, 52, 43, 86, 88, &mut q);
+    sector[ECC_Q_OFFSET..ECC_Q_OFFSET + 104].copy_from_slice(&q);
+
+    if zero_address {
+        sector[12..16].copy_from_slice(&address);
+    }
+}
+
+/// Compute one of the two parity vectors. The data is seen as a
+/// matrix of `major_count` x `minor_count` 16bit words, each byte of
+/// the words being encoded separately.
+fn compute_block(src: &[u8],
+                 div3: &[u8; 0x100],
+                 major_count: usize,
+                 minor_count: usize,
+                 major_mult: usize,
+                 minor_inc: usize,
+                 dest: &mut [u8]) {
+    let size = major_count * minor_count;
+
+    for major in 0..major_count {
+        let mut index = (major >> 1) * major_mult + (major & 1);
+
+        let mut ecc_a = 0u8;
+        let mut ecc_b = 0u8;
+
+        for _ in 0..minor_count {
+            let v = src[index];
+
+            index += minor_inc;
+            if index >= size {
+                index -= size;
+            }
+
+            ecc_a ^= v;
+            ecc_b ^= v;
+            ecc_a = gf_mul2(ecc_a);
+        }
+
+        ecc_a = div3[(gf_mul2(ecc_a) ^ ecc_b) as usize];
+
+        dest[major] = ecc_a;
+        dest[major + major_count] = ecc_a ^ ecc_b;
+    }
+}
+
+/// Multiply `v` by 2 in GF(2^8) (polynomial x^8 + x^4 + x^3 + x^2 +
+/// 1)
+fn gf_mul2(v: u8) -> u8 {
+    let v = v as u16;
+
+    let r = (v << 1) ^ if v & 0x80 != 0 { 0x11d } else { 0 };
+
+    r as u8
+}
+
+/// Build the table of divisions by 3 in GF(2^8). Since 3 * x = x ^
+/// (2 * x) we can build it from the multiplication.
+fn div3_table() -> [u8; 0x100] {
+    let mut table = [0; 0x100];
+
+    for x in 0..0x100u16 {
+        let x = x as u8;
+
+        table[(x ^ gf_mul2(x)) as usize] = x;
+    }
+
+    table
+}
+
+#[test]
+fn div3_table_is_bijective() {
+    let table = div3_table();
+
+    let mut seen = [false; 0x100];
+
+    for &x in table.iter() {
+        assert!(!seen[x as usize]);
+        seen[x as usize] = true;
+    }
+}
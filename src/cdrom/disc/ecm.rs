@@ -0,0 +1,418 @@
+//! ECM ("Error Code Modeler") images.
+//!
+//! ECM strips the sync patterns, EDC and ECC from the sectors of a
+//! BIN image since they can be regenerated from the rest of the
+//! data. The file is a sequence of chunks, each containing a number
+//! of units of one of the following types:
+//!
+//! * 0: raw bytes, copied verbatim
+//! * 1: Mode 1 sectors, stored as 3 address bytes + 2048 data bytes
+//! * 2: Mode 2 Form 1 sectors without sync and header (2336 bytes),
+//!   stored as 4 subheader bytes + 2048 data bytes
+//! * 3: Mode 2 Form 2 sectors without sync and header (2336 bytes),
+//!   stored as 4 subheader bytes + 2324 data bytes
+//!
+//! Since the units don't necessarily line up with the sector
+//! boundaries of the decoded BIN image we index all the chunks when
+//! the file is opened and decode the units on demand.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, Read, BufReader};
+use std::cmp::{self, Ordering};
+use std::ops::Range;
+
+use super::image::{Image, Toc, Track, TrackFormat};
+use super::msf::Msf;
+use super::{XaSector, SECTOR_SIZE, SECTOR_SYNC_PATTERN};
+use super::crc::crc32;
+use super::ecc;
+
+/// Size of a decoded unit for each chunk type
+const UNIT_OUT_SIZE: [usize; 4] = [1, 2352, 2336, 2336];
+/// Size of a stored unit for each chunk type
+const UNIT_IN_SIZE: [usize; 4] = [1, 2051, 2052, 2328];
+
+pub struct Ecm {
+    file: File,
+    chunks: Vec<Chunk>,
+    toc: Toc,
+}
+
+/// Run of units of the same type
+struct Chunk {
+    /// Unit type
+    kind: usize,
+    /// Number of units in the chunk
+    count: u64,
+    /// Offset of the first unit in the decoded image
+    out_offset: u64,
+    /// Offset of the first unit in the ECM file
+    in_offset: u64,
+}
+
+impl Chunk {
+    fn out_end(&self) -> u64 {
+        self.out_offset + self.count * UNIT_OUT_SIZE[self.kind] as u64
+    }
+}
+
+impl Ecm {
+    pub fn open(path: &Path) -> io::Result<Ecm> {
+        let file = try!(File::open(path));
+
+        let chunks = try!(index_chunks(&file));
+
+        let len = chunks.last().map_or(0, |c| c.out_end());
+
+        if len % SECTOR_SIZE as u64 != 0 {
+            let msg = format!("decoded ECM size ({}) is not a multiple \
+                               of the sector size", len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        // XXX Like for raw BIN files we assume a single data track
+        // with a 2 second pregap.
+        let track = Track {
+            number: 1,
+            format: TrackFormat::Mode2Xa,
+            start: 150,
+            pregap: 150,
+            length: (len / SECTOR_SIZE as u64) as u32,
+        };
+
+        Ok(Ecm {
+            file: file,
+            chunks: chunks,
+            toc: try!(Toc::new(vec![track])),
+        })
+    }
+
+    /// Fill `buf` with the decoded image data at `offset`
+    fn read_decoded(&mut self,
+                    mut offset: u64,
+                    buf: &mut [u8]) -> io::Result<()> {
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            // Find the chunk containing `offset`
+            let index =
+                match self.chunks.binary_search_by(|c| {
+                    if c.out_end() <= offset {
+                        Ordering::Less
+                    } else if c.out_offset > offset {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Equal
+                    }
+                }) {
+                    Ok(i) => i,
+                    Err(_) => {
+                        let msg = "read past the end of ECM image";
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  msg));
+                    }
+                };
+
+            let (kind, in_offset, unit_offset) = {
+                let chunk = &self.chunks[index];
+
+                let out_size = UNIT_OUT_SIZE[chunk.kind] as u64;
+
+                let rel = offset - chunk.out_offset;
+                let unit = rel / out_size;
+
+                (chunk.kind,
+                 chunk.in_offset + unit * UNIT_IN_SIZE[chunk.kind] as u64,
+                 (rel % out_size) as usize)
+            };
+
+            try!(self.file.seek(io::SeekFrom::Start(in_offset)));
+
+            let copied =
+                if kind == 0 {
+                    // Raw bytes, copy as much as possible from this
+                    // chunk. `in_offset` already points at `offset`
+                    // since units are 1 byte long.
+                    let end = self.chunks[index].out_end();
+                    let len = cmp::min((end - offset) as usize,
+                                       buf.len() - pos);
+
+                    try!(self.file.read_exact(&mut buf[pos..pos + len]));
+
+                    len
+                } else {
+                    let mut sector = [0; SECTOR_SIZE];
+
+                    let out =
+                        try!(decode_unit(&mut self.file, kind, &mut sector));
+
+                    let len = cmp::min(out.len() - unit_offset,
+                                       buf.len() - pos);
+
+                    let start = out.start + unit_offset;
+
+                    buf[pos..pos + len]
+                        .copy_from_slice(&sector[start..start + len]);
+
+                    len
+                };
+
+            pos += copied;
+            offset += copied as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl Image for Ecm {
+    fn image_format(&self) -> &'static str {
+        "ECM"
+    }
+
+    fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    fn read_sector(&mut self, msf: Msf) -> io::Result<XaSector> {
+        let index = msf.sector_index();
+
+        let start = self.toc.tracks()[0].start;
+
+        if index < start {
+            let msg = format!("attempted to read pregap sector {}", msf);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let pos = (index - start) as u64 * SECTOR_SIZE as u64;
+
+        let mut sector = XaSector::new();
+
+        try!(self.read_decoded(pos, &mut sector.raw));
+
+        Ok(sector)
+    }
+}
+
+/// Parse the chunk headers of the whole file
+fn index_chunks(file: &File) -> io::Result<Vec<Chunk>> {
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0; 4];
+
+    try!(reader.read_exact(&mut magic));
+
+    if &magic != b"ECM\0" {
+        return error("not an ECM file");
+    }
+
+    let mut chunks = Vec::new();
+
+    let mut in_offset = 4u64;
+    let mut out_offset = 0u64;
+
+    loop {
+        // Chunk header: 2 bits of type and a variable length count
+        // of units
+        let mut b = try!(read_byte(&mut reader));
+        in_offset += 1;
+
+        let kind = (b & 3) as usize;
+        let mut count = ((b >> 2) & 0x1f) as u64;
+        let mut shift = 5;
+
+        while b & 0x80 != 0 {
+            if shift > 32 {
+                return error("invalid ECM chunk header");
+            }
+
+            b = try!(read_byte(&mut reader));
+            in_offset += 1;
+
+            count |= ((b & 0x7f) as u64) << shift;
+            shift += 7;
+        }
+
+        if count == 0xffffffff {
+            // End of stream, followed by the EDC of the whole decoded
+            // image which we don't bother checking
+            break;
+        }
+
+        let count = count + 1;
+
+        chunks.push(Chunk {
+            kind: kind,
+            count: count,
+            out_offset: out_offset,
+            in_offset: in_offset,
+        });
+
+        let in_len = count * UNIT_IN_SIZE[kind] as u64;
+
+        // Skip the chunk's data
+        let skipped =
+            try!(io::copy(&mut (&mut reader).take(in_len), &mut io::sink()));
+
+        if skipped != in_len {
+            return error("truncated ECM file");
+        }
+
+        in_offset += in_len;
+        out_offset += count * UNIT_OUT_SIZE[kind] as u64;
+    }
+
+    Ok(chunks)
+}
+
+/// Decode a sector unit of type `kind` from `file` into `sector`,
+/// returns the range of `sector` containing the decoded unit.
+fn decode_unit(file: &mut File,
+               kind: usize,
+               sector: &mut [u8; SECTOR_SIZE])
+               -> io::Result<Range<usize>> {
+    match kind {
+        1 => {
+            sector[0..12].copy_from_slice(&SECTOR_SYNC_PATTERN);
+
+            try!(file.read_exact(&mut sector[12..15]));
+            sector[15] = 1;
+
+            try!(file.read_exact(&mut sector[16..2064]));
+
+            let edc = crc32(&sector[0..2064]);
+            set_edc(&mut sector[2064..2068], edc);
+
+            // Intermediate field, always 0
+            for b in &mut sector[2068..2076] {
+                *b = 0;
+            }
+
+            ecc::generate(sector, false);
+
+            Ok(0..SECTOR_SIZE)
+        }
+        2 => {
+            try!(file.read_exact(&mut sector[20..2072]));
+
+            copy_subheader(sector);
+
+            let edc = crc32(&sector[16..2072]);
+            set_edc(&mut sector[2072..2076], edc);
+
+            // The header is not covered by the ECC in Mode 2, it's
+            // not stored in the ECM unit anyway.
+            ecc::generate(sector, true);
+
+            Ok(16..SECTOR_SIZE)
+        }
+        _ => {
+            try!(file.read_exact(&mut sector[20..2348]));
+
+            copy_subheader(sector);
+
+            let edc = crc32(&sector[16..2348]);
+            set_edc(&mut sector[2348..2352], edc);
+
+            Ok(16..SECTOR_SIZE)
+        }
+    }
+}
+
+/// Only the second copy of the XA subheader is stored in the ECM
+/// file
+fn copy_subheader(sector: &mut [u8]) {
+    for i in 16..20 {
+        sector[i] = sector[i + 4];
+    }
+}
+
+fn set_edc(dest: &mut [u8], edc: u32) {
+    for (i, b) in dest.iter_mut().enumerate() {
+        *b = (edc >> (i * 8)) as u8;
+    }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut b = [0];
+
+    try!(reader.read_exact(&mut b));
+
+    Ok(b[0])
+}
+
+fn error<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Append an ECM chunk header for `count` units of type `kind`
+#[cfg(test)]
+fn push_chunk_header(out: &mut Vec<u8>, kind: u8, count: u32) {
+    let mut n = count.wrapping_sub(1);
+
+    let mut b = kind | ((n & 0x1f) << 2) as u8;
+    n >>= 5;
+
+    while n != 0 {
+        out.push(b | 0x80);
+        b = (n & 0x7f) as u8;
+        n >>= 7;
+    }
+
+    out.push(b);
+}
+
+#[test]
+fn ecm_matches_iso() {
+    use super::iso::Iso;
+
+    let data = super::test_user_data(3);
+
+    // Same subheader as the one regenerated by the ISO reader
+    let subheader = [0x00, 0x00, 0x08, 0x00];
+
+    let mut ecm = b"ECM\0".to_vec();
+
+    for (i, user_data) in data.chunks(2048).enumerate() {
+        let msf = Msf::try_from_sector_index(150 + i as u32).unwrap();
+        let (m, s, f) = msf.into_bcd();
+
+        // Sync pattern and header as raw bytes
+        push_chunk_header(&mut ecm, 0, 16);
+        ecm.extend_from_slice(&SECTOR_SYNC_PATTERN);
+        ecm.extend_from_slice(&[m, s, f, 2]);
+
+        // Mode 2 Form 1 unit
+        push_chunk_header(&mut ecm, 2, 1);
+        ecm.extend_from_slice(&subheader);
+        ecm.extend_from_slice(user_data);
+    }
+
+    // End of stream marker and (unchecked) EDC
+    push_chunk_header(&mut ecm, 0, 0);
+    ecm.extend_from_slice(&[0; 4]);
+
+    let ecm_path = super::test_file("ecm_matches_iso.ecm", &ecm);
+    let iso_path = super::test_file("ecm_matches_iso.iso", &data);
+
+    let mut ecm = Ecm::open(&ecm_path).unwrap();
+    let mut iso = Iso::open(&iso_path).unwrap();
+
+    assert!(ecm.toc().lead_out() == 153);
+
+    for i in 0..3 {
+        let msf = Msf::try_from_sector_index(150 + i).unwrap();
+
+        let a = ecm.read_sector(msf).unwrap();
+        let b = iso.read_sector(msf).unwrap();
+
+        assert!(&a.raw[..] == &b.raw[..]);
+
+        let a = a.validate_mode_1_2(msf).unwrap();
+
+        assert!(a.user_data() == &data[i as usize * 2048..
+                                       (i as usize + 1) * 2048]);
+    }
+}
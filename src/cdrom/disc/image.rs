@@ -0,0 +1,190 @@
+//! Disc image abstraction.
+//!
+//! Each supported image format implements the `Image` trait, the
+//! rest of the emulator only deals with absolute sector positions and
+//! a table of contents.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, Read};
+
+use super::msf::Msf;
+use super::{XaSector, SECTOR_SIZE};
+
+/// Interface to a disc image
+pub trait Image {
+    /// Return a short description of the image format (for debug
+    /// messages)
+    fn image_format(&self) -> &'static str;
+
+    /// Return the disc's table of contents
+    fn toc(&self) -> &Toc;
+
+    /// Read the raw 2352 byte sector at absolute position
+    /// `msf`. Sector 00:00:00 is the beginning of track 01's pregap.
+    fn read_sector(&mut self, msf: Msf) -> io::Result<XaSector>;
+}
+
+/// Disc table of contents
+pub struct Toc {
+    tracks: Vec<Track>,
+}
+
+impl Toc {
+    /// Build a table of contents from a list of tracks, sorted by
+    /// position. The whole disc must fit in the MSF range.
+    pub fn new(tracks: Vec<Track>) -> io::Result<Toc> {
+        if tracks.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "disc has no tracks"));
+        }
+
+        for t in &tracks {
+            let end = t.start.checked_add(t.length)
+                .and_then(|e| Msf::try_from_sector_index(e));
+
+            if t.pregap > t.start || end.is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "track is out of the MSF range"));
+            }
+        }
+
+        Ok(Toc {
+            tracks: tracks,
+        })
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Return the track containing the absolute sector `index`
+    /// (including its pregap) or None if `index` is in the lead-out
+    pub fn track_for_sector(&self, index: u32) -> Option<&Track> {
+        self.tracks.iter()
+            .find(|t| index >= t.pregap_start() && index < t.end())
+    }
+
+    /// Return the absolute sector index of the end of the last track
+    /// (i.e. the beginning of the lead-out)
+    pub fn lead_out(&self) -> u32 {
+        // Toc::new makes sure we have at least one track
+        self.tracks[self.tracks.len() - 1].end()
+    }
+}
+
+/// Description of a single track
+#[derive(Clone, Copy, Debug)]
+pub struct Track {
+    /// Track number (starting at 1)
+    pub number: u8,
+    /// Track content
+    pub format: TrackFormat,
+    /// Absolute sector index of INDEX 01 (beginning of the track
+    /// data after the pregap)
+    pub start: u32,
+    /// Number of pregap sectors before `start`
+    pub pregap: u32,
+    /// Number of sectors in the track, not including the pregap
+    pub length: u32,
+}
+
+impl Track {
+    /// Absolute sector index of INDEX 00 (beginning of the pregap)
+    pub fn pregap_start(&self) -> u32 {
+        self.start - self.pregap
+    }
+
+    /// Absolute sector index of the first sector after the track
+    pub fn end(&self) -> u32 {
+        self.start + self.length
+    }
+}
+
+/// Type of data stored in a track
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackFormat {
+    /// CD-DA audio
+    Audio,
+    /// CD-ROM Mode 1
+    Mode1,
+    /// CD-ROM XA Mode 2
+    Mode2Xa,
+}
+
+/// Raw BIN image containing a single data track of 2352 byte
+/// sectors. The track 01 pregap is not stored in the image.
+pub struct Bin {
+    file: File,
+    toc: Toc,
+}
+
+impl Bin {
+    pub fn open(path: &Path) -> io::Result<Bin> {
+        let file = try!(File::open(path));
+
+        let len = try!(file.metadata()).len();
+
+        if len % SECTOR_SIZE as u64 != 0 {
+            let msg = format!("BIN file size ({}) is not a multiple \
+                               of the sector size", len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        // XXX for now I assume that the track 01 pregap is 2 seconds
+        // (150 sectors), needs to parse cuesheet.
+        let track = Track {
+            number: 1,
+            format: TrackFormat::Mode2Xa,
+            start: 150,
+            pregap: 150,
+            length: (len / SECTOR_SIZE as u64) as u32,
+        };
+
+        Ok(Bin {
+            file: file,
+            toc: try!(Toc::new(vec![track])),
+        })
+    }
+}
+
+impl Image for Bin {
+    fn image_format(&self) -> &'static str {
+        "BIN"
+    }
+
+    fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    fn read_sector(&mut self, msf: Msf) -> io::Result<XaSector> {
+        let index = msf.sector_index();
+
+        let start = self.toc.tracks[0].start;
+
+        if index < start {
+            let msg = format!("attempted to read pregap sector {}", msf);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        // Convert in a byte offset in the bin file
+        let pos = (index - start) as u64 * SECTOR_SIZE as u64;
+
+        try!(self.file.seek(io::SeekFrom::Start(pos)));
+
+        let mut sector = XaSector::new();
+        let mut nread = 0;
+
+        while nread < SECTOR_SIZE {
+            nread +=
+                match try!(self.file.read(&mut sector.raw[nread..])) {
+                    0 => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                   "short sector read")),
+                    n => n,
+                };
+        }
+
+        Ok(sector)
+    }
+}
@@ -0,0 +1,108 @@
+//! "Cooked" ISO images containing only the 2048 bytes of user data
+//! of each sector.
+//!
+//! PlayStation discs use CD-ROM XA Mode 2 Form 1 sectors, the sync
+//! pattern, header, XA subheader, EDC and ECC are regenerated on the
+//! fly. Since the subheader is lost we can't reproduce XA audio or
+//! the "end of record" flags, games relying on those won't work.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, Read};
+
+use super::image::{Image, Toc, Track, TrackFormat};
+use super::msf::Msf;
+use super::XaSector;
+
+/// Size of a sector in an ISO image
+const ISO_SECTOR_SIZE: usize = 2048;
+
+/// XA subheader used for all the sectors: file 0, channel 0, submode
+/// "data", coding info 0.
+const DATA_SUBHEADER: [u8; 4] = [0x00, 0x00, 0x08, 0x00];
+
+pub struct Iso {
+    file: File,
+    toc: Toc,
+}
+
+impl Iso {
+    pub fn open(path: &Path) -> io::Result<Iso> {
+        let file = try!(File::open(path));
+
+        let len = try!(file.metadata()).len();
+
+        if len % ISO_SECTOR_SIZE as u64 != 0 {
+            let msg = format!("ISO file size ({}) is not a multiple \
+                               of the sector size", len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        let track = Track {
+            number: 1,
+            format: TrackFormat::Mode2Xa,
+            start: 150,
+            pregap: 150,
+            length: (len / ISO_SECTOR_SIZE as u64) as u32,
+        };
+
+        Ok(Iso {
+            file: file,
+            toc: try!(Toc::new(vec![track])),
+        })
+    }
+}
+
+impl Image for Iso {
+    fn image_format(&self) -> &'static str {
+        "ISO"
+    }
+
+    fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    fn read_sector(&mut self, msf: Msf) -> io::Result<XaSector> {
+        let index = msf.sector_index();
+
+        let start = self.toc.tracks()[0].start;
+
+        if index < start {
+            let msg = format!("attempted to read pregap sector {}", msf);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let pos = (index - start) as u64 * ISO_SECTOR_SIZE as u64;
+
+        try!(self.file.seek(io::SeekFrom::Start(pos)));
+
+        let mut data = [0; ISO_SECTOR_SIZE];
+
+        try!(self.file.read_exact(&mut data));
+
+        Ok(XaSector::new_mode2_form1(msf, &DATA_SUBHEADER, &data))
+    }
+}
+
+#[test]
+fn iso_read_sector() {
+    let data = super::test_user_data(3);
+
+    let path = super::test_file("iso_read_sector.iso", &data);
+
+    let mut iso = Iso::open(&path).unwrap();
+
+    assert!(iso.toc().lead_out() == 153);
+
+    for i in 0..3 {
+        let msf = Msf::try_from_sector_index(150 + i as u32).unwrap();
+
+        let sector = iso.read_sector(msf).unwrap();
+
+        // The regenerated sync, header and EDC must be valid
+        let sector = sector.validate_mode_1_2(msf).unwrap();
+
+        assert!(sector.user_data() == &data[i * 2048..(i + 1) * 2048]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Minimal read-only ISO9660 filesystem implementation, used to
+//! identify the game by looking at the files on the disc.
+//!
+//! Only the primary volume descriptor is supported, no Joliet or Rock
+//! Ridge extensions (PlayStation discs don't use them anyway).
+
+use std::io;
+
+use super::image::Image;
+use super::msf::Msf;
+use super::read_data_sector;
+
+/// Size of a logical block
+const BLOCK_SIZE: usize = 2048;
+
+/// Index of the first sector of the disc's data track, the logical
+/// block addresses are relative to it
+const LBA_OFFSET: u32 = 150;
+
+/// Offset of the first volume descriptor (in logical blocks)
+const VOLUME_DESCRIPTOR_LBA: u32 = 16;
+
+/// Largest file we accept to load in memory, this code is only meant
+/// to load small configuration files
+const MAX_FILE_SIZE: u32 = 1024 * 1024;
+
+/// ISO9660 filesystem reader
+pub struct Iso9660<'a> {
+    image: &'a mut Image,
+    /// Root directory
+    root: Entry,
+}
+
+impl<'a> Iso9660<'a> {
+    /// Parse the primary volume descriptor of `image`
+    pub fn new(image: &'a mut Image) -> io::Result<Iso9660<'a>> {
+        let pvd = try!(read_block(image, VOLUME_DESCRIPTOR_LBA));
+
+        // Volume descriptor type 1 followed by the "CD001" identifier
+        if &pvd[0..6] != b"\x01CD001" {
+            return error("couldn't find ISO9660 primary volume descriptor");
+        }
+
+        // The root directory record is stored directly in the volume
+        // descriptor
+        let root =
+            match Entry::parse(&pvd[156..190]) {
+                Some(e) => e,
+                None => return error("invalid ISO9660 root directory"),
+            };
+
+        Ok(Iso9660 {
+            image: image,
+            root: root,
+        })
+    }
+
+    /// Look up the file at `path`. Components are separated by
+    /// backslashes or slashes and are not case sensitive, the version
+    /// suffix (";1") can be omitted.
+    pub fn lookup(&mut self, path: &str) -> io::Result<Entry> {
+        let mut entry = self.root.clone();
+
+        for component in path.split(|c| c == '\\' || c == '/') {
+            if component.is_empty() {
+                continue;
+            }
+
+            if !entry.is_dir {
+                return not_found(path);
+            }
+
+            let entries = try!(self.read_dir(&entry));
+
+            entry =
+                match entries.into_iter().find(|e| e.matches(component)) {
+                    Some(e) => e,
+                    None => return not_found(path),
+                };
+        }
+
+        Ok(entry)
+    }
+
+    /// Read the full contents of the file at `path`
+    pub fn read_file(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let entry = try!(self.lookup(path));
+
+        if entry.is_dir {
+            let msg = format!("{} is a directory", path);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        if entry.size > MAX_FILE_SIZE {
+            let msg = format!("{} is too big ({} bytes)", path, entry.size);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        self.read_extent(&entry)
+    }
+
+    /// Return the list of entries in directory `dir`
+    fn read_dir(&mut self, dir: &Entry) -> io::Result<Vec<Entry>> {
+        if dir.size > MAX_FILE_SIZE {
+            return error("ISO9660 directory is too big");
+        }
+
+        let data = try!(self.read_extent(dir));
+
+        let mut entries = Vec::new();
+
+        // Directory records can't cross block boundaries, the end of
+        // each block is padded with zeroes
+        for block in data.chunks(BLOCK_SIZE) {
+            let mut pos = 0;
+
+            while pos < block.len() {
+                let len = block[pos] as usize;
+
+                if len == 0 {
+                    // Padding until the end of the block
+                    break;
+                }
+
+                if pos + len > block.len() {
+                    return error("invalid ISO9660 directory record");
+                }
+
+                if let Some(e) = Entry::parse(&block[pos..pos + len]) {
+                    entries.push(e);
+                }
+
+                pos += len;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Read the data of `entry`
+    fn read_extent(&mut self, entry: &Entry) -> io::Result<Vec<u8>> {
+        let size = entry.size as usize;
+
+        let mut data = Vec::with_capacity(size);
+
+        let mut lba = entry.lba;
+
+        while data.len() < size {
+            let block = try!(read_block(self.image, lba));
+
+            let len = ::std::cmp::min(size - data.len(), BLOCK_SIZE);
+
+            data.extend_from_slice(&block[0..len]);
+
+            lba += 1;
+        }
+
+        Ok(data)
+    }
+}
+
+/// Directory entry
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Name of the entry, including the version suffix for files
+    name: String,
+    /// Logical block address of the first block of the entry
+    lba: u32,
+    /// Size in bytes
+    size: u32,
+    /// True if the entry is a directory
+    is_dir: bool,
+}
+
+impl Entry {
+    /// Parse a directory record. Returns None if the record is
+    /// invalid.
+    fn parse(record: &[u8]) -> Option<Entry> {
+        if record.len() < 33 {
+            return None;
+        }
+
+        let name_len = record[32] as usize;
+
+        if record.len() < 33 + name_len {
+            return None;
+        }
+
+        // Extent location and size are stored in both little and
+        // big endian, we only use the little endian copy
+        let lba = le32(&record[2..6]);
+        let size = le32(&record[10..14]);
+
+        let flags = record[25];
+
+        let name: String =
+            record[33..33 + name_len].iter()
+            .map(|&b| b as char)
+            .collect();
+
+        Some(Entry {
+            name: name,
+            lba: lba,
+            size: size,
+            is_dir: flags & 2 != 0,
+        })
+    }
+
+    /// Return true if the entry's name matches `name`, ignoring the
+    /// case and the version suffix
+    fn matches(&self, name: &str) -> bool {
+        let strip = |n: &str| -> String {
+            let n =
+                match n.find(';') {
+                    Some(p) => &n[..p],
+                    None => n,
+                };
+
+            // Files without an extension can have a trailing dot
+            let n =
+                if n.ends_with('.') {
+                    &n[..n.len() - 1]
+                } else {
+                    n
+                };
+
+            n.to_ascii_uppercase()
+        };
+
+        strip(&self.name) == strip(name)
+    }
+}
+
+/// Read the 2048 bytes of user data of logical block `lba`
+fn read_block(image: &mut Image, lba: u32) -> io::Result<[u8; BLOCK_SIZE]> {
+    // The LBA comes straight from the image, it can be bogus
+    let msf =
+        match lba.checked_add(LBA_OFFSET)
+                 .and_then(|i| Msf::try_from_sector_index(i)) {
+            Some(msf) => msf,
+            None => return error("ISO9660 block is out of range"),
+        };
+
+    let sector = try!(read_data_sector(image, msf));
+
+    let mut block = [0; BLOCK_SIZE];
+
+    block.copy_from_slice(sector.user_data());
+
+    Ok(block)
+}
+
+fn le32(b: &[u8]) -> u32 {
+    (b[0] as u32) | ((b[1] as u32) << 8)
+        | ((b[2] as u32) << 16) | ((b[3] as u32) << 24)
+}
+
+fn not_found<T>(path: &str) -> io::Result<T> {
+    let msg = format!("{}: file not found", path);
+
+    Err(io::Error::new(io::ErrorKind::NotFound, msg))
+}
+
+fn error<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
@@ -1,27 +1,46 @@
 use std::path::Path;
-use std::fs::File;
 use std::io;
-use std::io::{Seek, Read};
 
 use self::crc::crc32;
 use self::msf::Msf;
+use self::image::Image;
+use self::subchannel::{SubchannelQ, SubchannelPatches};
+use self::system_cnf::SystemCnf;
+use self::iso9660::Iso9660;
 
 pub mod msf;
+pub mod set;
+pub mod image;
+pub mod subchannel;
+pub mod iso9660;
+pub mod system_cnf;
 mod crc;
+mod cue;
+mod ecc;
+mod chd;
+mod ecm;
+mod pbp;
+mod iso;
+mod decompress;
 
 /// PlayStation disc.
 ///
 /// XXX: add support for CD-DA? Not really useful but shouldn't
 /// be very hard either. We need to support audio tracks anyway...
 pub struct Disc {
-    /// BIN file
-    file: File,
+    /// Disc image
+    image: Box<Image>,
     /// Disc region
     region: Region,
+    /// Contents of the SYSTEM.CNF file, None if the filesystem
+    /// couldn't be read
+    system_cnf: Option<SystemCnf>,
+    /// Sectors with non-standard Q subchannel data (LibCrypt)
+    subchannel_patches: SubchannelPatches,
 }
 
 /// Disc region coding
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Region {
     /// Japan (NTSC): SCEI
     Japan,
@@ -32,106 +51,198 @@ pub enum Region {
 }
 
 impl Disc {
-    /// Reify a disc from file at `path` and attempt to identify it.
+    /// Reify a disc from file at `path` and attempt to identify
+    /// it. The image format is selected based on the extension of
+    /// `path`, unknown extensions are treated as raw BIN files. If an
+    /// SBI or LSD file with the same name is found next to the image
+    /// it's used to patch the subchannel data.
     pub fn from_path(path: &Path) -> io::Result<Disc> {
-        let file = try!(File::open(path));
+        let extension =
+            path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
 
-        let disc = Disc {
-            file: file,
-            // Use a dummy id for now.
-            region: Region::Japan,
-        };
+        let image: Box<Image> =
+            match extension.as_ref().map(|e| e.as_ref()) {
+                Some("cue") => {
+                    let bin = try!(cue::data_file(path));
+
+                    Box::new(try!(image::Bin::open(&bin)))
+                }
+                Some("chd") =>
+                    Box::new(try!(chd::Chd::open(path))),
+                Some("ecm") =>
+                    Box::new(try!(ecm::Ecm::open(path))),
+                Some("pbp") =>
+                    Box::new(try!(pbp::Pbp::open(path, 0))),
+                Some("iso") =>
+                    Box::new(try!(iso::Iso::open(path))),
+                _ =>
+                    Box::new(try!(image::Bin::open(path))),
+            };
+
+        Disc::from_image(path, image)
+    }
+
+    /// Load all the discs contained in the multi-disc PBP file at
+    /// `path`
+    pub fn from_pbp(path: &Path) -> io::Result<Vec<Disc>> {
+        let count = try!(pbp::disc_count(path));
+
+        (0..count).map(|n| {
+            let image = try!(pbp::Pbp::open(path, n));
+
+            Disc::from_image(path, Box::new(image))
+        }).collect()
+    }
+
+    fn from_image(path: &Path, mut image: Box<Image>) -> io::Result<Disc> {
+        let system_cnf = read_system_cnf(&mut *image);
 
-        disc.extract_region()
+        let serial = system_cnf.as_ref().and_then(|c| c.serial());
+
+        let region = try!(extract_region(&mut *image,
+                                         serial.as_ref().map(|s| s.as_ref())));
+
+        let subchannel_patches = try!(SubchannelPatches::for_image(path));
+
+        Ok(Disc {
+            image: image,
+            region: region,
+            system_cnf: system_cnf,
+            subchannel_patches: subchannel_patches,
+        })
     }
 
     pub fn region(&self) -> Region {
         self.region
     }
 
-    /// Attempt to discover the region of the disc. This way we know
-    /// which string to return in the CD-ROM drive's "get id" command
-    /// and we can also decide which BIOS and output video standard to
-    /// use based on the game disc.
-    fn extract_region(mut self) -> io::Result<Disc> {
-        // In order to identify the type of disc we're going to use
-        // sector 00:02:04 which should contain the "Licensed by..."
-        // string.
-        let msf = Msf::from_bcd(0x00, 0x02, 0x04);
-
-        let sector = try!(self.read_data_sector(msf));
-
-        // On the discs I've tried we always have an ASCII license
-        // string in the first 76 data bytes (after the header). I
-        // hope it's always like that...
-        let license_blob = &sector.data_bytes()[24..100];
-
-        // There are spaces everywhere in the string (including in the
-        // middle of some words), let's clean it up and convert to a
-        // string
-        let license: String = license_blob.iter()
-            .filter_map(|&b| {
-                match b {
-                    b'A'...b'z' => Some(b as char),
-                    _ => None,
-                }
-            })
-            .collect();
-
-        self.region =
-            match license.as_ref() {
-                "LicensedbySonyComputerEntertainmentInc"
-                    => Region::Japan,
-                "LicensedbySonyComputerEntertainmentAmerica"
-                    => Region::NorthAmerica,
-                "LicensedbySonyComputerEntertainmentEurope"
-                    => Region::Europe,
-                _ => {
-                    let msg =
-                        format!("couldn't identify disc region string: {}",
-                                license);
-                    return Err(io::Error::new(io::ErrorKind::InvalidData,
-                                              msg));
-                }
-            };
+    /// Return the game's serial number (e.g. "SLUS-00594") if it
+    /// could be extracted from the SYSTEM.CNF
+    pub fn serial(&self) -> Option<String> {
+        self.system_cnf.as_ref().and_then(|c| c.serial())
+    }
 
-        Ok(self)
+    /// Return the parsed SYSTEM.CNF, None if the disc's filesystem
+    /// couldn't be read
+    pub fn system_cnf(&self) -> Option<&SystemCnf> {
+        self.system_cnf.as_ref()
+    }
+
+    /// Return the disc's table of contents
+    pub fn toc(&self) -> &image::Toc {
+        self.image.toc()
+    }
+
+    /// Return a short description of the disc image format
+    pub fn image_format(&self) -> &'static str {
+        self.image.image_format()
+    }
+
+    /// Return the number of sectors with patched Q subchannel data
+    pub fn subchannel_patch_count(&self) -> usize {
+        self.subchannel_patches.len()
+    }
+
+    /// Return the Q subchannel data for the sector at `msf`
+    pub fn subchannel_q(&self, msf: Msf) -> SubchannelQ {
+        let index = msf.sector_index();
+
+        let toc = self.image.toc();
+
+        match self.subchannel_patches.get(toc, index) {
+            Some(q) => q,
+            None => SubchannelQ::from_toc(toc, index),
+        }
     }
 
     /// Read a Mode 1 or 2 CD-ROM XA sector and validate it. Will
     /// return an error if used on a CD-DA raw audio sector.
     pub fn read_data_sector(&mut self, msf: Msf) -> io::Result<XaSector> {
-        let sector = try!(self.read_sector(msf));
-
-        sector.validate_mode_1_2(msf)
+        read_data_sector(&mut *self.image, msf)
     }
+}
 
-    /// Read a raw CD sector without any validation. For Mode 1 and 2
-    /// sectors XaSector::validate_mode_1_2 should then be called to
-    /// make sure the sector is valid.
-    fn read_sector(&mut self, msf: Msf) -> io::Result<XaSector> {
-        // XXX for now I assume that the track 01 pregap is 2 seconds
-        // (150 sectors), needs to parse cuesheet.
-        let index = msf.sector_index() - 150;
-
-        // Convert in a byte offset in the bin file
-        let pos = index as u64 * SECTOR_SIZE as u64;
+/// Read a Mode 1 or 2 CD-ROM XA sector from `image` and validate it
+fn read_data_sector(image: &mut Image, msf: Msf) -> io::Result<XaSector> {
+    let sector = try!(image.read_sector(msf));
 
-        try!(self.file.seek(io::SeekFrom::Start(pos)));
+    sector.validate_mode_1_2(msf)
+}
 
-        let mut sector = XaSector::new();
-        let mut nread = 0;
-
-        while nread < SECTOR_SIZE {
-            nread +=
-                match try!(self.file.read(&mut sector.raw[nread..])) {
-                    0 => return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                   "short sector read")),
-                    n => n,
-                };
+/// Attempt to load and parse the SYSTEM.CNF at the root of the
+/// disc. If the file doesn't exist the BIOS boots "PSX.EXE" with the
+/// default configuration. Returns None if the filesystem couldn't be
+/// read.
+fn read_system_cnf(image: &mut Image) -> Option<SystemCnf> {
+    let cnf =
+        Iso9660::new(image)
+        .and_then(|mut fs| fs.read_file("SYSTEM.CNF"))
+        .and_then(|data| SystemCnf::parse(&data));
+
+    match cnf {
+        Ok(c) => Some(c),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            Some(SystemCnf::default()),
+        Err(e) => {
+            println!("Couldn't read SYSTEM.CNF: {}", e);
+            None
         }
+    }
+}
+
+/// Attempt to discover the region of the disc. This way we know
+/// which string to return in the CD-ROM drive's "get id" command and
+/// we can also decide which BIOS and output video standard to use
+/// based on the game disc.
+///
+/// The region is normally identified by the license string, if it's
+/// missing or unknown we fall back on the game's `serial` (if any).
+fn extract_region(image: &mut Image,
+                  serial: Option<&str>) -> io::Result<Region> {
+    // In order to identify the type of disc we're going to use
+    // sector 00:02:04 which should contain the "Licensed by..."
+    // string.
+    let msf = Msf::from_bcd(0x00, 0x02, 0x04);
+
+    let sector = try!(read_data_sector(image, msf));
+
+    // On the discs I've tried we always have an ASCII license string
+    // in the first 76 data bytes (after the header). I hope it's
+    // always like that...
+    let license_blob = &sector.data_bytes()[24..100];
+
+    // There are spaces everywhere in the string (including in the
+    // middle of some words), let's clean it up and convert to a
+    // string
+    let license: String = license_blob.iter()
+        .filter_map(|&b| {
+            match b {
+                b'A'...b'z' => Some(b as char),
+                _ => None,
+            }
+        })
+        .collect();
+
+    let region =
+        match license.as_ref() {
+            "LicensedbySonyComputerEntertainmentInc"
+                => Some(Region::Japan),
+            "LicensedbySonyComputerEntertainmentAmerica"
+                => Some(Region::NorthAmerica),
+            "LicensedbySonyComputerEntertainmentEurope"
+                => Some(Region::Europe),
+            _ => None,
+        };
 
-        Ok(sector)
+    match region.or_else(|| serial.and_then(system_cnf::serial_region)) {
+        Some(r) => Ok(r),
+        None => {
+            let msg = format!("couldn't identify disc region string: {}",
+                              license);
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
     }
 }
 
@@ -148,6 +259,64 @@ impl XaSector {
         }
     }
 
+    /// Build a Mode 1 sector at `msf` containing the 2048 bytes of
+    /// `data`. The EDC and ECC are regenerated.
+    fn new_mode1(msf: Msf, data: &[u8]) -> XaSector {
+        let mut sector = XaSector::new();
+
+        sector.set_header(msf, 1);
+
+        sector.raw[16..2064].copy_from_slice(data);
+
+        let edc = crc32(&sector.raw[0..2064]);
+        sector.set_edc(2064, edc);
+
+        ecc::generate(&mut sector.raw, false);
+
+        sector
+    }
+
+    /// Build a Mode 2 Form 1 sector at `msf` with the given 4 byte XA
+    /// `subheader` and containing the 2048 bytes of `data`. The EDC
+    /// and ECC are regenerated.
+    fn new_mode2_form1(msf: Msf, subheader: &[u8], data: &[u8]) -> XaSector {
+        let mut sector = XaSector::new();
+
+        sector.set_header(msf, 2);
+
+        // The subheader is repeated twice
+        sector.raw[16..20].copy_from_slice(subheader);
+        sector.raw[20..24].copy_from_slice(subheader);
+
+        sector.raw[24..2072].copy_from_slice(data);
+
+        let edc = crc32(&sector.raw[16..2072]);
+        sector.set_edc(2072, edc);
+
+        // For Mode 2 the header is not covered by the ECC
+        ecc::generate(&mut sector.raw, true);
+
+        sector
+    }
+
+    /// Write the sync pattern and the header
+    fn set_header(&mut self, msf: Msf, mode: u8) {
+        let (m, s, f) = msf.into_bcd();
+
+        self.raw[0..12].copy_from_slice(&SECTOR_SYNC_PATTERN);
+        self.raw[12] = m;
+        self.raw[13] = s;
+        self.raw[14] = f;
+        self.raw[15] = mode;
+    }
+
+    /// Store the little endian `edc` at `offset`
+    fn set_edc(&mut self, offset: usize, edc: u32) {
+        for i in 0..4 {
+            self.raw[offset + i] = (edc >> (i * 8)) as u8;
+        }
+    }
+
     /// Return payload data byte at `index`
     pub fn data_byte(&self, index: u16) -> u8 {
         let index = index as usize;
@@ -160,6 +329,15 @@ impl XaSector {
         &self.raw
     }
 
+    /// Return the 2048 bytes of user data of a Mode 1 or Mode 2 Form
+    /// 1 sector
+    fn user_data(&self) -> &[u8] {
+        match self.raw[15] {
+            1 => &self.raw[16..2064],
+            _ => &self.raw[24..2072],
+        }
+    }
+
     /// Validate CD-ROM XA Mode 1 or 2 sector
     fn validate_mode_1_2(self, msf: Msf) -> io::Result<XaSector> {
         let error = |what| {
@@ -181,14 +359,35 @@ impl XaSector {
         let mode = self.raw[15];
 
         match mode {
-            // XXX handle Mode 1
-            1 => panic!("Unhandled Mode 1 sector at {}", msf),
+            1 => self.validate_mode1(),
             2 => self.validate_mode2(),
             _ => error(format!("unhandled sector mode {} at {}",
                                mode, msf)),
         }
     }
 
+    /// CD-ROM Mode 1: 0x800 bytes of data protected by a 32bit CRC
+    /// (covering the sync pattern and header as well) and 276 bytes
+    /// of error correction codes. Not used by PlayStation games but
+    /// some discs might contain Mode 1 sectors anyway.
+    fn validate_mode1(self) -> io::Result<XaSector> {
+        let crc = crc32(&self.raw[0..2064]);
+
+        let sector_crc = self.raw[2064] as u32
+            | ((self.raw[2065] as u32) << 8)
+            | ((self.raw[2066] as u32) << 16)
+            | ((self.raw[2067] as u32) << 24);
+
+        if crc != sector_crc {
+            let msg =
+                format!("Sector {}: Mode 1 CRC missmatch", self.msf());
+
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        Ok(self)
+    }
+
     /// Parse and validate CD-ROM XA mode 2 sector.
     ///
     /// Regular CD-ROM defines mode2 as just containing 0x920 bytes of
@@ -288,3 +487,24 @@ const SECTOR_SYNC_PATTERN: [u8; 12] = [0x00,
                                        0xff, 0xff, 0xff, 0xff, 0xff,
                                        0xff, 0xff, 0xff, 0xff, 0xff,
                                        0x00];
+
+/// Write `data` to a file named `name` in the temporary directory
+/// and return its path. Used by the image format tests.
+#[cfg(test)]
+fn test_file(name: &str, data: &[u8]) -> ::std::path::PathBuf {
+    use std::io::Write;
+
+    let path = ::std::env::temp_dir().join(format!("rustation-{}", name));
+
+    let mut file = ::std::fs::File::create(&path).unwrap();
+
+    file.write_all(data).unwrap();
+
+    path
+}
+
+/// Build the 2048 bytes of user data of `count` test sectors
+#[cfg(test)]
+fn test_user_data(count: usize) -> Vec<u8> {
+    (0..count * 2048).map(|i| (i * 7 + i / 2048) as u8).collect()
+}
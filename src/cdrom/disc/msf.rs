@@ -32,6 +32,31 @@ impl Msf {
         msf
     }
 
+    /// Return the BCD-encoded (minute, second, frame) triplet
+    pub fn into_bcd(self) -> (u8, u8, u8) {
+        let Msf(m, s, f) = self;
+
+        (m, s, f)
+    }
+
+    /// Build an MSF from a sector index. Inverse of `sector_index`.
+    /// Returns None if `index` is past 99:59:74.
+    pub fn try_from_sector_index(index: u32) -> Option<Msf> {
+        let to_bcd = |v: u32| -> u8 {
+            (((v / 10) << 4) | (v % 10)) as u8
+        };
+
+        let m = index / (60 * 75);
+        let s = (index / 75) % 60;
+        let f = index % 75;
+
+        if m > 99 {
+            return None;
+        }
+
+        Some(Msf(to_bcd(m), to_bcd(s), to_bcd(f)))
+    }
+
     /// Convert an MSF "coordinate" into a sector index. In this
     /// convention sector 0 is 00:00:00 (i.e. before track 01's
     /// pregap).
@@ -111,3 +136,11 @@ impl cmp::Ord for Msf {
         a.cmp(&b)
     }
 }
+
+#[test]
+fn msf_sector_index_range() {
+    let last = Msf::from_bcd(0x99, 0x59, 0x74);
+
+    assert!(Msf::try_from_sector_index(last.sector_index()) == Some(last));
+    assert!(Msf::try_from_sector_index(last.sector_index() + 1).is_none());
+}
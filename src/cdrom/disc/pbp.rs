@@ -0,0 +1,453 @@
+//! PSP "EBOOT.PBP" images containing a PlayStation game.
+//!
+//! The PBP file is a container, the disc image is stored in the
+//! DATA.PSAR section as a "PSISOIMG0000" blob:
+//!
+//! * 0x800: table of contents, same format as the lead-in Q
+//!   subchannel entries
+//! * 0x4000: block index, one 32 byte entry per block
+//! * 0x100000: block data, each block contains 16 raw sectors
+//!   compressed with DEFLATE (or stored uncompressed if compression
+//!   wouldn't save any space)
+//!
+//! Multi-disc games store a "PSTITLEIMG" blob instead, containing
+//! the offsets of up to five PSISOIMG.
+//!
+//! Only unencrypted images (as created by "popstation" and similar
+//! tools) are supported.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, Read};
+use std::cmp;
+
+use super::image::{Image, Toc, Track, TrackFormat};
+use super::msf::Msf;
+use super::{XaSector, SECTOR_SIZE};
+use super::decompress::inflate;
+
+/// Number of sectors per compressed block
+const BLOCK_SECTORS: usize = 16;
+/// Size of an uncompressed block
+const BLOCK_SIZE: usize = BLOCK_SECTORS * SECTOR_SIZE;
+
+pub struct Pbp {
+    file: File,
+    /// File offset and size of each block
+    blocks: Vec<(u64, usize)>,
+    toc: Toc,
+    /// Last decompressed block
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl Pbp {
+    /// Open the disc number `disc` (starting at 0) of the PBP file
+    /// at `path`
+    pub fn open(path: &Path, disc: usize) -> io::Result<Pbp> {
+        let mut file = try!(File::open(path));
+
+        let iso =
+            match try!(disc_offsets(&mut file)).get(disc) {
+                Some(&o) => o,
+                None => return error("invalid PBP disc number"),
+            };
+
+        let blocks = try!(read_block_index(&mut file, iso));
+
+        let toc = try!(read_toc(&mut file, iso, blocks.len()));
+
+        Ok(Pbp {
+            file: file,
+            blocks: blocks,
+            toc: toc,
+            cache: None,
+        })
+    }
+
+    /// Return the uncompressed contents of `block`
+    fn block(&mut self, block: usize) -> io::Result<&[u8]> {
+        let cached =
+            match self.cache {
+                Some((b, _)) => b == block,
+                None => false,
+            };
+
+        if !cached {
+            let (offset, size) =
+                match self.blocks.get(block) {
+                    Some(&b) => b,
+                    None => return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "read past the end of PBP image")),
+                };
+
+            let mut compressed = vec![0; size];
+
+            try!(read_at(&mut self.file, offset, &mut compressed));
+
+            let data =
+                if size == BLOCK_SIZE {
+                    // Stored uncompressed
+                    compressed
+                } else {
+                    let mut data = vec![0; BLOCK_SIZE];
+
+                    let len = try!(inflate::inflate(&compressed, &mut data));
+
+                    // The last block can be shorter
+                    data.truncate(len);
+
+                    data
+                };
+
+            self.cache = Some((block, data));
+        }
+
+        match self.cache {
+            Some((_, ref data)) => Ok(data),
+            None => unreachable!(),
+        }
+    }
+}
+
+impl Image for Pbp {
+    fn image_format(&self) -> &'static str {
+        "PBP"
+    }
+
+    fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    fn read_sector(&mut self, msf: Msf) -> io::Result<XaSector> {
+        let index = msf.sector_index();
+
+        // The image starts with track 01's INDEX 01
+        if index < 150 {
+            let msg = format!("attempted to read pregap sector {}", msf);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let index = (index - 150) as usize;
+
+        let offset = (index % BLOCK_SECTORS) * SECTOR_SIZE;
+
+        let mut sector = XaSector::new();
+
+        {
+            let data = try!(self.block(index / BLOCK_SECTORS));
+
+            if offset + SECTOR_SIZE > data.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "short sector read"));
+            }
+
+            sector.raw.copy_from_slice(&data[offset..offset + SECTOR_SIZE]);
+        }
+
+        Ok(sector)
+    }
+}
+
+/// Return the number of discs in the PBP file at `path`
+pub fn disc_count(path: &Path) -> io::Result<usize> {
+    let mut file = try!(File::open(path));
+
+    disc_offsets(&mut file).map(|d| d.len())
+}
+
+/// Return the file offset of the PSISOIMG of each disc in the PBP
+/// `file`
+fn disc_offsets(file: &mut File) -> io::Result<Vec<u64>> {
+    let mut header = [0; 0x28];
+
+    try!(read_at(file, 0, &mut header));
+
+    if &header[0..4] != b"\0PBP" {
+        return error("not a PBP file");
+    }
+
+    // The DATA.PSAR offset is the last of the 8 section offsets
+    let psar = le32(&header[0x24..]) as u64;
+
+    let mut magic = [0; 16];
+
+    try!(read_at(file, psar, &mut magic));
+
+    let offsets =
+        if &magic == b"PSTITLEIMG000000" {
+            // Multi-disc image, the offsets of the individual discs
+            // relative to the DATA.PSAR are stored at 0x200. There can
+            // be up to 5 discs, unused entries are 0.
+            let mut table = [0; 4 * 5];
+
+            try!(read_at(file, psar + 0x200, &mut table));
+
+            table.chunks(4)
+                .map(|e| le32(e) as u64)
+                .take_while(|&o| o != 0)
+                .map(|o| psar + o)
+                .collect()
+        } else {
+            vec![psar]
+        };
+
+    if offsets.is_empty() {
+        return error("empty PBP disc table");
+    }
+
+    for &iso in &offsets {
+        try!(read_at(file, iso, &mut magic));
+
+        if &magic[0..12] != b"PSISOIMG0000" {
+            return error("PBP doesn't contain a PlayStation disc image");
+        }
+    }
+
+    Ok(offsets)
+}
+
+/// Read the block index of the PSISOIMG at `iso`
+fn read_block_index(file: &mut File,
+                    iso: u64) -> io::Result<Vec<(u64, usize)>> {
+    // The index goes up to the beginning of the data at 0x100000
+    let mut index = vec![0; 0x100000 - 0x4000];
+
+    try!(read_at(file, iso + 0x4000, &mut index));
+
+    let data_start = iso + 0x100000;
+
+    let blocks: Vec<_> =
+        index.chunks(0x20)
+        .map(|e| (le32(e) as u64, (e[4] as usize) | ((e[5] as usize) << 8)))
+        .take_while(|&(_, size)| size != 0)
+        .map(|(offset, size)| (data_start + offset, size))
+        .collect();
+
+    if blocks.is_empty() {
+        return error("empty PBP block index");
+    }
+
+    Ok(blocks)
+}
+
+/// Parse the table of contents of the PSISOIMG at `iso`. Each entry
+/// is 10 bytes long:
+///
+/// * 0: control/ADR, 0x41 for data tracks and 0x01 for audio tracks
+/// * 2: track number or pointer (BCD)
+/// * 3-5: running time relative to the track (BCD MSF), unused
+/// * 7-9: INDEX 01 position (BCD MSF)
+///
+/// The first three entries are pointers giving the first track
+/// number, the last track number and the lead-out position.
+fn read_toc(file: &mut File, iso: u64, nblocks: usize) -> io::Result<Toc> {
+    let mut raw = [0; 10 * 102];
+
+    try!(read_at(file, iso + 0x800, &mut raw));
+
+    let entry_msf = |e: &[u8]| -> Option<u32> {
+        let valid = e.iter().all(|&b| b <= 0x99 && b & 0xf <= 9);
+
+        if valid && e[1] < 0x60 && e[2] < 0x75 {
+            Some(Msf::from_bcd(e[0], e[1], e[2]).sector_index())
+        } else {
+            None
+        }
+    };
+
+    let from_bcd = |b: u8| (b >> 4) * 10 + (b & 0xf);
+
+    let last_track = cmp::min(from_bcd(raw[10 + 7]) as usize, 99);
+    let lead_out = entry_msf(&raw[20 + 7..20 + 10]);
+
+    let mut tracks: Vec<Track> = Vec::new();
+
+    for i in 0..last_track {
+        let e = &raw[(3 + i) * 10..(4 + i) * 10];
+
+        let start =
+            match entry_msf(&e[7..10]) {
+                Some(s) => s,
+                None => return error("invalid PBP table of contents"),
+            };
+
+        let format =
+            if e[0] & 0x40 != 0 {
+                TrackFormat::Mode2Xa
+            } else {
+                TrackFormat::Audio
+            };
+
+        if let Some(prev) = tracks.last_mut() {
+            if start < prev.start {
+                return error("invalid PBP table of contents");
+            }
+
+            prev.length = start - prev.start;
+        }
+
+        // We don't know where the INDEX 00 of the following tracks
+        // is, the pregaps end up being part of the previous track.
+        let pregap =
+            if tracks.is_empty() {
+                start
+            } else {
+                0
+            };
+
+        tracks.push(Track {
+            number: from_bcd(e[2]),
+            format: format,
+            start: start,
+            pregap: pregap,
+            length: 0,
+        });
+    }
+
+    // Image size in sectors, used if the TOC is missing or
+    // truncated
+    let image_end = (150 + nblocks * BLOCK_SECTORS) as u32;
+
+    match tracks.last_mut() {
+        Some(last) => {
+            let end = lead_out.unwrap_or(image_end);
+
+            last.length = end.saturating_sub(last.start);
+        }
+        None => {
+            tracks.push(Track {
+                number: 1,
+                format: TrackFormat::Mode2Xa,
+                start: 150,
+                pregap: 150,
+                length: image_end - 150,
+            });
+        }
+    }
+
+    Toc::new(tracks)
+}
+
+/// Read exactly `buf.len()` bytes at `offset` in `file`
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    try!(file.seek(io::SeekFrom::Start(offset)));
+
+    file.read_exact(buf)
+}
+
+fn le32(b: &[u8]) -> u32 {
+    (b[0] as u32) | ((b[1] as u32) << 8)
+        | ((b[2] as u32) << 16) | ((b[3] as u32) << 24)
+}
+
+fn error<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Build a PSISOIMG blob containing a single uncompressed block made
+/// of the sectors of `user_data`
+#[cfg(test)]
+fn test_psisoimg(user_data: &[u8]) -> Vec<u8> {
+    let mut iso = vec![0; 0x100000];
+
+    iso[0..12].copy_from_slice(b"PSISOIMG0000");
+
+    // One block at offset 0, stored uncompressed
+    iso[0x4004] = BLOCK_SIZE as u8;
+    iso[0x4005] = (BLOCK_SIZE >> 8) as u8;
+
+    let subheader = [0x00, 0x00, 0x08, 0x00];
+
+    for i in 0..BLOCK_SECTORS {
+        let msf = Msf::try_from_sector_index(150 + i as u32).unwrap();
+
+        let data =
+            match user_data.chunks(2048).nth(i) {
+                Some(d) => d.to_vec(),
+                None => vec![0; 2048],
+            };
+
+        let sector = XaSector::new_mode2_form1(msf, &subheader, &data);
+
+        iso.extend_from_slice(&sector.raw);
+    }
+
+    iso
+}
+
+/// Build a PBP file containing the DATA.PSAR `psar`
+#[cfg(test)]
+fn test_pbp(psar: &[u8]) -> Vec<u8> {
+    let mut pbp = vec![0; 0x28];
+
+    pbp[0..4].copy_from_slice(b"\0PBP");
+    // DATA.PSAR offset
+    pbp[0x24] = 0x28;
+
+    pbp.extend_from_slice(psar);
+
+    pbp
+}
+
+#[test]
+fn pbp_read_sector() {
+    let data = super::test_user_data(3);
+
+    let pbp = test_pbp(&test_psisoimg(&data));
+
+    let path = super::test_file("pbp_read_sector.pbp", &pbp);
+
+    let mut pbp = Pbp::open(&path, 0).unwrap();
+
+    // No TOC, the size of the image is used
+    assert!(pbp.toc().lead_out() == 150 + BLOCK_SECTORS as u32);
+
+    for i in 0..3 {
+        let msf = Msf::try_from_sector_index(150 + i as u32).unwrap();
+
+        let sector = pbp.read_sector(msf).unwrap();
+        let sector = sector.validate_mode_1_2(msf).unwrap();
+
+        assert!(sector.user_data() == &data[i * 2048..(i + 1) * 2048]);
+    }
+}
+
+#[test]
+fn pbp_multi_disc() {
+    let disc1 = test_psisoimg(&[1; 2048]);
+    let disc2 = test_psisoimg(&[2; 2048]);
+
+    let mut psar = vec![0; 0x400];
+
+    psar[0..16].copy_from_slice(b"PSTITLEIMG000000");
+
+    // Disc offset table
+    let offsets = [0x400, 0x400 + disc1.len()];
+
+    for (i, &o) in offsets.iter().enumerate() {
+        for b in 0..4 {
+            psar[0x200 + i * 4 + b] = (o >> (b * 8)) as u8;
+        }
+    }
+
+    psar.extend_from_slice(&disc1);
+    psar.extend_from_slice(&disc2);
+
+    let path = super::test_file("pbp_multi_disc.pbp", &test_pbp(&psar));
+
+    assert!(disc_count(&path).unwrap() == 2);
+
+    let msf = Msf::try_from_sector_index(150).unwrap();
+
+    for disc in 0..2 {
+        let mut pbp = Pbp::open(&path, disc).unwrap();
+
+        let sector = pbp.read_sector(msf).unwrap();
+
+        assert!(sector.user_data()[0] == disc as u8 + 1);
+    }
+
+    assert!(Pbp::open(&path, 2).is_err());
+}
@@ -0,0 +1,224 @@
+//! Multi-disc games support.
+//!
+//! Games spanning several discs are loaded from an M3U playlist
+//! listing one disc image per line. The whole set is kept open for
+//! the duration of the emulation so that the discs can be swapped
+//! quickly.
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+
+use super::{Disc, Region};
+
+/// A set of discs, only one of them can be in the drive at any
+/// given time.
+pub struct DiscSet {
+    /// Discs in the set. The entry of the disc currently in the drive
+    /// is `None`.
+    discs: Vec<Option<Disc>>,
+    /// Index of the disc currently in the drive
+    current: usize,
+    /// Region common to all the discs in the set
+    region: Region,
+}
+
+impl DiscSet {
+    /// Load the disc set at `path`. If the file is an M3U playlist
+    /// all the discs it lists are loaded, if it's a PBP all the discs
+    /// it contains are loaded, otherwise `path` is loaded as a single
+    /// disc set.
+    pub fn from_path(path: &Path) -> io::Result<DiscSet> {
+        let extension =
+            path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        let discs: Vec<Option<Disc>> =
+            match extension.as_ref().map(|e| e.as_ref()) {
+                Some("m3u") => {
+                    let paths = try!(parse_m3u(path));
+
+                    let mut discs = Vec::with_capacity(paths.len());
+
+                    for p in &paths {
+                        discs.push(Some(try!(load_disc(p))));
+                    }
+
+                    discs
+                }
+                Some("pbp") =>
+                    try!(Disc::from_pbp(path)).into_iter().map(Some).collect(),
+                _ =>
+                    vec![Some(try!(Disc::from_path(path)))],
+            };
+
+        let region =
+            match discs.first() {
+                Some(&Some(ref d)) => d.region(),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               "empty disc playlist")),
+            };
+
+        // The console region is selected based on the first disc, if
+        // the other discs don't match we wouldn't be able to boot
+        // them after a swap.
+        for (i, d) in discs.iter().enumerate() {
+            if let Some(ref d) = *d {
+                if d.region() != region {
+                    let msg =
+                        format!("disc {} region ({:?}) doesn't match \
+                                 disc 1 region ({:?})",
+                                i + 1, d.region(), region);
+
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              msg));
+                }
+            }
+        }
+
+        Ok(DiscSet {
+            discs: discs,
+            current: 0,
+            region: region,
+        })
+    }
+
+    /// Region of the discs in the set
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Number of discs in the set
+    pub fn len(&self) -> usize {
+        self.discs.len()
+    }
+
+    /// Index of the current disc in the set (starting at 0)
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Take the current disc out of the set in order to put it in the
+    /// drive. Returns `None` if the disc has already been taken.
+    pub fn take_current(&mut self) -> Option<Disc> {
+        self.discs[self.current].take()
+    }
+
+    /// Put the `ejected` disc back in its slot and return the next
+    /// disc in the set, wrapping around after the last one.
+    pub fn next(&mut self, ejected: Option<Disc>) -> Option<Disc> {
+        if ejected.is_some() {
+            self.discs[self.current] = ejected;
+        }
+
+        self.current = (self.current + 1) % self.discs.len();
+
+        self.take_current()
+    }
+}
+
+/// Load the disc image at `path`, adding the path to the error
+/// message since playlists can list many images
+fn load_disc(path: &Path) -> io::Result<Disc> {
+    Disc::from_path(path).map_err(|e| {
+        let msg = format!("{}: {}", path.display(), e);
+
+        io::Error::new(e.kind(), msg)
+    })
+}
+
+/// Parse the M3U playlist at `path` and return the paths of the
+/// entries. Relative paths are relative to the playlist's location.
+fn parse_m3u(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let file = try!(File::open(path));
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut paths = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+        let line = line.trim();
+
+        // Skip blank lines and comments (including extended M3U
+        // directives)
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        paths.push(dir.join(line));
+    }
+
+    Ok(paths)
+}
+
+/// Build a 5 sector ISO image with the license string of `region` in
+/// sector 00:02:04
+#[cfg(test)]
+fn test_iso(region: &str) -> Vec<u8> {
+    let mut iso = vec![0; 5 * 2048];
+
+    let license = format!("Licensed by Sony Computer Entertainment {}",
+                          region);
+
+    iso[4 * 2048..][..license.len()].copy_from_slice(license.as_bytes());
+
+    iso
+}
+
+#[test]
+fn disc_set_m3u() {
+    use super::test_file;
+
+    test_file("set_m3u_1.iso", &test_iso("America"));
+    test_file("set_m3u_2.iso", &test_iso("America"));
+
+    let m3u = test_file("set_m3u.m3u",
+                        b"# Disc set\n\
+                          \n\
+                          rustation-set_m3u_1.iso\n\
+                          \n\
+                          #EXTINF:0,Disc 2\n\
+                          rustation-set_m3u_2.iso\n");
+
+    let mut set = DiscSet::from_path(&m3u).unwrap();
+
+    assert!(set.len() == 2);
+    assert!(set.region() == Region::NorthAmerica);
+    assert!(set.current() == 0);
+
+    let disc = set.take_current();
+
+    assert!(disc.is_some());
+    assert!(set.take_current().is_none());
+
+    let disc = set.next(disc);
+
+    assert!(disc.is_some());
+    assert!(set.current() == 1);
+
+    // Wraps back to the first disc
+    let disc = set.next(disc);
+
+    assert!(disc.is_some());
+    assert!(set.current() == 0);
+}
+
+#[test]
+fn disc_set_region_mismatch() {
+    use super::test_file;
+
+    test_file("set_mismatch_1.iso", &test_iso("America"));
+    test_file("set_mismatch_2.iso", &test_iso("Europe"));
+
+    let m3u = test_file("set_mismatch.m3u",
+                        b"rustation-set_mismatch_1.iso\n\
+                          rustation-set_mismatch_2.iso\n");
+
+    match DiscSet::from_path(&m3u) {
+        Ok(_) => panic!("mixed region disc set loaded"),
+        Err(e) => assert!(e.to_string().contains("doesn't match")),
+    }
+}
@@ -0,0 +1,432 @@
+//! Subchannel Q data.
+//!
+//! Each CD sector comes with 12 bytes of Q subchannel data, in the
+//! program area it normally contains the current track, index and
+//! position:
+//!
+//! * 0: control/ADR (0x41 for data tracks, 0x01 for audio tracks)
+//! * 1: track number (BCD)
+//! * 2: index number (BCD)
+//! * 3-5: relative position within the track (BCD MSF)
+//! * 6: always 0
+//! * 7-9: absolute position (BCD MSF)
+//! * 10-11: CRC16 of the first 10 bytes (big endian, inverted)
+//!
+//! Disc images don't store the subchannel data so we regenerate it
+//! from the table of contents. LibCrypt protected games (PAL only)
+//! however contain deliberately corrupted Q data (with a bad CRC) in
+//! some sectors and check that it's present on the disc. Those
+//! sectors can be overridden using an SBI or LSD file sitting next to
+//! the disc image.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::collections::HashMap;
+
+use super::image::{Toc, TrackFormat};
+use super::msf::Msf;
+
+/// The 12 bytes of Q subchannel data of a sector
+#[derive(Clone, Copy)]
+pub struct SubchannelQ {
+    raw: [u8; 12],
+}
+
+impl SubchannelQ {
+    /// Return empty Q data, used when nothing has been read yet
+    pub fn new() -> SubchannelQ {
+        SubchannelQ {
+            raw: [0; 12],
+        }
+    }
+
+    /// Generate the Q subchannel data of the sector at absolute
+    /// position `index` from the table of contents
+    pub fn from_toc(toc: &Toc, index: u32) -> SubchannelQ {
+        let lead_out = toc.lead_out();
+
+        let (control, track, index_number, relative) =
+            match toc.track_for_sector(index) {
+                Some(t) => {
+                    let control =
+                        match t.format {
+                            TrackFormat::Audio => 0x01,
+                            _ => 0x41,
+                        };
+
+                    if index < t.start {
+                        // In the pregap the relative position counts
+                        // down towards INDEX 01
+                        (control, to_bcd(t.number), 0x00, t.start - index)
+                    } else {
+                        (control, to_bcd(t.number), 0x01, index - t.start)
+                    }
+                }
+                // Lead-out
+                None => (0x41, 0xaa, 0x01, index.saturating_sub(lead_out)),
+            };
+
+        // Toc::new makes sure the whole disc fits in the MSF range,
+        // out of range positions can't be reached
+        let to_msf = |i| Msf::try_from_sector_index(i).unwrap_or(Msf::zero());
+
+        let (rm, rs, rf) = to_msf(relative).into_bcd();
+        let (am, as_, af) = to_msf(index).into_bcd();
+
+        let mut q = SubchannelQ {
+            raw: [control, track, index_number,
+                  rm, rs, rf,
+                  0,
+                  am, as_, af,
+                  0, 0],
+        };
+
+        q.update_crc();
+
+        q
+    }
+
+    /// Compute the CRC of the first 10 bytes and store it in the
+    /// last two
+    fn update_crc(&mut self) {
+        let crc = crc16(&self.raw[0..10]);
+
+        self.raw[10] = (crc >> 8) as u8;
+        self.raw[11] = crc as u8;
+    }
+
+    /// Store a deliberately bad CRC, as found in the LibCrypt
+    /// sectors. SBI files don't store the CRC so we generate an
+    /// invalid one by inverting the correct one.
+    fn invalidate_crc(&mut self) {
+        self.update_crc();
+
+        self.raw[10] ^= 0xff;
+        self.raw[11] ^= 0xff;
+    }
+
+    /// Return true if the stored CRC matches the data
+    pub fn crc_valid(&self) -> bool {
+        let crc = crc16(&self.raw[0..10]);
+
+        self.raw[10] == (crc >> 8) as u8 && self.raw[11] == crc as u8
+    }
+
+    /// Return the track number (BCD)
+    pub fn track(&self) -> u8 {
+        self.raw[1]
+    }
+
+    /// Return the index number (BCD)
+    pub fn index(&self) -> u8 {
+        self.raw[2]
+    }
+
+    /// Return the position relative to the track as BCD (m, s, f)
+    pub fn relative(&self) -> (u8, u8, u8) {
+        (self.raw[3], self.raw[4], self.raw[5])
+    }
+
+    /// Return the absolute position as BCD (m, s, f)
+    pub fn absolute(&self) -> (u8, u8, u8) {
+        (self.raw[7], self.raw[8], self.raw[9])
+    }
+}
+
+/// Replacement Q data for a single sector
+#[derive(Clone, Copy)]
+enum Patch {
+    /// Complete Q data
+    Full(SubchannelQ),
+    /// Only the relative position (BCD MSF) differs from the TOC
+    Relative([u8; 3]),
+    /// Only the absolute position (BCD MSF) differs from the TOC
+    Absolute([u8; 3]),
+}
+
+/// Set of sectors whose Q subchannel data differs from what the
+/// table of contents says
+pub struct SubchannelPatches {
+    /// Patches indexed by absolute sector index
+    sectors: HashMap<u32, Patch>,
+}
+
+impl SubchannelPatches {
+    pub fn new() -> SubchannelPatches {
+        SubchannelPatches {
+            sectors: HashMap::new(),
+        }
+    }
+
+    /// Look for an SBI or LSD file with the same name as the disc
+    /// image at `image_path`. Returns an empty set if none is found.
+    pub fn for_image(image_path: &Path) -> io::Result<SubchannelPatches> {
+        let sbi = image_path.with_extension("sbi");
+
+        if sbi.is_file() {
+            return SubchannelPatches::from_sbi(&sbi);
+        }
+
+        let lsd = image_path.with_extension("lsd");
+
+        if lsd.is_file() {
+            return SubchannelPatches::from_lsd(&lsd);
+        }
+
+        Ok(SubchannelPatches::new())
+    }
+
+    /// Load an SBI file: "SBI\0" magic followed by entries made of a
+    /// BCD MSF, a type byte and the payload. Type 1 is 10 bytes of Q
+    /// data without the CRC, types 2 and 3 only replace the relative
+    /// and absolute BCD MSF respectively.
+    pub fn from_sbi(path: &Path) -> io::Result<SubchannelPatches> {
+        let data = try!(read_file(path));
+
+        if data.len() < 4 || &data[0..4] != b"SBI\0" {
+            return error("invalid SBI file");
+        }
+
+        let mut patches = SubchannelPatches::new();
+
+        let mut pos = 4;
+
+        while pos < data.len() {
+            if pos + 4 > data.len() {
+                return error("truncated SBI file");
+            }
+
+            let index = try!(bcd_sector_index(&data[pos..pos + 3]));
+            let kind = data[pos + 3];
+
+            pos += 4;
+
+            let len =
+                match kind {
+                    1 => 10,
+                    2 | 3 => 3,
+                    _ => return error("invalid SBI entry type"),
+                };
+
+            if pos + len > data.len() {
+                return error("truncated SBI file");
+            }
+
+            let payload = &data[pos..pos + len];
+
+            let patch =
+                match kind {
+                    1 => {
+                        let mut q = SubchannelQ::new();
+
+                        q.raw[0..10].copy_from_slice(payload);
+                        q.invalidate_crc();
+
+                        Patch::Full(q)
+                    }
+                    2 => Patch::Relative([payload[0], payload[1], payload[2]]),
+                    _ => Patch::Absolute([payload[0], payload[1], payload[2]]),
+                };
+
+            patches.sectors.insert(index, patch);
+
+            pos += len;
+        }
+
+        Ok(patches)
+    }
+
+    /// Load an LSD file: a sequence of 15 byte entries made of a BCD
+    /// MSF followed by the full 12 bytes of Q data (including the
+    /// CRC).
+    pub fn from_lsd(path: &Path) -> io::Result<SubchannelPatches> {
+        let data = try!(read_file(path));
+
+        if data.len() % 15 != 0 {
+            return error("invalid LSD file size");
+        }
+
+        let mut patches = SubchannelPatches::new();
+
+        for entry in data.chunks(15) {
+            let index = try!(bcd_sector_index(&entry[0..3]));
+
+            let mut q = SubchannelQ::new();
+
+            q.raw.copy_from_slice(&entry[3..15]);
+
+            patches.sectors.insert(index, Patch::Full(q));
+        }
+
+        Ok(patches)
+    }
+
+    /// Return the number of patched sectors
+    pub fn len(&self) -> usize {
+        self.sectors.len()
+    }
+
+    /// Return the patched Q data of sector `index`, if any. Partial
+    /// patches are applied on top of the Q data generated from `toc`.
+    pub fn get(&self, toc: &Toc, index: u32) -> Option<SubchannelQ> {
+        let (offset, msf) =
+            match self.sectors.get(&index) {
+                Some(&Patch::Full(q)) => return Some(q),
+                Some(&Patch::Relative(msf)) => (3, msf),
+                Some(&Patch::Absolute(msf)) => (7, msf),
+                None => return None,
+            };
+
+        let mut q = SubchannelQ::from_toc(toc, index);
+
+        q.raw[offset..offset + 3].copy_from_slice(&msf);
+        q.invalidate_crc();
+
+        Some(q)
+    }
+}
+
+/// Convert a BCD MSF in `b` into an absolute sector index
+fn bcd_sector_index(b: &[u8]) -> io::Result<u32> {
+    let valid = b.iter().all(|&v| v <= 0x99 && v & 0xf <= 9);
+
+    if !valid || b[1] >= 0x60 || b[2] >= 0x75 {
+        return error("invalid BCD MSF in subchannel file");
+    }
+
+    Ok(Msf::from_bcd(b[0], b[1], b[2]).sector_index())
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = try!(File::open(path));
+
+    let mut data = Vec::new();
+
+    try!(file.read_to_end(&mut data));
+
+    Ok(data)
+}
+
+/// CRC16-CCITT (polynomial 0x1021) with an initial value of 0. The
+/// result is inverted as specified for the Q subchannel.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+
+    for &b in data {
+        crc ^= (b as u16) << 8;
+
+        for _ in 0..8 {
+            crc =
+                if crc & 0x8000 != 0 {
+                    (crc << 1) ^ 0x1021
+                } else {
+                    crc << 1
+                };
+        }
+    }
+
+    !crc
+}
+
+fn to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+fn error<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[test]
+fn subchannel_crc16() {
+    // CRC-16/XMODEM check value, inverted
+    assert!(crc16(b"123456789") == !0x31c3);
+}
+
+#[test]
+fn subchannel_q_from_toc() {
+    use super::image::Track;
+
+    let track = Track {
+        number: 1,
+        format: TrackFormat::Mode2Xa,
+        start: 150,
+        pregap: 150,
+        length: 1000,
+    };
+
+    let toc = Toc::new(vec![track]).unwrap();
+
+    // Track 01 INDEX 01
+    let q = SubchannelQ::from_toc(&toc, 150);
+
+    assert!(&q.raw[0..10] == &[0x41, 0x01, 0x01, 0x00, 0x00, 0x00,
+                               0x00, 0x00, 0x02, 0x00]);
+    assert!(q.crc_valid());
+
+    // In the pregap the relative position counts down
+    let q = SubchannelQ::from_toc(&toc, 1);
+
+    assert!(q.index() == 0x00);
+    assert!(q.relative() == (0x00, 0x01, 0x74));
+
+    // Lead-out
+    let q = SubchannelQ::from_toc(&toc, 1150);
+
+    assert!(q.track() == 0xaa);
+    assert!(q.relative() == (0x00, 0x00, 0x00));
+    assert!(q.absolute() == (0x00, 0x15, 0x25));
+}
+
+#[test]
+fn subchannel_sbi() {
+    use super::image::Track;
+
+    let track = Track {
+        number: 1,
+        format: TrackFormat::Mode2Xa,
+        start: 150,
+        pregap: 150,
+        length: 1000,
+    };
+
+    let toc = Toc::new(vec![track]).unwrap();
+
+    let mut sbi = b"SBI\0".to_vec();
+
+    // 00:03:00, full Q data
+    sbi.extend_from_slice(&[0x00, 0x03, 0x00, 1,
+                            0x41, 0x01, 0x01, 0x00, 0x01, 0x00,
+                            0x00, 0x00, 0x13, 0x00]);
+    // 00:03:01, relative position
+    sbi.extend_from_slice(&[0x00, 0x03, 0x01, 2, 0x00, 0x21, 0x01]);
+    // 00:03:02, absolute position
+    sbi.extend_from_slice(&[0x00, 0x03, 0x02, 3, 0x00, 0x23, 0x02]);
+
+    let path = super::test_file("subchannel.sbi", &sbi);
+
+    let patches = SubchannelPatches::from_sbi(&path).unwrap();
+
+    assert!(patches.len() == 3);
+    assert!(patches.get(&toc, 150).is_none());
+
+    let q = patches.get(&toc, 225).unwrap();
+
+    assert!(q.relative() == (0x00, 0x01, 0x00));
+    assert!(q.absolute() == (0x00, 0x13, 0x00));
+    assert!(!q.crc_valid());
+
+    let q = patches.get(&toc, 226).unwrap();
+
+    assert!(q.track() == 0x01);
+    assert!(q.relative() == (0x00, 0x21, 0x01));
+    assert!(q.absolute() == (0x00, 0x03, 0x01));
+    assert!(!q.crc_valid());
+
+    let q = patches.get(&toc, 227).unwrap();
+
+    assert!(q.relative() == (0x00, 0x01, 0x02));
+    assert!(q.absolute() == (0x00, 0x23, 0x02));
+    assert!(!q.crc_valid());
+}
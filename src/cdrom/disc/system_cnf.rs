@@ -0,0 +1,201 @@
+//! Parser for the SYSTEM.CNF file found at the root of PlayStation
+//! discs. It tells the BIOS which executable to boot and how to
+//! configure the kernel:
+//!
+//! ```text
+//! BOOT = cdrom:\SLUS_005.94;1
+//! TCB = 4
+//! EVENT = 10
+//! STACK = 801FFF00
+//! ```
+//!
+//! The name of the boot executable is normally the serial number of
+//! the game which we use to identify it.
+
+use std::io;
+
+use super::Region;
+
+/// Parsed SYSTEM.CNF
+#[derive(Clone, Debug)]
+pub struct SystemCnf {
+    /// Path of the boot executable
+    boot: String,
+    /// Number of thread control blocks (TCB), None if not specified
+    tcb: Option<u32>,
+    /// Number of event control blocks (EVENT), None if not specified
+    event: Option<u32>,
+    /// Initial stack pointer, None if not specified
+    stack: Option<u32>,
+}
+
+impl SystemCnf {
+    /// Default configuration used by the BIOS when the disc doesn't
+    /// contain a SYSTEM.CNF
+    pub fn default() -> SystemCnf {
+        SystemCnf {
+            boot: "cdrom:PSX.EXE;1".to_owned(),
+            tcb: None,
+            event: None,
+            stack: None,
+        }
+    }
+
+    /// Parse the contents of a SYSTEM.CNF file
+    pub fn parse(data: &[u8]) -> io::Result<SystemCnf> {
+        let mut boot = None;
+        let mut tcb = None;
+        let mut event = None;
+        let mut stack = None;
+
+        // The file is supposed to be ASCII but it's sometimes padded
+        // with garbage, stop at the first NUL
+        let data =
+            match data.iter().position(|&b| b == 0) {
+                Some(p) => &data[..p],
+                None => data,
+            };
+
+        let text = String::from_utf8_lossy(data);
+
+        for line in text.lines() {
+            let mut kv = line.splitn(2, '=');
+
+            let key = kv.next().unwrap_or("").trim();
+            let value =
+                match kv.next() {
+                    Some(v) => v.trim(),
+                    None => continue,
+                };
+
+            // The BIOS parses all the numbers as hexadecimal
+            let hex = || -> io::Result<u32> {
+                match u32::from_str_radix(value, 16) {
+                    Ok(v) => Ok(v),
+                    Err(_) => {
+                        let msg = format!("SYSTEM.CNF: invalid {}: {}",
+                                          key, value);
+                        Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+                    }
+                }
+            };
+
+            match key {
+                "BOOT" => boot = Some(value.to_owned()),
+                "TCB" => tcb = Some(try!(hex())),
+                "EVENT" => event = Some(try!(hex())),
+                "STACK" => stack = Some(try!(hex())),
+                // Ignore unknown keys
+                _ => (),
+            }
+        }
+
+        let boot =
+            match boot {
+                Some(b) => b,
+                None => {
+                    let msg = "SYSTEM.CNF: missing BOOT entry";
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              msg));
+                }
+            };
+
+        Ok(SystemCnf {
+            boot: boot,
+            tcb: tcb,
+            event: event,
+            stack: stack,
+        })
+    }
+
+    /// Return the path of the boot executable, as found in the file
+    /// (e.g. "cdrom:\SLUS_005.94;1")
+    pub fn boot(&self) -> &str {
+        &self.boot
+    }
+
+    pub fn tcb(&self) -> Option<u32> {
+        self.tcb
+    }
+
+    pub fn event(&self) -> Option<u32> {
+        self.event
+    }
+
+    pub fn stack(&self) -> Option<u32> {
+        self.stack
+    }
+
+    /// Return the name of the boot executable without the device,
+    /// directories and version (e.g. "SLUS_005.94")
+    pub fn boot_file_name(&self) -> &str {
+        let name =
+            match self.boot.rfind(|c| c == '\\' || c == '/' || c == ':') {
+                Some(p) => &self.boot[p + 1..],
+                None => &self.boot,
+            };
+
+        match name.find(';') {
+            Some(p) => &name[..p],
+            None => name,
+        }
+    }
+
+    /// Attempt to extract the game's serial number from the name of
+    /// the boot executable: "SLUS_005.94" becomes "SLUS-00594".
+    /// Returns None if the name doesn't look like a serial.
+    pub fn serial(&self) -> Option<String> {
+        let name = self.boot_file_name();
+
+        let prefix: String = name.chars().take(4).collect();
+
+        let digits: String =
+            name.chars().skip(4).filter(|&c| c != '_' && c != '-' && c != '.')
+            .collect();
+
+        let valid =
+            prefix.len() == 4
+            && prefix.chars().all(|c| c.is_alphabetic())
+            && digits.len() == 5
+            && digits.chars().all(|c| c.is_digit(10));
+
+        if valid {
+            Some(format!("{}-{}", prefix.to_ascii_uppercase(), digits))
+        } else {
+            None
+        }
+    }
+}
+
+/// Guess the region of a game from its serial number
+pub fn serial_region(serial: &str) -> Option<Region> {
+    // The first two letters identify the publisher (SC: Sony, SL:
+    // licensee...), the next two the region.
+    let region: String = serial.chars().skip(2).take(2).collect();
+
+    match region.as_ref() {
+        "US" => Some(Region::NorthAmerica),
+        "ES" | "ED" => Some(Region::Europe),
+        "PS" | "PM" | "KA" => Some(Region::Japan),
+        _ => None,
+    }
+}
+
+#[test]
+fn system_cnf_parse() {
+    let cnf = b"BOOT = cdrom:\\SLUS_005.94;1\r\n\
+                TCB = 4\r\n\
+                EVENT = 10\r\n\
+                STACK = 801FFF00\r\n";
+
+    let cnf = SystemCnf::parse(cnf).unwrap();
+
+    assert!(cnf.boot_file_name() == "SLUS_005.94");
+    assert!(cnf.serial() == Some("SLUS-00594".to_owned()));
+    assert!(cnf.tcb() == Some(4));
+    assert!(cnf.event() == Some(0x10));
+    assert!(cnf.stack() == Some(0x801fff00));
+    assert!(serial_region("SLUS-00594") == Some(Region::NorthAmerica));
+
+    assert!(SystemCnf::default().serial() == None);
+}
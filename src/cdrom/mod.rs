@@ -1,9 +1,12 @@
+use std::mem;
+
 use memory::{Addressable, AccessWidth};
 use timekeeper::{TimeKeeper, Peripheral, Cycles};
 use memory::interrupts::{Interrupt, InterruptState};
 
 use self::disc::{Disc, Region, XaSector};
 use self::disc::msf::Msf;
+use self::disc::subchannel::SubchannelQ;
 
 pub mod disc;
 
@@ -29,6 +32,9 @@ pub struct CdRom {
     on_ack: fn (&mut CdRom) -> CommandState,
     /// Currently loaded disc or None if no disc is present
     disc: Option<(Disc)>,
+    /// Set when the shell has been opened since the last "get stat"
+    /// command. Used by the games to detect disc swaps.
+    shell_opened: bool,
     /// Target of the next seek command
     seek_target: Msf,
     /// True if `seek_target` has been set but no seek took place
@@ -56,6 +62,9 @@ pub struct CdRom {
     read_whole_sector: bool,
     /// CDROM audio mixer connected to the SPU
     mixer: Mixer,
+    /// Last Q subchannel data read with a valid CRC, returned by the
+    /// GetlocP command
+    subq: SubchannelQ,
 }
 
 impl CdRom {
@@ -70,6 +79,7 @@ impl CdRom {
             irq_flags: 0,
             on_ack: CdRom::ack_idle,
             disc: disc,
+            shell_opened: false,
             seek_target: Msf::zero(),
             seek_target_pending: false,
             position: Msf::zero(),
@@ -81,6 +91,7 @@ impl CdRom {
             rx_len: 0,
             read_whole_sector: true,
             mixer: Mixer::new(),
+            subq: SubchannelQ::new(),
         }
     }
 
@@ -322,6 +333,37 @@ impl CdRom {
 
         self.position = self.seek_target;
         self.seek_target_pending = false;
+
+        let position = self.position;
+
+        self.update_subq(position);
+    }
+
+    /// Read the Q subchannel data for the sector at `msf`. The drive
+    /// ignores data with a bad CRC and keeps the previous value,
+    /// that's what LibCrypt relies on to detect the modified sectors.
+    fn update_subq(&mut self, msf: Msf) {
+        let q = self.disc_or_die().subchannel_q(msf);
+
+        if q.crc_valid() {
+            self.subq = q;
+        }
+    }
+
+    /// Simulate opening the shell, replacing the disc with `disc`
+    /// and closing it again. Returns the disc previously in the drive
+    /// (if any).
+    pub fn swap_disc(&mut self, disc: Option<Disc>) -> Option<Disc> {
+        // Opening the shell stops the drive
+        self.read_state = ReadState::Idle;
+        self.position = Msf::zero();
+        self.seek_target = Msf::zero();
+        self.seek_target_pending = false;
+        self.subq = SubchannelQ::new();
+
+        self.shell_opened = true;
+
+        mem::replace(&mut self.disc, disc)
     }
 
     /// Retrieve the current disc or panic if there's none. Used in
@@ -345,6 +387,8 @@ impl CdRom {
                 Err(e) => panic!("Couldn't read sector: {}", e),
             };
 
+        self.update_subq(position);
+
         if self.read_whole_sector {
             // Read the entire sector except for the sync pattern
             self.rx_offset = 12;
@@ -517,6 +561,9 @@ impl CdRom {
                 0x0a => CdRom::cmd_init,
                 0x0c => CdRom::cmd_demute,
                 0x0e => CdRom::cmd_set_mode,
+                0x11 => CdRom::cmd_get_loc_p,
+                0x13 => CdRom::cmd_get_tn,
+                0x14 => CdRom::cmd_get_td,
                 0x15 => CdRom::cmd_seek_l,
                 0x1a => CdRom::cmd_get_id,
                 0x1e => CdRom::cmd_read_toc,
@@ -566,6 +613,8 @@ impl CdRom {
 
                 // Motor on
                 r |= 1 << 1;
+                // Shell has been opened since the last "get stat"
+                r |= (self.shell_opened as u8) << 4;
                 r |= (reading as u8) << 5;
 
                 r
@@ -592,9 +641,11 @@ impl CdRom {
 
         response.push(self.drive_status());
 
-        // XXX Apparently we should also clear bit 4 of status if the
-        // tray is closed (or all the time? not sure what triggers
-        // that bit)
+        // The "shell opened" bit is latched until it's been read
+        // with the shell closed.
+        if self.disc.is_some() {
+            self.shell_opened = false;
+        }
 
         // The response comes earlier when there's no disc
         let rx_delay =
@@ -715,6 +766,12 @@ impl CdRom {
         self.double_speed = (mode & 0x80) != 0;
         self.read_whole_sector = (mode & 0x20) != 0;
 
+        if mode & 0x04 != 0 {
+            // Report interrupts are only generated during CD-DA
+            // playback
+            panic!("CDROM: unsupported report mode: {:02x}", mode);
+        }
+
         if mode & 0x5f != 0 {
             panic!("CDROM: unhandled mode: {:02x}", mode);
         }
@@ -726,6 +783,113 @@ impl CdRom {
                                     self.drive_status()]))
     }
 
+    /// Return the current position from the Q subchannel: track,
+    /// index, position within the track and absolute position.
+    ///
+    /// The real drive also sends this data in "report" interrupts
+    /// during CD-DA playback when bit 2 of the mode is set. CD-DA
+    /// playback isn't emulated so `cmd_set_mode` rejects that bit and
+    /// GetlocP is the only way to read the Q subchannel.
+    fn cmd_get_loc_p(&mut self) -> CommandState {
+        if self.disc.is_none() {
+            return CommandState::RxPending(25_000,
+                                           25_000 + 6763,
+                                           IrqCode::Error,
+                                           Fifo::from_bytes(&[0x11, 0x80]));
+        }
+
+        let q = self.subq;
+
+        let (rm, rs, rf) = q.relative();
+        let (am, as_, af) = q.absolute();
+
+        // XXX timings are a rough guess
+        CommandState::RxPending(25_000,
+                                25_000 + 5401,
+                                IrqCode::Ok,
+                                Fifo::from_bytes(&[q.track(), q.index(),
+                                                   rm, rs, rf,
+                                                   am, as_, af]))
+    }
+
+    /// Return the first and last track numbers on the disc
+    fn cmd_get_tn(&mut self) -> CommandState {
+        let (first, last) =
+            match self.disc {
+                Some(ref d) => {
+                    let tracks = d.toc().tracks();
+
+                    (tracks[0].number, tracks[tracks.len() - 1].number)
+                }
+                None => return CommandState::RxPending(25_000,
+                                                       25_000 + 6763,
+                                                       IrqCode::Error,
+                                                       Fifo::from_bytes(&[
+                                                           0x11, 0x80])),
+            };
+
+        // XXX timings are a rough guess
+        CommandState::RxPending(25_000,
+                                25_000 + 5401,
+                                IrqCode::Ok,
+                                Fifo::from_bytes(&[self.drive_status(),
+                                                   to_bcd(first),
+                                                   to_bcd(last)]))
+    }
+
+    /// Return the start position of the track given as parameter
+    /// (in BCD). Track 0 returns the position of the lead-out.
+    fn cmd_get_td(&mut self) -> CommandState {
+        if self.params.len() != 1 {
+            // XXX: should trigger IRQ 5 with response 0x13, 0x20
+            panic!("CDROM: bad number of parameters for GetTD: {:?}",
+                   self.params);
+        }
+
+        let track = from_bcd(self.params.pop());
+
+        let start =
+            match self.disc {
+                Some(ref d) => {
+                    let toc = d.toc();
+
+                    if track == 0 {
+                        Some(toc.lead_out())
+                    } else {
+                        toc.tracks().iter()
+                            .find(|t| t.number == track)
+                            .map(|t| t.start)
+                    }
+                }
+                None => return CommandState::RxPending(25_000,
+                                                       25_000 + 6763,
+                                                       IrqCode::Error,
+                                                       Fifo::from_bytes(&[
+                                                           0x11, 0x80])),
+            };
+
+        // Toc::new makes sure the positions fit in the MSF range
+        match start.and_then(|s| Msf::try_from_sector_index(s)) {
+            Some(start) => {
+                let (m, s, _) = start.into_bcd();
+
+                // XXX timings are a rough guess
+                CommandState::RxPending(25_000,
+                                        25_000 + 5401,
+                                        IrqCode::Ok,
+                                        Fifo::from_bytes(&[
+                                            self.drive_status(), m, s]))
+            }
+            // Invalid track number
+            None => CommandState::RxPending(25_000,
+                                            25_000 + 6763,
+                                            IrqCode::Error,
+                                            Fifo::from_bytes(&[
+                                                self.drive_status() | 1,
+                                                0x10])),
+        }
+    }
+
     /// Execute seek. Target is given by previous "set loc" command.
     fn cmd_seek_l(&mut self) -> CommandState {
         self.do_seek();
@@ -1090,3 +1254,13 @@ impl Mixer {
         }
     }
 }
+
+/// Convert a binary value (0-99) to BCD
+fn to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+/// Convert a BCD value to binary
+fn from_bcd(b: u8) -> u8 {
+    (b >> 4) * 10 + (b & 0xf)
+}
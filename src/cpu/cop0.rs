@@ -2,6 +2,18 @@ use memory::interrupts::InterruptState;
 
 /// Coprocessor 0: System control
 pub struct Cop0 {
+    /// Cop0 register 3: Breakpoint on execute address
+    bpc: u32,
+    /// Cop0 register 5: Breakpoint on data access address
+    bda: u32,
+    /// Cop0 register 7: Debug and cache invalidate control
+    dcic: u32,
+    /// Cop0 register 8: Address of the last address error
+    bad_vaddr: u32,
+    /// Cop0 register 9: Breakpoint on data access mask
+    bdam: u32,
+    /// Cop0 register 11: Breakpoint on execute mask
+    bpcm: u32,
     /// Cop0 register 12: Status register
     sr: u32,
     /// Cop0 register 13: Cause register
@@ -14,18 +26,61 @@ impl Cop0 {
 
     pub fn new() -> Cop0 {
         Cop0 {
-            sr:    0,
-            cause: 0,
-            epc:   0,
+            bpc:       0,
+            bda:       0,
+            dcic:      0,
+            bad_vaddr: 0,
+            bdam:      0,
+            bpcm:      0,
+            sr:        0,
+            cause:     0,
+            epc:       0,
         }
     }
 
-    pub fn sr(&self) -> u32 {
-        self.sr
+    /// Read cop0 register `reg`
+    pub fn register(&self, reg: u32, irq_state: InterruptState) -> u32 {
+        match reg {
+            3 => self.bpc,
+            5 => self.bda,
+            // XXX JUMPDEST: address of the last jump, not emulated
+            6 => 0,
+            7 => self.dcic,
+            8 => self.bad_vaddr,
+            9 => self.bdam,
+            11 => self.bpcm,
+            12 => self.sr,
+            13 => self.cause(irq_state),
+            14 => self.epc,
+            // Processor ID
+            15 => 2,
+            // Unused registers, they return garbage on the real
+            // hardware
+            0...2 | 4 | 10 => 0,
+            _  => panic!("Unhandled read from cop0r{}", reg),
+        }
+    }
+
+    /// Write `val` to cop0 register `reg`
+    pub fn set_register(&mut self, reg: u32, val: u32) {
+        match reg {
+            3 => self.bpc = val,
+            5 => self.bda = val,
+            // Only the control bits and the hit flags are writeable
+            7 => self.dcic = val & 0xff80f03f,
+            9 => self.bdam = val,
+            11 => self.bpcm = val,
+            12 => self.sr = val,
+            // Only the two software interrupt bits are writeable
+            13 => self.cause = (self.cause & !0x300) | (val & 0x300),
+            // Read-only or unused registers
+            0...2 | 4 | 6 | 8 | 10 | 14 | 15 => (),
+            _  => panic!("Unhandled write to cop0r{}: {:08x}", reg, val),
+        }
     }
 
-    pub fn set_sr(&mut self, sr: u32) {
-        self.sr = sr;
+    pub fn sr(&self) -> u32 {
+        self.sr
     }
 
     /// Retreive the value of the CAUSE register. We need the
@@ -36,8 +91,62 @@ impl Cop0 {
         self.cause | ((irq_state.active() as u32) << 10)
     }
 
-    pub fn epc(&self) -> u32 {
-        self.epc
+    pub fn bad_vaddr(&self) -> u32 {
+        self.bad_vaddr
+    }
+
+    /// Called on address errors with the offending address
+    pub fn set_bad_vaddr(&mut self, addr: u32) {
+        self.bad_vaddr = addr;
+    }
+
+    /// Return true if the debug registers are globally enabled
+    /// along with the breakpoint enable `bit` in DCIC
+    fn breakpoint_enabled(&self, bit: u32) -> bool {
+        // Both "super master" enable bits and the master enable for
+        // the execution and data breakpoints
+        let mask = (1 << 31) | (1 << 30) | (1 << 23) | (1 << bit);
+
+        self.dcic & mask == mask
+    }
+
+    /// Check for an execution breakpoint at `pc`. Returns true if
+    /// the debug exception must be raised.
+    pub fn code_breakpoint(&mut self, pc: u32) -> bool {
+        if !self.breakpoint_enabled(24) ||
+            (pc ^ self.bpc) & self.bpcm != 0 {
+            return false;
+        }
+
+        // Any break + BPC code break
+        self.dcic |= 0x3;
+
+        true
+    }
+
+    /// Check for a data breakpoint on an access at `addr`. Returns
+    /// true if the debug exception must be raised.
+    pub fn data_breakpoint(&mut self, addr: u32, write: bool) -> bool {
+        let type_bit = match write {
+            true => 27,
+            false => 26,
+        };
+
+        if !self.breakpoint_enabled(25) ||
+            self.dcic & (1 << type_bit) == 0 ||
+            (addr ^ self.bda) & self.bdam != 0 {
+            return false;
+        }
+
+        let type_flag = match write {
+            true => 1 << 4,
+            false => 1 << 3,
+        };
+
+        // Any break + BDA data break + read or write flag
+        self.dcic |= 0x5 | type_flag;
+
+        true
     }
 
     pub fn cache_isolated(&self) -> bool {
@@ -85,6 +194,21 @@ impl Cop0 {
         }
     }
 
+    /// Enter the debug exception triggered by the breakpoint
+    /// registers. Returns the address of the debug handler.
+    pub fn enter_debug_exception(&mut self,
+                                 pc: u32,
+                                 in_delay_slot: bool) -> u32 {
+        // The debug exception is reported as a breakpoint but uses a
+        // dedicated vector
+        self.enter_exception(Exception::Break, pc, in_delay_slot);
+
+        match self.sr & (1 << 22) != 0 {
+            true  => 0xbfc00140,
+            false => 0x80000040,
+        }
+    }
+
     /// The counterpart to "enter_exception": shift SR's mode back
     /// into place. Doesn't touch CAUSE or EPC however.
     pub fn return_from_exception(&mut self) {
@@ -129,6 +253,10 @@ pub enum Exception {
     LoadAddressError = 0x4,
     /// Address error on store
     StoreAddressError = 0x5,
+    /// Bus error on instruction fetch
+    InstructionBusError = 0x6,
+    /// Bus error on data load or store
+    DataBusError = 0x7,
     /// System call (caused by the SYSCALL opcode)
     SysCall = 0x8,
     /// Breakpoint (caused by the BREAK opcode)
@@ -140,3 +268,30 @@ pub enum Exception {
     /// Arithmetic overflow
     Overflow = 0xc,
 }
+
+#[test]
+fn cop0_breakpoints() {
+    let mut cop0 = Cop0::new();
+
+    // Execution breakpoint on a 16 byte range and data write
+    // breakpoint on a single word
+    cop0.set_register(3, 0x80010000);
+    cop0.set_register(11, 0xfffffff0);
+    cop0.set_register(5, 0x1f801070);
+    cop0.set_register(9, 0xfffffffc);
+    cop0.set_register(7, 0xcb800000);
+
+    assert!(!cop0.code_breakpoint(0x80010010));
+    assert!(cop0.code_breakpoint(0x8001000c));
+    assert!(cop0.dcic & 0x3f == 0x3);
+
+    assert!(!cop0.data_breakpoint(0x1f801070, false));
+    assert!(!cop0.data_breakpoint(0x1f801074, true));
+    assert!(cop0.data_breakpoint(0x1f801072, true));
+    assert!(cop0.dcic & 0x3f == 0x17);
+
+    let handler = cop0.enter_debug_exception(0x80010008, false);
+
+    assert!(handler == 0x80000040);
+    assert!(cop0.epc == 0x80010008);
+}
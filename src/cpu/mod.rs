@@ -5,10 +5,16 @@ use std::fmt::{Display, Formatter, Error};
 
 use self::cop0::{Cop0, Exception};
 use self::gte::Gte;
-use memory::{Interconnect, Addressable, AccessWidth};
+use memory::{Interconnect, Addressable, AccessWidth, BusError};
+use memory::expansion::Expansion;
 use timekeeper::TimeKeeper;
 use debugger::Debugger;
+use gpu::BeamPosition;
 use padmemcard::gamepad;
+use padmemcard::gamepad::GamePad;
+use padmemcard::memcard::MemoryCard;
+use cdrom::disc::Disc;
+use sio1::link::Link;
 
 /// CPU state
 pub struct Cpu {
@@ -101,12 +107,20 @@ impl Cpu {
 
         if self.current_pc % 4 != 0 {
             // PC is not correctly aligned!
-            self.exception(Exception::LoadAddressError);
+            let pc = self.current_pc;
+            self.address_error(Exception::LoadAddressError, pc);
             return;
         }
 
         // Fetch instruction at PC
-        let instruction = self.fetch_instruction();
+        let instruction =
+            match self.fetch_instruction() {
+                Ok(i) => i,
+                Err(_) => {
+                    self.exception(Exception::InstructionBusError);
+                    return;
+                }
+            };
 
         // Increment PC to point to the next instruction. and
         // `next_pc` to the one after that. Both values can be
@@ -133,6 +147,8 @@ impl Cpu {
         // Check for pending interrupts
         if self.cop0.irq_active(self.inter.irq_state()) {
             self.exception(Exception::Interrupt);
+        } else if self.cop0.code_breakpoint(self.current_pc) {
+            self.debug_exception();
         } else {
             // No interrupt pending, run the current instruction
             self.decode_and_execute(instruction, debugger);
@@ -144,7 +160,7 @@ impl Cpu {
 
     /// Fetch the instruction at `current_pc` through the instruction
     /// cache
-    fn fetch_instruction(&mut self) -> Instruction {
+    fn fetch_instruction(&mut self) -> Result<Instruction, BusError> {
         let pc = self.current_pc;
         let cc = self.inter.cache_control();
 
@@ -176,15 +192,26 @@ impl Cpu {
                 // words are going to remain invalid in the cacheline.
                 let mut cpc = pc;
 
-                // Fetching takes 3 cycles + 1 per instruction on
-                // average.
-                self.tk.tick(3);
+                // The first word takes the full memory latency, the
+                // following ones are fetched in burst mode if the
+                // memory supports it
+                let (first, seq) = self.inter.instruction_fetch_cycles(pc);
+
+                self.tk.tick(first);
 
                 for i in index..4 {
-                    self.tk.tick(1);
+                    if i != index {
+                        self.tk.tick(seq);
+                    }
 
                     let instruction =
-                        Instruction(self.inter.load_instruction(cpc));
+                        match self.inter.load_instruction(cpc) {
+                            Ok(i) => Instruction(i),
+                            Err(e) => {
+                                line.invalidate();
+                                return Err(e);
+                            }
+                        };
 
                     line.set_instruction(i, instruction);
                     cpc += 4;
@@ -195,36 +222,44 @@ impl Cpu {
             }
 
             // Cache line is now guaranteed to be valid
-            line.instruction(index)
+            Ok(line.instruction(index))
         } else {
-            // XXX Apparently pointing the PC to KSEG2 causes a bus
-            // error no matter what, even if you point it at some
-            // valid register address (like the "cache control"
-            // register). Not like it should happen anyway, there's
-            // nowhere to put code in KSEG2, only a bunch of
-            // registers.
-
-            // Cache disabled, fetch directly from memory. Takes 4
-            // cycles on average.
-            self.tk.tick(4);
-            
-            Instruction(self.inter.load_instruction(pc))
+            // Cache disabled, fetch directly from memory. Each
+            // instruction pays the full memory latency.
+            let (first, _) = self.inter.instruction_fetch_cycles(pc);
+
+            self.tk.tick(first);
+
+            self.inter.load_instruction(pc).map(Instruction)
         }
     }
 
-    /// Memory read
+    /// Memory read. Returns `None` and triggers a bus error
+    /// exception if nothing responds at `addr`.
     fn load<T: Addressable>(&mut self,
                             addr: u32,
-                            debugger: &mut Debugger) -> T {
+                            debugger: &mut Debugger) -> Option<T> {
         debugger.memory_read(self, addr);
 
-        self.inter.load(&mut self.tk, addr)
+        if self.cop0.data_breakpoint(addr, false) {
+            self.debug_exception();
+            return None;
+        }
+
+        match self.inter.load(&mut self.tk, addr) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.exception(Exception::DataBusError);
+                None
+            }
+        }
     }
 
     /// Memory read with as little side-effect as possible. Used for
-    /// debugging.
+    /// debugging. Returns 0 if nothing responds at `addr`.
     pub fn examine<T: Addressable>(&mut self, addr: u32) -> T {
         self.inter.load(&mut self.tk, addr)
+            .unwrap_or(Addressable::from_u32(0))
     }
 
     /// Memory write
@@ -234,10 +269,15 @@ impl Cpu {
                              debugger: &mut Debugger) {
         debugger.memory_write(self, addr);
 
+        if self.cop0.data_breakpoint(addr, true) {
+            self.debug_exception();
+            return;
+        }
+
         if self.cop0.cache_isolated() {
             self.cache_maintenance(addr, val);
-        } else {
-            self.inter.store(&mut self.tk, addr, val);
+        } else if self.inter.store(&mut self.tk, addr, val).is_err() {
+            self.exception(Exception::DataBusError);
         }
     }
 
@@ -290,6 +330,22 @@ impl Cpu {
         self.branch = true;
     }
 
+    /// Trigger an address error exception for `addr`
+    fn address_error(&mut self, cause: Exception, addr: u32) {
+        self.cop0.set_bad_vaddr(addr);
+        self.exception(cause);
+    }
+
+    /// Trigger the debug exception when a hardware breakpoint hits
+    fn debug_exception(&mut self) {
+        let handler_addr =
+            self.cop0.enter_debug_exception(self.current_pc,
+                                            self.delay_slot);
+
+        self.pc      = handler_addr;
+        self.next_pc = self.pc.wrapping_add(4);
+    }
+
     /// Trigger an exception
     fn exception(&mut self, cause: Exception) {
         // Update the status register
@@ -341,9 +397,7 @@ impl Cpu {
     }
 
     pub fn bad(&self) -> u32 {
-        // XXX we don't emulate the "BAD" cop0 register yet. It's
-        // almost useless in the PSX anyway since there's no MMU.
-        0
+        self.cop0.bad_vaddr()
     }
 
     /// Force PC address. Meant to be used from the debugger. Use at
@@ -354,10 +408,44 @@ impl Cpu {
         self.delay_slot = false;
     }
 
-    pub fn pad_profiles(&mut self) -> [&mut gamepad::Profile; 2] {
+    pub fn pad_profiles(&mut self) -> Vec<&mut gamepad::Profile> {
         self.inter.pad_profiles()
     }
 
+    pub fn memory_cards(&mut self) -> Vec<&mut MemoryCard> {
+        self.inter.memory_cards()
+    }
+
+    pub fn gamepads(&mut self) -> Vec<&mut GamePad> {
+        self.inter.gamepads()
+    }
+
+    /// Return the position of the video beam when it draws the pixel
+    /// at VRAM coordinates (`x`, `y`), used for the light guns
+    pub fn beam_position(&self, x: u16, y: u16) -> Option<BeamPosition> {
+        self.inter.beam_position(x, y)
+    }
+
+    /// Connect or disconnect a multitap on controller `port`
+    pub fn set_multitap(&mut self, port: usize, connected: bool) {
+        self.inter.set_multitap(port, connected)
+    }
+
+    /// Connect a new device to the serial port
+    pub fn set_sio1_link(&mut self, link: Box<Link>) {
+        self.inter.set_sio1_link(link)
+    }
+
+    /// Plug a new device in the expansion port
+    pub fn set_expansion(&mut self, expansion: Box<Expansion>) {
+        self.inter.set_expansion(expansion)
+    }
+
+    /// Replace the disc in the CD-ROM drive, returns the previous one
+    pub fn swap_disc(&mut self, disc: Option<Disc>) -> Option<Disc> {
+        self.inter.swap_disc(disc)
+    }
+
     /// Decode `instruction`'s opcode and run the function
     fn decode_and_execute(&mut self,
                           instruction: Instruction,
@@ -984,12 +1072,7 @@ impl Cpu {
         let cpu_r = instruction.t();
         let cop_r = instruction.d().0;
 
-        let v = match cop_r {
-            12 => self.cop0.sr(),
-            13 => self.cop0.cause(self.inter.irq_state()),
-            14 => self.cop0.epc(),
-            _  => panic!("Unhandled read from cop0r{}", cop_r),
-        };
+        let v = self.cop0.register(cop_r, self.inter.irq_state());
 
         self.load = (cpu_r, v)
     }
@@ -1001,18 +1084,7 @@ impl Cpu {
 
         let v = self.reg(cpu_r);
 
-        match cop_r {
-            3 | 5 | 6 | 7 | 9 | 11  => // Breakpoints registers
-                if v != 0 {
-                    panic!("Unhandled write to cop0r{}: {:08x}", cop_r, v)
-                },
-            12 => self.cop0.set_sr(v),
-            13 => // Cause register
-                if v != 0 {
-                    panic!("Unhandled write to CAUSE register: {:08x}", v)
-                },
-            _  => panic!("Unhandled cop0 register {}", cop_r),
-        }
+        self.cop0.set_register(cop_r, v);
     }
 
     /// Return From Exception
@@ -1117,7 +1189,12 @@ impl Cpu {
         let addr = self.reg(s).wrapping_add(i);
 
         // Cast as i8 to force sign extension
-        let v = self.load::<u8>(addr, debugger) as i8;
+        let v =
+            match self.load::<u8>(addr, debugger) {
+                Some(v) => v as i8,
+                // Bus error
+                None => return,
+            };
 
         // Put the load in the delay slot
         self.load = (t, v as u32);
@@ -1135,7 +1212,12 @@ impl Cpu {
         let addr = self.reg(s).wrapping_add(i);
 
         // Cast as i16 to force sign extension
-        let v = self.load::<u16>(addr, debugger) as i16;
+        let v =
+            match self.load::<u16>(addr, debugger) {
+                Some(v) => v as i16,
+                // Bus error
+                None => return,
+            };
 
         // Put the load in the delay slot
         self.load = (t, v as u32);
@@ -1160,7 +1242,12 @@ impl Cpu {
         // Next we load the *aligned* word containing the first
         // addressed byte
         let aligned_addr = addr & !3;
-        let aligned_word = self.load::<u32>(aligned_addr, debugger);
+        let aligned_word =
+            match self.load::<u32>(aligned_addr, debugger) {
+                Some(v) => v,
+                // Bus error
+                None => return,
+            };
 
         // Depending on the address alignment we fetch the 1, 2, 3 or
         // 4 *most* significant bytes and put them in the target
@@ -1190,12 +1277,17 @@ impl Cpu {
 
         // Address must be 32bit aligned
         if addr % 4 == 0 {
-            let v = self.load(addr, debugger);
+            let v =
+                match self.load(addr, debugger) {
+                    Some(v) => v,
+                    // Bus error
+                    None => return,
+                };
 
             // Put the load in the delay slot
             self.load = (t, v);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1210,7 +1302,12 @@ impl Cpu {
 
         let addr = self.reg(s).wrapping_add(i);
 
-        let v = self.load::<u8>(addr, debugger);
+        let v =
+            match self.load::<u8>(addr, debugger) {
+                Some(v) => v,
+                // Bus error
+                None => return,
+            };
 
         // Put the load in the delay slot
         self.load = (t, v as u32);
@@ -1229,12 +1326,17 @@ impl Cpu {
 
         // Address must be 16bit aligned
         if addr % 2 == 0 {
-            let v = self.load::<u16>(addr, debugger);
+            let v =
+                match self.load::<u16>(addr, debugger) {
+                    Some(v) => v,
+                    // Bus error
+                    None => return,
+                };
 
             // Put the load in the delay slot
             self.load = (t, v as u32);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1257,7 +1359,12 @@ impl Cpu {
         // Next we load the *aligned* word containing the first
         // addressed byte
         let aligned_addr = addr & !3;
-        let aligned_word = self.load::<u32>(aligned_addr, debugger);
+        let aligned_word =
+            match self.load::<u32>(aligned_addr, debugger) {
+                Some(v) => v,
+                // Bus error
+                None => return,
+            };
 
         // Depending on the address alignment we fetch the 1, 2, 3 or
         // 4 *least* significant bytes and put them in the target
@@ -1305,7 +1412,7 @@ impl Cpu {
         if addr % 2 == 0 {
             self.store(addr, v as u16, debugger);
         } else {
-            self.exception(Exception::StoreAddressError);
+            self.address_error(Exception::StoreAddressError, addr);
         }
     }
 
@@ -1323,8 +1430,10 @@ impl Cpu {
 
         let aligned_addr = addr & !3;
         // Load the current value for the aligned word at the target
-        // address
-        let cur_mem = self.load::<u32>(aligned_addr, debugger);
+        // address. This isn't an architectural read: breakpoints and
+        // bus errors are handled by the store below.
+        let cur_mem =
+            self.inter.load::<u32>(&mut self.tk, aligned_addr).unwrap_or(0);
 
         let mem = match addr & 3 {
             0 => (cur_mem & 0xffffff00) | (v >> 24),
@@ -1353,7 +1462,7 @@ impl Cpu {
         if addr % 4 == 0 {
             self.store(addr, v, debugger);
         } else {
-            self.exception(Exception::StoreAddressError);
+            self.address_error(Exception::StoreAddressError, addr);
         }
     }
 
@@ -1371,8 +1480,10 @@ impl Cpu {
 
         let aligned_addr = addr & !3;
         // Load the current value for the aligned word at the target
-        // address
-        let cur_mem = self.load::<u32>(aligned_addr, debugger);
+        // address. This isn't an architectural read: breakpoints and
+        // bus errors are handled by the store below.
+        let cur_mem =
+            self.inter.load::<u32>(&mut self.tk, aligned_addr).unwrap_or(0);
 
         let mem = match addr & 3 {
             0 => (cur_mem & 0x00000000) | (v << 0),
@@ -1409,12 +1520,17 @@ impl Cpu {
 
         // Address must be 32bit aligned
         if addr % 4 == 0 {
-            let v = self.load(addr, debugger);
+            let v =
+                match self.load(addr, debugger) {
+                    Some(v) => v,
+                    // Bus error
+                    None => return,
+                };
 
             // Send to coprocessor
             self.gte.set_data(cop_r, v);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1451,7 +1567,7 @@ impl Cpu {
         if addr % 4 == 0 {
             self.store(addr, v, debugger);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::StoreAddressError, addr);
         }
     }
 
@@ -1,7 +1,9 @@
+use std::cmp;
+
 use self::opengl::{Renderer, Position, Color};
 use memory::{Addressable, AccessWidth};
 use memory::interrupts::{Interrupt, InterruptState};
-use memory::timers::Timers;
+use memory::timers::{Timers, BlankSignal};
 use timekeeper::{TimeKeeper, Peripheral, Cycles, FracCycles};
 use HardwareType;
 
@@ -108,6 +110,17 @@ pub struct Gpu {
     hardware: HardwareType,
     /// Next word returned by the GPUREAD command
     read_word: u32,
+    /// Copy of the VRAM contents, 1024x512 16bit pixels. For now it's
+    /// only used for the CPU and DMA image transfers, the renderer
+    /// doesn't use it.
+    vram: Box<[u16]>,
+    /// Image being loaded into VRAM by GP0(0xA0)
+    vram_load: Option<VRamTransfer>,
+    /// Image being read from VRAM through GPUREAD after GP0(0xC0)
+    vram_store: Option<VRamTransfer>,
+    /// Position where the light gun sensor sees the beam, triggers
+    /// the lightpen interrupt (IRQ10)
+    lightpen_target: Option<BeamPosition>,
 }
 
 impl Gpu {
@@ -159,6 +172,10 @@ impl Gpu {
             display_line_tick: 0,
             hardware: hardware,
             read_word: 0,
+            vram: vec![0; VRAM_WIDTH * VRAM_HEIGHT].into_boxed_slice(),
+            vram_load: None,
+            vram_store: None,
+            lightpen_target: None,
         }
     }
 
@@ -235,6 +252,139 @@ impl Gpu {
         phase.multiply(self.gpu_to_cpu_clock_ratio())
     }
 
+    /// Return the horizontal blanking signal, used by the timer 0
+    /// synchronization modes
+    pub fn hblank_signal(&self) -> BlankSignal {
+        let (ticks_per_line, _) = self.vmode_timings();
+
+        let period = ticks_per_line as Cycles;
+        let start = self.display_horiz_start as Cycles;
+        let end = self.display_horiz_end as Cycles;
+
+        let pos = self.display_line_tick as Cycles;
+
+        self.blank_signal(period, start, end, pos)
+    }
+
+    /// Return the vertical blanking signal, used by the timer 1
+    /// synchronization modes
+    pub fn vblank_signal(&self) -> BlankSignal {
+        let (ticks_per_line, lines_per_frame) = self.vmode_timings();
+
+        let ticks_per_line = ticks_per_line as Cycles;
+
+        let period = ticks_per_line * lines_per_frame as Cycles;
+        let start = self.display_line_start as Cycles * ticks_per_line;
+        let end = self.display_line_end as Cycles * ticks_per_line;
+
+        let pos = self.display_line as Cycles * ticks_per_line +
+                  self.display_line_tick as Cycles;
+
+        self.blank_signal(period, start, end, pos)
+    }
+
+    /// Build a blanking signal from the `period` of the signal, the
+    /// `start` and `end` of the display area and the current
+    /// position `pos`, all in GPU clock cycles
+    fn blank_signal(&self,
+                    period: Cycles,
+                    start: Cycles,
+                    end: Cycles,
+                    pos: Cycles) -> BlankSignal {
+        let ratio = self.gpu_to_cpu_clock_ratio();
+
+        // The blank signal's period starts with the display area
+        let start = start % period;
+        let active = cmp::min(end.saturating_sub(start), period);
+        let pos = (pos + period - start) % period;
+
+        // Convert from GPU clock cycles into CPU clock cycles
+        let period = FracCycles::from_cycles(period).divide(ratio);
+        let active = FracCycles::from_cycles(active).divide(ratio);
+
+        let pos = FracCycles::from_cycles(pos).get_fp() +
+                  self.gpu_clock_phase as Cycles;
+        let pos = FracCycles::from_fp(pos).divide(ratio);
+
+        BlankSignal::new(period, active, pos)
+    }
+
+    /// Return the position of the video beam when it draws the pixel
+    /// at VRAM coordinates (`x`, `y`). Returns None if the pixel is
+    /// not in the displayed area. Used to emulate the light guns.
+    pub fn beam_position(&self, x: u16, y: u16) -> Option<BeamPosition> {
+        let divider = self.hres.dotclock_divider() as u16;
+
+        // Position of the pixel relative to the top-left corner of
+        // the display area
+        let dot = x.wrapping_sub(self.display_vram_x_start) & 0x3ff;
+        let line = y.wrapping_sub(self.display_vram_y_start) & 0x1ff;
+
+        // In interlaced mode each field contains every other VRAM
+        // line
+        let line =
+            match self.interlaced {
+                true  => line / 2,
+                false => line,
+            };
+
+        // Display ranges in dotclock ticks and lines
+        let first_dot = self.display_horiz_start / divider;
+        let last_dot = self.display_horiz_end / divider;
+
+        let width = last_dot.saturating_sub(first_dot);
+        let height =
+            self.display_line_end.saturating_sub(self.display_line_start);
+
+        if dot >= width || line >= height {
+            return None;
+        }
+
+        // Position in the line in GPU clock ticks
+        let tick = (first_dot + dot) as Cycles * divider as Cycles;
+
+        // Convert from GPU cycles into CPU cycles
+        let cycles =
+            FracCycles::from_cycles(tick).divide(self.gpu_to_cpu_clock_ratio());
+
+        if cycles.get_fp() >= self.hsync_period().get_fp() {
+            // Past the end of the line
+            return None;
+        }
+
+        Some(BeamPosition {
+            cycles: cycles.ceil(),
+            line: self.display_line_start + line,
+        })
+    }
+
+    /// Set the position of the lightpen sensor, the lightpen
+    /// interrupt will be triggered every time the beam reaches it.
+    pub fn set_lightpen_target(&mut self, target: Option<BeamPosition>) {
+        self.lightpen_target = target;
+    }
+
+    /// Return the number of GPU clock ticks before the beam reaches
+    /// `target`, between 1 and one full frame.
+    fn ticks_to_beam_position(&self, target: BeamPosition) -> Cycles {
+        let (ticks_per_line, lines_per_frame) = self.vmode_timings();
+
+        let ticks_per_line = ticks_per_line as Cycles;
+        let frame_ticks = ticks_per_line * lines_per_frame as Cycles;
+
+        // Convert the position within the line into GPU clock ticks
+        let tick = FracCycles::from_cycles(target.cycles)
+            .multiply(self.gpu_to_cpu_clock_ratio())
+            .ceil();
+
+        let target = target.line as Cycles * ticks_per_line + tick;
+        let current = self.display_line as Cycles * ticks_per_line +
+                      self.display_line_tick as Cycles;
+
+        // Compute the distance in [1; frame_ticks]
+        (target + frame_ticks - current - 1) % frame_ticks + 1
+    }
+
     /// Update the GPU state to its current status
     pub fn sync(&mut self,
                 tk: &mut TimeKeeper,
@@ -253,6 +403,14 @@ impl Gpu {
         // Conwert delta back to integer
         let delta = delta >> 16;
 
+        if let Some(target) = self.lightpen_target {
+            if delta >= self.ticks_to_beam_position(target) {
+                // XXX If more than a frame elapsed the interrupt would
+                // have triggered several times
+                irq_state.assert(Interrupt::Lightpen);
+            }
+        }
+
         // Compute the current line and position within the line.
 
         let (ticks_per_line, lines_per_frame) = self.vmode_timings();
@@ -346,6 +504,16 @@ impl Gpu {
             delta += (display_line_end - 1 - cur_line) * ticks_per_line;
         }
 
+        if let Some(target) = self.lightpen_target {
+            // Make sure we're synchronized when the beam reaches the
+            // lightpen sensor
+            let lightpen_delta = self.ticks_to_beam_position(target);
+
+            if lightpen_delta < delta {
+                delta = lightpen_delta;
+            }
+        }
+
         // Convert delta in CPU clock periods.
         delta <<= FracCycles::frac_bits();
         // Remove the current fractional cycle to be more accurate
@@ -444,11 +612,12 @@ impl Gpu {
         r |= (self.display_disabled as u32) << 23;
         r |= (self.gp0_interrupt as u32) << 24;
 
-        // For now we pretend that the GPU is always ready:
+        // For now we pretend that the GPU is always ready to receive
+        // commands and DMA blocks:
         // Ready to receive command
         r |= 1 << 26;
         // Ready to send VRAM to CPU
-        r |= 1 << 27;
+        r |= (self.vram_store.is_some() as u32) << 27;
         // Ready to receive DMA block
         r |= 1 << 28;
 
@@ -460,31 +629,53 @@ impl Gpu {
             r |= ((self.displayed_vram_line() & 1) as u32) << 31
         }
 
-        // Not sure about that, I'm guessing that it's the signal
-        // checked by the DMA in when sending data in Request
-        // synchronization mode. For now I blindly follow the Nocash
-        // spec.
-        let dma_request =
-            match self.dma_direction {
-                // Always 0
-                DmaDirection::Off => 0,
-                // Should be 0 if FIFO is full, 1 otherwise
-                DmaDirection::Fifo => 1,
-                // Should be the same as status bit 28
-                DmaDirection::CpuToGp0 => (r >> 28) & 1,
-                // Should be the same as status bit 27
-                DmaDirection::VRamToCpu => (r >> 27) & 1,
-            };
-
-        r |= dma_request << 25;
+        r |= (self.dma_request() as u32) << 25;
 
         r
     }
 
-    /// Retrieve value of the "read" register
-    fn read(&self) -> u32 {
-        println!("GPUREAD");
-        // XXX framebuffer read not supported
+    /// Return the state of the DMA request signal, checked by the DMA
+    /// when transferring data in Request synchronization mode. It
+    /// depends on the direction set with GP1(0x04).
+    pub fn dma_request(&self) -> bool {
+        match self.dma_direction {
+            DmaDirection::Off => false,
+            // Should be false if FIFO is full
+            DmaDirection::Fifo => true,
+            // Ready to receive DMA block, always true for now
+            DmaDirection::CpuToGp0 => true,
+            // Ready to send VRAM to CPU
+            DmaDirection::VRamToCpu => self.vram_store.is_some(),
+        }
+    }
+
+    /// Retrieve value of the "read" register. If an image store is
+    /// in progress the next two pixels are returned.
+    pub fn read(&mut self) -> u32 {
+        let done =
+            match self.vram_store {
+                Some(ref mut transfer) => {
+                    let mut word = 0;
+
+                    for i in 0..2 {
+                        // If we have an odd number of pixels the last
+                        // word is padded with 0
+                        if let Some(index) = transfer.next() {
+                            word |= (self.vram[index] as u32) << (i * 16);
+                        }
+                    }
+
+                    self.read_word = word;
+
+                    transfer.done()
+                }
+                None => false,
+            };
+
+        if done {
+            self.vram_store = None;
+        }
+
         self.read_word
     }
 
@@ -559,16 +750,44 @@ impl Gpu {
                 }
             }
             Gp0Mode::ImageLoad => {
-                // XXX Should copy pixel data to VRAM
+                self.vram_load_word(val);
 
                 if self.gp0_words_remaining == 0 {
                     // Load done, switch back to command mode
                     self.gp0_mode = Gp0Mode::Command;
+                    self.vram_load = None;
                 }
             }
         }
     }
 
+    /// Store the two pixels in `val` at the current position of the
+    /// image load
+    fn vram_load_word(&mut self, val: u32) {
+        let transfer =
+            match self.vram_load {
+                Some(ref mut t) => t,
+                None => return,
+            };
+
+        for &pixel in [val as u16, (val >> 16) as u16].iter() {
+            // The padding of the last word is ignored
+            let index =
+                match transfer.next() {
+                    Some(i) => i,
+                    None => break,
+                };
+
+            if self.preserve_masked_pixels && self.vram[index] & 0x8000 != 0 {
+                continue;
+            }
+
+            let mask = (self.force_set_mask_bit as u16) << 15;
+
+            self.vram[index] = pixel | mask;
+        }
+    }
+
     /// GP0(0x00): No Operation
     fn gp0_nop(&mut self) {
         // NOP
@@ -752,14 +971,13 @@ impl Gpu {
 
     /// GP0(0xA0): Image Load
     fn gp0_image_load(&mut self) {
-        // Parameter 2 contains the image resolution
-        let res = self.gp0_command[2];
-
-        let width  = res & 0xffff;
-        let height = res >> 16;
+        // Parameter 1 contains the destination and parameter 2 the
+        // image resolution
+        let transfer = VRamTransfer::from_gp0(self.gp0_command[1],
+                                              self.gp0_command[2]);
 
         // Size of the image in 16bit pixels
-        let imgsize = width * height;
+        let imgsize = transfer.width as u32 * transfer.height as u32;
 
         // If we have an odd number of pixels we must round up since
         // we transfer 32bits at a time. There'll be 16bits of padding
@@ -771,17 +989,17 @@ impl Gpu {
 
         // Put the GP0 state machine in ImageLoad mode
         self.gp0_mode = Gp0Mode::ImageLoad;
+        self.vram_load = Some(transfer);
     }
 
     /// GP0(0xC0): Image Store
     fn gp0_image_store(&mut self) {
-        // Parameter 2 contains the image resolution
-        let res = self.gp0_command[2];
-
-        let width  = res & 0xffff;
-        let height = res >> 16;
+        // Parameter 1 contains the source and parameter 2 the image
+        // resolution. The pixels are then read through GPUREAD.
+        let transfer = VRamTransfer::from_gp0(self.gp0_command[1],
+                                              self.gp0_command[2]);
 
-        println!("Unhandled image store: {}x{}", width, height);
+        self.vram_store = Some(transfer);
     }
 
     /// GP0(0xE1): Draw Mode
@@ -875,8 +1093,15 @@ impl Gpu {
             0x03 => self.gp1_display_enable(val),
             0x04 => self.gp1_dma_direction(val),
             0x05 => self.gp1_display_vram_start(val),
-            0x06 => self.gp1_display_horizontal_range(val),
-            0x07 => self.gp1_display_vertical_range(val, tk, irq_state),
+            0x06 => {
+                self.gp1_display_horizontal_range(val);
+                // The blanking timings changed
+                timers.video_timings_changed(tk, irq_state, self);
+            }
+            0x07 => {
+                self.gp1_display_vertical_range(val, tk, irq_state);
+                timers.video_timings_changed(tk, irq_state, self);
+            }
             0x10 => self.gp1_get_info(val),
             0x08 => {
                 self.gp1_display_mode(val, tk, irq_state);
@@ -944,6 +1169,7 @@ impl Gpu {
         self.gp0_command.clear();
         self.gp0_words_remaining = 0;
         self.gp0_mode = Gp0Mode::Command;
+        self.vram_load = None;
         // XXX should also clear the command FIFO when we implement it
     }
 
@@ -1066,6 +1292,83 @@ impl Gpu {
     }
 }
 
+/// Position of the video beam, used to emulate the light guns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BeamPosition {
+    /// Position within the line in CPU clock periods since the
+    /// beginning of the line
+    pub cycles: Cycles,
+    /// Line number since the beginning of the frame (or field in
+    /// interlaced mode)
+    pub line: u16,
+}
+
+/// VRAM width in 16bit pixels
+const VRAM_WIDTH: usize = 1024;
+/// VRAM height in lines
+const VRAM_HEIGHT: usize = 512;
+
+/// Rectangle of VRAM being transferred to or from the CPU (or DMA)
+struct VRamTransfer {
+    /// Left edge of the rectangle in VRAM
+    left: u16,
+    /// Top edge of the rectangle in VRAM
+    top: u16,
+    /// Width in pixels, from 1 to 1024
+    width: u16,
+    /// Height in lines, from 1 to 512
+    height: u16,
+    /// Horizontal position of the next pixel relative to `left`
+    x: u16,
+    /// Vertical position of the next pixel relative to `top`
+    y: u16,
+}
+
+impl VRamTransfer {
+    /// Build a transfer from the position and size parameters of
+    /// the GP0 image commands
+    fn from_gp0(position: u32, size: u32) -> VRamTransfer {
+        let width = size as u16;
+        let height = (size >> 16) as u16;
+
+        VRamTransfer {
+            left: (position & 0x3ff) as u16,
+            top: ((position >> 16) & 0x1ff) as u16,
+            // A size of 0 is treated like the maximum size
+            width: (width.wrapping_sub(1) & 0x3ff) + 1,
+            height: (height.wrapping_sub(1) & 0x1ff) + 1,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    /// Return the index in VRAM of the next pixel and advance the
+    /// position, `None` if the transfer is done. Coordinates wrap
+    /// around the edges of the VRAM.
+    fn next(&mut self) -> Option<usize> {
+        if self.done() {
+            return None;
+        }
+
+        let x = (self.left + self.x) as usize % VRAM_WIDTH;
+        let y = (self.top + self.y) as usize % VRAM_HEIGHT;
+
+        self.x += 1;
+
+        if self.x == self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+
+        Some(y * VRAM_WIDTH + x)
+    }
+
+    /// Return true once every pixel has been transferred
+    fn done(&self) -> bool {
+        self.y >= self.height
+    }
+}
+
 /// Possible states for the GP0 command register
 enum Gp0Mode {
     /// Default mode: handling commands
@@ -1226,3 +1529,25 @@ impl ::std::ops::Index<usize> for CommandBuffer {
         &self.buffer[index]
     }
 }
+
+#[test]
+fn vram_transfer_wrap() {
+    // 3x2 pixels at 1023x511, wraps around both edges
+    let mut transfer = VRamTransfer::from_gp0(0x01ff03ff, 0x00020003);
+
+    let expected = [
+        511 * VRAM_WIDTH + 1023,
+        511 * VRAM_WIDTH,
+        511 * VRAM_WIDTH + 1,
+        1023,
+        0,
+        1,
+        ];
+
+    for &index in expected.iter() {
+        assert!(transfer.next() == Some(index));
+    }
+
+    assert!(transfer.done());
+    assert!(transfer.next() == None);
+}
@@ -16,6 +16,7 @@
 // Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 
 extern crate sdl2;
+extern crate sdl2_sys;
 extern crate gl;
 
 mod cpu;
@@ -26,18 +27,31 @@ mod debugger;
 mod cdrom;
 mod padmemcard;
 mod spu;
+mod mdec;
+mod bindings;
+mod sio1;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{self, Write};
 
 use gpu::Gpu;
 use gpu::opengl::Renderer;
 use cpu::Cpu;
 use memory::Interconnect;
 use memory::bios::Bios;
+use memory::expansion::CheatCart;
+use memory::ram::RamSize;
 use debugger::Debugger;
 use padmemcard::gamepad;
-use padmemcard::gamepad::{Button, ButtonState};
-use cdrom::disc::{Disc, Region};
+use padmemcard::gamepad::GamePad;
+use padmemcard::gamepad::{ButtonState, Rumble};
+use padmemcard::memcard::MemoryCard;
+use padmemcard::memcard::save::Save;
+use cdrom::disc::Region;
+use cdrom::disc::set::DiscSet;
+use bindings::{Bindings, Input, MAX_PLAYERS};
+use sio1::link::{self, Link};
 
 use sdl2::{controller, EventPump};
 use sdl2::event::{Event};
@@ -48,24 +62,55 @@ use sdl2::keyboard::Keycode;
 fn main() {
     let argv: Vec<_> = std::env::args().collect();
 
+    if argv.len() > 1 && argv[1] == "--memcard" {
+        if let Err(e) = memory_card_tool(&argv[2..]) {
+            println!("{}", e);
+        }
+        return;
+    }
+
+    let (argv, bindings, sio1_link, cheat_cart, ram_size) =
+        match parse_options(&argv) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+
     if argv.len() < 2 {
-        println!("Usage: {} <BIOS-file> [CDROM-bin-file]", argv[0]);
+        println!("Usage: {} [--padN <type>] [--config <ini-file>] \
+                  [--sio1 <link>] [--cheat-cart <rom>] [--dev-kit] \
+                  <BIOS-file> [CDROM-image/m3u-file]",
+                 argv[0]);
+        println!("       {} --memcard <card> [list|convert <out>|\
+                  export <n> <out>|import <save>|icon <n> <out.pam>]",
+                 argv[0]);
         println!("Recommended BIOS: SCPH1001.BIN");
+        println!("Pad types: digital, dualshock, mouse, negcon, jogcon, \
+                  guncon, justifier or none");
+        println!("Serial links: loopback, terminal, tcp:<host:port>, \
+                  tcp-listen:<addr:port>, unix:<path>, unix-listen:<path>");
         return;
     }
 
     let bios = Bios::new(&Path::new(&argv[1])).unwrap();
 
-    let (disc, video_standard) =
+    let (mut discs, video_standard) =
         if argv.len() > 2 {
             let disc_path = &Path::new(&argv[2]);
 
-            match Disc::from_path(disc_path) {
-                Ok(disc) => {
-                    let region = disc.region();
+            match DiscSet::from_path(disc_path) {
+                Ok(discs) => {
+                    let region = discs.region();
 
                     println!("Disc region: {:?}", region);
 
+                    if discs.len() > 1 {
+                        println!("Loaded {} discs, press F1 to swap",
+                                 discs.len());
+                    }
+
                     let video_standard =
                         match region {
                             Region::Europe => HardwareType::Pal,
@@ -73,7 +118,7 @@ fn main() {
                             Region::Japan => HardwareType::Ntsc,
                         };
 
-                    (Some(disc), video_standard)
+                    (Some(discs), video_standard)
                 }
                 Err(e) => {
                     println!("Bad disc: {}", e);
@@ -86,6 +131,35 @@ fn main() {
             (None, HardwareType::Ntsc)
         };
 
+    let disc = discs.as_mut().and_then(|d| d.take_current());
+
+    // Serial of the first disc, used to select the memory cards
+    let serial = disc.as_ref().and_then(|d| d.serial());
+
+    if let Some(ref d) = disc {
+        println!("Disc image: {}, {} track(s)",
+                 d.image_format(), d.toc().tracks().len());
+
+        if let Some(serial) = d.serial() {
+            println!("Game serial: {}", serial);
+        }
+
+        if let Some(cnf) = d.system_cnf() {
+            let show = |v: Option<u32>| v.map_or("default".to_owned(),
+                                                 |v| format!("0x{:x}", v));
+
+            println!("Boot executable: {} (TCB: {}, EVENT: {}, STACK: {})",
+                     cnf.boot(),
+                     show(cnf.tcb()), show(cnf.event()), show(cnf.stack()));
+        }
+
+        let patched = d.subchannel_patch_count();
+
+        if patched > 0 {
+            println!("Subchannel data patched for {} sector(s)", patched);
+        }
+    }
+
     // We must initialize SDL before the interconnect is created since
     // it contains the GPU and the GPU needs to create a window
     let sdl_context = sdl2::init().unwrap();
@@ -93,13 +167,70 @@ fn main() {
     // When the controller is destroyed SDL2 will stop reporting
     // controller events so we have to make sure to keep it alive
     // until the end of the program.
-    let _controller = initialize_sdl2_controllers(&sdl_context);
+    let mut controllers = initialize_sdl2_controllers(&sdl_context);
 
     let renderer = Renderer::new(&sdl_context);
     let gpu = Gpu::new(renderer, video_standard);
-    let inter = Interconnect::new(bios, gpu, disc);
+    let inter = Interconnect::new(bios, gpu, disc, ram_size);
     let mut cpu = Cpu::new(inter);
 
+    for (slot, card) in cpu.memory_cards().iter_mut().enumerate() {
+        let path = memory_card_path(serial.as_ref(), slot);
+
+        match MemoryCard::from_path(&path) {
+            Ok(c) => {
+                println!("Memory card {}: {} ({} free block(s))",
+                         slot + 1, path.display(), c.free_blocks());
+
+                if let Ok(saves) = c.directory() {
+                    for s in saves {
+                        println!("    {} ({})", s.title(), s.name());
+                    }
+                }
+
+                **card = c;
+            }
+            Err(e) => println!("Couldn't load memory card {}: {}",
+                               path.display(), e),
+        }
+    }
+
+    if let Some(ref spec) = sio1_link {
+        match open_sio1_link(spec) {
+            Ok(link) => cpu.set_sio1_link(link),
+            Err(e) => {
+                println!("Can't open serial link {}: {}", spec, e);
+                return;
+            }
+        }
+    }
+
+    if let Some(ref path) = cheat_cart {
+        match CheatCart::from_path(path) {
+            Ok(cart) => cpu.set_expansion(Box::new(cart)),
+            Err(e) => {
+                println!("Can't load cheat cartridge {}: {}",
+                         path.display(), e);
+                return;
+            }
+        }
+    }
+
+    let mouse_player =
+        connect_controllers(&sdl_context, &mut cpu, &controllers, &bindings);
+
+    let (mouse_player, gun_player) =
+        match mouse_player {
+            Some((p, gamepad::Type::GunCon)) |
+            Some((p, gamepad::Type::Justifier)) => (Some(p), Some(p)),
+            Some((p, _)) => {
+                // Capture the mouse and only report relative motion
+                sdl_context.mouse().set_relative_mouse_mode(true);
+                (Some(p), None)
+            }
+            None => (None, None),
+        };
+
     let mut debugger = Debugger::new();
 
     let mut event_pump = sdl_context.event_pump().unwrap();
@@ -109,24 +240,163 @@ fn main() {
             cpu.run_next_instruction(&mut debugger);
         }
 
-        match handle_events(&mut event_pump, &mut cpu) {
+        if let Some(player) = gun_player {
+            // The window displays the entire VRAM so the mouse
+            // coordinates are VRAM coordinates
+            let (_, x, y) = sdl_context.mouse().mouse_state();
+
+            let target =
+                if x >= 0 && y >= 0 {
+                    cpu.beam_position(x as u16, y as u16)
+                } else {
+                    None
+                };
+
+            cpu.pad_profiles()[player].set_gun_target(target);
+        }
+
+        match handle_events(&mut event_pump,
+                            &mut cpu,
+                            &controllers,
+                            &bindings,
+                            mouse_player) {
             Action::None => {},
             Action::Quit => return,
             Action::Debug => debugger.debug(&mut cpu),
+            Action::ControllerAdded(id) =>
+                controller_added(&sdl_context,
+                                 &mut cpu,
+                                 &mut controllers,
+                                 &bindings,
+                                 id),
+            Action::ControllerRemoved(instance_id) =>
+                controller_removed(&mut cpu,
+                                   &mut controllers,
+                                   &bindings,
+                                   instance_id),
+            Action::SwapDisc => {
+                if let Some(ref mut discs) = discs {
+                    let ejected = cpu.swap_disc(None);
+                    let next = discs.next(ejected);
+
+                    println!("Inserting disc {}/{}",
+                             discs.current() + 1, discs.len());
+
+                    cpu.swap_disc(next);
+                }
+            }
         }
     }
 }
 
+/// Return the path of the memory card image for `slot`. If we know
+/// the game's `serial` each game gets its own set of cards, otherwise
+/// they're shared.
+fn memory_card_path(serial: Option<&String>, slot: usize) -> PathBuf {
+    let name =
+        match serial {
+            Some(s) => format!("{}-{}.mcr", s, slot + 1),
+            None => format!("memcard{}.mcr", slot + 1),
+        };
+
+    PathBuf::from(name)
+}
+
+/// Memory card management mode, `args` contains the card path
+/// followed by the command
+fn memory_card_tool(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err("Missing memory card path".to_owned());
+    }
+
+    let path = Path::new(&args[0]);
+
+    let mut card = try!(MemoryCard::from_path(path)
+                        .map_err(|e| format!("Can't load {}: {}",
+                                             path.display(), e)));
+
+    let saves = try!(card.directory().map_err(|e| e.to_string()));
+
+    let command: Vec<&str> = args[1..].iter().map(|a| a.as_ref()).collect();
+
+    // Return the save at (1-based) index `n`
+    let nth_save = |n: &str| {
+        n.parse::<usize>().ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|n| saves.get(n))
+            .ok_or(format!("Invalid save index: {}", n))
+    };
+
+    match &command[..] {
+        &[] | &["list"] => {
+            for (i, s) in saves.iter().enumerate() {
+                let frames = s.icon().map_or(0, |i| i.frame_count());
+
+                println!("{:2}: {} ({})", i + 1, s.title(), s.name());
+                println!("    {} bytes, blocks {:?}, {} icon frame(s)",
+                         s.size(), s.blocks(), frames);
+            }
+
+            println!("{} free block(s)", card.free_blocks());
+        }
+        &["convert", out] => {
+            try!(card.save_as(Path::new(out)).map_err(|e| e.to_string()));
+        }
+        &["export", n, out] => {
+            let save = card.export_save(try!(nth_save(n)));
+
+            try!(save.write(Path::new(out)).map_err(|e| e.to_string()));
+        }
+        &["import", save] => {
+            let save = try!(Save::from_path(Path::new(save))
+                            .map_err(|e| e.to_string()));
+
+            println!("Importing {} ({}, {} block(s))",
+                     save.title(), save.name(), save.blocks());
+
+            try!(card.import_save(&save).map_err(|e| e.to_string()));
+        }
+        &["icon", n, out] => {
+            let icon = try!(nth_save(n)).icon();
+            let icon = try!(icon.ok_or("Save has no icon".to_owned()));
+
+            // Dump the first frame as a PAM image
+            let mut pam = b"P7\nWIDTH 16\nHEIGHT 16\nDEPTH 4\nMAXVAL 255\n\
+                            TUPLTYPE RGB_ALPHA\nENDHDR\n".to_vec();
+
+            pam.extend_from_slice(&icon.to_rgba(0));
+
+            try!(File::create(out)
+                 .and_then(|mut f| f.write_all(&pam))
+                 .map_err(|e| e.to_string()));
+        }
+        _ => return Err(format!("Invalid memory card command: {:?}",
+                                command)),
+    }
+
+    Ok(())
+}
+
 enum Action {
     None,
     Quit,
     Debug,
+    /// Replace the disc with the next one in the set
+    SwapDisc,
+    /// A game controller has been plugged in, contains the joystick
+    /// index
+    ControllerAdded(u32),
+    /// A game controller has been removed, contains its instance ID
+    ControllerRemoved(i32),
 }
 
 // Handle SDL events
-fn handle_events(event_pump: &mut EventPump, cpu: &mut Cpu) -> Action {
-    // Only handle Pad 0 for now.
-    let pad = &mut *cpu.pad_profiles()[0];
+fn handle_events(event_pump: &mut EventPump,
+                 cpu: &mut Cpu,
+                 controllers: &[Option<Controller>],
+                 bindings: &Bindings,
+                 mouse_player: Option<usize>) -> Action {
+    let mut pads = cpu.pad_profiles();
 
     for e in event_pump.poll_iter() {
         match e {
@@ -135,25 +405,91 @@ fn handle_events(event_pump: &mut EventPump, cpu: &mut Cpu) -> Action {
             Event::Quit {..} => return Action::Quit,
             Event::KeyDown { keycode: Some(Keycode::Pause), .. } =>
                 return Action::Debug,
-            Event::KeyDown { keycode: k, .. } =>
-                handle_keyboard(pad, k, ButtonState::Pressed),
-            Event::KeyUp { keycode: k, .. } =>
-                handle_keyboard(pad, k, ButtonState::Released),
-            Event::ControllerButtonDown { button: b, .. } =>
-                handle_controller(pad, b, ButtonState::Pressed),
-            Event::ControllerButtonUp { button: b, .. } =>
-                handle_controller(pad, b, ButtonState::Released),
-            Event::ControllerAxisMotion { axis, value: val, .. } =>
-                update_controller_axis(pad, axis, val),
+            Event::KeyDown { keycode: Some(Keycode::F1), .. } =>
+                return Action::SwapDisc,
+            Event::ControllerDeviceAdded { which, .. } =>
+                return Action::ControllerAdded(which as u32),
+            Event::ControllerDeviceRemoved { which, .. } =>
+                return Action::ControllerRemoved(which),
             _ => (),
         }
+
+        // Host controller generating the event, controller events
+        // are only sent to the pads bound to it
+        let controller =
+            match e {
+                Event::ControllerButtonDown { which, .. } |
+                Event::ControllerButtonUp { which, .. } |
+                Event::ControllerAxisMotion { which, .. } =>
+                    match controller_slot(controllers, which) {
+                        Some(c) => Some(c),
+                        // Unknown controller
+                        None => continue,
+                    },
+                _ => None,
+            };
+
+        for (player, pad) in pads.iter_mut().enumerate() {
+            let pad = &mut **pad;
+            let b = bindings.pad(player);
+
+            if controller.is_some() && b.controller != controller {
+                continue;
+            }
+
+            match e {
+                Event::KeyDown { keycode: Some(k), .. } =>
+                    b.digital(pad, Input::Key(k), ButtonState::Pressed),
+                Event::KeyUp { keycode: Some(k), .. } =>
+                    b.digital(pad, Input::Key(k), ButtonState::Released),
+                Event::ControllerButtonDown { button, .. } =>
+                    b.digital(pad,
+                              Input::Button(button),
+                              ButtonState::Pressed),
+                Event::ControllerButtonUp { button, .. } =>
+                    b.digital(pad,
+                              Input::Button(button),
+                              ButtonState::Released),
+                Event::ControllerAxisMotion { axis, value, .. } =>
+                    b.axis(pad, axis, value),
+                Event::MouseButtonDown { mouse_btn, .. } =>
+                    b.digital(pad,
+                              Input::Mouse(mouse_btn),
+                              ButtonState::Pressed),
+                Event::MouseButtonUp { mouse_btn, .. } =>
+                    b.digital(pad,
+                              Input::Mouse(mouse_btn),
+                              ButtonState::Released),
+                // Mouse motion only controls a single player
+                Event::MouseMotion { xrel, yrel, .. }
+                if mouse_player == Some(player) =>
+                    pad.add_relative_motion(xrel, yrel),
+                _ => (),
+            }
+        }
     }
 
     Action::None
 }
 
-fn initialize_sdl2_controllers(sdl_context: &sdl2::Sdl) -> Option<controller::GameController> {
-    // Attempt to discover and enable a game controller
+/// Host game controller
+struct Controller {
+    /// When the controller is destroyed SDL2 will stop reporting
+    /// controller events so we have to keep it alive
+    _controller: controller::GameController,
+    /// Joystick index, used to open the haptic device
+    index: u32,
+    /// Instance ID used to identify the controller in SDL events
+    instance_id: i32,
+}
+
+/// Open the available game controllers. By default the first one is
+/// bound to player 1, the 2nd to player 2 etc... Returns
+/// `MAX_PLAYERS` controller slots, the empty ones can be filled by
+/// controllers plugged in later.
+fn initialize_sdl2_controllers(sdl_context: &sdl2::Sdl)
+                               -> Vec<Option<Controller>> {
+    // Attempt to discover and enable the game controllers
     let joystick = sdl_context.joystick().unwrap();
     let game_controller = sdl_context.game_controller().unwrap();
 
@@ -166,109 +502,472 @@ fn initialize_sdl2_controllers(sdl_context: &sdl2::Sdl) -> Option<controller::Ga
             }
         };
 
-    let mut controller = None;
+    let mut controllers = Vec::new();
 
-    // For now we just take the first controller we manage to open
-    // (if any)
     for id in 0..njoysticks {
+        if controllers.len() == MAX_PLAYERS {
+            break;
+        }
+
         if game_controller.is_game_controller(id) {
-            println!("Attempting to open controller {}", id);
-
-            match game_controller.open(id) {
-                Ok(c) => {
-                    // We managed to find and open a game controller,
-                    // exit the loop
-                    println!("Successfully opened \"{}\"", c.name());
-                    controller = Some(c);
-                    break;
-                },
-                Err(e) => println!("failed: {:?}", e),
+            let slot = controllers.len();
+
+            if let Some(c) = open_controller(sdl_context, id, slot) {
+                controllers.push(Some(c));
             }
         }
     }
 
-    match controller {
-        Some(_) => println!("Controller support enabled"),
-        None    => println!("No controller found"),
+    if controllers.is_empty() {
+        println!("No controller found");
+    } else {
+        println!("Controller support enabled");
+    }
+
+    while controllers.len() < MAX_PLAYERS {
+        controllers.push(None);
     }
 
-    controller
+    controllers
 }
 
-fn handle_keyboard(pad: &mut gamepad::Profile, key: Option<Keycode>, state: ButtonState) {
-    let keycode = key.unwrap();
-    let button =
-        match keycode {
-            Keycode::Return => Button::Start,
-            Keycode::RShift => Button::Select,
-            Keycode::Up => Button::DUp,
-            Keycode::Down => Button::DDown,
-            Keycode::Left => Button::DLeft,
-            Keycode::Right => Button::DRight,
-            Keycode::Kp2 => Button::Cross,
-            Keycode::Kp4 => Button::Square,
-            Keycode::Kp6 => Button::Circle,
-            Keycode::Kp8 => Button::Triangle,
-            Keycode::Kp7 => Button::L1,
-            Keycode::NumLockClear => Button::L2,
-            Keycode::Kp9 => Button::R1,
-            Keycode::KpMultiply => Button::R2,
-            // Unhandled key
-            _ => return,
+/// Open the game controller with joystick index `id` for controller
+/// `slot`
+fn open_controller(sdl_context: &sdl2::Sdl,
+                   id: u32,
+                   slot: usize) -> Option<Controller> {
+    let joystick = sdl_context.joystick().unwrap();
+    let game_controller = sdl_context.game_controller().unwrap();
+
+    println!("Attempting to open controller {}", id);
+
+    // The game controller API doesn't give us the instance ID, we
+    // have to get it from the underlying joystick
+    let instance_id = joystick.open(id).map(|j| j.instance_id());
+
+    match (game_controller.open(id), instance_id) {
+        (Ok(c), Ok(instance_id)) => {
+            println!("Successfully opened \"{}\" as controller {}",
+                     c.name(), slot + 1);
+
+            Some(Controller {
+                _controller: c,
+                index: id,
+                instance_id: instance_id,
+            })
+        },
+        (Err(e), _) | (_, Err(e)) => {
+            println!("failed: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Return the slot of the controller with the given `instance_id`
+fn controller_slot(controllers: &[Option<Controller>],
+                   instance_id: i32) -> Option<usize> {
+    controllers.iter().position(|c| {
+        c.as_ref().map_or(false, |c| c.instance_id == instance_id)
+    })
+}
+
+/// Handle a controller plugged in while the emulator is running: bind
+/// it to the first player whose controller is missing and connect a
+/// new pad for this player.
+fn controller_added(sdl_context: &sdl2::Sdl,
+                    cpu: &mut Cpu,
+                    controllers: &mut [Option<Controller>],
+                    bindings: &Bindings,
+                    id: u32) {
+    // SDL also reports the controllers already present at startup
+    let joystick = sdl_context.joystick().unwrap();
+
+    if let Ok(instance_id) = joystick.open(id).map(|j| j.instance_id()) {
+        if controller_slot(controllers, instance_id).is_some() {
+            return;
+        }
+    }
+
+    // Players connected to the console (directly or through a
+    // multitap)
+    let players = cpu.gamepads().len();
+
+    let free =
+        (0..players)
+        .filter_map(|p| bindings.pad(p).controller.map(|c| (p, c)))
+        .find(|&(_, c)| controllers.get(c).map_or(false, |c| c.is_none()));
+
+    let (player, slot) =
+        match free {
+            Some(f) => f,
+            None => {
+                println!("No free port for controller {}", id);
+                return;
+            }
         };
 
-    pad.set_button_state(button, state);
+    let controller =
+        match open_controller(sdl_context, id, slot) {
+            Some(c) => c,
+            None => return,
+        };
+
+    let pad_type =
+        bindings.pad(player).device.unwrap_or(gamepad::Type::DualShock);
+
+    println!("Player {} connected: {:?}", player + 1, pad_type);
+
+    connect_pad(sdl_context,
+                &mut cpu.gamepads()[player],
+                pad_type,
+                Some(&controller));
+
+    controllers[slot] = Some(controller);
 }
 
-fn handle_controller(pad: &mut gamepad::Profile,
-                     button: controller::Button,
-                     state: ButtonState) {
-
-    // Map the original playstation controller as closely as possible
-    // on an XBox 360 controller.
-    let button =
-        match button {
-            controller::Button::Start => Button::Start,
-            controller::Button::Back => Button::Select,
-            controller::Button::DPadLeft => Button::DLeft,
-            controller::Button::DPadRight => Button::DRight,
-            controller::Button::DPadUp => Button::DUp,
-            controller::Button::DPadDown => Button::DDown,
-            controller::Button::A => Button::Cross,
-            controller::Button::B => Button::Circle,
-            controller::Button::X => Button::Square,
-            controller::Button::Y => Button::Triangle,
-            controller::Button::LeftShoulder => Button::L1,
-            controller::Button::RightShoulder => Button::R1,
-            // Unhandled button
-            _ => return,
+/// Handle a controller unplugged while the emulator is running, the
+/// pads bound to it are disconnected
+fn controller_removed(cpu: &mut Cpu,
+                      controllers: &mut [Option<Controller>],
+                      bindings: &Bindings,
+                      instance_id: i32) {
+    let slot =
+        match controller_slot(controllers, instance_id) {
+            Some(s) => s,
+            None => return,
         };
 
-    pad.set_button_state(button, state);
+    println!("Controller {} removed", slot + 1);
+
+    controllers[slot] = None;
+
+    for (player, pad) in cpu.gamepads().iter_mut().enumerate() {
+        if bindings.pad(player).controller == Some(slot) {
+            println!("Player {} disconnected", player + 1);
+
+            **pad = GamePad::new(gamepad::Type::Disconnected);
+        }
+    }
 }
 
-fn update_controller_axis(pad: &mut gamepad::Profile,
-                          axis: controller::Axis,
-                          val: i16) {
+/// Parse the `--padN <type>` options selecting the type of pad
+/// connected for player N, the `--config <ini-file>` option loading
+/// the input bindings and the `--sio1 <link>` option selecting the
+/// device connected to the serial port. If no configuration is given
+/// `rustation.ini` is loaded from the current directory if it
+/// exists. Returns the remaining arguments, the bindings and the
+/// serial link.
+fn parse_options(argv: &[String])
+                 -> Result<(Vec<String>, Bindings, Option<String>,
+                            Option<PathBuf>, RamSize),
+                           String> {
+    let mut args = Vec::new();
+    let mut pad_types = vec![None; MAX_PLAYERS];
+    let mut config = None;
+    let mut sio1_link = None;
+    let mut cheat_cart = None;
+    let mut ram_size = RamSize::Retail;
+
+    let mut iter = argv.iter();
+
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            match iter.next() {
+                Some(path) => config = Some(PathBuf::from(path)),
+                None => return Err("Missing configuration file".to_owned()),
+            }
+            continue;
+        }
+
+        if arg == "--sio1" {
+            match iter.next() {
+                Some(link) => sio1_link = Some(link.clone()),
+                None => return Err("Missing serial link".to_owned()),
+            }
+            continue;
+        }
+
+        if arg == "--cheat-cart" {
+            match iter.next() {
+                Some(path) => cheat_cart = Some(PathBuf::from(path)),
+                None => return Err("Missing cartridge ROM".to_owned()),
+            }
+            continue;
+        }
+
+        if arg == "--dev-kit" {
+            ram_size = RamSize::DevKit;
+            continue;
+        }
+
+        if !arg.starts_with("--pad") {
+            args.push(arg.clone());
+            continue;
+        }
+
+        let player =
+            match arg[5..].parse::<usize>() {
+                Ok(n) if n >= 1 && n <= MAX_PLAYERS => n - 1,
+                _ => return Err(format!("Invalid pad option {}", arg)),
+            };
+
+        let pad_type =
+            match iter.next().and_then(|t| bindings::parse_device(t)) {
+                Some(t) => t,
+                None => return Err(format!("Invalid pad type for {}", arg)),
+            };
+
+        pad_types[player] = Some(pad_type);
+    }
+
+    let default_config = Path::new("rustation.ini");
 
-    let button =
-        match axis {
-            controller::Axis::TriggerLeft => Button::L2,
-            controller::Axis::TriggerRight => Button::R2,
-            // Unhandled axis
-            _ => return,
+    let config =
+        match config {
+            Some(c) => Some(c),
+            None if default_config.exists() =>
+                Some(default_config.to_path_buf()),
+            None => None,
         };
 
-    let state =
-        if val < 0x4000 {
-            ButtonState::Released
-        } else {
-            ButtonState::Pressed
+    let mut bindings =
+        match config {
+            Some(path) => {
+                println!("Loading bindings from {}", path.display());
+
+                match Bindings::from_path(&path) {
+                    Ok(b) => b,
+                    Err(e) => return Err(format!("Can't load {}: {}",
+                                                 path.display(), e)),
+                }
+            }
+            None => Bindings::new(),
         };
 
-    pad.set_button_state(button, state);
+    // The command line overrides the configuration file
+    for (player, t) in pad_types.into_iter().enumerate() {
+        if let Some(t) = t {
+            bindings.set_device(player, t);
+        }
+    }
+
+    Ok((args, bindings, sio1_link, cheat_cart, ram_size))
 }
 
+/// Open the serial link described by `spec`. Listening links wait
+/// for the other emulator to connect.
+fn open_sio1_link(spec: &str) -> io::Result<Box<Link>> {
+    let (kind, addr) =
+        match spec.find(':') {
+            Some(p) => (&spec[..p], &spec[p + 1..]),
+            None => (spec, ""),
+        };
+
+    let link: Box<Link> =
+        match kind {
+            "loopback" => Box::new(link::Loopback::new()),
+            "terminal" => Box::new(link::Terminal),
+            "tcp" => Box::new(try!(link::StreamLink::tcp_connect(addr))),
+            "tcp-listen" => {
+                println!("Waiting for serial link connection on {}", addr);
+                Box::new(try!(link::StreamLink::tcp_listen(addr)))
+            }
+            #[cfg(unix)]
+            "unix" => Box::new(try!(link::StreamLink::unix_connect(addr))),
+            #[cfg(unix)]
+            "unix-listen" => {
+                println!("Waiting for serial link connection on {}", addr);
+                Box::new(try!(link::StreamLink::unix_listen(addr)))
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           "unknown link type")),
+        };
+
+    Ok(link)
+}
+
+/// Connect a pad for each player and forward their rumble to the
+/// corresponding controller. By default players with a controller
+/// get a DualShock. Multitaps are connected if there are more than
+/// two players. Returns the player controlled with the mouse and its
+/// pad type if any.
+fn connect_controllers(sdl_context: &sdl2::Sdl,
+                       cpu: &mut Cpu,
+                       controllers: &[Option<Controller>],
+                       bindings: &Bindings)
+                       -> Option<(usize, gamepad::Type)> {
+    // Controller bound to `player`, if it's connected
+    let controller = |player: usize| {
+        bindings.pad(player).controller
+            .and_then(|c| controllers.get(c))
+            .and_then(|c| c.as_ref())
+    };
+
+    // Players with a pad type or a connected controller
+    let active = |player| {
+        bindings.pad(player).device.is_some() || controller(player).is_some()
+    };
+
+    let players =
+        (0..MAX_PLAYERS).rposition(|p| active(p)).map_or(0, |p| p + 1);
+
+    if players > 2 {
+        println!("Multitap connected on port 1");
+        cpu.set_multitap(0, true);
+    }
+
+    if players > 5 {
+        println!("Multitap connected on port 2");
+        cpu.set_multitap(1, true);
+    }
+
+    let mut mouse_player = None;
+
+    for player in 0..players {
+        let pad_type =
+            match bindings.pad(player).device {
+                Some(t) => t,
+                None if active(player) => gamepad::Type::DualShock,
+                // Player 1 always has a pad, for the keyboard
+                None if player == 0 => gamepad::Type::DualShock,
+                None => continue,
+            };
+
+        connect_pad(sdl_context,
+                    &mut cpu.gamepads()[player],
+                    pad_type,
+                    controller(player));
+
+        if pad_type != gamepad::Type::DualShock {
+            println!("Player {}: {:?}", player + 1, pad_type);
+        }
+
+        match pad_type {
+            gamepad::Type::Mouse | gamepad::Type::Jogcon |
+            gamepad::Type::GunCon | gamepad::Type::Justifier =>
+                if mouse_player.is_none() {
+                    mouse_player = Some((player, pad_type));
+                },
+            _ => (),
+        }
+    }
+
+    mouse_player
+}
+
+/// Replace `pad` with a new pad of type `pad_type` and forward its
+/// rumble to `controller`
+fn connect_pad(sdl_context: &sdl2::Sdl,
+               pad: &mut GamePad,
+               pad_type: gamepad::Type,
+               controller: Option<&Controller>) {
+    *pad = GamePad::new(pad_type);
+
+    if let Some(c) = controller {
+        if let Some(mut haptic) = Haptic::open(sdl_context, c.index) {
+            pad.profile().set_rumble_callback(
+                Box::new(move |rumble| haptic.rumble(rumble)));
+        }
+    }
+}
+
+/// Rumble support for the host controller using SDL's haptic API
+struct Haptic {
+    /// SDL haptic subsystem, must outlive the haptic device
+    _subsystem: sdl2::HapticSubsystem,
+    joystick: *mut sdl2_sys::joystick::SDL_Joystick,
+    haptic: *mut sdl2_sys::haptic::SDL_Haptic,
+}
+
+impl Haptic {
+    /// Open the haptic device of the joystick at `index`. Returns
+    /// None if the controller doesn't support rumble.
+    fn open(sdl_context: &sdl2::Sdl, index: u32) -> Option<Haptic> {
+        use sdl2_sys::joystick::{SDL_JoystickOpen, SDL_JoystickClose};
+        use sdl2_sys::haptic::{SDL_HapticOpenFromJoystick, SDL_HapticClose,
+                               SDL_HapticRumbleSupported,
+                               SDL_HapticRumbleInit};
+
+        let subsystem =
+            match sdl_context.haptic() {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("Can't initialize haptic subsystem: {:?}", e);
+                    return None;
+                }
+            };
+
+        unsafe {
+            let joystick = SDL_JoystickOpen(index as i32);
+
+            if joystick.is_null() {
+                return None;
+            }
+
+            let haptic = SDL_HapticOpenFromJoystick(joystick);
+
+            if haptic.is_null() {
+                println!("Controller doesn't support haptic feedback");
+                SDL_JoystickClose(joystick);
+                return None;
+            }
+
+            if SDL_HapticRumbleSupported(haptic) != 1 ||
+                SDL_HapticRumbleInit(haptic) != 0 {
+                println!("Controller doesn't support rumble");
+                SDL_HapticClose(haptic);
+                SDL_JoystickClose(joystick);
+                return None;
+            }
+
+            println!("Rumble support enabled");
+
+            Some(Haptic {
+                _subsystem: subsystem,
+                joystick: joystick,
+                haptic: haptic,
+            })
+        }
+    }
+
+    fn rumble(&mut self, rumble: Rumble) {
+        use sdl2_sys::haptic::{SDL_HapticRumblePlay, SDL_HapticRumbleStop};
+
+        // SDL's simple rumble API only has a single strength setting
+        // so we have to combine both motors. The small motor is
+        // fast but weak, let's say it's about half the strength of
+        // the large one at full speed.
+        let large = rumble.large as f32 / 255.;
+        let small = if rumble.small { 0.5 } else { 0. };
+
+        let strength = if large > small { large } else { small };
+
+        unsafe {
+            if strength > 0. {
+                // Play until the next update
+                SDL_HapticRumblePlay(self.haptic,
+                                     strength,
+                                     SDL_HAPTIC_INFINITY);
+            } else {
+                SDL_HapticRumbleStop(self.haptic);
+            }
+        }
+    }
+}
+
+impl Drop for Haptic {
+    fn drop(&mut self) {
+        unsafe {
+            sdl2_sys::haptic::SDL_HapticClose(self.haptic);
+            sdl2_sys::joystick::SDL_JoystickClose(self.joystick);
+        }
+    }
+}
+
+/// Haptic effect duration meaning "play until stopped", missing from
+/// sdl2-sys
+const SDL_HAPTIC_INFINITY: u32 = 4294967295;
+
 /// The are a few hardware differences between PAL and NTSC consoles,
 /// for instance runs slightly slower on PAL consoles.
 #[derive(Clone,Copy)]
@@ -0,0 +1,575 @@
+//! Motion Decoder (MDEC) emulation. The MDEC decompresses the
+//! JPEG-like macroblocks used by the FMVs: run-length decoding,
+//! inverse DCT and YUV to RGB conversion.
+//!
+//! Commands and data are sent through DMA0 (or the command register)
+//! and the decoded pixels are read back through DMA1 (or the data
+//! register).
+
+use std::collections::VecDeque;
+use std::mem;
+
+use memory::Addressable;
+
+pub struct MDec {
+    /// True if the DMA0 data-in request is enabled
+    dma_in_enable: bool,
+    /// True if the DMA1 data-out request is enabled
+    dma_out_enable: bool,
+    /// Output pixel format
+    depth: OutputDepth,
+    /// If true the output is signed (-128 to 127) instead of unsigned
+    /// (0 to 255)
+    signed: bool,
+    /// Value of the bit 15 of the pixels in 15bit output mode
+    bit15: bool,
+    /// Command being received
+    command: Command,
+    /// Number of parameter words remaining for the current command
+    remaining: u32,
+    /// Parameters received for the current command
+    params: Vec<u32>,
+    /// Luminance quantization table
+    quant_luma: [u8; 64],
+    /// Color quantization table
+    quant_color: [u8; 64],
+    /// Inverse DCT matrix
+    scale: [i16; 64],
+    /// Decoded data waiting to be read
+    output: VecDeque<u32>,
+}
+
+impl MDec {
+    pub fn new() -> MDec {
+        MDec {
+            dma_in_enable: false,
+            dma_out_enable: false,
+            depth: OutputDepth::D4Bits,
+            signed: false,
+            bit15: false,
+            command: Command::None,
+            remaining: 0,
+            params: Vec::new(),
+            quant_luma: [0; 64],
+            quant_color: [0; 64],
+            scale: [0; 64],
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn load<T: Addressable>(&mut self, offset: u32) -> T {
+        let v =
+            match offset {
+                0 => self.dma_read(),
+                4 => self.status(),
+                _ => panic!("Unhandled MDEC load {:?} 0x{:x}",
+                            T::width(), offset),
+            };
+
+        T::from_u32(v)
+    }
+
+    pub fn store<T: Addressable>(&mut self, offset: u32, val: T) {
+        let val = val.as_u32();
+
+        match offset {
+            0 => self.dma_write(val),
+            4 => self.set_control(val),
+            _ => panic!("Unhandled MDEC store 0x{:x}: {:08x}",
+                        offset, val),
+        }
+    }
+
+    /// Receive a command or parameter word (DMA0)
+    pub fn dma_write(&mut self, word: u32) {
+        if self.remaining == 0 {
+            self.start_command(word);
+        } else {
+            self.params.push(word);
+            self.remaining -= 1;
+        }
+
+        if self.remaining == 0 {
+            self.execute();
+        }
+    }
+
+    /// Return the next decoded word (DMA1)
+    pub fn dma_read(&mut self) -> u32 {
+        match self.output.pop_front() {
+            Some(w) => w,
+            None => {
+                // XXX Not sure what the real hardware returns
+                println!("MDEC read while output FIFO is empty");
+                0
+            }
+        }
+    }
+
+    /// Number of decoded words waiting to be read
+    pub fn output_len(&self) -> u32 {
+        self.output.len() as u32
+    }
+
+    fn status(&self) -> u32 {
+        let mut r = 0u32;
+
+        // Number of parameter words remaining minus 1, 0xffff if
+        // we're not receiving a command
+        r |= self.remaining.wrapping_sub(1) & 0xffff;
+        // Current block. We decode the input as soon as it's
+        // received so we're always waiting for the first block of the
+        // next macroblock (Cr or Y in monochrome mode).
+        r |= 4 << 16;
+        r |= (self.bit15 as u32) << 23;
+        r |= (self.signed as u32) << 24;
+        r |= (self.depth as u32) << 25;
+        r |= ((self.dma_out_enable && !self.output.is_empty()) as u32) << 27;
+        r |= (self.dma_in_enable as u32) << 28;
+        // Command busy
+        r |= ((self.remaining != 0) as u32) << 29;
+        // The input FIFO is never full since we process the data
+        // immediately
+        r |= 0 << 30;
+        r |= (self.output.is_empty() as u32) << 31;
+
+        r
+    }
+
+    fn set_control(&mut self, ctrl: u32) {
+        if ctrl & 0x80000000 != 0 {
+            // Reset: abort the current command and clear the FIFOs
+            self.command = Command::None;
+            self.remaining = 0;
+            self.params.clear();
+            self.output.clear();
+            self.depth = OutputDepth::D4Bits;
+            self.signed = false;
+            self.bit15 = false;
+        }
+
+        self.dma_in_enable = ctrl & 0x40000000 != 0;
+        self.dma_out_enable = ctrl & 0x20000000 != 0;
+    }
+
+    fn start_command(&mut self, cmd: u32) {
+        // The output format bits are latched for all commands
+        self.depth = OutputDepth::from_command(cmd);
+        self.signed = (cmd >> 26) & 1 != 0;
+        self.bit15 = (cmd >> 25) & 1 != 0;
+
+        let (command, len) =
+            match cmd >> 29 {
+                1 => (Command::Decode, cmd & 0xffff),
+                2 => {
+                    let color = cmd & 1 != 0;
+
+                    // 64 bytes per table
+                    let len = if color { 32 } else { 16 };
+
+                    (Command::SetQuantTables(color), len)
+                }
+                3 => (Command::SetScaleTable, 32),
+                _ => {
+                    // The parameters are ignored
+                    println!("Unhandled MDEC command 0x{:08x}", cmd);
+                    (Command::None, cmd & 0xffff)
+                }
+            };
+
+        self.command = command;
+        self.remaining = len;
+        self.params.clear();
+    }
+
+    /// Execute the current command once all the parameters have been
+    /// received
+    fn execute(&mut self) {
+        let params = mem::replace(&mut self.params, Vec::new());
+
+        match self.command {
+            Command::None => (),
+            Command::Decode => self.decode(&params),
+            Command::SetQuantTables(color) => {
+                for (i, &w) in params.iter().enumerate() {
+                    for b in 0..4 {
+                        let q = (w >> (b * 8)) as u8;
+                        let pos = i * 4 + b;
+
+                        if pos < 64 {
+                            self.quant_luma[pos] = q;
+                        } else if color {
+                            self.quant_color[pos - 64] = q;
+                        }
+                    }
+                }
+            }
+            Command::SetScaleTable => {
+                for (i, &w) in params.iter().enumerate() {
+                    self.scale[i * 2] = w as i16;
+                    self.scale[i * 2 + 1] = (w >> 16) as i16;
+                }
+            }
+        }
+
+        self.command = Command::None;
+    }
+
+    /// Decode the macroblocks in `params`.
+    ///
+    /// XXX The real MDEC decodes the data as it's received and
+    /// stalls when the output FIFO is full, we decode the whole
+    /// stream at once.
+    fn decode(&mut self, params: &[u32]) {
+        let mut halfwords = Vec::with_capacity(params.len() * 2);
+
+        for &w in params {
+            halfwords.push(w as u16);
+            halfwords.push((w >> 16) as u16);
+        }
+
+        let mut input = halfwords.iter().cloned();
+
+        if self.depth.is_monochrome() {
+            while let Some(y) = self.decode_block(&mut input, true) {
+                self.output_monochrome(&y);
+            }
+        } else {
+            loop {
+                // Color macroblocks contain the two 8x8 subsampled
+                // chroma blocks followed by the four 8x8 luma blocks
+                let mut blocks = [[0; 64]; 6];
+
+                for (i, b) in blocks.iter_mut().enumerate() {
+                    *b =
+                        match self.decode_block(&mut input, i >= 2) {
+                            Some(b) => b,
+                            // End of input. If the last macroblock is
+                            // incomplete it's dropped.
+                            None => return,
+                        };
+                }
+
+                self.output_color(&blocks);
+            }
+        }
+    }
+
+    /// Run-length decode and dequantize a block then apply the
+    /// inverse DCT. Returns `None` if the input ends before the end
+    /// of the block.
+    fn decode_block<I>(&self, input: &mut I, luma: bool) -> Option<[i32; 64]>
+        where I: Iterator<Item=u16> {
+        let quant =
+            if luma {
+                &self.quant_luma
+            } else {
+                &self.quant_color
+            };
+
+        let mut block = [0i32; 64];
+
+        // Skip the padding before the block
+        let mut n;
+
+        loop {
+            n = match input.next() {
+                Some(n) => n,
+                None => return None,
+            };
+
+            if n != 0xfe00 {
+                break;
+            }
+        }
+
+        // The first halfword contains the quantization scale and the
+        // DC coefficient
+        let q_scale = ((n >> 10) & 0x3f) as i32;
+
+        let mut k = 0;
+        let mut val = signed10(n) * quant[0] as i32;
+
+        loop {
+            if q_scale == 0 {
+                // Uncompressed coefficients
+                val = signed10(n) * 2;
+            }
+
+            let coef = clamp(val, -0x400, 0x3ff);
+
+            if q_scale == 0 {
+                block[k] = coef;
+            } else {
+                block[ZAGZIG[k]] = coef;
+            }
+
+            n = match input.next() {
+                Some(n) => n,
+                None => return None,
+            };
+
+            // The 6 MSBs contain the number of zero coefficients to
+            // skip, 0xfe00 ends the block.
+            k += ((n >> 10) & 0x3f) as usize + 1;
+
+            if k > 63 {
+                break;
+            }
+
+            val = (signed10(n) * quant[k] as i32 * q_scale + 4) / 8;
+        }
+
+        idct(&self.scale, &mut block);
+
+        Some(block)
+    }
+
+    /// Output a 8x8 monochrome block
+    fn output_monochrome(&mut self, block: &[i32; 64]) {
+        let mut pixels = [0u8; 64];
+
+        for (p, &y) in pixels.iter_mut().zip(block.iter()) {
+            // Clip to signed 9bits then saturate to 8bits
+            let y = (y << 23) >> 23;
+
+            *p = self.to_output(clamp(y, -128, 127));
+        }
+
+        match self.depth {
+            OutputDepth::D4Bits => {
+                let mut nibbles = [0u8; 32];
+
+                for (i, n) in nibbles.iter_mut().enumerate() {
+                    *n = (pixels[i * 2] >> 4) | (pixels[i * 2 + 1] & 0xf0);
+                }
+
+                self.push_bytes(&nibbles);
+            }
+            _ => self.push_bytes(&pixels),
+        }
+    }
+
+    /// Output a 16x16 color macroblock
+    fn output_color(&mut self, blocks: &[[i32; 64]; 6]) {
+        let cr = &blocks[0];
+        let cb = &blocks[1];
+
+        let mut rgb = Vec::with_capacity(16 * 16 * 3);
+        let mut bgr15 = Vec::with_capacity(16 * 16 * 2);
+
+        for y in 0..16 {
+            for x in 0..16 {
+                // Y1 is the top left block, Y2 top right, Y3 bottom
+                // left and Y4 bottom right
+                let yblock = &blocks[2 + (y / 8) * 2 + x / 8];
+
+                let luma = yblock[(x % 8) + (y % 8) * 8];
+
+                let c = (x / 2) + (y / 2) * 8;
+                let (cr, cb) = (cr[c], cb[c]);
+
+                let r = luma + ((359 * cr) >> 8);
+                let g = luma + ((-88 * cb - 183 * cr) >> 8);
+                let b = luma + ((454 * cb) >> 8);
+
+                let r = self.to_output(clamp(r, -128, 127));
+                let g = self.to_output(clamp(g, -128, 127));
+                let b = self.to_output(clamp(b, -128, 127));
+
+                if self.depth == OutputDepth::D24Bits {
+                    rgb.push(r);
+                    rgb.push(g);
+                    rgb.push(b);
+                } else {
+                    let pixel =
+                        (r as u16 >> 3) |
+                        ((g as u16 >> 3) << 5) |
+                        ((b as u16 >> 3) << 10) |
+                        ((self.bit15 as u16) << 15);
+
+                    bgr15.push(pixel as u8);
+                    bgr15.push((pixel >> 8) as u8);
+                }
+            }
+        }
+
+        if self.depth == OutputDepth::D24Bits {
+            self.push_bytes(&rgb);
+        } else {
+            self.push_bytes(&bgr15);
+        }
+    }
+
+    /// Convert a signed 8bit component into the output format
+    fn to_output(&self, v: i32) -> u8 {
+        if self.signed {
+            v as u8
+        } else {
+            (v + 128) as u8
+        }
+    }
+
+    /// Push `bytes` into the output FIFO, the length must be a
+    /// multiple of 4
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for w in bytes.chunks(4) {
+            let w =
+                (w[0] as u32) |
+                ((w[1] as u32) << 8) |
+                ((w[2] as u32) << 16) |
+                ((w[3] as u32) << 24);
+
+            self.output.push_back(w);
+        }
+    }
+}
+
+/// Inverse DCT using the `scale` matrix. Each pass transposes the
+/// block.
+fn idct(scale: &[i16; 64], block: &mut [i32; 64]) {
+    let mut tmp = [0i32; 64];
+
+    idct_pass(scale, block, &mut tmp);
+    idct_pass(scale, &tmp, block);
+}
+
+fn idct_pass(scale: &[i16; 64], src: &[i32; 64], dst: &mut [i32; 64]) {
+    for x in 0..8 {
+        for y in 0..8 {
+            let mut sum = 0i32;
+
+            for z in 0..8 {
+                sum += src[y + z * 8] * ((scale[x + z * 8] as i32) >> 3);
+            }
+
+            dst[x + y * 8] = (sum + 0xfff) >> 13;
+        }
+    }
+}
+
+/// Sign-extend the 10 LSBs of `v`
+fn signed10(v: u16) -> i32 {
+    ((v as i32) << 22) >> 22
+}
+
+fn clamp(v: i32, min: i32, max: i32) -> i32 {
+    if v < min {
+        min
+    } else if v > max {
+        max
+    } else {
+        v
+    }
+}
+
+/// MDEC commands
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Command {
+    /// No command or unsupported command
+    None,
+    /// Decode macroblocks
+    Decode,
+    /// Set the quantization tables. If the parameter is true both the
+    /// luminance and color tables are set, otherwise only the
+    /// luminance.
+    SetQuantTables(bool),
+    /// Set the inverse DCT matrix
+    SetScaleTable,
+}
+
+/// Output pixel format
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OutputDepth {
+    /// Monochrome, 4 bits per pixel
+    D4Bits = 0,
+    /// Monochrome, 8 bits per pixel
+    D8Bits = 1,
+    /// Color, 24 bits per pixel
+    D24Bits = 2,
+    /// Color, 15 bits per pixel
+    D15Bits = 3,
+}
+
+impl OutputDepth {
+    fn from_command(cmd: u32) -> OutputDepth {
+        match (cmd >> 27) & 3 {
+            0 => OutputDepth::D4Bits,
+            1 => OutputDepth::D8Bits,
+            2 => OutputDepth::D24Bits,
+            _ => OutputDepth::D15Bits,
+        }
+    }
+
+    fn is_monochrome(self) -> bool {
+        self == OutputDepth::D4Bits || self == OutputDepth::D8Bits
+    }
+}
+
+/// Position in the 8x8 block of each coefficient in the zigzag
+/// order used by the input stream
+const ZAGZIG: [usize; 64] = [
+    0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+#[test]
+fn mdec_decode_flat_macroblock() {
+    let mut mdec = MDec::new();
+
+    // Standard inverse DCT matrix
+    let scale: [u16; 64] = [
+        0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82,
+        0x7d8a, 0x6a6d, 0x471c, 0x18f8, 0xe707, 0xb8e3, 0x9592, 0x8275,
+        0x7641, 0x30fb, 0xcf04, 0x89be, 0x89be, 0xcf04, 0x30fb, 0x7641,
+        0x6a6d, 0xe707, 0x8275, 0xb8e3, 0x471c, 0x7d8a, 0x18f8, 0x9592,
+        0x5a82, 0xa57d, 0xa57d, 0x5a82, 0x5a82, 0xa57d, 0xa57d, 0x5a82,
+        0x471c, 0x8275, 0x18f8, 0x6a6d, 0x9592, 0xe707, 0x7d8a, 0xb8e3,
+        0x30fb, 0x89be, 0x7641, 0xcf04, 0xcf04, 0x7641, 0x89be, 0x30fb,
+        0x18f8, 0xb8e3, 0x6a6d, 0x8275, 0x7d8a, 0x9592, 0x471c, 0xe707,
+    ];
+
+    mdec.dma_write(0x60000000);
+
+    for i in 0..32 {
+        let w = scale[i * 2] as u32 | ((scale[i * 2 + 1] as u32) << 16);
+
+        mdec.dma_write(w);
+    }
+
+    // Quantization tables
+    mdec.dma_write(0x40000001);
+
+    for _ in 0..32 {
+        mdec.dma_write(0x02020202);
+    }
+
+    // Decode a single macroblock with only DC coefficients: the Cr
+    // and Cb blocks are 0, the Y blocks 256. 15bit unsigned output.
+    mdec.dma_write(0x38000006);
+    // Cr, Cb
+    mdec.dma_write(0xfe000400);
+    mdec.dma_write(0xfe000400);
+    // Y1 to Y4
+    for _ in 0..4 {
+        mdec.dma_write(0xfe000500);
+    }
+
+    assert!(mdec.status() & (1 << 29) == 0);
+
+    // 16x16 pixels at 2 bytes per pixel
+    assert!(mdec.output_len() == 128);
+
+    // Every pixel has the same (192, 192, 192) color
+    for _ in 0..128 {
+        assert!(mdec.dma_read() == 0x63186318);
+    }
+
+    assert!(mdec.status() & (1 << 31) != 0);
+}
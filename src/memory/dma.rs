@@ -1,4 +1,7 @@
+use std::cmp;
+
 use memory::interrupts::{Interrupt, InterruptState};
+use timekeeper::Cycles;
 
 /// Direct Memory Access
 pub struct Dma {
@@ -103,6 +106,67 @@ impl Dma {
         &mut self.channels[port as usize]
     }
 
+    /// Return true if `port` is enabled in the control register
+    fn port_enabled(&self, port: usize) -> bool {
+        (self.control >> (port * 4 + 3)) & 1 != 0
+    }
+
+    /// Return the priority of `port` from the control register,
+    /// 0 is the highest
+    fn port_priority(&self, port: usize) -> u32 {
+        (self.control >> (port * 4)) & 7
+    }
+
+    /// Return the highest priority channel that can transfer data
+    /// now. `device_ready` says which devices are ready to handle a
+    /// block in request sync mode.
+    pub fn next_port(&self, device_ready: &[bool; 7]) -> Option<Port> {
+        let mut next = None;
+        let mut best_priority = 8;
+
+        for (i, channel) in self.channels.iter().enumerate() {
+            let ready = match channel.sync {
+                Sync::Request => device_ready[i],
+                _ => true,
+            };
+
+            if !channel.active() ||
+                !self.port_enabled(i) ||
+                channel.chop_delay > 0 ||
+                !ready {
+                continue;
+            }
+
+            let priority = self.port_priority(i);
+
+            // If several channels have the same priority the highest
+            // port number wins
+            if priority <= best_priority {
+                best_priority = priority;
+                next = Some(Port::from_index(i as u32));
+            }
+        }
+
+        next
+    }
+
+    /// Let `cycles` elapse in the CPU windows of the chopped
+    /// transfers
+    pub fn elapse(&mut self, cycles: Cycles) {
+        for channel in self.channels.iter_mut() {
+            channel.chop_delay = channel.chop_delay.saturating_sub(cycles);
+        }
+    }
+
+    /// Return the number of cycles until a chopped transfer resumes,
+    /// if any
+    pub fn next_chop_delay(&self) -> Option<Cycles> {
+        self.channels.iter()
+            .filter(|c| c.active() && c.chop_delay > 0)
+            .map(|c| c.chop_delay)
+            .min()
+    }
+
     pub fn done(&mut self,
                 port: Port,
                 irq_state: &mut InterruptState) {
@@ -152,6 +216,16 @@ struct Channel {
     block_count: u16,
     /// Unkown 2 RW bits in configuration register
     dummy: u8,
+    /// True when a transfer has been started and the fields below
+    /// are valid
+    running: bool,
+    /// Address of the next word to transfer. In linked list mode
+    /// address of the next header.
+    cur_addr: u32,
+    /// Number of words remaining in the current block
+    remaining: u32,
+    /// Number of cycles before a chopped transfer resumes
+    chop_delay: Cycles,
 }
 
 impl Channel {
@@ -169,6 +243,10 @@ impl Channel {
             block_size: 0,
             block_count: 0,
             dummy: 0,
+            running: false,
+            cur_addr: 0,
+            remaining: 0,
+            chop_delay: 0,
         }
     }
 
@@ -229,6 +307,12 @@ impl Channel {
         self.trigger = (val >> 28) & 1 != 0;
 
         self.dummy = ((val >> 29) & 3) as u8;
+
+        if !self.enable {
+            // Transfer aborted
+            self.running = false;
+            self.chop_delay = 0;
+        }
     }
 
     /// Retrieve value of the Block Control register
@@ -261,37 +345,95 @@ impl Channel {
     fn done(&mut self) {
         self.enable = false;
         self.trigger = false;
+        self.running = false;
+        self.chop_delay = 0;
     }
 
-    pub fn direction(&self) -> Direction {
-        self.direction
+    /// Return true if the transfer has been started
+    pub fn running(&self) -> bool {
+        self.running
     }
 
-    pub fn step(&self) -> Step {
-        self.step
+    /// Start a new transfer
+    pub fn start_transfer(&mut self) {
+        self.running = true;
+        self.cur_addr = self.base;
+        self.chop_delay = 0;
+
+        self.remaining =
+            match self.sync {
+                Sync::Manual => self.block_size as u32,
+                Sync::Request if self.block_count > 0 =>
+                    self.block_size as u32,
+                // Packets are read from the linked list
+                _ => 0,
+            };
     }
 
-    pub fn sync(&self) -> Sync {
-        self.sync
+    /// Address of the next word to transfer
+    pub fn current_address(&self) -> u32 {
+        self.cur_addr
     }
 
-    /// Return the DMA transfer size in bytes or None for linked list
-    /// mode.
-    pub fn transfer_size(&self) -> Option<u32> {
-        let bs = self.block_size as u32;
-        let bc = self.block_count as u32;
+    /// Number of words remaining in the current block
+    pub fn words_remaining(&self) -> u32 {
+        self.remaining
+    }
+
+    /// Number of words in the next burst: the whole block or the
+    /// chopping window if chopping is enabled
+    pub fn burst_size(&self) -> u32 {
+        if self.chop {
+            cmp::min(self.remaining, 1 << self.chop_dma_sz)
+        } else {
+            self.remaining
+        }
+    }
+
+    /// Called at the end of a chopped burst, the CPU can run for a
+    /// while before the transfer resumes
+    pub fn chop_pause(&mut self) {
+        if self.chop {
+            self.chop_delay = 1 << self.chop_cpu_sz;
+        }
+    }
+
+    /// Move on to the next word of the transfer
+    pub fn next_word(&mut self) {
+        let increment = match self.step {
+            Step::Increment =>  4,
+            Step::Decrement => -4i32 as u32,
+        };
 
-        match self.sync {
-            // For manual mode only the block size is used
-            Sync::Manual => Some(bs),
-            // In DMA request mode we must transfer `bc` blocks
-            Sync::Request => Some(bc * bs),
-            // In linked list mode the size is not known ahead of
-            // time: we stop when we encounter the "end of list"
-            // marker (0xffffff)
-            Sync::LinkedList => None,
+        self.cur_addr = self.cur_addr.wrapping_add(increment) & 0xffffff;
+        self.remaining -= 1;
+
+        if self.remaining == 0 && self.sync == Sync::Request {
+            // The hardware updates the base address and block count
+            // after each block
+            self.base = self.cur_addr;
+            self.block_count -= 1;
+
+            if self.block_count > 0 {
+                self.remaining = self.block_size as u32;
+            }
         }
     }
+
+    /// Set the address of the next header in linked list mode. The
+    /// hardware updates the base address as well.
+    pub fn set_next_header(&mut self, addr: u32) {
+        self.cur_addr = addr & 0xffffff;
+        self.base = self.cur_addr;
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn sync(&self) -> Sync {
+        self.sync
+    }
 }
 
 /// DMA transfer direction
@@ -309,7 +451,7 @@ pub enum Step {
 }
 
 /// DMA transfer synchronization mode
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,PartialEq,Eq)]
 pub enum Sync {
     /// Transfer starts when the CPU writes to the Trigger bit and
     /// transfers everything at once
@@ -353,3 +495,57 @@ impl Port {
         }
     }
 }
+
+#[test]
+fn dma_priority_and_chopping() {
+    let mut dma = Dma::new();
+    let ready = [true; 7];
+
+    // Enable GPU (priority 3) and OTC (priority 1)
+    dma.set_control(0x09000b00);
+
+    // GPU: manual sync, chopping with 4 words bursts and a 16 cycles
+    // CPU window
+    {
+        let gpu = dma.channel_mut(Port::Gpu);
+        gpu.set_block_control(10);
+        gpu.set_control(0x11420101);
+        gpu.start_transfer();
+    }
+
+    assert!(dma.next_port(&ready) == Some(Port::Gpu));
+
+    // OTC has a higher priority
+    {
+        let otc = dma.channel_mut(Port::Otc);
+        otc.set_block_control(4);
+        otc.set_control(0x11000002);
+        otc.start_transfer();
+    }
+
+    assert!(dma.next_port(&ready) == Some(Port::Otc));
+
+    let mut irq_state = InterruptState::new();
+    dma.done(Port::Otc, &mut irq_state);
+
+    {
+        let gpu = dma.channel_mut(Port::Gpu);
+
+        assert!(gpu.burst_size() == 4);
+
+        for _ in 0..4 {
+            gpu.next_word();
+        }
+
+        gpu.chop_pause();
+    }
+
+    // The GPU channel waits for the end of the CPU window
+    assert!(dma.next_port(&ready) == None);
+    assert!(dma.next_chop_delay() == Some(16));
+
+    dma.elapse(16);
+
+    assert!(dma.next_port(&ready) == Some(Port::Gpu));
+    assert!(dma.channel(Port::Gpu).words_remaining() == 6);
+}
@@ -0,0 +1,133 @@
+//! Devices connected to the parallel expansion port on the back of
+//! the early console models, as well as the POST display found on
+//! the expansion 2 bus.
+//!
+//! The expansion 1 region is 8bit wide by default, wider accesses are
+//! split in byte accesses by the interconnect.
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{Result, Error, ErrorKind, Read};
+
+/// Interface to the device plugged in the expansion port
+pub trait Expansion {
+    /// Read the byte at `offset` in the expansion 1 region. The bus
+    /// is pulled up so all ones are read when nothing drives it.
+    fn load_exp1(&self, _offset: u32) -> u8 {
+        0xff
+    }
+
+    /// Write `val` at `offset` in the expansion 1 region
+    fn store_exp1(&mut self, _offset: u32, _val: u8) {
+    }
+
+    /// Read the byte at `offset` in the expansion 2 region
+    fn load_exp2(&self, _offset: u32) -> u8 {
+        0xff
+    }
+
+    /// Write `val` at `offset` in the expansion 2 region
+    fn store_exp2(&mut self, _offset: u32, _val: u8) {
+    }
+}
+
+/// Nothing plugged in the expansion port
+pub struct Disconnected;
+
+impl Expansion for Disconnected {
+}
+
+/// Cheat cartridge (Action Replay, GameShark, Xplorer...). The
+/// cartridge ROM is mapped at the beginning of the expansion 1
+/// region, if it contains the "Licensed by Sony Computer
+/// Entertainment Inc." signature the BIOS calls its entry points
+/// during the boot.
+pub struct CheatCart {
+    rom: Vec<u8>,
+}
+
+impl CheatCart {
+    pub fn new(rom: Vec<u8>) -> CheatCart {
+        CheatCart {
+            rom: rom,
+        }
+    }
+
+    /// Load the cartridge ROM image located at `path`
+    pub fn from_path(path: &Path) -> Result<CheatCart> {
+        let mut file = try!(File::open(path));
+
+        let mut rom = Vec::new();
+
+        try!(file.read_to_end(&mut rom));
+
+        if rom.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Cartridge ROM is empty"));
+        }
+
+        if rom.len() > EXPANSION_1_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Cartridge ROM is too big"));
+        }
+
+        Ok(CheatCart::new(rom))
+    }
+}
+
+impl Expansion for CheatCart {
+    fn load_exp1(&self, offset: u32) -> u8 {
+        // Past the end of the ROM nothing drives the bus
+        self.rom.get(offset as usize).cloned().unwrap_or(0xff)
+    }
+
+    fn store_exp1(&mut self, _offset: u32, _val: u8) {
+        // XXX Some cartridges use a flash memory that can be
+        // reprogrammed from the console, it's not supported. The
+        // front panel switch and the cartridge I/O ports are not
+        // emulated either.
+    }
+}
+
+/// Seven-segment display on the expansion 2 bus of the development
+/// boards. The BIOS writes its boot progress codes there, it's
+/// useful to figure out where it gets stuck.
+pub struct PostDisplay {
+    code: u8,
+}
+
+impl PostDisplay {
+    pub fn new() -> PostDisplay {
+        PostDisplay {
+            code: 0,
+        }
+    }
+
+    pub fn store(&mut self, val: u8) {
+        // Only the low nibble is displayed
+        let code = val & 0xf;
+
+        if code != self.code {
+            println!("POST: 0x{:x}", code);
+        }
+
+        self.code = code;
+    }
+}
+
+/// Offset of the POST register in the expansion 2 region
+pub const POST_OFFSET: u32 = 0x41;
+
+/// Size of the expansion 1 region as configured by the BIOS
+const EXPANSION_1_SIZE: usize = 512 * 1024;
+
+#[test]
+fn cheat_cart_rom() {
+    let cart = CheatCart::new(vec![0x12, 0x34, 0x56]);
+
+    assert!(cart.load_exp1(0) == 0x12);
+    assert!(cart.load_exp1(2) == 0x56);
+    // Nothing mapped past the end of the ROM
+    assert!(cart.load_exp1(3) == 0xff);
+    assert!(cart.load_exp2(POST_OFFSET) == 0xff);
+}
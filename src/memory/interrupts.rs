@@ -15,6 +15,11 @@ pub enum Interrupt {
     Timer2 = 6,
     /// Gamepad and Memory Card controller interrupt
     PadMemCard = 7,
+    /// Serial port (SIO1) interrupt
+    Sio = 8,
+    /// Lightpen interrupt, triggered by the Konami Justifier light
+    /// gun
+    Lightpen = 10,
 }
 
 #[derive(Clone,Copy)]
@@ -62,7 +67,9 @@ impl InterruptState {
                           Interrupt::Timer0,
                           Interrupt::Timer1,
                           Interrupt::Timer2,
-                          Interrupt::PadMemCard];
+                          Interrupt::PadMemCard,
+                          Interrupt::Sio,
+                          Interrupt::Lightpen];
 
         let rem = supported.iter().fold(mask,
                                         |mask, &it| mask & !(1 << it as u16));
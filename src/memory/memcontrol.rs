@@ -0,0 +1,216 @@
+//! Memory control registers. They configure the base address, size
+//! and access timings of the devices connected to the external bus:
+//! the BIOS ROM, the SPU, the CD-ROM controller and the expansion
+//! ports.
+
+use std::cmp;
+
+use timekeeper::Cycles;
+use super::AccessWidth;
+
+/// Devices with a delay/size register, in register order. Register 1
+/// configures the expansion 3 region which is not emulated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Device {
+    Expansion1 = 0,
+    Bios = 2,
+    Spu = 3,
+    CdRom = 4,
+    Expansion2 = 5,
+}
+
+pub struct MemControl {
+    /// Expansion 1 base address
+    expansion1_base: u32,
+    /// Expansion 2 base address
+    expansion2_base: u32,
+    /// Delay/size register for each device
+    delay_size: [u32; 6],
+    /// COM_DELAY register: common delays used by the devices whose
+    /// delay/size register requests them
+    com_delay: u32,
+    /// Read access times for each device, computed from the
+    /// registers above
+    timings: [AccessTimes; 6],
+}
+
+impl MemControl {
+    pub fn new() -> MemControl {
+        let mut mc = MemControl {
+            expansion1_base: 0x1f000000,
+            expansion2_base: 0x1f802000,
+            // The values set by the BIOS during the boot
+            delay_size: [
+                0x0013243f,
+                0x00003022,
+                0x0013243f,
+                0x200931e1,
+                0x00020843,
+                0x00070777,
+                ],
+            com_delay: 0x00031125,
+            timings: [AccessTimes::new(); 6],
+        };
+
+        mc.update_timings();
+
+        mc
+    }
+
+    pub fn load(&self, offset: u32) -> u32 {
+        match offset {
+            0 => self.expansion1_base,
+            4 => self.expansion2_base,
+            8...0x1f => self.delay_size[(offset as usize - 8) / 4],
+            0x20 => self.com_delay,
+            _ => panic!("Unhandled MEM_CONTROL read {:x}", offset),
+        }
+    }
+
+    pub fn store(&mut self, offset: u32, val: u32) {
+        match offset {
+            0 => {
+                if val != 0x1f000000 {
+                    panic!("Bad expansion 1 base address: 0x{:08x}", val);
+                }
+
+                self.expansion1_base = val;
+            }
+            4 => {
+                if val != 0x1f802000 {
+                    panic!("Bad expansion 2 base address: 0x{:08x}", val);
+                }
+
+                self.expansion2_base = val;
+            }
+            8...0x1f => {
+                // Bit 28 is the "address error" flag, writing 1
+                // clears it. We never set it.
+                self.delay_size[(offset as usize - 8) / 4] =
+                    val & 0xaf1fffff;
+            }
+            0x20 => self.com_delay = val & 0xffff,
+            _ => panic!("Unhandled MEM_CONTROL write {:x}: 0x{:08x}",
+                        offset, val),
+        }
+
+        self.update_timings();
+    }
+
+    /// Return the number of cycles needed to read from `device` using
+    /// an access of width `width`
+    pub fn read_cycles(&self, device: Device, width: AccessWidth) -> Cycles {
+        let t = &self.timings[device as usize];
+
+        match width {
+            AccessWidth::Byte => t.byte,
+            AccessWidth::HalfWord => t.halfword,
+            AccessWidth::Word => t.word,
+        }
+    }
+
+    fn update_timings(&mut self) {
+        for i in 0..self.timings.len() {
+            self.timings[i] = AccessTimes::compute(self.delay_size[i],
+                                                   self.com_delay);
+        }
+    }
+}
+
+/// Read access times for the various access widths, not including
+/// the cycle spent by the CPU to execute the instruction
+#[derive(Clone, Copy)]
+struct AccessTimes {
+    byte: Cycles,
+    halfword: Cycles,
+    word: Cycles,
+}
+
+impl AccessTimes {
+    fn new() -> AccessTimes {
+        AccessTimes {
+            byte: 0,
+            halfword: 0,
+            word: 0,
+        }
+    }
+
+    /// Compute the access times from a delay/size register and the
+    /// COM_DELAY register. The formula comes from the Nocash PSX
+    /// spec.
+    fn compute(delay_size: u32, com_delay: u32) -> AccessTimes {
+        let com0 = (com_delay & 0xf) as Cycles;
+        let com2 = ((com_delay >> 8) & 0xf) as Cycles;
+        let com3 = ((com_delay >> 12) & 0xf) as Cycles;
+
+        let read_delay = ((delay_size >> 4) & 0xf) as Cycles;
+
+        // Duration of the first access and of the following
+        // sequential accesses when the CPU access is wider than the
+        // bus
+        let mut first = 0;
+        let mut seq = 0;
+        let mut min = 0;
+
+        // Recovery period
+        if delay_size & (1 << 8) != 0 {
+            first += com0.saturating_sub(1);
+            seq += com0.saturating_sub(1);
+        }
+
+        // Floating period
+        if delay_size & (1 << 10) != 0 {
+            first += com2;
+            seq += com2;
+        }
+
+        // Pre-strobe period
+        if delay_size & (1 << 11) != 0 {
+            min = com3;
+        }
+
+        if first < 6 {
+            first += 1;
+        }
+
+        first += read_delay + 2;
+        seq += read_delay + 2;
+
+        first = cmp::max(first, min + 6);
+        seq = cmp::max(seq, min + 2);
+
+        let bus_16bits = delay_size & (1 << 12) != 0;
+
+        // 16 and 32bit accesses on a narrower bus are split in
+        // several sequential accesses
+        let (halfword, word) =
+            match bus_16bits {
+                true => (first, first + seq),
+                false => (first + seq, first + 3 * seq),
+            };
+
+        // The CPU already spends one cycle on the instruction
+        AccessTimes {
+            byte: first - 1,
+            halfword: halfword - 1,
+            word: word - 1,
+        }
+    }
+}
+
+#[test]
+fn memcontrol_default_timings() {
+    let mc = MemControl::new();
+
+    // 8bit BIOS bus
+    assert!(mc.read_cycles(Device::Bios, AccessWidth::Byte) == 6);
+    assert!(mc.read_cycles(Device::Bios, AccessWidth::HalfWord) == 12);
+    assert!(mc.read_cycles(Device::Bios, AccessWidth::Word) == 24);
+
+    // 16bit SPU bus
+    assert!(mc.read_cycles(Device::Spu, AccessWidth::HalfWord) == 20);
+    assert!(mc.read_cycles(Device::Spu, AccessWidth::Word) == 40);
+
+    // CD-ROM uses the pre-strobe period
+    assert!(mc.read_cycles(Device::CdRom, AccessWidth::Byte) == 6);
+}
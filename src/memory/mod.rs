@@ -1,21 +1,30 @@
 pub mod bios;
 pub mod interrupts;
 pub mod timers;
-mod ram;
+pub mod expansion;
+pub mod ram;
 mod dma;
+mod memcontrol;
 
 use self::bios::Bios;
-use self::ram::{Ram, ScratchPad};
-use self::dma::{Dma, Port, Direction, Step, Sync};
+use self::ram::{Ram, ScratchPad, RamSize, RamMapping, RAM_SIZE_DEFAULT};
+use self::dma::{Dma, Port, Direction, Sync};
+use self::memcontrol::{MemControl, Device};
 use self::timers::Timers;
 use self::interrupts::InterruptState;
-use timekeeper::{TimeKeeper, Peripheral};
-use gpu::Gpu;
+use self::expansion::{Expansion, PostDisplay};
+use timekeeper::{TimeKeeper, Peripheral, Cycles};
+use gpu::{Gpu, BeamPosition};
 use spu::Spu;
+use mdec::MDec;
 use cdrom::CdRom;
 use cdrom::disc::Disc;
 use padmemcard::PadMemCard;
 use padmemcard::gamepad;
+use padmemcard::gamepad::GamePad;
+use padmemcard::memcard::MemoryCard;
+use sio1::Sio1;
+use sio1::link::Link;
 
 /// Global interconnect
 pub struct Interconnect {
@@ -32,6 +41,8 @@ pub struct Interconnect {
     gpu: Gpu,
     /// Sound Processing Unit
     spu: Spu,
+    /// Motion Decoder
+    mdec: MDec,
     /// System timers
     timers: Timers,
     /// Cache Control register
@@ -40,31 +51,52 @@ pub struct Interconnect {
     cdrom: CdRom,
     /// Gamepad and memory card controller
     pad_memcard: PadMemCard,
-    /// Contents of the RAM_SIZE register which is probably a
-    /// configuration register for the memory controller.
+    /// Serial port
+    sio1: Sio1,
+    /// Memory control registers
+    mem_control: MemControl,
+    /// Device plugged in the expansion port
+    expansion: Box<Expansion>,
+    /// POST display on the expansion 2 bus
+    post: PostDisplay,
+    /// Contents of the RAM_SIZE register, it configures the RAM
+    /// window
     ram_size: u32,
 }
 
 impl Interconnect {
-    pub fn new(bios: Bios, gpu: Gpu, disc: Option<Disc>) -> Interconnect {
+    pub fn new(bios: Bios,
+               gpu: Gpu,
+               disc: Option<Disc>,
+               ram_size: RamSize) -> Interconnect {
         Interconnect {
             irq_state: InterruptState::new(),
             bios: bios,
-            ram: Ram::new(),
+            ram: Ram::new(ram_size),
             scratch_pad: ScratchPad::new(),
             dma: Dma::new(),
             gpu: gpu,
             spu: Spu::new(),
+            mdec: MDec::new(),
             timers: Timers::new(),
             cache_control: CacheControl(0),
             cdrom: CdRom::new(disc),
             pad_memcard: PadMemCard::new(),
-            ram_size: 0,
+            sio1: Sio1::new(),
+            mem_control: MemControl::new(),
+            expansion: Box::new(expansion::Disconnected),
+            post: PostDisplay::new(),
+            ram_size: RAM_SIZE_DEFAULT,
         }
     }
 
     pub fn sync(&mut self, tk: &mut TimeKeeper) {
         if tk.needs_sync(Peripheral::Gpu) {
+            // Light guns can move between syncs
+            let lightpen = self.pad_memcard.lightpen_target();
+
+            self.gpu.set_lightpen_target(lightpen);
+
             self.gpu.sync(tk, &mut self.irq_state);
         }
 
@@ -72,11 +104,19 @@ impl Interconnect {
             self.pad_memcard.sync(tk, &mut self.irq_state);
         }
 
+        if tk.needs_sync(Peripheral::Sio1) {
+            self.sio1.sync(tk, &mut self.irq_state);
+        }
+
         self.timers.sync(tk, &mut self.irq_state);
 
         if tk.needs_sync(Peripheral::CdRom) {
             self.cdrom.sync(tk, &mut self.irq_state);
         }
+
+        if tk.needs_sync(Peripheral::Dma) {
+            self.run_dma(tk);
+        }
     }
 
     pub fn cache_control(&self) -> CacheControl {
@@ -87,52 +127,129 @@ impl Interconnect {
         self.irq_state
     }
 
-    pub fn pad_profiles(&mut self) -> [&mut gamepad::Profile; 2] {
+    pub fn pad_profiles(&mut self) -> Vec<&mut gamepad::Profile> {
         self.pad_memcard.pad_profiles()
     }
 
-    /// Interconnect: load instruction at `PC`. Only the RAM and BIOS
-    /// are supported, would it make sense to fetch instructions from
-    /// anything else?
-    pub fn load_instruction<T: Addressable>(&self, pc: u32) -> T {
+    pub fn memory_cards(&mut self) -> Vec<&mut MemoryCard> {
+        self.pad_memcard.memory_cards()
+    }
+
+    pub fn gamepads(&mut self) -> Vec<&mut GamePad> {
+        self.pad_memcard.gamepads()
+    }
+
+    /// Return the position of the video beam when it draws the pixel
+    /// at VRAM coordinates (`x`, `y`), used for the light guns
+    pub fn beam_position(&self, x: u16, y: u16) -> Option<BeamPosition> {
+        self.gpu.beam_position(x, y)
+    }
+
+    /// Connect or disconnect a multitap on controller `port`
+    pub fn set_multitap(&mut self, port: usize, connected: bool) {
+        self.pad_memcard.set_multitap(port, connected)
+    }
+
+    /// Connect a new device to the serial port
+    pub fn set_sio1_link(&mut self, link: Box<Link>) {
+        self.sio1.set_link(link)
+    }
+
+    /// Plug a new device in the expansion port
+    pub fn set_expansion(&mut self, expansion: Box<Expansion>) {
+        self.expansion = expansion
+    }
+
+    /// Replace the disc in the CD-ROM drive, returns the previous one
+    pub fn swap_disc(&mut self, disc: Option<Disc>) -> Option<Disc> {
+        self.cdrom.swap_disc(disc)
+    }
+
+    /// Interconnect: load instruction at `PC`. Code can run from the
+    /// RAM, the BIOS and the expansion 1 ROM (used by the cheat
+    /// cartridges).
+    pub fn load_instruction<T: Addressable>(&self,
+                                            pc: u32) -> Result<T, BusError> {
+        // Apparently pointing the PC to KSEG2 causes a bus error no
+        // matter what, even if you point it at some valid register
+        // address (like the "cache control" register). Not like it
+        // should happen anyway, there's nowhere to put code in KSEG2,
+        // only a bunch of registers.
+        if pc >= 0xc0000000 {
+            return Err(BusError);
+        }
+
         let abs_addr = map::mask_region(pc);
 
         if let Some(offset) = map::RAM.contains(abs_addr) {
-            return self.ram.load(offset);
+            return self.ram_load(offset);
         }
 
         if let Some(offset) = map::BIOS.contains(abs_addr) {
-            return self.bios.load(offset);
+            return Ok(self.bios.load(offset));
         }
 
-        panic!("unhandled instruction load at address {:08x}", pc);
+        if let Some(offset) = map::EXPANSION_1.contains(abs_addr) {
+            return Ok(self.expansion_1_load(offset));
+        }
+
+        Err(BusError)
+    }
+
+    /// Return the number of cycles needed to fetch the instruction
+    /// at `pc` and the following words when filling a cacheline
+    pub fn instruction_fetch_cycles(&self, pc: u32) -> (Cycles, Cycles) {
+        let abs_addr = map::mask_region(pc);
+
+        let device =
+            if map::BIOS.contains(abs_addr).is_some() {
+                Some(Device::Bios)
+            } else if map::EXPANSION_1.contains(abs_addr).is_some() {
+                Some(Device::Expansion1)
+            } else {
+                None
+            };
+
+        if let Some(device) = device {
+            // The BIOS and expansion ROM are on the 8bit external
+            // bus, there's no burst mode: each word takes a full
+            // access
+            let c = self.mem_control.read_cycles(device, AccessWidth::Word);
+
+            (c, c)
+        } else {
+            // XXX RAM latency followed by one word per cycle, these
+            // are averages that need more tests
+            (4, 1)
+        }
     }
 
     /// Interconnect: load value at `addr`
     pub fn load<T: Addressable>(&mut self,
                                 tk: &mut TimeKeeper,
-                                addr: u32) -> T {
-        // XXX Average RAM load delay, needs to do per-device tests
-        // XXX This does not take the CPU pipelining into account so
-        // it might be a little too slow in some cases actually.
-        tk.tick(5);
-
+                                addr: u32) -> Result<T, BusError> {
         let abs_addr = map::mask_region(addr);
 
+        let cycles = self.load_cycles(abs_addr, T::width());
+
+        tk.tick(cycles);
+
         if let Some(offset) = map::RAM.contains(abs_addr) {
-            return self.ram.load(offset);
+            return self.ram_load(offset);
         }
 
         if let Some(offset) = map::SCRATCH_PAD.contains(abs_addr) {
-            if addr > 0xa0000000 {
-                panic!("ScratchPad access through uncached memory");
+            if addr >= 0xa0000000 {
+                // The scratchpad is part of the data cache, it's not
+                // reachable through uncached memory
+                return Err(BusError);
             }
 
-            return self.scratch_pad.load(offset);
+            return Ok(self.scratch_pad.load(offset));
         }
 
         if let Some(offset) = map::BIOS.contains(abs_addr) {
-            return self.bios.load(offset);
+            return Ok(self.bios.load(offset));
         }
 
         if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
@@ -143,72 +260,140 @@ impl Interconnect {
                     _ => panic!("Unhandled IRQ load at address {:08x}", addr),
                 };
 
-            return v;
+            return Ok(v);
         }
 
         if let Some(offset) = map::DMA.contains(abs_addr) {
-            return self.dma_reg(offset);
+            return Ok(self.dma_reg(offset));
         }
 
         if let Some(offset) = map::GPU.contains(abs_addr) {
-            return self.gpu.load(tk, &mut self.irq_state, offset);
+            return Ok(self.gpu.load(tk, &mut self.irq_state, offset));
         }
 
         if let Some(offset) = map::TIMERS.contains(abs_addr) {
-            return self.timers.load(tk, &mut self.irq_state, offset);
+            return Ok(self.timers.load(tk, &mut self.irq_state, offset));
         }
 
         if let Some(offset) = map::CDROM.contains(abs_addr) {
-            return self.cdrom.load(tk, &mut self.irq_state, offset);
+            return Ok(self.cdrom.load(tk, &mut self.irq_state, offset));
         }
 
         if let Some(offset) = map::MDEC.contains(abs_addr) {
-            println!("Unhandled load from MDEC register {:x}", offset);
-            return Addressable::from_u32(0);
+            return Ok(self.mdec.load(offset));
         }
 
         if let Some(offset) = map::SPU.contains(abs_addr) {
-            return self.spu.load(offset);
+            return Ok(self.spu.load(offset));
         }
 
         if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
-            return self.pad_memcard.load(tk, &mut self.irq_state, offset);
+            let v = self.pad_memcard.load(tk, &mut self.irq_state, offset);
+
+            return Ok(v);
+        }
+
+        if let Some(offset) = map::SIO1.contains(abs_addr) {
+            return Ok(self.sio1.load(tk, &mut self.irq_state, offset));
         }
 
-        if let Some(_) = map::EXPANSION_1.contains(abs_addr) {
-            // No expansion implemented. Returns full ones when no
-            // expansion is present
-            return Addressable::from_u32(!0);
+        if let Some(offset) = map::EXPANSION_1.contains(abs_addr) {
+            return Ok(self.expansion_1_load(offset));
+        }
+
+        if let Some(offset) = map::EXPANSION_2.contains(abs_addr) {
+            let mut r = 0;
+
+            for i in 0..T::width() as u32 {
+                let b = self.expansion.load_exp2(offset + i);
+
+                r |= (b as u32) << (8 * i);
+            }
+
+            return Ok(Addressable::from_u32(r));
         }
 
+        if let Some(offset) = map::MEM_CONTROL.contains(abs_addr) {
+            if T::width() != AccessWidth::Word {
+                panic!("Unhandled {:?} MEM_CONTROL load", T::width());
+            }
+
+            let v = self.mem_control.load(offset);
+
+            return Ok(Addressable::from_u32(v));
+        }
 
         if let Some(_) = map::RAM_SIZE.contains(abs_addr) {
-            // We ignore writes at this address
-            return Addressable::from_u32(self.ram_size);
+            return Ok(Addressable::from_u32(self.ram_size));
         }
 
-        panic!("unhandled load at address {:08x}", addr);
+        // Nothing mapped at this address
+        Err(BusError)
+    }
+
+    /// Return the number of cycles needed to load a value of width
+    /// `width` from the physical address `abs_addr`.
+    ///
+    /// XXX This does not take the CPU pipelining into account so it
+    /// might be a little too slow in some cases actually. Stores are
+    /// not timed at all since they go through the write queue.
+    fn load_cycles(&self, abs_addr: u32, width: AccessWidth) -> Cycles {
+        let device =
+            if map::RAM.contains(abs_addr).is_some() {
+                // XXX Average RAM load delay, needs to do more tests
+                return 5;
+            } else if map::SCRATCH_PAD.contains(abs_addr).is_some() {
+                // The scratchpad is as fast as the data cache
+                return 0;
+            } else if map::BIOS.contains(abs_addr).is_some() {
+                Device::Bios
+            } else if map::SPU.contains(abs_addr).is_some() {
+                Device::Spu
+            } else if map::CDROM.contains(abs_addr).is_some() {
+                Device::CdRom
+            } else if map::EXPANSION_1.contains(abs_addr).is_some() {
+                Device::Expansion1
+            } else if map::EXPANSION_2.contains(abs_addr).is_some() {
+                Device::Expansion2
+            } else {
+                // XXX The other registers are on the 32bit internal
+                // bus, the delay is a rough estimate and probably
+                // varies from one device to the next
+                return 2;
+            };
+
+        self.mem_control.read_cycles(device, width)
     }
 
     /// Interconnect: store `val` into `addr`
     pub fn store<T: Addressable>(&mut self,
                                  tk: &mut TimeKeeper,
                                  addr: u32,
-                                 val: T) {
+                                 val: T) -> Result<(), BusError> {
 
         let abs_addr = map::mask_region(addr);
 
         if let Some(offset) = map::RAM.contains(abs_addr) {
-            self.ram.store(offset, val);
-            return;
+            return match self.ram.map(offset) {
+                RamMapping::Ram(offset) => {
+                    self.ram.store(offset, val);
+                    Ok(())
+                }
+                // Nothing to write to
+                RamMapping::HighZ => Ok(()),
+                RamMapping::Locked => Err(BusError),
+            };
         }
 
         if let Some(offset) = map::SCRATCH_PAD.contains(abs_addr) {
-            if addr > 0xa0000000 {
-                panic!("ScratchPad access through uncached memory");
+            if addr >= 0xa0000000 {
+                // The scratchpad is part of the data cache, it's not
+                // reachable through uncached memory
+                return Err(BusError);
             }
 
-            return self.scratch_pad.store(offset, val);
+            self.scratch_pad.store(offset, val);
+            return Ok(());
         }
 
         if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
@@ -217,12 +402,12 @@ impl Interconnect {
                 4 => self.irq_state.set_mask(val.as_u32() as u16),
                 _ => panic!("Unhandled IRQ store at address {:08x}"),
             }
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::DMA.contains(abs_addr) {
-            self.set_dma_reg(offset, val);
-            return;
+            self.set_dma_reg(tk, offset, val);
+            return Ok(());
         }
 
         if let Some(offset) = map::GPU.contains(abs_addr) {
@@ -231,7 +416,7 @@ impl Interconnect {
                            &mut self.irq_state,
                            offset,
                            val);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::TIMERS.contains(abs_addr) {
@@ -240,26 +425,34 @@ impl Interconnect {
                               &mut self.gpu,
                               offset,
                               val);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::CDROM.contains(abs_addr) {
-            return self.cdrom.store(tk, &mut self.irq_state, offset, val);
+            self.cdrom.store(tk, &mut self.irq_state, offset, val);
+            return Ok(());
         }
 
         if let Some(offset) = map::MDEC.contains(abs_addr) {
-            println!("Unhandled write to MDEC register {:x}", offset);
-            return;
+            self.mdec.store(offset, val);
+            // The MDEC output DMA might be waiting for this data
+            self.run_dma(tk);
+            return Ok(());
         }
 
         if let Some(offset) = map::SPU.contains(abs_addr) {
             self.spu.store(offset, val);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
             self.pad_memcard.store(tk, &mut self.irq_state, offset, val);
-            return;
+            return Ok(());
+        }
+
+        if let Some(offset) = map::SIO1.contains(abs_addr) {
+            self.sio1.store(tk, &mut self.irq_state, offset, val);
+            return Ok(());
         }
 
         if let Some(_) = map::CACHE_CONTROL.contains(abs_addr) {
@@ -269,28 +462,17 @@ impl Interconnect {
 
             self.cache_control = CacheControl(val.as_u32());
 
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::MEM_CONTROL.contains(abs_addr) {
-            let val = val.as_u32();
-
-            match offset {
-                0 => // Expansion 1 base address
-                    if val != 0x1f000000 {
-                        panic!("Bad expansion 1 base address: 0x{:08x}", val);
-                    },
-                4 => // Expansion 2 base address
-                    if val != 0x1f802000 {
-                        panic!("Bad expansion 2 base address: 0x{:08x}", val);
-                    },
-                _ =>
-                    println!("Unhandled write to MEM_CONTROL register {:x}: \
-                              0x{:08x}",
-                             offset, val),
+            if T::width() != AccessWidth::Word {
+                panic!("Unhandled {:?} MEM_CONTROL store", T::width());
             }
 
-            return;
+            self.mem_control.store(offset, val.as_u32());
+
+            return Ok(());
         }
 
         if let Some(_) = map::RAM_SIZE.contains(abs_addr) {
@@ -300,16 +482,63 @@ impl Interconnect {
             }
 
             self.ram_size = val.as_u32();
-            return;
+            self.ram.set_config(self.ram_size);
+
+            return Ok(());
+        }
+
+        if let Some(offset) = map::EXPANSION_1.contains(abs_addr) {
+            let val = val.as_u32();
+
+            for i in 0..T::width() as u32 {
+                self.expansion.store_exp1(offset + i, (val >> (8 * i)) as u8);
+            }
+
+            return Ok(());
         }
 
         if let Some(offset) = map::EXPANSION_2.contains(abs_addr) {
-            println!("Unhandled write to expansion 2 register {:x}", offset);
-            return;
+            let val = val.as_u32();
+
+            for i in 0..T::width() as u32 {
+                let b = (val >> (8 * i)) as u8;
+
+                match offset + i {
+                    expansion::POST_OFFSET => self.post.store(b),
+                    o => self.expansion.store_exp2(o, b),
+                }
+            }
+
+            return Ok(());
         }
 
-        panic!("unhandled store into address {:08x}: {:08x}",
-               addr, val.as_u32());
+        // Nothing mapped at this address
+        Err(BusError)
+    }
+
+    /// Load a little endian value from the RAM window
+    fn ram_load<T: Addressable>(&self, offset: u32) -> Result<T, BusError> {
+        match self.ram.map(offset) {
+            RamMapping::Ram(offset) => Ok(self.ram.load(offset)),
+            // XXX Nothing drives the bus, what's really read back
+            // probably depends on the previous bus activity
+            RamMapping::HighZ => Ok(Addressable::from_u32(!0)),
+            RamMapping::Locked => Err(BusError),
+        }
+    }
+
+    /// Load a little endian value from the expansion 1 region, the
+    /// bus is 8bit wide
+    fn expansion_1_load<T: Addressable>(&self, offset: u32) -> T {
+        let mut r = 0;
+
+        for i in 0..T::width() as u32 {
+            let b = self.expansion.load_exp1(offset + i);
+
+            r |= (b as u32) << (8 * i);
+        }
+
+        Addressable::from_u32(r)
     }
 
     /// DMA register read
@@ -348,7 +577,10 @@ impl Interconnect {
     }
 
     /// DMA register write
-    fn set_dma_reg<T: Addressable>(&mut self, offset: u32, val: T) {
+    fn set_dma_reg<T: Addressable>(&mut self,
+                                   tk: &mut TimeKeeper,
+                                   offset: u32,
+                                   val: T) {
         if T::width() != AccessWidth::Word {
             panic!("Unhandled {:?} DMA store", T::width());
         }
@@ -358,136 +590,195 @@ impl Interconnect {
         let major = (offset & 0x70) >> 4;
         let minor = offset & 0xf;
 
-        let active_port =
-            match major {
-                // Per-channel registers
-                0...6 => {
-                    let port = Port::from_index(major);
-                    let channel = self.dma.channel_mut(port);
-
-                    match minor {
-                        0 => channel.set_base(val),
-                        4 => channel.set_block_control(val),
-                        8 => channel.set_control(val),
-                        _ => panic!("Unhandled DMA write {:x}: {:08x}",
-                                    offset, val)
-                    }
-
-                    if channel.active() {
-                        Some(port)
-                    } else {
-                        None
-                    }
-                },
-                // Common DMA registers
-                7 => {
-                    match minor {
-                        0 => self.dma.set_control(val),
-                        4 => self.dma.set_interrupt(val, &mut self.irq_state),
-                        _ => panic!("Unhandled DMA write {:x}: {:08x}",
-                                    offset, val),
+        match major {
+            // Per-channel registers
+            0...6 => {
+                let port = Port::from_index(major);
+                let channel = self.dma.channel_mut(port);
+
+                match minor {
+                    0 => channel.set_base(val),
+                    4 => channel.set_block_control(val),
+                    8 => {
+                        let val =
+                            match port {
+                                // The ordering table clear channel always
+                                // transfers to RAM in manual mode with a
+                                // decrementing address. Only the enable,
+                                // trigger and unknown bit 30 can be
+                                // written.
+                                Port::Otc => (val & 0x51000000) | 2,
+                                // I don't know if the DMA even supports
+                                // linked list mode for anything besides
+                                // the GPU
+                                Port::Gpu => val,
+                                _ if (val >> 9) & 3 == 2 => {
+                                    println!("Linked list DMA on port {:?} \
+                                              not supported, using request \
+                                              mode", port);
+                                    (val & !(3 << 9)) | (1 << 9)
+                                }
+                                _ => val,
+                            };
+
+                        channel.set_control(val)
                     }
+                    _ => panic!("Unhandled DMA write {:x}: {:08x}",
+                                offset, val)
+                }
 
-                    None
+                if channel.active() && !channel.running() {
+                    channel.start_transfer();
                 }
-                _ => panic!("Unhandled DMA write {:x}: {:08x}",
-                            offset, val),
-            };
+            },
+            // Common DMA registers
+            7 => {
+                match minor {
+                    0 => self.dma.set_control(val),
+                    4 => self.dma.set_interrupt(val, &mut self.irq_state),
+                    _ => panic!("Unhandled DMA write {:x}: {:08x}",
+                                offset, val),
+                }
+            }
+            _ => panic!("Unhandled DMA write {:x}: {:08x}",
+                        offset, val),
+        }
 
-        if let Some(port) = active_port {
-            self.do_dma(port);
+        // A channel might have been started or enabled
+        self.run_dma(tk);
+    }
+
+    /// Return true if the device connected to `port` is ready to
+    /// handle a block in request synchronization mode
+    fn dma_device_ready(&self, port: Port) -> bool {
+        match port {
+            // The MDEC only requests the output transfer when the
+            // data is available
+            Port::MDecOut => {
+                let block_size = self.dma.channel(port).words_remaining();
+
+                self.mdec.output_len() >= block_size
+            }
+            Port::Gpu => self.gpu.dma_request(),
+            // XXX The other devices are always considered ready, the
+            // CD-ROM should check its sector buffer.
+            _ => true,
         }
     }
 
-    /// Execute DMA transfer for a port
-    fn do_dma(&mut self, port: Port) {
-        // DMA transfer has been started, for now let's
-        // process everything in one pass (i.e. no
-        // chopping or priority handling)
+    /// Run the active DMA transfers until they're all completed or
+    /// waiting. The CPU is stalled while the DMA has the bus.
+    fn run_dma(&mut self, tk: &mut TimeKeeper) {
+        let elapsed = tk.sync(Peripheral::Dma);
+        self.dma.elapse(elapsed);
 
-        match self.dma.channel(port).sync() {
-                Sync::LinkedList => self.do_dma_linked_list(port),
-                _                => self.do_dma_block(port),
+        loop {
+            let mut ready = [false; 7];
+
+            for (i, r) in ready.iter_mut().enumerate() {
+                *r = self.dma_device_ready(Port::from_index(i as u32));
+            }
+
+            let port =
+                match self.dma.next_port(&ready) {
+                    Some(p) => p,
+                    None => break,
+                };
+
+            let (cycles, done) =
+                match self.dma.channel(port).sync() {
+                    Sync::LinkedList => self.dma_linked_list_packet(port),
+                    _                => self.dma_block_burst(port),
+                };
+
+            // Stall the CPU for the duration of the burst. The other
+            // channels' chopping windows keep running meanwhile.
+            tk.tick(cycles);
+
+            let elapsed = tk.sync(Peripheral::Dma);
+            self.dma.elapse(elapsed);
+
+            if done {
+                self.dma.done(port, &mut self.irq_state);
+            } else {
+                self.dma.channel_mut(port).chop_pause();
+            }
         }
 
-        self.dma.done(port, &mut self.irq_state);
+        match self.dma.next_chop_delay() {
+            Some(delay) => tk.set_next_sync_delta(Peripheral::Dma, delay),
+            None => tk.no_sync_needed(Peripheral::Dma),
+        }
     }
 
-    /// Emulate DMA transfer for linked list synchronization mode.
-    fn do_dma_linked_list(&mut self, port: Port) {
+    /// Transfer one packet in linked list synchronization mode.
+    /// Returns the duration of the transfer and whether the end of
+    /// the list has been reached.
+    fn dma_linked_list_packet(&mut self, port: Port) -> (Cycles, bool) {
+        let mask = self.ram.address_mask() & !3;
+
         let channel = self.dma.channel_mut(port);
 
-        let mut addr = channel.base() & 0x1ffffc;
+        let mut addr = channel.current_address() & mask;
 
         if channel.direction() == Direction::ToRam {
             panic!("Invalid DMA direction for linked list mode");
         }
 
-        // I don't know if the DMA even supports linked list mode for
-        // anything besides the GPU
-        if port != Port::Gpu {
-            panic!("Attempted linked list DMA on port {:?}", port);
-        }
+        // `set_dma_reg` only lets the GPU channel use linked list mode
 
-        loop {
-            // In linked list mode, each entry starts with a "header"
-            // word. The high byte contains the number of words in the
-            // "packet" (not counting the header word)
-            let header = self.ram.load::<u32>(addr);
+        // In linked list mode, each entry starts with a "header"
+        // word. The high byte contains the number of words in the
+        // "packet" (not counting the header word)
+        let header = self.ram.load::<u32>(addr);
 
-            let mut remsz = header >> 24;
+        let len = header >> 24;
 
-            while remsz > 0 {
-                addr = (addr + 4) & 0x1ffffc;
+        for _ in 0..len {
+            addr = (addr + 4) & mask;
 
-                let command = self.ram.load::<u32>(addr);
+            let command = self.ram.load::<u32>(addr);
 
-                // Send command to the GPU
-                self.gpu.gp0(command);
+            // Send command to the GPU
+            self.gpu.gp0(command);
+        }
 
-                remsz -= 1;
-            }
+        channel.set_next_header(header);
 
-            // The end-of-table marker is usually 0xffffff but
-            // mednafen only checks for the MSB so maybe that's what
-            // the hardware does? Since this bit is not part of any
-            // valid address it makes some sense. I'll have to test
-            // that at some point...
-            if header & 0x800000 != 0 {
-                break;
-            }
+        // The end-of-table marker is usually 0xffffff but mednafen
+        // only checks for the MSB so maybe that's what the hardware
+        // does? Since this bit is not part of any valid address it
+        // makes some sense. I'll have to test that at some point...
+        let done = header & 0x800000 != 0;
 
-            addr = header & 0x1ffffc;
-        }
+        // XXX the per-packet overhead is a guess
+        let cycles = (len + 1) as Cycles * dma_word_cycles(port) + 1;
+
+        (cycles, done)
     }
 
-    /// Emulate DMA transfer for Manual and Request synchronization
-    /// modes.
-    fn do_dma_block(&mut self, port: Port) {
-        let channel = self.dma.channel_mut(port);
+    /// Transfer one burst in Manual or Request synchronization mode:
+    /// the whole transfer or one block respectively, or the chopping
+    /// window if chopping is enabled. Returns the duration of the
+    /// transfer and whether it's completed.
+    fn dma_block_burst(&mut self, port: Port) -> (Cycles, bool) {
+        let ram_mask = self.ram.address_mask();
 
-        let increment = match channel.step() {
-            Step::Increment =>  4,
-            Step::Decrement => -4i32 as u32,
-        };
+        let channel = self.dma.channel_mut(port);
 
-        let mut addr = channel.base();
+        let len = channel.burst_size();
 
-        // Transfer size in words
-        let mut remsz = match channel.transfer_size() {
-            Some(n) => n,
-            // Shouldn't happen since we shouldn't be reaching this code
-            // in linked list mode
-            None    => panic!("Couldn't figure out DMA block transfer size"),
-        };
+        for _ in 0..len {
+            let addr = channel.current_address();
+            // Words remaining in the current block, including this one
+            let remsz = channel.words_remaining();
 
-        while remsz > 0 {
             // Not sure what happens if address is
             // bogus... Mednafen just masks addr this way, maybe
             // that's how the hardware behaves (i.e. the RAM
             // address wraps and the two LSB are ignored, seems
             // reasonable enough
-            let cur_addr = addr & 0x1ffffc;
+            let cur_addr = addr & ram_mask & !3;
 
             match channel.direction() {
                 Direction::FromRam => {
@@ -495,7 +786,7 @@ impl Interconnect {
 
                     match port {
                         Port::Gpu => self.gpu.gp0(src_word),
-                        Port::MDecIn => (),
+                        Port::MDecIn => self.mdec.dma_write(src_word),
                         _ => panic!("Unhandled DMA destination port {:?}",
                                     port),
                     }
@@ -508,14 +799,12 @@ impl Interconnect {
                             // of table marker
                             1 => 0xffffff,
                             // Pointer to the previous entry
-                            _ => addr.wrapping_sub(4) & 0x1fffff,
+                            _ => addr.wrapping_sub(4) & ram_mask,
                         },
-                        Port::Gpu => {
-                            // XXX to be implemented
-                            println!("DMA GPU READ");
-                            0
-                        }
+                        // VRAM image store
+                        Port::Gpu => self.gpu.read(),
                         Port::CdRom => self.cdrom.dma_read_word(),
+                        Port::MDecOut => self.mdec.dma_read(),
                         _ => panic!("Unhandled DMA source port {:?}", port),
                     };
 
@@ -523,12 +812,31 @@ impl Interconnect {
                 }
             }
 
-            addr = addr.wrapping_add(increment);
-            remsz -= 1;
+            channel.next_word();
         }
+
+        let done = channel.words_remaining() == 0;
+
+        (len as Cycles * dma_word_cycles(port), done)
+    }
+}
+
+/// Number of cycles needed by the DMA to transfer one word to or
+/// from `port`. XXX These are approximations, the actual timings
+/// depend on the memory control registers' DMA settings.
+fn dma_word_cycles(port: Port) -> Cycles {
+    match port {
+        Port::CdRom => 24,
+        Port::Spu => 4,
+        _ => 1,
     }
 }
 
+/// Error returned when nothing responds at the address of a load or
+/// store, triggers a bus error exception in the CPU
+#[derive(Clone,Copy,Debug)]
+pub struct BusError;
+
 #[derive(Clone,Copy)]
 pub struct CacheControl(u32);
 
@@ -654,8 +962,9 @@ mod map {
         addr & REGION_MASK[index]
     }
 
-    /// Main RAM: 2MB mirrored four times over the first 8MB (probably
-    /// in case they decided to use a bigger RAM later on?)
+    /// Main RAM window: the 2MB of the retail consoles are mirrored
+    /// over the first 8MB depending on the RAM_SIZE configuration, the
+    /// development kits have 8MB
     pub const RAM: Range = Range(0x00000000, 8 * 1024 * 1024);
 
     /// Expansion region 1
@@ -672,8 +981,10 @@ mod map {
     /// Gamepad and memory card controller
     pub const PAD_MEMCARD: Range = Range(0x1f801040, 32);
 
-    /// Register that has something to do with RAM configuration,
-    /// configured by the BIOS
+    /// Serial port
+    pub const SIO1: Range = Range(0x1f801050, 16);
+
+    /// RAM window configuration, set by the BIOS
     pub const RAM_SIZE: Range = Range(0x1f801060, 4);
 
     /// Interrupt Control registers (status and mask)
@@ -1,27 +1,117 @@
 use super::Addressable;
 
+/// Amount of RAM installed in the console
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RamSize {
+    /// 2MB, the retail consoles
+    Retail,
+    /// 8MB, the DTL-H2000 development kit
+    DevKit,
+}
+
+impl RamSize {
+    fn bytes(self) -> usize {
+        match self {
+            RamSize::Retail => 2 * 1024 * 1024,
+            RamSize::DevKit => 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Result of the decoding of an address in the 8MB RAM window
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RamMapping {
+    /// Offset in the RAM
+    Ram(u32),
+    /// Second memory bank without any RAM chip connected, nothing
+    /// drives the bus
+    HighZ,
+    /// Outside of the configured memory, causes a bus error
+    Locked,
+}
+
 /// RAM
 pub struct Ram {
     /// RAM buffer. Boxed in order not to overflow the stack at the
-    /// construction site. Might change once "placement new" is
-    /// available.
-    data: Box<[u8; RAM_SIZE]>
+    /// construction site.
+    data: Box<[u8]>,
+    /// Size of each memory bank in the 8MB window
+    bank_size: u32,
+    /// True if the window contains two banks (the second one is
+    /// selected by a different /RAS line)
+    two_banks: bool,
 }
 
 impl Ram {
 
     /// Instantiate main RAM with garbage values
-    pub fn new() -> Ram {
+    pub fn new(size: RamSize) -> Ram {
+        let mut ram = Ram {
+            data: vec![0xca; size.bytes()].into_boxed_slice(),
+            bank_size: 0,
+            two_banks: false,
+        };
+
+        ram.set_config(RAM_SIZE_DEFAULT);
 
-        Ram { data: Box::new([0xca; RAM_SIZE]) }
+        ram
     }
 
+    /// Reconfigure the memory window using the value of the
+    /// RAM_SIZE register
+    pub fn set_config(&mut self, config: u32) {
+        let mb = 1024 * 1024;
+
+        let (bank_size, two_banks) =
+            match (config >> 9) & 7 {
+                0 => (1 * mb, false),
+                // Same as 3: 4MB followed by the second bank
+                1 => (4 * mb, true),
+                2 => (1 * mb, true),
+                3 => (4 * mb, true),
+                4 => (2 * mb, false),
+                5 => (8 * mb, false),
+                6 => (2 * mb, true),
+                7 => (8 * mb, false),
+                _ => unreachable!(),
+            };
+
+        self.bank_size = bank_size;
+        self.two_banks = two_banks;
+    }
+
+    /// Decode `offset` in the 8MB window. The RAM is mirrored over
+    /// the configured bank size.
+    pub fn map(&self, offset: u32) -> RamMapping {
+        let bank = offset / self.bank_size;
+        let bank_offset = offset % self.bank_size;
+
+        match bank {
+            0 => RamMapping::Ram(bank_offset),
+            1 if self.two_banks => {
+                // Only the development kit has RAM chips on the
+                // second bank, they hold the upper half of its 8MB
+                if self.data.len() > 2 * 1024 * 1024 {
+                    let half = self.data.len() as u32 / 2;
+
+                    RamMapping::Ram(half + (bank_offset & (half - 1)))
+                } else {
+                    RamMapping::HighZ
+                }
+            }
+            _ => RamMapping::Locked,
+        }
+    }
+
+    /// Return the mask used to wrap addresses around the RAM size
+    pub fn address_mask(&self) -> u32 {
+        self.data.len() as u32 - 1
+    }
 
     /// Fetch the little endian value at `offset`
     pub fn load<T: Addressable>(&self, offset: u32) -> T {
-        // The two MSB are ignored, the 2MB RAM is mirorred four times
-        // over the first 8MB of address space
-        let offset = (offset & 0x1fffff) as usize;
+        // Addresses wrap around the installed RAM
+        let offset = (offset & self.address_mask()) as usize;
 
         let mut v = 0;
 
@@ -34,9 +124,8 @@ impl Ram {
 
     /// Store the 32bit little endian word `val` into `offset`
     pub fn store<T: Addressable>(&mut self, offset: u32, val: T) {
-        // The two MSB are ignored, the 2MB RAM is mirorred four times
-        // over the first 8MB of address space
-        let offset = (offset & 0x1fffff) as usize;
+        // Addresses wrap around the installed RAM
+        let offset = (offset & self.address_mask()) as usize;
 
         let val = val.as_u32();
 
@@ -83,8 +172,42 @@ impl ScratchPad {
     }
 }
 
-/// Main PlayStation RAM: 2Megabytes
-const RAM_SIZE: usize = 2 * 1024 * 1024;
+/// Value written to the RAM_SIZE register by the BIOS: 8MB window.
+/// We use it as the reset value since the BIOS sets it before
+/// touching the RAM anyway.
+pub const RAM_SIZE_DEFAULT: u32 = 0x00000b88;
 
 /// ScatchPad (data cache used as fast RAM): 1Kilobyte
 const SCRATCH_PAD_SIZE: usize = 1024;
+
+#[test]
+fn ram_window() {
+    let mb = 1024 * 1024;
+
+    let mut ram = Ram::new(RamSize::Retail);
+
+    // The BIOS configuration mirrors the 2MB four times
+    ram.store::<u32>(0x10, 0x12345678);
+    assert!(ram.map(6 * mb + 0x10) == RamMapping::Ram(6 * mb + 0x10));
+    assert!(ram.load::<u32>(6 * mb + 0x10) == 0x12345678);
+
+    // 2MB + 2MB HighZ + 4MB locked
+    ram.set_config(6 << 9);
+    assert!(ram.map(0x10) == RamMapping::Ram(0x10));
+    assert!(ram.map(2 * mb) == RamMapping::HighZ);
+    assert!(ram.map(4 * mb) == RamMapping::Locked);
+
+    // 4MB (the 2MB mirrored twice) + 4MB HighZ
+    ram.set_config(1 << 9);
+    assert!(ram.map(2 * mb + 0x10) == RamMapping::Ram(2 * mb + 0x10));
+    assert!(ram.load::<u32>(2 * mb + 0x10) == 0x12345678);
+    assert!(ram.map(4 * mb) == RamMapping::HighZ);
+    assert!(ram.map(8 * mb - 4) == RamMapping::HighZ);
+
+    // The development kit has RAM in the second bank
+    let mut ram = Ram::new(RamSize::DevKit);
+
+    ram.set_config(6 << 9);
+    assert!(ram.map(2 * mb + 0x10) == RamMapping::Ram(4 * mb + 0x10));
+    assert!(ram.map(4 * mb) == RamMapping::Locked);
+}
@@ -1,8 +1,13 @@
+use std::cmp;
+
 use timekeeper::{TimeKeeper, Cycles, FracCycles, Peripheral};
 use gpu::Gpu;
 use super::{Addressable, AccessWidth};
 use super::interrupts::{InterruptState, Interrupt};
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Debug)]
 pub struct Timers {
     /// The three timers. They're mostly identical except that they
@@ -161,6 +166,14 @@ struct Timer {
     phase: FracCycles,
     /// True if interrupt signal is active
     interrupt: bool,
+    /// True if the interrupt has been triggered since the last mode
+    /// write, used by the one-shot mode
+    irq_fired: bool,
+    /// Blanking signal used by the synchronization modes: HBlank for
+    /// the timer 0, VBlank for the timer 1 and `None` for the timer 2
+    blank: Option<BlankSignal>,
+    /// In `WaitForSync` mode: true until the first blanking starts
+    wait_for_blank: bool,
 }
 
 impl Timer {
@@ -182,6 +195,9 @@ impl Timer {
             period: FracCycles::from_cycles(1),
             phase: FracCycles::from_cycles(0),
             interrupt: false,
+            irq_fired: false,
+            blank: None,
+            wait_for_blank: false,
         }
     }
 
@@ -214,6 +230,13 @@ impl Timer {
             }
         }
 
+        self.blank =
+            match self.instance {
+                Peripheral::Timer0 => Some(gpu.hblank_signal()),
+                Peripheral::Timer1 => Some(gpu.vblank_signal()),
+                _ => None,
+            };
+
         self.predict_next_sync(tk);
     }
 
@@ -230,6 +253,95 @@ impl Timer {
             return;
         }
 
+        self.run(delta, irq_state);
+
+        self.predict_next_sync(tk)
+    }
+
+    /// Advance the timer by `delta` CPU cycles. The time is split at
+    /// the edges of the blanking signal when the synchronization mode
+    /// uses them.
+    fn run(&mut self, mut delta: Cycles, irq_state: &mut InterruptState) {
+        if !self.negate_irq {
+            // The interrupt pulse is over
+            self.interrupt = false;
+        }
+
+        while delta > 0 {
+            let edge =
+                match self.blank {
+                    Some(ref b) if self.use_sync => b.cycles_to_edge(),
+                    _ => None,
+                };
+
+            let step =
+                match edge {
+                    Some(e) => cmp::min(delta, e),
+                    None => delta,
+                };
+
+            if !self.paused() {
+                self.count(step, irq_state);
+            }
+
+            delta -= step;
+
+            let edge =
+                match self.blank {
+                    Some(ref mut b) => b.advance(step),
+                    None => None,
+                };
+
+            if self.use_sync {
+                if let Some(entered_blank) = edge {
+                    self.blank_edge(entered_blank);
+                }
+            }
+        }
+    }
+
+    /// Return true if the counter is currently paused by the
+    /// synchronization mode
+    fn paused(&self) -> bool {
+        if !self.use_sync {
+            return false;
+        }
+
+        let in_blank = self.blank.map_or(false, |b| b.in_blank());
+
+        match self.instance {
+            Peripheral::Timer2 =>
+                match self.sync {
+                    // Stop counter
+                    Sync::Pause | Sync::WaitForSync => true,
+                    // Free run
+                    Sync::Reset | Sync::ResetAndPause => false,
+                },
+            _ =>
+                match self.sync {
+                    Sync::Pause => in_blank,
+                    Sync::Reset => false,
+                    Sync::ResetAndPause => !in_blank,
+                    Sync::WaitForSync => self.wait_for_blank,
+                },
+        }
+    }
+
+    /// Called when the blanking signal changes state
+    fn blank_edge(&mut self, entered_blank: bool) {
+        if !entered_blank {
+            return;
+        }
+
+        match self.sync {
+            Sync::Reset | Sync::ResetAndPause => self.counter = 0,
+            Sync::WaitForSync => self.wait_for_blank = false,
+            Sync::Pause => (),
+        }
+    }
+
+    /// Run the counter for `delta` CPU cycles
+    fn count(&mut self, delta: Cycles, irq_state: &mut InterruptState) {
         let delta_frac = FracCycles::from_cycles(delta);
 
         let ticks = delta_frac.add(self.phase);
@@ -281,66 +393,113 @@ impl Timer {
         }
 
         self.counter = count as u16;
+
         if (self.wrap_irq && overflow) || (self.target_irq && target_passed) {
-            let interrupt =
-                match self.instance {
-                    Peripheral::Timer0 => Interrupt::Timer0,
-                    Peripheral::Timer1 => Interrupt::Timer1,
-                    Peripheral::Timer2 => Interrupt::Timer2,
-                    _ => unreachable!(),
-                };
+            self.trigger_irq(irq_state);
+        }
+    }
+
+    /// Called when one of the interrupt conditions is reached
+    fn trigger_irq(&mut self, irq_state: &mut InterruptState) {
+        if self.irq_fired && !self.repeat_irq {
+            // One-shot mode, the interrupt can only trigger once
+            // until the mode register is rewritten
+            return;
+        }
+
+        self.irq_fired = true;
+
+        let interrupt =
+            match self.instance {
+                Peripheral::Timer0 => Interrupt::Timer0,
+                Peripheral::Timer1 => Interrupt::Timer1,
+                Peripheral::Timer2 => Interrupt::Timer2,
+                _ => unreachable!(),
+            };
+
+        if self.negate_irq {
+            // Toggle mode: the interrupt signal is inverted and the
+            // IRQ triggers when it becomes active
+            self.interrupt = !self.interrupt;
 
-            if self.negate_irq {
-                panic!("Unhandled negate IRQ!");
-            } else {
-                // Pulse interrupt
+            if self.interrupt {
                 irq_state.assert(interrupt);
-                self.interrupt = true;
             }
-        } else if !self.negate_irq {
-            // Pulse is over
-            self.interrupt = false;
+        } else {
+            // Pulse interrupt
+            irq_state.assert(interrupt);
+            self.interrupt = true;
         }
-
-        self.predict_next_sync(tk)
     }
 
     fn predict_next_sync(&mut self, tk: &mut TimeKeeper) {
-        // XXX add support for wrap IRQ
+        let mut next_sync = None;
 
-        if !self.target_irq {
-            // No IRQ enabled, we don't need to be called back.
-            tk.no_sync_needed(self.instance);
-            return;
+        if self.use_sync {
+            // We need to handle the blanking edges
+            next_sync = self.blank.and_then(|b| b.cycles_to_edge());
         }
 
-        let countdown =
-            if self.counter <= self.target {
-                self.target - self.counter
-            } else {
-                0xffff - self.counter + self.target
-            };
+        if let Some(delta) = self.next_irq_delta() {
+            next_sync = Some(next_sync.map_or(delta,
+                                              |n| cmp::min(n, delta)));
+        }
+
+        match next_sync {
+            Some(delta) => tk.set_next_sync_delta(self.instance, delta),
+            // No IRQ enabled and no blanking edge coming, we don't
+            // need to be called back.
+            None => tk.no_sync_needed(self.instance),
+        }
+    }
+
+    /// Return the number of CPU cycles until the next interrupt
+    /// condition if the counter is running and an IRQ can trigger
+    fn next_irq_delta(&self) -> Option<Cycles> {
+        if self.paused() || (self.irq_fired && !self.repeat_irq) {
+            return None;
+        }
+
+        let mut countdown = None;
 
-        // Convert from timer count to CPU cycles. I add + 1 to the
-        // countdown because the interrupt is generated on the
-        // following cycle (I think?)
-        let mut delta = self.period.get_fp() * (countdown as Cycles + 1);
-        delta -= self.phase.get_fp();
+        if self.target_irq {
+            let c =
+                if self.counter <= self.target {
+                    self.target - self.counter
+                } else {
+                    0xffff - self.counter + self.target
+                };
+
+            countdown = Some(c);
+        }
+
+        // The counter only reaches 0xffff if it doesn't wrap at the
+        // target first
+        if self.wrap_irq && (!self.target_wrap || self.target == 0xffff) {
+            let c = 0xffff - self.counter;
+
+            countdown = Some(countdown.map_or(c, |n| cmp::min(n, c)));
+        }
 
-        // Round up to the next CPU cycle
-        let delta = FracCycles::from_fp(delta).ceil();
+        countdown.map(|countdown| {
+            // Convert from timer count to CPU cycles. I add + 1 to
+            // the countdown because the interrupt is generated on the
+            // following cycle (I think?)
+            let mut delta = self.period.get_fp() * (countdown as Cycles + 1);
+            delta -= self.phase.get_fp();
 
-        tk.set_next_sync_delta(self.instance, delta);
+            // Round up to the next CPU cycle
+            FracCycles::from_fp(delta).ceil()
+        })
     }
 
     /// Return true if the timer relies on the GPU for the clock
     /// source or synchronization
     pub fn needs_gpu(&self) -> bool {
-        if self.use_sync {
-            println!("Sync mode not supported!");
-        }
+        let blank_sync =
+            self.use_sync && self.instance != Peripheral::Timer2;
 
-        self.clock_source.clock(self.instance).needs_gpu()
+        blank_sync || self.clock_source.clock(self.instance).needs_gpu()
     }
 
     fn mode(&mut self) -> u16 {
@@ -379,25 +538,12 @@ impl Timer {
 
         // Writing to mode resets the interrupt flag
         self.interrupt = false;
+        self.irq_fired = false;
 
         // Writing to mode resets the counter
         self.counter = 0;
 
-        if self.wrap_irq {
-            panic!("Wrap IRQ not supported");
-        }
-
-        if (self.wrap_irq || self.target_irq) && !self.repeat_irq {
-            panic!("One shot timer interrupts are not supported: {:?}", self);
-        }
-
-        if self.negate_irq {
-            panic!("Only pulse interrupts are supported: {:?}", self);
-        }
-
-        if self.use_sync {
-            println!("Sync mode is not supported: {:?}", self);
-        }
+        self.wait_for_blank = true;
     }
 
     fn target(&self) -> u16 {
@@ -512,3 +658,79 @@ impl Clock {
         }
     }
 }
+
+/// Periodic blanking signal used by the timer synchronization
+/// modes. The origin of the period is the end of the blanking: the
+/// signal is inactive from 0 to `blank_start` and active from
+/// `blank_start` to `period`.
+#[derive(Clone, Copy, Debug)]
+pub struct BlankSignal {
+    /// Period of the signal in CPU cycles
+    period: FracCycles,
+    /// Position of the beginning of the blanking in the period
+    blank_start: FracCycles,
+    /// Current position in the period
+    phase: FracCycles,
+}
+
+impl BlankSignal {
+    pub fn new(period: FracCycles,
+               blank_start: FracCycles,
+               phase: FracCycles) -> BlankSignal {
+        BlankSignal {
+            period: period,
+            blank_start: blank_start,
+            phase: FracCycles::from_fp(phase.get_fp() % period.get_fp()),
+        }
+    }
+
+    /// Return true if we're currently in the blanking
+    fn in_blank(&self) -> bool {
+        self.phase.get_fp() >= self.blank_start.get_fp()
+    }
+
+    /// Return true if the signal never changes state
+    fn constant(&self) -> bool {
+        self.blank_start.get_fp() == 0 ||
+            self.blank_start.get_fp() >= self.period.get_fp()
+    }
+
+    /// Return the number of CPU cycles until the signal changes
+    /// state, rounded up. Returns None if the signal is constant.
+    fn cycles_to_edge(&self) -> Option<Cycles> {
+        if self.constant() {
+            return None;
+        }
+
+        let edge =
+            match self.in_blank() {
+                true => self.period,
+                false => self.blank_start,
+            };
+
+        let delta = FracCycles::from_fp(edge.get_fp() - self.phase.get_fp());
+
+        Some(delta.ceil())
+    }
+
+    /// Advance the signal by `delta` CPU cycles. If the signal
+    /// changed state returns `Some(true)` when the blanking started
+    /// and `Some(false)` when it ended.
+    fn advance(&mut self, delta: Cycles) -> Option<bool> {
+        let was_in_blank = self.in_blank();
+
+        let delta = FracCycles::from_cycles(delta);
+
+        let phase = self.phase.get_fp() + delta.get_fp();
+
+        self.phase = FracCycles::from_fp(phase % self.period.get_fp());
+
+        let in_blank = self.in_blank();
+
+        if in_blank != was_in_blank {
+            Some(in_blank)
+        } else {
+            None
+        }
+    }
+}
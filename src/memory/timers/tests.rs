@@ -0,0 +1,211 @@
+use timekeeper::{TimeKeeper, FracCycles, Peripheral};
+use memory::interrupts::InterruptState;
+
+use super::{Timer, BlankSignal};
+
+/// Build a timer running at the system clock with a fake blanking
+/// signal: 100 cycles period, blanking from cycle 80 to 100 and
+/// starting at the beginning of the display area.
+fn timer(instance: Peripheral, mode: u16) -> Timer {
+    let mut t = Timer::new(instance);
+
+    t.set_mode(mode);
+
+    if instance != Peripheral::Timer2 {
+        t.blank = Some(BlankSignal::new(FracCycles::from_cycles(100),
+                                        FracCycles::from_cycles(80),
+                                        FracCycles::from_cycles(0)));
+    }
+
+    t
+}
+
+#[test]
+fn timer_free_run() {
+    let mut irq_state = InterruptState::new();
+    let mut t = timer(Peripheral::Timer0, 0);
+
+    t.run(250, &mut irq_state);
+
+    assert!(t.counter() == 250);
+}
+
+#[test]
+fn timer_sync_pause_in_blank() {
+    let mut irq_state = InterruptState::new();
+    // Sync mode 0: pause during the blanking
+    let mut t = timer(Peripheral::Timer0, 0x1);
+
+    // Two full periods, 20 cycles of blanking each
+    t.run(200, &mut irq_state);
+    assert!(t.counter() == 160);
+
+    t.run(90, &mut irq_state);
+    assert!(t.counter() == 240);
+}
+
+#[test]
+fn timer_sync_reset_at_blank() {
+    let mut irq_state = InterruptState::new();
+    // Sync mode 1: reset at the beginning of the blanking
+    let mut t = timer(Peripheral::Timer1, 0x3);
+
+    t.run(79, &mut irq_state);
+    assert!(t.counter() == 79);
+
+    t.run(31, &mut irq_state);
+    assert!(t.counter() == 30);
+}
+
+#[test]
+fn timer_sync_reset_and_pause_outside_blank() {
+    let mut irq_state = InterruptState::new();
+    // Sync mode 2: reset at the beginning of the blanking and pause
+    // outside of it
+    let mut t = timer(Peripheral::Timer0, 0x5);
+
+    t.run(80, &mut irq_state);
+    assert!(t.counter() == 0);
+
+    t.run(15, &mut irq_state);
+    assert!(t.counter() == 15);
+
+    // The counter is reset at the next blanking and counts through
+    // it again, then stays at 20
+    t.run(150, &mut irq_state);
+    assert!(t.counter() == 20);
+
+    t.run(40, &mut irq_state);
+    assert!(t.counter() == 5);
+}
+
+#[test]
+fn timer_sync_wait_for_blank() {
+    let mut irq_state = InterruptState::new();
+    // Sync mode 3: wait for the blanking and then free run
+    let mut t = timer(Peripheral::Timer1, 0x7);
+
+    t.run(50, &mut irq_state);
+    assert!(t.counter() == 0);
+
+    t.run(130, &mut irq_state);
+    assert!(t.counter() == 100);
+}
+
+#[test]
+fn timer2_sync_stop() {
+    let mut irq_state = InterruptState::new();
+
+    // Sync modes 0 and 3 stop the counter
+    for &mode in &[0x1, 0x7] {
+        let mut t = timer(Peripheral::Timer2, mode);
+
+        t.run(100, &mut irq_state);
+        assert!(t.counter() == 0);
+    }
+
+    // Sync modes 1 and 2 free run
+    for &mode in &[0x3, 0x5] {
+        let mut t = timer(Peripheral::Timer2, mode);
+
+        t.run(100, &mut irq_state);
+        assert!(t.counter() == 100);
+    }
+}
+
+#[test]
+fn timer_one_shot_irq() {
+    let mut irq_state = InterruptState::new();
+
+    // Target IRQ, reset at target, one-shot pulse
+    let mut t = timer(Peripheral::Timer2, 0x18);
+    t.set_target(10);
+
+    t.run(11, &mut irq_state);
+    assert!(irq_state.status() & (1 << 6) != 0);
+    assert!(t.mode() & (1 << 10) == 0);
+
+    irq_state.ack(0);
+
+    t.run(11, &mut irq_state);
+    assert!(irq_state.status() == 0);
+    // The pulse is over
+    assert!(t.mode() & (1 << 10) != 0);
+}
+
+#[test]
+fn timer_repeat_irq() {
+    let mut irq_state = InterruptState::new();
+
+    // Target IRQ, reset at target, repeat pulse
+    let mut t = timer(Peripheral::Timer2, 0x58);
+    t.set_target(10);
+
+    for _ in 0..3 {
+        t.run(11, &mut irq_state);
+        assert!(irq_state.status() & (1 << 6) != 0);
+
+        irq_state.ack(0);
+    }
+}
+
+#[test]
+fn timer_toggle_irq() {
+    let mut irq_state = InterruptState::new();
+
+    // Overflow IRQ, repeat, toggle mode
+    let mut t = timer(Peripheral::Timer2, 0xe0);
+
+    // Bit 10 is set after writing the mode
+    assert!(t.mode() & (1 << 10) != 0);
+
+    t.set_counter(0xfff0);
+    t.run(0x10, &mut irq_state);
+    assert!(irq_state.status() & (1 << 6) != 0);
+    assert!(t.mode() & (1 << 10) == 0);
+
+    irq_state.ack(0);
+
+    // The signal goes back to inactive without triggering an IRQ
+    t.run(0x10000, &mut irq_state);
+    assert!(irq_state.status() == 0);
+    assert!(t.mode() & (1 << 10) != 0);
+
+    t.run(0x10000, &mut irq_state);
+    assert!(irq_state.status() & (1 << 6) != 0);
+}
+
+#[test]
+fn timer_reached_flags() {
+    let mut irq_state = InterruptState::new();
+    let mut t = timer(Peripheral::Timer2, 0);
+    t.set_target(100);
+
+    t.run(0x10000, &mut irq_state);
+
+    // Both flags are set and cleared by the read
+    assert!(t.mode() & 0x1800 == 0x1800);
+    assert!(t.mode() & 0x1800 == 0);
+}
+
+#[test]
+fn timer_sync_empty_blank() {
+    let mut irq_state = InterruptState::new();
+    let mut tk = TimeKeeper::new();
+    // Sync mode 1: reset at the beginning of the blanking
+    let mut t = timer(Peripheral::Timer1, 0x3);
+
+    // Empty blanking range (X2 <= X1): the signal never changes
+    t.blank = Some(BlankSignal::new(FracCycles::from_cycles(100),
+                                    FracCycles::from_cycles(0),
+                                    FracCycles::from_cycles(0)));
+
+    t.run(250, &mut irq_state);
+    assert!(t.counter() == 250);
+
+    // No edge and no IRQ target coming, no sync must be scheduled
+    tk.tick(250);
+    t.predict_next_sync(&mut tk);
+    tk.tick(0x10000000);
+    assert!(!tk.needs_sync(Peripheral::Timer1));
+}
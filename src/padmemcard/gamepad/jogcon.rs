@@ -0,0 +1,84 @@
+use super::{Profile, Button, ButtonState};
+use super::update_button;
+
+/// Namco Jogcon: digital pad with a dial in the middle. The dial
+/// position is reported as a 16bit rotation counter.
+///
+/// XXX This is a basic implementation: the controller is always in
+/// "Jogcon" mode (the real one starts in digital mode and is switched
+/// using the same configuration commands as the DualShock) and the
+/// force feedback motor is not emulated.
+pub struct JogconProfile {
+    /// Button state, same layout as the digital pad
+    buttons: u16,
+    /// Dial rotation counter, positive is clockwise
+    dial: i16,
+}
+
+impl JogconProfile {
+    pub fn new() -> JogconProfile {
+        JogconProfile {
+            buttons: 0xffff,
+            dial: 0,
+        }
+    }
+}
+
+impl Profile for JogconProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // Response 0xe3: Jogcon
+            1 => (0xe3, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5 => (self.dial as u8, true),
+            6 => ((self.dial >> 8) as u8, true),
+            // XXX dial status and force feedback state
+            7 => (0x00, true),
+            8 => (0x00, false),
+            // Shouldn't be reached
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        self.buttons = update_button(self.buttons, button, state);
+    }
+
+    fn add_relative_motion(&mut self, dx: i32, _: i32) {
+        // The dial is controlled by horizontal motion. The counter
+        // wraps around.
+        self.dial = self.dial.wrapping_add(dx as i16);
+    }
+}
+
+#[test]
+fn jogcon_dial() {
+    let mut jogcon = JogconProfile::new();
+
+    fn read(jogcon: &mut JogconProfile) -> Vec<u8> {
+        [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0].iter().enumerate()
+            .map(|(seq, &b)| jogcon.handle_command(seq as u8, b).0)
+            .collect()
+    }
+
+    assert!(read(&mut jogcon) ==
+            [0xff, 0xe3, 0x5a, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00]);
+
+    jogcon.add_relative_motion(0x123, 0);
+
+    assert!(read(&mut jogcon)[5..7] == [0x23, 0x01]);
+
+    // Counter-clockwise past 0
+    jogcon.add_relative_motion(-0x124, 0);
+
+    assert!(read(&mut jogcon)[5..7] == [0xff, 0xff]);
+
+    // The counter wraps around
+    jogcon.add_relative_motion(0x7fff, 0);
+    jogcon.add_relative_motion(2, 0);
+
+    assert!(read(&mut jogcon)[5..7] == [0x00, 0x80]);
+}
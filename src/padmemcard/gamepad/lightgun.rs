@@ -0,0 +1,182 @@
+use super::{Profile, Button, ButtonState};
+use super::update_button;
+
+use gpu::BeamPosition;
+use timekeeper::Cycles;
+use cpu::CPU_FREQ_HZ;
+
+/// Map the host inputs to the light gun buttons: the left mouse
+/// button is the trigger, the right one and `Start` are the gun's
+/// "A" button (or "Start" on the Justifier) and `Cross` is its "B"
+/// button (or "Aux" on the Justifier).
+fn gun_button(button: Button) -> Option<Button> {
+    match button {
+        Button::MouseLeft | Button::Circle => Some(Button::Circle),
+        Button::MouseRight | Button::Start => Some(Button::Start),
+        Button::Cross => Some(Button::Cross),
+        _ => None,
+    }
+}
+
+/// Namco GunCon: the gun latches the beam position when its sensor
+/// sees it and reports it to the console. The trigger uses the same
+/// bit as `Circle` on the digital pad, "A" as `Start` and "B" as
+/// `Cross`.
+pub struct GunConProfile {
+    buttons: u16,
+    /// Reported position: X in 8MHz clock ticks since the beginning
+    /// of the line and Y in lines since the beginning of the frame
+    position: (u16, u16),
+}
+
+impl GunConProfile {
+    pub fn new() -> GunConProfile {
+        GunConProfile {
+            buttons: 0xffff,
+            position: GUNCON_OFFSCREEN,
+        }
+    }
+}
+
+/// Position reported by the GunCon when it doesn't see the beam
+const GUNCON_OFFSCREEN: (u16, u16) = (0x0001, 0x000a);
+
+impl Profile for GunConProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        let (x, y) = self.position;
+
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // Response 0x63: GunCon
+            1 => (0x63, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5 => (x as u8, true),
+            6 => ((x >> 8) as u8, true),
+            7 => (y as u8, true),
+            8 => ((y >> 8) as u8, false),
+            // Shouldn't be reached
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        if let Some(b) = gun_button(button) {
+            self.buttons = update_button(self.buttons, b, state);
+        }
+    }
+
+    fn set_gun_target(&mut self, target: Option<BeamPosition>) {
+        self.position =
+            match target {
+                Some(t) => {
+                    // Convert from CPU clock periods to 8MHz ticks
+                    let x = t.cycles * 8_000_000 / CPU_FREQ_HZ as Cycles;
+
+                    (x as u16, t.line)
+                }
+                None => GUNCON_OFFSCREEN,
+            };
+    }
+}
+
+/// Konami Justifier: the gun doesn't report its position, instead it
+/// triggers the lightpen interrupt (IRQ10) when its sensor sees the
+/// beam and the game uses the timers to work out the position.
+pub struct JustifierProfile {
+    buttons: u16,
+    /// Beam position at the point targeted by the gun
+    target: Option<BeamPosition>,
+}
+
+impl JustifierProfile {
+    pub fn new() -> JustifierProfile {
+        JustifierProfile {
+            buttons: 0xffff,
+            target: None,
+        }
+    }
+}
+
+impl Profile for JustifierProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // Response 0x31: Justifier
+            1 => (0x31, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, false),
+            // Shouldn't be reached
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        if let Some(b) = gun_button(button) {
+            self.buttons = update_button(self.buttons, b, state);
+        }
+    }
+
+    fn set_gun_target(&mut self, target: Option<BeamPosition>) {
+        self.target = target;
+    }
+
+    fn lightpen_target(&self) -> Option<BeamPosition> {
+        // XXX The real gun only triggers the interrupt when it's
+        // enabled by the console through the poll command, we
+        // trigger it every frame while the gun points at the screen.
+        self.target
+    }
+}
+
+#[test]
+fn guncon_position() {
+    let mut gun = GunConProfile::new();
+
+    gun.set_button_state(Button::MouseLeft, ButtonState::Pressed);
+
+    fn read(gun: &mut GunConProfile) -> Vec<u8> {
+        [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0].iter().enumerate()
+            .map(|(seq, &b)| gun.handle_command(seq as u8, b).0)
+            .collect()
+    }
+
+    assert!(read(&mut gun) ==
+            [0xff, 0x63, 0x5a, 0xff, 0xdf, 0x01, 0x00, 0x0a, 0x00]);
+
+    // Half a line after HSYNC
+    gun.set_gun_target(Some(BeamPosition { cycles: 1077, line: 100 }));
+
+    assert!(read(&mut gun)[5..] == [0xfe, 0x00, 100, 0x00]);
+}
+
+#[test]
+fn justifier_lightpen() {
+    let mut gun = JustifierProfile::new();
+
+    gun.set_button_state(Button::MouseLeft, ButtonState::Pressed);
+
+    let response: Vec<(u8, bool)> =
+        [0x01, 0x42, 0, 0, 0].iter().enumerate()
+        .map(|(seq, &b)| gun.handle_command(seq as u8, b))
+        .collect();
+
+    // 5 byte response, the last one doesn't expect more data
+    assert!(response == [(0xff, true), (0x31, true), (0x5a, true),
+                         (0xff, true), (0xdf, false)]);
+
+    assert!(gun.lightpen_target().is_none());
+
+    let target = BeamPosition { cycles: 1077, line: 100 };
+
+    gun.set_gun_target(Some(target));
+
+    assert!(gun.lightpen_target() == Some(target));
+
+    // Pointing away from the screen
+    gun.set_gun_target(None);
+
+    assert!(gun.lightpen_target().is_none());
+}
@@ -0,0 +1,650 @@
+use self::mouse::MouseProfile;
+use self::negcon::NeGconProfile;
+use self::jogcon::JogconProfile;
+use self::lightgun::{GunConProfile, JustifierProfile};
+
+use gpu::BeamPosition;
+
+mod mouse;
+mod negcon;
+mod jogcon;
+mod lightgun;
+
+pub struct GamePad {
+    /// Gamepad profile
+    profile: Box<Profile>,
+    /// Counter keeping track of the current position in the reply
+    /// sequence
+    seq:    u8,
+    /// False if the pad is done processing the current command
+    active: bool,
+}
+
+impl GamePad {
+    pub fn new(pad_type: Type) -> GamePad {
+        let profile: Box<Profile> =
+            match pad_type {
+                Type::Disconnected =>
+                    Box::new(DisconnectedProfile),
+                Type::Digital =>
+                    Box::new(DigitalProfile::new()),
+                Type::DualShock =>
+                    Box::new(DualShockProfile::new()),
+                Type::Mouse =>
+                    Box::new(MouseProfile::new()),
+                Type::NeGcon =>
+                    Box::new(NeGconProfile::new()),
+                Type::Jogcon =>
+                    Box::new(JogconProfile::new()),
+                Type::GunCon =>
+                    Box::new(GunConProfile::new()),
+                Type::Justifier =>
+                    Box::new(JustifierProfile::new()),
+            };
+
+        GamePad {
+            seq: 0,
+            active: true,
+            profile: profile,
+        }
+    }
+
+    /// Called when the "select" line goes down.
+    pub fn select(&mut self) {
+        // Prepare for incomming command
+        self.active = true;
+        self.seq = 0;
+    }
+
+    /// The first return value is true if the gamepad issues a DSR
+    /// pulse after the byte is read to notify the controller that
+    /// more data can be read. The 2nd return value is the response
+    /// byte.
+    pub fn send_command(&mut self, cmd: u8) -> (u8, bool) {
+        if !self.active {
+            return (0xff, false);
+        }
+
+        let (resp, dsr) = self.profile.handle_command(self.seq, cmd);
+
+        // If we're not asserting DSR it either means that we've
+        // encountered an error or that we have nothing else to
+        // reply. In either case we won't be handling any more command
+        // bytes in this transaction.
+        self.active = dsr;
+
+        self.seq += 1;
+
+        (resp, dsr)
+    }
+
+    /// Return a mutable reference to the underlying gamepad Profile
+    pub fn profile(&mut self) -> &mut Profile {
+        &mut *self.profile
+    }
+}
+
+/// GamePad types supported by the emulator
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Type {
+    /// No gamepad connected
+    Disconnected,
+    /// SCPH-1080: original gamepad without analog sticks
+    Digital,
+    /// SCPH-1200: DualShock analog controller
+    DualShock,
+    /// SCPH-1030: PlayStation Mouse
+    Mouse,
+    /// Namco NeGcon: twistable racing controller with analog buttons
+    NeGcon,
+    /// Namco Jogcon: gamepad with a force feedback dial
+    Jogcon,
+    /// Namco GunCon: light gun reporting the beam position
+    GunCon,
+    /// Konami Justifier: light gun using the lightpen interrupt
+    Justifier,
+}
+
+#[derive(Clone,Copy,Debug)]
+pub enum Button {
+    Select = 0,
+    /// Left stick click, analog mode only
+    L3 = 1,
+    /// Right stick click, analog mode only
+    R3 = 2,
+    Start = 3,
+    DUp = 4,
+    DRight = 5,
+    DDown = 6,
+    DLeft = 7,
+    L2 = 8,
+    R2 = 9,
+    L1 = 10,
+    R1 = 11,
+    Triangle = 12,
+    Circle = 13,
+    Cross = 14,
+    Square = 15,
+    /// "Analog" mode button, not part of the button state reported
+    /// to the console
+    Analog = 16,
+    /// Mouse buttons, only used by the mouse profile
+    MouseLeft = 17,
+    MouseRight = 18,
+}
+
+/// State of the rumble motors
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Rumble {
+    /// The small motor can only be turned on or off
+    pub small: bool,
+    /// Large motor speed, from 0x00 (off) to 0xff (full speed)
+    pub large: u8,
+}
+
+impl Rumble {
+    pub fn off() -> Rumble {
+        Rumble {
+            small: false,
+            large: 0,
+        }
+    }
+}
+
+/// Callback used to forward the state of the rumble motors to the
+/// frontend. It's called every time the state changes.
+pub type RumbleCallback = Box<FnMut(Rumble)>;
+
+/// Analog stick and trigger axes
+#[derive(Clone,Copy,Debug)]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    /// Analog triggers, only used by the NeGcon
+    LeftTrigger,
+    RightTrigger,
+}
+
+#[derive(Clone,Copy,Debug)]
+pub enum ButtonState {
+    Pressed,
+    Released,
+}
+
+/// Trait used to abstract away the various controller types.
+pub trait Profile {
+    /// Handle a command byte sent by the console. `seq` is the byte
+    /// position in the current command starting with `1` since byte
+    /// `0` is expected to always be `0x01` when addressing a
+    /// controller and is handled at the top level.
+    ///
+    /// Returns a pair `(response, dsr)`. If DSR is false the
+    /// subsequent command bytes will be ignored for the current
+    /// transaction.
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool);
+
+    /// Set a button's state. The function can be called several time
+    /// in a row with the same button and the same state, it should be
+    /// idempotent.
+    fn set_button_state(&mut self, button: Button, state: ButtonState);
+
+    /// Set an analog axis position. 0x00 is left/up, 0x80 is centered
+    /// and 0xff is right/down. Triggers go from 0x00 (released) to
+    /// 0xff (fully pressed).
+    fn set_axis_state(&mut self, _axis: Axis, _val: u8) {
+    }
+
+    /// Relative motion of a pointing device, in host mouse units.
+    /// The motion accumulates until it's read by the console.
+    fn add_relative_motion(&mut self, _dx: i32, _dy: i32) {
+    }
+
+    /// Install the callback used to report the state of the rumble
+    /// motors. Profiles without motors never call it.
+    fn set_rumble_callback(&mut self, _callback: RumbleCallback) {
+    }
+
+    /// Set the position of the video beam at the point targeted by a
+    /// light gun, None if the gun doesn't point at the picture. Only
+    /// used by the light guns.
+    fn set_gun_target(&mut self, _target: Option<BeamPosition>) {
+    }
+
+    /// Return the beam position where the lightpen interrupt should
+    /// be triggered, if any. Only used by the Justifier.
+    fn lightpen_target(&self) -> Option<BeamPosition> {
+        None
+    }
+}
+
+/// Dummy profile emulating an empty pad slot
+struct DisconnectedProfile;
+
+impl Profile for DisconnectedProfile {
+    fn handle_command(&mut self, _: u8, _: u8) -> (u8, bool) {
+        // The bus is open, no response
+        (0xff, false)
+    }
+
+    fn set_button_state(&mut self, _: Button, _: ButtonState) {
+    }
+}
+
+/// SCPH-1080: Digital gamepad.
+/// Full state is only two bytes since we only need one bit per
+/// button.
+struct DigitalProfile(u16);
+
+impl DigitalProfile {
+    pub fn new() -> DigitalProfile {
+        DigitalProfile(0xffff)
+    }
+}
+
+impl Profile for DigitalProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            // First byte should be 0x01 if the command targets
+            // the controller
+            0 => (0xff, (cmd == 0x01)),
+            // Digital gamepad only supports command 0x42: read
+            // buttons.
+            // Response 0x41: we're a digital PSX controller
+            1 => (0x41, (cmd == 0x42)),
+            // From then on the command byte is ignored.
+            // Response 0x5a: 2nd controller ID byte
+            2 => (0x5a, true),
+            // First button state byte: direction cross, start and
+            // select.
+            3 => (self.0 as u8, true),
+            // 2nd button state byte: shoulder buttons and "shape"
+            // buttons. We don't asert DSR for the last byte.
+            4 => ((self.0 >> 8) as u8, false),
+            // Shouldn't be reached
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        match button {
+            // Not present on the digital pad
+            Button::L3 | Button::R3 => (),
+            _ => self.0 = update_button(self.0, button, state),
+        }
+    }
+}
+
+/// SCPH-1200: DualShock controller. It starts in digital mode where
+/// it behaves like the `DigitalProfile`, the "analog" button or the
+/// configuration commands switch it to analog mode where the stick
+/// positions are reported as well.
+struct DualShockProfile {
+    /// Button state, same layout as the `DigitalProfile`
+    buttons: u16,
+    /// Stick positions in the order they're reported to the console:
+    /// right X, right Y, left X, left Y
+    axes: [u8; 4],
+    /// True if the controller is in analog mode
+    analog: bool,
+    /// If true the "analog" button is disabled, only the console can
+    /// change the mode
+    locked: bool,
+    /// True if the controller is in configuration mode (also known
+    /// as "escape" mode)
+    config: bool,
+    /// Command being handled in the current transaction
+    command: u8,
+    /// Response bytes following the 0x5a ID byte
+    reply: [u8; 6],
+    /// Number of valid bytes in `reply`
+    reply_len: u8,
+    /// Rumble motor mapping set by command 0x4D. Each byte
+    /// configures the meaning of the corresponding parameter byte
+    /// of the read command: 0x00 for the small motor, 0x01 for the
+    /// large motor and 0xff when unused.
+    rumble_map: [u8; 6],
+    /// Current state of the motors
+    rumble: Rumble,
+    /// Frontend callback notified when the motor state changes
+    rumble_callback: Option<RumbleCallback>,
+}
+
+impl DualShockProfile {
+    pub fn new() -> DualShockProfile {
+        DualShockProfile {
+            buttons: 0xffff,
+            axes: [0x80; 4],
+            analog: false,
+            locked: false,
+            config: false,
+            command: 0,
+            reply: [0; 6],
+            reply_len: 0,
+            rumble_map: [0xff; 6],
+            rumble: Rumble::off(),
+            rumble_callback: None,
+        }
+    }
+
+    /// Update the motor state and notify the frontend if it changed
+    fn set_rumble(&mut self, rumble: Rumble) {
+        if rumble == self.rumble {
+            return;
+        }
+
+        self.rumble = rumble;
+
+        if let Some(ref mut callback) = self.rumble_callback {
+            callback(rumble);
+        }
+    }
+
+    /// Return the controller ID byte
+    fn id(&self) -> u8 {
+        if self.config {
+            0xf3
+        } else if self.analog {
+            0x73
+        } else {
+            0x41
+        }
+    }
+
+    /// Set the `reply` to `bytes`
+    fn set_reply(&mut self, bytes: &[u8]) {
+        self.reply[..bytes.len()].copy_from_slice(bytes);
+        self.reply_len = bytes.len() as u8;
+    }
+
+    /// Build the reply to the read command
+    fn reply_state(&mut self) {
+        let buttons = [self.buttons as u8, (self.buttons >> 8) as u8];
+
+        if self.analog {
+            let a = self.axes;
+
+            self.set_reply(&[buttons[0], buttons[1],
+                             a[0], a[1], a[2], a[3]]);
+        } else {
+            self.set_reply(&buttons);
+        }
+    }
+
+    /// Called with the command byte, returns false if the command is
+    /// not supported
+    fn start_command(&mut self, cmd: u8) -> bool {
+        self.command = cmd;
+
+        match (cmd, self.config) {
+            // Read buttons and sticks. Also accepted in config mode
+            (0x42, _) => self.reply_state(),
+            // Enter/exit config mode. Outside of config mode it also
+            // returns the controller state
+            (0x43, false) => self.reply_state(),
+            (0x43, true) => self.set_reply(&[0; 6]),
+            // Set analog mode and lock
+            (0x44, true) => self.set_reply(&[0; 6]),
+            // Get status: controller type (DualShock), analog mode,
+            // number of actuators...
+            (0x45, true) => {
+                let analog = self.analog as u8;
+
+                self.set_reply(&[0x01, 0x02, analog, 0x02, 0x01, 0x00])
+            }
+            // Query actuator info, the last bytes depend on the
+            // parameter, see `handle_param`
+            (0x46, true) => self.set_reply(&[0, 0, 0x01, 0x02, 0x00, 0x0a]),
+            (0x47, true) => self.set_reply(&[0, 0, 0x02, 0x00, 0x01, 0x00]),
+            (0x4c, true) => self.set_reply(&[0, 0, 0x00, 0x04, 0x00, 0x00]),
+            // Set rumble mapping, the previous mapping is returned
+            (0x4d, true) => {
+                let map = self.rumble_map;
+
+                self.set_reply(&map)
+            }
+            // Unknown config commands, always return zeroes
+            (0x40, true) | (0x41, true) | (0x48, true) | (0x49, true) |
+            (0x4a, true) | (0x4b, true) | (0x4e, true) | (0x4f, true) =>
+                self.set_reply(&[0; 6]),
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Handle the parameter byte `n` of the current command
+    fn handle_param(&mut self, n: usize, param: u8) {
+        match (self.command, n) {
+            // The parameters of the read command control the rumble
+            // motors depending on the mapping set by command 0x4D
+            (0x42, n) => {
+                let mut rumble = self.rumble;
+
+                match self.rumble_map[n] {
+                    0x00 => rumble.small = param & 1 != 0,
+                    0x01 => rumble.large = param,
+                    _ => (),
+                }
+
+                self.set_rumble(rumble);
+            }
+            (0x43, 0) => self.config = param == 0x01,
+            (0x44, 0) => {
+                if param <= 0x01 {
+                    self.analog = param == 0x01;
+                }
+            }
+            (0x44, 1) => self.locked = param == 0x03,
+            (0x46, 0) => {
+                if param == 0x01 {
+                    self.set_reply(&[0, 0, 0x01, 0x01, 0x01, 0x14]);
+                }
+            }
+            (0x4c, 0) => {
+                if param == 0x01 {
+                    self.reply[3] = 0x07;
+                }
+            }
+            (0x4d, n) => {
+                self.rumble_map[n] = param;
+
+                // Changing the mapping stops the motors
+                self.set_rumble(Rumble::off());
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Profile for DualShockProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            // First byte should be 0x01 if the command targets the
+            // controller
+            0 => (0xff, (cmd == 0x01)),
+            // Command byte, the ID is returned before we know which
+            // command is being sent
+            1 => (self.id(), self.start_command(cmd)),
+            2 => (0x5a, true),
+            _ => {
+                let n = (seq - 3) as usize;
+
+                if n >= self.reply_len as usize {
+                    // Shouldn't be reached
+                    return (0xff, false);
+                }
+
+                let response = self.reply[n];
+
+                self.handle_param(n, cmd);
+
+                // We don't assert DSR for the last byte
+                (response, n + 1 < self.reply_len as usize)
+            }
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        match button {
+            Button::Analog => {
+                // The mode toggles when the button is pressed
+                if let ButtonState::Pressed = state {
+                    if !self.locked {
+                        self.analog = !self.analog;
+                    }
+                }
+            }
+            _ => self.buttons = update_button(self.buttons, button, state),
+        }
+    }
+
+    fn set_axis_state(&mut self, axis: Axis, val: u8) {
+        let index =
+            match axis {
+                Axis::RightX => 0,
+                Axis::RightY => 1,
+                Axis::LeftX => 2,
+                Axis::LeftY => 3,
+                _ => return,
+            };
+
+        self.axes[index] = val;
+    }
+
+    fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble_callback = Some(callback);
+    }
+}
+
+/// Update the active low button bitfield `buttons` with the new state
+/// of `button`. Buttons which are not part of the bitfield (analog,
+/// mouse...) are ignored.
+fn update_button(buttons: u16, button: Button, state: ButtonState) -> u16 {
+    let bit = button as usize;
+
+    if bit >= 16 {
+        return buttons;
+    }
+
+    let mask = 1 << bit;
+
+    match state {
+        ButtonState::Pressed  => buttons & !mask,
+        ButtonState::Released => buttons | mask,
+    }
+}
+
+#[test]
+fn dualshock_config_mode() {
+    fn run(pad: &mut Profile, cmd: &[u8]) -> Vec<u8> {
+        let mut reply = Vec::new();
+
+        for (seq, &b) in cmd.iter().enumerate() {
+            let (r, dsr) = pad.handle_command(seq as u8, b);
+
+            reply.push(r);
+
+            if !dsr {
+                break;
+            }
+        }
+
+        reply
+    }
+
+    let mut pad = DualShockProfile::new();
+
+    // Digital mode by default
+    assert!(run(&mut pad, &[0x01, 0x42, 0, 0, 0]) ==
+            [0xff, 0x41, 0x5a, 0xff, 0xff]);
+
+    // Config mode commands are rejected outside of config mode
+    assert!(run(&mut pad, &[0x01, 0x45, 0, 0, 0, 0, 0, 0, 0]) == [0xff, 0x41]);
+
+    // Enter config mode
+    run(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+
+    // Switch to analog mode and lock
+    assert!(run(&mut pad, &[0x01, 0x44, 0x00, 0x01, 0x03, 0, 0, 0, 0]) ==
+            [0xff, 0xf3, 0x5a, 0, 0, 0, 0, 0, 0]);
+
+    assert!(run(&mut pad, &[0x01, 0x45, 0, 0, 0, 0, 0, 0, 0]) ==
+            [0xff, 0xf3, 0x5a, 0x01, 0x02, 0x01, 0x02, 0x01, 0x00]);
+
+    // Map the large motor to the second parameter of the read command
+    run(&mut pad, &[0x01, 0x4d, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff]);
+
+    assert!(run(&mut pad, &[0x01, 0x4d, 0x00, 0xff, 0xff, 0xff, 0xff,
+                          0xff, 0xff]) ==
+            [0xff, 0xf3, 0x5a, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff]);
+
+    // Exit config mode
+    run(&mut pad, &[0x01, 0x43, 0x00, 0x00, 0, 0, 0, 0, 0]);
+
+    pad.set_button_state(Button::Cross, ButtonState::Pressed);
+    pad.set_axis_state(Axis::LeftX, 0x00);
+
+    // Analog button is locked
+    pad.set_button_state(Button::Analog, ButtonState::Pressed);
+
+    assert!(run(&mut pad, &[0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]) ==
+            [0xff, 0x73, 0x5a, 0xff, 0xbf, 0x80, 0x80, 0x00, 0x80]);
+}
+
+#[test]
+fn dualshock_rumble() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    let mut pad = DualShockProfile::new();
+
+    let state = Rc::new(Cell::new(Rumble::off()));
+    let calls = Rc::new(Cell::new(0));
+
+    {
+        let state = state.clone();
+        let calls = calls.clone();
+
+        pad.set_rumble_callback(Box::new(move |r| {
+            state.set(r);
+            calls.set(calls.get() + 1);
+        }));
+    }
+
+    fn transaction(pad: &mut Profile, cmd: &[u8]) {
+        for (seq, &b) in cmd.iter().enumerate() {
+            if !pad.handle_command(seq as u8, b).1 {
+                break;
+            }
+        }
+    }
+
+    // Motors are not mapped yet
+    transaction(&mut pad, &[0x01, 0x42, 0x00, 0x01, 0xff]);
+
+    assert!(calls.get() == 0);
+
+    // Enter config mode, switch to analog, map small motor to byte 0
+    // and large to byte 1 and exit config mode
+    transaction(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+    transaction(&mut pad, &[0x01, 0x44, 0x00, 0x01, 0x00, 0, 0, 0, 0]);
+    transaction(&mut pad, &[0x01, 0x4d, 0x00, 0x00, 0x01, 0xff, 0xff,
+                            0xff, 0xff]);
+    transaction(&mut pad, &[0x01, 0x43, 0x00, 0x00, 0, 0, 0, 0, 0]);
+
+    transaction(&mut pad, &[0x01, 0x42, 0x00, 0x01, 0x80, 0, 0, 0, 0]);
+
+    assert!(state.get() == Rumble { small: true, large: 0x80 });
+    assert!(calls.get() == 2);
+
+    // Same state, no new notification
+    transaction(&mut pad, &[0x01, 0x42, 0x00, 0x01, 0x80, 0, 0, 0, 0]);
+
+    assert!(calls.get() == 2);
+
+    transaction(&mut pad, &[0x01, 0x42, 0x00, 0x00, 0x00, 0, 0, 0, 0]);
+
+    assert!(state.get() == Rumble::off());
+}
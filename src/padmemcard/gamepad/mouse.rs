@@ -0,0 +1,115 @@
+use super::{Profile, Button, ButtonState};
+
+/// SCPH-1030: PlayStation Mouse. Reports two buttons and the relative
+/// motion since the previous read.
+pub struct MouseProfile {
+    /// Button state, only bits 10 (right) and 11 (left) are used
+    buttons: u16,
+    /// Motion accumulated since the last read
+    dx: i32,
+    dy: i32,
+    /// Motion returned by the current read
+    report: (i8, i8),
+}
+
+impl MouseProfile {
+    pub fn new() -> MouseProfile {
+        MouseProfile {
+            // Bits 8 and 9 always read as 0
+            buttons: 0xfcff,
+            dx: 0,
+            dy: 0,
+            report: (0, 0),
+        }
+    }
+
+    /// Latch the motion for the current read. If the motion doesn't
+    /// fit a single report the rest will be returned by the next
+    /// reads.
+    fn latch_motion(&mut self) {
+        let clamp = |v: i32| {
+            if v < -128 {
+                -128
+            } else if v > 127 {
+                127
+            } else {
+                v
+            }
+        };
+
+        let dx = clamp(self.dx);
+        let dy = clamp(self.dy);
+
+        self.dx -= dx;
+        self.dy -= dy;
+
+        self.report = (dx as i8, dy as i8);
+    }
+}
+
+impl Profile for MouseProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // Only the read command is supported. Response 0x12:
+            // mouse
+            1 => {
+                let read = cmd == 0x42;
+
+                if read {
+                    self.latch_motion();
+                }
+
+                (0x12, read)
+            }
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5 => (self.report.0 as u8, true),
+            6 => (self.report.1 as u8, false),
+            // Shouldn't be reached
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        let mask =
+            match button {
+                Button::MouseRight => 1 << 10,
+                Button::MouseLeft => 1 << 11,
+                _ => return,
+            };
+
+        self.buttons =
+            match state {
+                ButtonState::Pressed  => self.buttons & !mask,
+                ButtonState::Released => self.buttons | mask,
+            };
+    }
+
+    fn add_relative_motion(&mut self, dx: i32, dy: i32) {
+        self.dx += dx;
+        self.dy += dy;
+    }
+}
+
+#[test]
+fn mouse_motion() {
+    let mut mouse = MouseProfile::new();
+
+    mouse.set_button_state(Button::MouseLeft, ButtonState::Pressed);
+    mouse.add_relative_motion(-200, 3);
+
+    let reply: Vec<u8> =
+        [0x01, 0x42, 0, 0, 0, 0, 0].iter().enumerate()
+        .map(|(seq, &b)| mouse.handle_command(seq as u8, b).0)
+        .collect();
+
+    assert!(reply == [0xff, 0x12, 0x5a, 0xff, 0xf4, 0x80, 0x03]);
+
+    // Remaining motion is returned by the next read
+    mouse.handle_command(0, 0x01);
+    mouse.handle_command(1, 0x42);
+
+    assert!(mouse.report == (-72, 0));
+}
@@ -0,0 +1,97 @@
+use super::{Profile, Button, ButtonState, Axis};
+use super::update_button;
+
+/// Namco NeGcon: the two halves of the controller twist around the
+/// center for steering and the I, II and L buttons are analog.
+pub struct NeGconProfile {
+    /// Digital buttons: Start, D-pad, R (bit 11), B (bit 12) and A
+    /// (bit 13). Same positions as the R1, Triangle and Circle buttons
+    /// on the digital pad
+    buttons: u16,
+    /// Analog state in the order they're reported to the console:
+    /// twist, I, II and L
+    analog: [u8; 4],
+}
+
+impl NeGconProfile {
+    pub fn new() -> NeGconProfile {
+        NeGconProfile {
+            buttons: 0xffff,
+            // Twist centered, buttons released
+            analog: [0x80, 0x00, 0x00, 0x00],
+        }
+    }
+}
+
+impl Profile for NeGconProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // Only the read command is supported. Response 0x23:
+            // NeGcon
+            1 => (0x23, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5...7 => (self.analog[(seq - 5) as usize], true),
+            8 => (self.analog[3], false),
+            // Shouldn't be reached
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        let pressed =
+            match state {
+                ButtonState::Pressed => 0xff,
+                ButtonState::Released => 0x00,
+            };
+
+        match button {
+            Button::Start | Button::DUp | Button::DRight |
+            Button::DDown | Button::DLeft |
+            Button::R1 | Button::Triangle | Button::Circle =>
+                self.buttons = update_button(self.buttons, button, state),
+            // Analog buttons, fully pressed or released when mapped
+            // to digital inputs
+            Button::Cross => self.analog[1] = pressed,
+            Button::Square => self.analog[2] = pressed,
+            Button::L1 => self.analog[3] = pressed,
+            _ => (),
+        }
+    }
+
+    fn set_axis_state(&mut self, axis: Axis, val: u8) {
+        match axis {
+            Axis::LeftX => self.analog[0] = val,
+            Axis::RightTrigger => self.analog[1] = val,
+            Axis::LeftTrigger => self.analog[2] = val,
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn negcon_analog() {
+    let mut negcon = NeGconProfile::new();
+
+    fn read(negcon: &mut NeGconProfile) -> Vec<u8> {
+        [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0].iter().enumerate()
+            .map(|(seq, &b)| negcon.handle_command(seq as u8, b).0)
+            .collect()
+    }
+
+    // Twist rests at the center
+    assert!(read(&mut negcon) ==
+            [0xff, 0x23, 0x5a, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00]);
+
+    negcon.set_button_state(Button::Start, ButtonState::Pressed);
+    negcon.set_axis_state(Axis::LeftX, 0x20);
+    negcon.set_axis_state(Axis::RightTrigger, 0x40);
+    negcon.set_axis_state(Axis::LeftTrigger, 0x60);
+    negcon.set_button_state(Button::L1, ButtonState::Pressed);
+
+    // Twist, I, II then L
+    assert!(read(&mut negcon) ==
+            [0xff, 0x23, 0x5a, 0xf7, 0xff, 0x20, 0x40, 0x60, 0xff]);
+}
@@ -0,0 +1,507 @@
+//! Memory card filesystem.
+//!
+//! The 128KiB of the card are divided in 16 blocks of 8KiB. The first
+//! block contains the card's header and directory, the 15 others are
+//! available for saves. Each save uses one or more blocks, chained
+//! through the directory.
+//!
+//! The first block is made of 64 frames of 128 bytes:
+//!
+//! * Frame 0: header, starts with "MC"
+//! * Frames 1-15: directory, one entry per data block
+//! * Frames 16-35: broken frame list
+//! * Frame 63: copy of the header, used by the BIOS to test writes
+//!
+//! Each directory frame ends with a checksum (XOR of the previous
+//! 127 bytes) and contains:
+//!
+//! * 0x00: block state (0x51: first block of a save, 0x52: middle
+//!   block, 0x53: last block, 0xa0: free, 0xa1-0xa3: deleted save)
+//! * 0x04: size of the save in bytes (only in the first block)
+//! * 0x08: index of the next block in the chain (0xffff for none),
+//!   the index is relative to the first data block
+//! * 0x0a: file name (up to 20 ASCII characters, first block only)
+//!
+//! The first frame of a save contains its title and icon.
+
+use std::io;
+
+use super::MEMORY_CARD_SIZE;
+use super::save::Save;
+
+/// Size of a frame in bytes
+pub const FRAME_SIZE: usize = 128;
+/// Size of a block in bytes
+pub const BLOCK_SIZE: usize = 8 * 1024;
+/// Number of blocks available for saves
+pub const DATA_BLOCKS: usize = 15;
+/// Maximum length of a file name
+pub const NAME_LEN: usize = 20;
+
+/// Directory entry states
+const STATE_FIRST: u8 = 0x51;
+const STATE_MIDDLE: u8 = 0x52;
+const STATE_LAST: u8 = 0x53;
+const STATE_FREE: u8 = 0xa0;
+
+/// Description of a save found in the directory
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// File name, the first characters normally contain the game's
+    /// region and serial (e.g. "BASLUS-00594...")
+    name: String,
+    /// Size in bytes as stored in the directory
+    size: u32,
+    /// Data blocks used by the save in chain order (1 to 15)
+    blocks: Vec<usize>,
+    /// Title of the save, decoded from the save's header
+    title: String,
+    /// Save icon, None if the save's header is invalid
+    icon: Option<Icon>,
+}
+
+impl Entry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn icon(&self) -> Option<&Icon> {
+        self.icon.as_ref()
+    }
+}
+
+/// Animated save icon: up to 3 frames of 16x16 pixels, 4bpp with a
+/// 16 color BGR555 palette
+#[derive(Clone, Debug)]
+pub struct Icon {
+    palette: [u16; 16],
+    frames: Vec<[u8; FRAME_SIZE]>,
+}
+
+impl Icon {
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Return the BGR555 color of the pixel at (`x`, `y`) in `frame`
+    pub fn pixel(&self, frame: usize, x: usize, y: usize) -> u16 {
+        let b = self.frames[frame][(y * 16 + x) / 2];
+
+        // The leftmost pixel is in the low nibble
+        let index = if x & 1 == 0 { b & 0xf } else { b >> 4 };
+
+        self.palette[index as usize]
+    }
+
+    /// Convert `frame` to 16x16 RGBA8888 pixels. Color 0x0000 is
+    /// transparent.
+    pub fn to_rgba(&self, frame: usize) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(16 * 16 * 4);
+
+        for y in 0..16 {
+            for x in 0..16 {
+                let c = self.pixel(frame, x, y);
+
+                rgba.push(((c & 0x1f) << 3) as u8);
+                rgba.push((((c >> 5) & 0x1f) << 3) as u8);
+                rgba.push((((c >> 10) & 0x1f) << 3) as u8);
+                rgba.push(if c == 0 { 0 } else { 0xff });
+            }
+        }
+
+        rgba
+    }
+}
+
+/// Parse the directory of `card` and return the list of saves
+pub fn read_directory(card: &[u8]) -> io::Result<Vec<Entry>> {
+    if &card[0..2] != b"MC" {
+        return error("memory card is not formatted");
+    }
+
+    let mut entries = Vec::new();
+
+    for first in 1..DATA_BLOCKS + 1 {
+        let frame = directory_frame(card, first);
+
+        if frame[0] != STATE_FIRST {
+            continue;
+        }
+
+        let mut blocks = vec![first];
+        let mut next = le16(&frame[8..]);
+
+        while next != 0xffff {
+            let block = next as usize + 1;
+
+            // Make sure we don't loop forever on a corrupted chain
+            if block > DATA_BLOCKS || blocks.contains(&block) {
+                return error("invalid memory card block chain");
+            }
+
+            let f = directory_frame(card, block);
+
+            if f[0] != STATE_MIDDLE && f[0] != STATE_LAST {
+                return error("invalid memory card block chain");
+            }
+
+            blocks.push(block);
+
+            next = le16(&f[8..]);
+        }
+
+        let header = &card[first * BLOCK_SIZE..(first + 1) * BLOCK_SIZE];
+
+        entries.push(Entry {
+            name: frame_name(frame),
+            size: le32(&frame[4..]),
+            blocks: blocks,
+            title: parse_title(header),
+            icon: parse_icon(header),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Return the number of free blocks on `card`
+pub fn free_blocks(card: &[u8]) -> usize {
+    (1..DATA_BLOCKS + 1)
+        .filter(|&b| is_free(directory_frame(card, b)[0]))
+        .count()
+}
+
+/// Copy the save described by `entry` out of `card`
+pub fn export(card: &[u8], entry: &Entry) -> Save {
+    let mut data = Vec::with_capacity(entry.blocks.len() * BLOCK_SIZE);
+
+    for &b in &entry.blocks {
+        data.extend_from_slice(&card[b * BLOCK_SIZE..(b + 1) * BLOCK_SIZE]);
+    }
+
+    Save::new(&entry.name, data)
+}
+
+/// Copy `save` into free blocks of `card`
+pub fn import(card: &mut [u8], save: &Save) -> io::Result<()> {
+    if &card[0..2] != b"MC" {
+        return error("memory card is not formatted");
+    }
+
+    let entries = try!(read_directory(card));
+
+    if entries.iter().any(|e| e.name == save.name()) {
+        let msg = format!("save {} already exists on the card",
+                          save.name());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+    }
+
+    let nblocks = save.blocks();
+
+    let free: Vec<usize> =
+        (1..DATA_BLOCKS + 1)
+        .filter(|&b| is_free(directory_frame(card, b)[0]))
+        .take(nblocks)
+        .collect();
+
+    if free.len() < nblocks {
+        let msg = format!("not enough free blocks on the card: {} needed",
+                          nblocks);
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+
+    for (i, &b) in free.iter().enumerate() {
+        {
+            let frame = directory_frame_mut(card, b);
+
+            for v in frame.iter_mut() {
+                *v = 0;
+            }
+
+            frame[0] =
+                if i == 0 {
+                    STATE_FIRST
+                } else if i == nblocks - 1 {
+                    STATE_LAST
+                } else {
+                    STATE_MIDDLE
+                };
+
+            if i == 0 {
+                set_le32(&mut frame[4..], (nblocks * BLOCK_SIZE) as u32);
+
+                let name = save.name().as_bytes();
+                let len = ::std::cmp::min(name.len(), NAME_LEN);
+
+                frame[10..10 + len].copy_from_slice(&name[..len]);
+            }
+
+            let next =
+                match free.get(i + 1) {
+                    Some(&n) => (n - 1) as u16,
+                    None => 0xffff,
+                };
+
+            frame[8] = next as u8;
+            frame[9] = (next >> 8) as u8;
+
+            set_frame_checksum(frame);
+        }
+
+        card[b * BLOCK_SIZE..(b + 1) * BLOCK_SIZE]
+            .copy_from_slice(&save.data()[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]);
+    }
+
+    Ok(())
+}
+
+/// Format `card`: header frame, 15 free directory entries and an
+/// empty broken frame list
+pub fn format(card: &mut [u8]) {
+    for b in card[..MEMORY_CARD_SIZE].iter_mut() {
+        *b = 0;
+    }
+
+    // Header frame
+    card[0] = b'M';
+    card[1] = b'C';
+    set_frame_checksum(&mut card[0..FRAME_SIZE]);
+
+    // Directory frames
+    for i in 1..16 {
+        let frame = &mut card[i * FRAME_SIZE..(i + 1) * FRAME_SIZE];
+
+        frame[0] = STATE_FREE;
+        // No next block
+        frame[8] = 0xff;
+        frame[9] = 0xff;
+
+        set_frame_checksum(frame);
+    }
+
+    // Broken frame list
+    for i in 16..36 {
+        let frame = &mut card[i * FRAME_SIZE..(i + 1) * FRAME_SIZE];
+
+        for b in &mut frame[0..4] {
+            *b = 0xff;
+        }
+
+        frame[8] = 0xff;
+        frame[9] = 0xff;
+
+        set_frame_checksum(frame);
+    }
+
+    // Frame 63 is a copy of the header frame
+    let (header, rest) = card.split_at_mut(FRAME_SIZE);
+
+    rest[62 * FRAME_SIZE..63 * FRAME_SIZE].copy_from_slice(header);
+}
+
+/// Decode the title in the save's header. The title is encoded in
+/// Shift-JIS, we only convert the ASCII and full width alphanumeric
+/// characters, the rest is replaced by '?'.
+pub fn parse_title(header: &[u8]) -> String {
+    if &header[0..2] != b"SC" {
+        return String::new();
+    }
+
+    let raw = &header[4..0x44];
+
+    let mut title = String::new();
+
+    let mut i = 0;
+
+    while i < raw.len() {
+        let b = raw[i];
+
+        if b == 0 {
+            break;
+        }
+
+        if b < 0x80 {
+            title.push(b as char);
+            i += 1;
+            continue;
+        }
+
+        // Two byte character
+        let c = ((b as u16) << 8) | (*raw.get(i + 1).unwrap_or(&0) as u16);
+
+        let ascii =
+            match c {
+                0x8140 => b' ',
+                0x8143 => b',',
+                0x8144 => b'.',
+                0x8146 => b':',
+                0x8147 => b';',
+                0x8148 => b'?',
+                0x8149 => b'!',
+                0x815e => b'/',
+                0x8169 => b'(',
+                0x816a => b')',
+                0x817b => b'+',
+                0x817c => b'-',
+                0x8181 => b'=',
+                0x8193 => b'%',
+                0x8194 => b'#',
+                0x8195 => b'&',
+                0x8196 => b'*',
+                0x8197 => b'@',
+                0x824f...0x8258 => b'0' + (c - 0x824f) as u8,
+                0x8260...0x8279 => b'A' + (c - 0x8260) as u8,
+                0x8281...0x829a => b'a' + (c - 0x8281) as u8,
+                _ => b'?',
+            };
+
+        title.push(ascii as char);
+
+        i += 2;
+    }
+
+    // Titles are often padded with spaces
+    while title.ends_with(' ') {
+        title.pop();
+    }
+
+    title
+}
+
+/// Extract the icon from the save's header
+pub fn parse_icon(header: &[u8]) -> Option<Icon> {
+    if &header[0..2] != b"SC" {
+        return None;
+    }
+
+    // Number of animation frames
+    let nframes =
+        match header[2] {
+            0x11 => 1,
+            0x12 => 2,
+            0x13 => 3,
+            _ => return None,
+        };
+
+    let mut palette = [0; 16];
+
+    for (i, c) in palette.iter_mut().enumerate() {
+        *c = le16(&header[0x60 + i * 2..]);
+    }
+
+    let frames =
+        (0..nframes).map(|f| {
+            let mut frame = [0; FRAME_SIZE];
+
+            frame.copy_from_slice(&header[(f + 1) * FRAME_SIZE..
+                                          (f + 2) * FRAME_SIZE]);
+
+            frame
+        })
+        .collect();
+
+    Some(Icon {
+        palette: palette,
+        frames: frames,
+    })
+}
+
+/// Return the file name stored in a directory frame
+pub fn frame_name(frame: &[u8]) -> String {
+    frame[10..10 + NAME_LEN].iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| b as char)
+        .collect()
+}
+
+/// Compute the checksum of a directory frame and store it in the
+/// last byte
+pub fn set_frame_checksum(frame: &mut [u8]) {
+    let checksum = frame[0..FRAME_SIZE - 1].iter().fold(0, |c, &b| c ^ b);
+
+    frame[FRAME_SIZE - 1] = checksum;
+}
+
+fn is_free(state: u8) -> bool {
+    state & 0xf0 == 0xa0
+}
+
+fn directory_frame(card: &[u8], block: usize) -> &[u8] {
+    &card[block * FRAME_SIZE..(block + 1) * FRAME_SIZE]
+}
+
+fn directory_frame_mut(card: &mut [u8], block: usize) -> &mut [u8] {
+    &mut card[block * FRAME_SIZE..(block + 1) * FRAME_SIZE]
+}
+
+fn le16(b: &[u8]) -> u16 {
+    (b[0] as u16) | ((b[1] as u16) << 8)
+}
+
+fn le32(b: &[u8]) -> u32 {
+    (b[0] as u32) | ((b[1] as u32) << 8)
+        | ((b[2] as u32) << 16) | ((b[3] as u32) << 24)
+}
+
+fn set_le32(b: &mut [u8], v: u32) {
+    for i in 0..4 {
+        b[i] = (v >> (i * 8)) as u8;
+    }
+}
+
+fn error<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[test]
+fn memory_card_import_export() {
+    let mut card = vec![0; MEMORY_CARD_SIZE];
+
+    format(&mut card);
+
+    assert!(read_directory(&card).unwrap().is_empty());
+    assert!(free_blocks(&card) == DATA_BLOCKS);
+
+    let mut data = vec![0; 2 * BLOCK_SIZE];
+
+    data[0..4].copy_from_slice(&[b'S', b'C', 0x11, 2]);
+    // "AB" in full width characters
+    data[4..8].copy_from_slice(&[0x82, 0x60, 0x82, 0x61]);
+    data[BLOCK_SIZE] = 0x42;
+
+    let save = Save::new("BASLUS-00000TEST", data);
+
+    import(&mut card, &save).unwrap();
+
+    // Importing twice is not allowed
+    assert!(import(&mut card, &save).is_err());
+
+    let entries = read_directory(&card).unwrap();
+
+    assert!(entries.len() == 1);
+
+    let e = &entries[0];
+
+    assert!(e.name() == "BASLUS-00000TEST");
+    assert!(e.title() == "AB");
+    assert!(e.blocks() == &[1, 2]);
+    assert!(e.size() == 2 * BLOCK_SIZE as u32);
+    assert!(e.icon().unwrap().frame_count() == 1);
+    assert!(free_blocks(&card) == DATA_BLOCKS - 2);
+
+    let exported = export(&card, e);
+
+    assert!(exported.data() == save.data());
+}
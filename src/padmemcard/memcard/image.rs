@@ -0,0 +1,169 @@
+//! Memory card image file formats.
+//!
+//! All the formats contain a raw dump of the 128KiB of the card,
+//! some of them add a header:
+//!
+//! * Raw (.mcr, .mcd, .psm, .mc, .ddf...): no header
+//! * DexDrive (.gme): 3904 byte header containing a copy of the
+//!   directory and a comment for each save
+//! * Connectix Virtual Game Station (.vgs, .mem): 64 byte header
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+
+use super::MEMORY_CARD_SIZE;
+use super::filesystem::FRAME_SIZE;
+
+/// Size of the DexDrive header
+const GME_HEADER_SIZE: usize = 3904;
+/// Size of the VGS header
+const VGS_HEADER_SIZE: usize = 64;
+
+/// Memory card image format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Raw dump without header
+    Raw,
+    /// DexDrive
+    Gme,
+    /// Connectix Virtual Game Station
+    Vgs,
+}
+
+impl Format {
+    /// Select the image format based on the extension of `path`,
+    /// unknown extensions are assumed to be raw images
+    pub fn from_path(path: &Path) -> Format {
+        let extension =
+            path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match extension.as_ref().map(|e| e.as_ref()) {
+            Some("gme") => Format::Gme,
+            Some("vgs") | Some("mem") => Format::Vgs,
+            _ => Format::Raw,
+        }
+    }
+
+    /// Return the size of the header preceding the card data
+    pub fn header_size(self) -> usize {
+        match self {
+            Format::Raw => 0,
+            Format::Gme => GME_HEADER_SIZE,
+            Format::Vgs => VGS_HEADER_SIZE,
+        }
+    }
+}
+
+/// Load the card image at `path`, the format is selected based on
+/// the extension.
+pub fn load(path: &Path) -> io::Result<Box<[u8; MEMORY_CARD_SIZE]>> {
+    let format = Format::from_path(path);
+
+    let mut file = try!(File::open(path));
+
+    let mut raw = Vec::new();
+
+    try!(file.read_to_end(&mut raw));
+
+    decode(&raw, format)
+}
+
+/// Write the card image `card` to `path`, the format is selected
+/// based on the extension.
+pub fn save(path: &Path, card: &[u8]) -> io::Result<()> {
+    let format = Format::from_path(path);
+
+    let raw = encode(card, format);
+
+    let mut file = try!(File::create(path));
+
+    file.write_all(&raw)
+}
+
+/// Extract the card data from the contents of an image file
+pub fn decode(raw: &[u8],
+              format: Format) -> io::Result<Box<[u8; MEMORY_CARD_SIZE]>> {
+    let header_size = format.header_size();
+
+    if raw.len() != header_size + MEMORY_CARD_SIZE {
+        let msg = format!("invalid {:?} memory card image size: {} bytes",
+                          format, raw.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    let header = &raw[..header_size];
+
+    let valid =
+        match format {
+            Format::Raw => true,
+            Format::Gme => &header[0..11] == b"123-456-STD",
+            Format::Vgs => &header[0..4] == b"VgsM",
+        };
+
+    if !valid {
+        let msg = format!("invalid {:?} memory card image header", format);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    let mut card = Box::new([0; MEMORY_CARD_SIZE]);
+
+    card.copy_from_slice(&raw[header_size..]);
+
+    Ok(card)
+}
+
+/// Build the contents of an image file for `card`
+pub fn encode(card: &[u8], format: Format) -> Vec<u8> {
+    let mut raw = vec![0; format.header_size()];
+
+    match format {
+        Format::Raw => (),
+        Format::Gme => {
+            raw[0..11].copy_from_slice(b"123-456-STD");
+            raw[18] = 0x01;
+            raw[20] = 0x01;
+            raw[21] = b'M';
+
+            // Copy of the first bytes of the directory entries (state
+            // and next block). XXX The comments are not supported,
+            // we leave them empty.
+            for i in 0..15 {
+                let frame = &card[(i + 1) * FRAME_SIZE..];
+
+                raw[22 + i] = frame[0];
+                raw[38 + i] = frame[8];
+            }
+        }
+        Format::Vgs => {
+            raw[0..4].copy_from_slice(b"VgsM");
+            raw[4] = 0x01;
+            raw[8] = 0x01;
+            raw[12] = 0x01;
+            raw[17] = 0x02;
+        }
+    }
+
+    raw.extend_from_slice(card);
+
+    raw
+}
+
+#[test]
+fn memory_card_image_roundtrip() {
+    let card: Vec<u8> =
+        (0..MEMORY_CARD_SIZE).map(|i| (i ^ (i >> 8)) as u8).collect();
+
+    for &format in &[Format::Raw, Format::Gme, Format::Vgs] {
+        let raw = encode(&card, format);
+
+        assert!(raw.len() == format.header_size() + MEMORY_CARD_SIZE);
+
+        let decoded = decode(&raw, format).unwrap();
+
+        assert!(&decoded[..] == &card[..]);
+    }
+}
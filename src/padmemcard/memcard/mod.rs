@@ -0,0 +1,479 @@
+//! Memory card emulation.
+//!
+//! A memory card contains 128KiB of flash divided in 1024 128 byte
+//! "sectors" (or "frames"). It shares the serial bus with the gamepad
+//! plugged in the same port, the first byte of each transaction
+//! selects the device: 0x01 for the gamepad, 0x81 for the memory
+//! card.
+//!
+//! The card image is stored on disk, normally in the raw ".mcr"
+//! format (a plain dump of the 128KiB) but the other formats handled
+//! by the `image` module are supported as well. Each written sector
+//! is immediately written back to the file.
+
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Write, Seek, SeekFrom};
+
+use self::filesystem::Entry;
+use self::save::Save;
+
+pub mod image;
+pub mod filesystem;
+pub mod save;
+
+/// Size of a memory card in bytes
+pub const MEMORY_CARD_SIZE: usize = 128 * 1024;
+
+/// Size of a sector in bytes
+const SECTOR_SIZE: usize = 128;
+
+/// Number of sectors on the card
+const SECTOR_COUNT: u16 = (MEMORY_CARD_SIZE / SECTOR_SIZE) as u16;
+
+/// FLAG bit 2: the last write command failed
+const FLAG_WRITE_ERROR: u8 = 0x04;
+/// FLAG bit 3: "new card", set when the card is inserted and cleared
+/// by the first successful write. Games use it to detect card swaps.
+const FLAG_NEW_CARD: u8 = 0x08;
+
+pub struct MemoryCard {
+    /// Card contents, None if no card is inserted
+    data: Option<Box<[u8; MEMORY_CARD_SIZE]>>,
+    /// Path of the image file backing the card
+    path: Option<PathBuf>,
+    /// Format of the image file
+    format: image::Format,
+    /// Backing file, opened on the first write
+    file: Option<File>,
+    /// FLAG byte returned at the beginning of each command
+    flag: u8,
+    /// Counter keeping track of the current position in the reply
+    /// sequence
+    seq: u8,
+    /// False if the card is done processing the current command
+    active: bool,
+    /// Command being executed
+    command: Command,
+    /// Sector address of the current read or write command
+    address: u16,
+    /// Running checksum of the current read or write command
+    checksum: u8,
+    /// Sector data received during a write command
+    write_buf: [u8; SECTOR_SIZE],
+    /// True if the checksum sent during a write command matched
+    write_checksum_ok: bool,
+}
+
+impl MemoryCard {
+    /// Empty memory card slot
+    pub fn disconnected() -> MemoryCard {
+        MemoryCard {
+            data: None,
+            path: None,
+            format: image::Format::Raw,
+            file: None,
+            flag: FLAG_NEW_CARD,
+            seq: 0,
+            active: false,
+            command: Command::None,
+            address: 0,
+            checksum: 0,
+            write_buf: [0; SECTOR_SIZE],
+            write_checksum_ok: false,
+        }
+    }
+
+    /// Load the memory card image at `path`, the format is selected
+    /// based on the extension. If the file doesn't exist a freshly
+    /// formatted card is created, the file itself will only be
+    /// created when the game writes to the card.
+    pub fn from_path(path: &Path) -> io::Result<MemoryCard> {
+        let data =
+            match image::load(path) {
+                Ok(d) => d,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    let mut data = Box::new([0; MEMORY_CARD_SIZE]);
+
+                    filesystem::format(&mut data[..]);
+
+                    data
+                }
+                Err(e) => return Err(e),
+            };
+
+        let mut card = MemoryCard::disconnected();
+
+        card.data = Some(data);
+        card.path = Some(path.to_owned());
+        card.format = image::Format::from_path(path);
+
+        Ok(card)
+    }
+
+    /// Return the list of saves on the card
+    pub fn directory(&self) -> io::Result<Vec<Entry>> {
+        match self.data {
+            Some(ref d) => filesystem::read_directory(&d[..]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Return the number of free blocks on the card
+    pub fn free_blocks(&self) -> usize {
+        match self.data {
+            Some(ref d) => filesystem::free_blocks(&d[..]),
+            None => 0,
+        }
+    }
+
+    /// Copy the save described by `entry` out of the card
+    pub fn export_save(&self, entry: &Entry) -> Save {
+        filesystem::export(&self.data()[..], entry)
+    }
+
+    /// Copy `save` to the card and write the card image back to disk
+    pub fn import_save(&mut self, save: &Save) -> io::Result<()> {
+        match self.data {
+            Some(ref mut d) => try!(filesystem::import(&mut d[..], save)),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              "no memory card inserted")),
+        }
+
+        self.flush_all()
+    }
+
+    /// Write the card image to `path`, converting it to the format
+    /// matching the extension
+    pub fn save_as(&self, path: &Path) -> io::Result<()> {
+        image::save(path, &self.data()[..])
+    }
+
+    /// Called when the "select" line goes down.
+    pub fn select(&mut self) {
+        // Prepare for incomming command
+        self.active = true;
+        self.seq = 0;
+        self.command = Command::None;
+    }
+
+    /// Handle a command byte, returns the response byte and the DSR
+    /// state like `GamePad::send_command`.
+    pub fn send_command(&mut self, cmd: u8) -> (u8, bool) {
+        if !self.active || self.data.is_none() {
+            return (0xff, false);
+        }
+
+        let (resp, dsr) = self.handle_command(cmd);
+
+        self.active = dsr;
+
+        self.seq = self.seq.wrapping_add(1);
+
+        (resp, dsr)
+    }
+
+    fn handle_command(&mut self, cmd: u8) -> (u8, bool) {
+        match self.seq {
+            // First byte should be 0x81 if the command targets the
+            // memory card
+            0 => (0xff, cmd == 0x81),
+            1 => {
+                self.command =
+                    match cmd {
+                        0x52 => Command::Read,
+                        0x57 => Command::Write,
+                        0x53 => Command::GetId,
+                        // Unknown command, the FLAG byte is still
+                        // returned but the card stops responding
+                        _ => return (self.flag, false),
+                    };
+
+                (self.flag, true)
+            }
+            // Memory card ID
+            2 => (0x5a, true),
+            3 => (0x5d, true),
+            n => {
+                let n = n - 4;
+
+                match self.command {
+                    Command::Read => self.read(n, cmd),
+                    Command::Write => self.write(n, cmd),
+                    Command::GetId => self.get_id(n),
+                    Command::None => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// Handle byte `n` (not counting the 4 header bytes) of a read
+    /// command
+    fn read(&mut self, n: u8, cmd: u8) -> (u8, bool) {
+        let address_valid = self.address < SECTOR_COUNT;
+
+        match n {
+            // Address MSB
+            0 => {
+                self.address = (cmd as u16) << 8;
+                (0x00, true)
+            }
+            // Address LSB, we return the previous byte
+            1 => {
+                let msb = (self.address >> 8) as u8;
+
+                self.address |= cmd as u16;
+                self.checksum = msb ^ cmd;
+
+                (msb, true)
+            }
+            // Command acknowledge
+            2 => (0x5c, true),
+            3 => (0x5d, true),
+            // Confirmed address, 0xffff if the address is invalid.
+            // In this case the card stops responding.
+            4 => {
+                if address_valid {
+                    ((self.address >> 8) as u8, true)
+                } else {
+                    (0xff, true)
+                }
+            }
+            5 => {
+                if address_valid {
+                    (self.address as u8, true)
+                } else {
+                    (0xff, false)
+                }
+            }
+            // Sector data
+            6...133 => {
+                let index = self.address as usize * SECTOR_SIZE
+                    + (n - 6) as usize;
+
+                let b = self.data()[index];
+
+                self.checksum ^= b;
+
+                (b, true)
+            }
+            134 => (self.checksum, true),
+            // End byte: 'G' for "good"
+            _ => (0x47, false),
+        }
+    }
+
+    /// Handle byte `n` (not counting the 4 header bytes) of a write
+    /// command
+    fn write(&mut self, n: u8, cmd: u8) -> (u8, bool) {
+        match n {
+            // Address MSB
+            0 => {
+                self.address = (cmd as u16) << 8;
+                (0x00, true)
+            }
+            // Address LSB, we return the previous byte
+            1 => {
+                let msb = (self.address >> 8) as u8;
+
+                self.address |= cmd as u16;
+                self.checksum = msb ^ cmd;
+
+                (msb, true)
+            }
+            // Sector data, we return the previous byte received
+            2...129 => {
+                let i = (n - 2) as usize;
+
+                let prev =
+                    if i == 0 {
+                        self.address as u8
+                    } else {
+                        self.write_buf[i - 1]
+                    };
+
+                self.write_buf[i] = cmd;
+                self.checksum ^= cmd;
+
+                (prev, true)
+            }
+            130 => {
+                self.write_checksum_ok = cmd == self.checksum;
+
+                (self.write_buf[SECTOR_SIZE - 1], true)
+            }
+            // Command acknowledge
+            131 => (0x5c, true),
+            132 => (0x5d, true),
+            // End byte
+            _ => {
+                let status =
+                    if self.address >= SECTOR_COUNT {
+                        // Bad sector
+                        0xff
+                    } else if !self.write_checksum_ok {
+                        // 'N': bad checksum
+                        self.flag |= FLAG_WRITE_ERROR;
+                        0x4e
+                    } else {
+                        self.write_sector();
+                        self.flag &= !(FLAG_WRITE_ERROR | FLAG_NEW_CARD);
+                        // 'G': good
+                        0x47
+                    };
+
+                (status, false)
+            }
+        }
+    }
+
+    /// Handle byte `n` (not counting the 4 header bytes) of a "get
+    /// ID" command
+    fn get_id(&mut self, n: u8) -> (u8, bool) {
+        match n {
+            // Command acknowledge
+            0 => (0x5c, true),
+            1 => (0x5d, true),
+            // Card size: 0x400 sectors of 0x80 bytes
+            2 => (0x04, true),
+            3 => (0x00, true),
+            4 => (0x00, true),
+            _ => (0x80, false),
+        }
+    }
+
+    fn data(&self) -> &[u8; MEMORY_CARD_SIZE] {
+        match self.data {
+            Some(ref d) => d,
+            None => unreachable!(),
+        }
+    }
+
+    /// Store the contents of `write_buf` at `address` and write it
+    /// back to the .mcr file
+    fn write_sector(&mut self) {
+        let offset = self.address as usize * SECTOR_SIZE;
+
+        match self.data {
+            Some(ref mut d) =>
+                d[offset..offset + SECTOR_SIZE]
+                .copy_from_slice(&self.write_buf),
+            None => unreachable!(),
+        }
+
+        if let Err(e) = self.flush_sector(offset) {
+            println!("Couldn't save memory card {}: {}",
+                     self.path.as_ref().map_or("".into(),
+                                               |p| p.display().to_string()),
+                     e);
+        }
+    }
+
+    /// Write the sector at `offset` to the backing file. If the file
+    /// hasn't been opened yet the whole card is written.
+    fn flush_sector(&mut self, offset: usize) -> io::Result<()> {
+        let sector = offset / SECTOR_SIZE;
+
+        // The DexDrive header contains a copy of the directory
+        let directory_changed = sector >= 1 && sector <= 15;
+
+        if self.file.is_none() ||
+            (self.format == image::Format::Gme && directory_changed) {
+            return self.flush_all();
+        }
+
+        let header_size = self.format.header_size();
+
+        match (&mut self.file, &self.data) {
+            (&mut Some(ref mut f), &Some(ref d)) => {
+                let pos = (header_size + offset) as u64;
+
+                try!(f.seek(SeekFrom::Start(pos)));
+                try!(f.write_all(&d[offset..offset + SECTOR_SIZE]));
+                f.flush()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Write the whole card image to the backing file
+    fn flush_all(&mut self) -> io::Result<()> {
+        let path =
+            match self.path {
+                Some(ref p) => p.clone(),
+                // Card without backing file
+                None => return Ok(()),
+            };
+
+        let raw = image::encode(&self.data()[..], self.format);
+
+        let mut file =
+            try!(OpenOptions::new().write(true).create(true).open(&path));
+
+        try!(file.write_all(&raw));
+        try!(file.flush());
+
+        self.file = Some(file);
+
+        Ok(())
+    }
+}
+
+/// Memory card commands
+#[derive(Clone, Copy)]
+enum Command {
+    /// No command received yet
+    None,
+    /// Read a sector (0x52)
+    Read,
+    /// Write a sector (0x57)
+    Write,
+    /// Return the card's ID and size (0x53)
+    GetId,
+}
+
+#[test]
+fn memory_card_write_read() {
+    let mut card = MemoryCard::disconnected();
+
+    card.data = Some(Box::new([0; MEMORY_CARD_SIZE]));
+
+    let sector: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i * 7) as u8).collect();
+
+    // Write sector 0x123
+    let mut cmd = vec![0x81, 0x57, 0x00, 0x00, 0x01, 0x23];
+    cmd.extend_from_slice(&sector);
+    cmd.push(sector.iter().fold(0x01 ^ 0x23, |c, &b| c ^ b));
+    cmd.extend_from_slice(&[0x00, 0x00, 0x00]);
+
+    card.select();
+
+    let resp: Vec<_> = cmd.iter().map(|&c| card.send_command(c)).collect();
+
+    // New card flag, then end status 'G'
+    assert!(resp[1] == (0x08, true));
+    assert!(resp[resp.len() - 1] == (0x47, false));
+
+    // Read it back
+    let mut cmd = vec![0x81, 0x52, 0x00, 0x00, 0x01, 0x23];
+    cmd.extend_from_slice(&[0; 4 + 128 + 2]);
+
+    card.select();
+
+    let resp: Vec<_> = cmd.iter().map(|&c| card.send_command(c)).collect();
+
+    // The new card flag is cleared by the write
+    assert!(resp[1] == (0x00, true));
+    assert!(resp[8] == (0x01, true));
+    assert!(resp[9] == (0x23, true));
+
+    for i in 0..SECTOR_SIZE {
+        assert!(resp[10 + i].0 == sector[i]);
+    }
+
+    let checksum = sector.iter().fold(0x01 ^ 0x23, |c, &b| c ^ b);
+
+    assert!(resp[138] == (checksum, true));
+    assert!(resp[139] == (0x47, false));
+}
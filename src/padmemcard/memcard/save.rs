@@ -0,0 +1,204 @@
+//! Single save file formats, used to move individual saves between
+//! memory cards:
+//!
+//! * PSXGameEdit (.mcs): 128 byte header containing a copy of the
+//!   save's first directory frame
+//! * Action Replay/Xploder (.psx): 54 byte header containing the
+//!   save's file name
+//! * Raw: no header, the file name of the save is the name of the
+//!   file
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+
+use super::filesystem::{self, FRAME_SIZE, BLOCK_SIZE, NAME_LEN};
+
+/// Size of the Action Replay header
+const PSX_HEADER_SIZE: usize = 54;
+
+/// Single save format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// PSXGameEdit
+    Mcs,
+    /// Action Replay/Xploder
+    Psx,
+    /// No header
+    Raw,
+}
+
+impl Format {
+    /// Select the format based on the extension of `path`, unknown
+    /// extensions are assumed to be raw saves
+    pub fn from_path(path: &Path) -> Format {
+        let extension =
+            path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match extension.as_ref().map(|e| e.as_ref()) {
+            Some("mcs") => Format::Mcs,
+            Some("psx") => Format::Psx,
+            _ => Format::Raw,
+        }
+    }
+}
+
+/// A single save: file name and data blocks
+#[derive(Clone)]
+pub struct Save {
+    name: String,
+    data: Vec<u8>,
+}
+
+impl Save {
+    /// Create a save named `name` containing `data`. The length of
+    /// `data` must be a multiple of the block size.
+    pub fn new(name: &str, data: Vec<u8>) -> Save {
+        assert!(!data.is_empty() && data.len() % BLOCK_SIZE == 0);
+
+        // The name is stored as ASCII in the directory
+        let name: String =
+            name.chars()
+            .filter(|&c| c >= ' ' && c <= '~')
+            .take(NAME_LEN)
+            .collect();
+
+        Save {
+            name: name,
+            data: data,
+        }
+    }
+
+    /// Load the save at `path`, the format is selected based on the
+    /// extension
+    pub fn from_path(path: &Path) -> io::Result<Save> {
+        let format = Format::from_path(path);
+
+        let mut file = try!(File::open(path));
+
+        let mut raw = Vec::new();
+
+        try!(file.read_to_end(&mut raw));
+
+        let (name, header_size) =
+            match format {
+                Format::Mcs => {
+                    if raw.len() < FRAME_SIZE || raw[0] != 0x51 {
+                        return error("invalid MCS save header");
+                    }
+
+                    let name = filesystem::frame_name(&raw[0..FRAME_SIZE]);
+
+                    (name, FRAME_SIZE)
+                }
+                Format::Psx => {
+                    if raw.len() < PSX_HEADER_SIZE {
+                        return error("invalid PSX save header");
+                    }
+
+                    let name =
+                        raw[0..NAME_LEN].iter()
+                        .take_while(|&&b| b != 0)
+                        .map(|&b| b as char)
+                        .collect();
+
+                    (name, PSX_HEADER_SIZE)
+                }
+                Format::Raw => {
+                    let name =
+                        path.file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or("")
+                        .to_owned();
+
+                    (name, 0)
+                }
+            };
+
+        let data = raw.split_off(header_size);
+
+        if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+            let msg = format!("invalid save size: {} bytes", data.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        if name.is_empty() {
+            return error("save has no name");
+        }
+
+        Ok(Save::new(&name, data))
+    }
+
+    /// Write the save to `path`, the format is selected based on the
+    /// extension
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let format = Format::from_path(path);
+
+        let mut raw =
+            match format {
+                Format::Mcs => {
+                    let mut header = vec![0; FRAME_SIZE];
+
+                    // Single block save directory entry
+                    header[0] = 0x51;
+
+                    let size = self.data.len() as u32;
+
+                    for i in 0..4 {
+                        header[4 + i] = (size >> (i * 8)) as u8;
+                    }
+
+                    header[8] = 0xff;
+                    header[9] = 0xff;
+
+                    header[10..10 + self.name.len()]
+                        .copy_from_slice(self.name.as_bytes());
+
+                    filesystem::set_frame_checksum(&mut header);
+
+                    header
+                }
+                Format::Psx => {
+                    let mut header = vec![0; PSX_HEADER_SIZE];
+
+                    header[0..self.name.len()]
+                        .copy_from_slice(self.name.as_bytes());
+
+                    header
+                }
+                Format::Raw => Vec::new(),
+            };
+
+        raw.extend_from_slice(&self.data);
+
+        let mut file = try!(File::create(path));
+
+        file.write_all(&raw)
+    }
+
+    /// Return the save's file name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return the number of blocks used by the save
+    pub fn blocks(&self) -> usize {
+        self.data.len() / BLOCK_SIZE
+    }
+
+    /// Return the title of the save, decoded from its header
+    pub fn title(&self) -> String {
+        filesystem::parse_title(&self.data)
+    }
+}
+
+fn error<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
@@ -1,11 +1,18 @@
 //! Gamepad and memory card controller emulation
 
+use std::mem;
+
 use memory::{Addressable, AccessWidth};
 use memory::interrupts::{Interrupt, InterruptState};
 use timekeeper::{TimeKeeper, Peripheral, Cycles};
+use gpu::BeamPosition;
 use self::gamepad::GamePad;
+use self::memcard::MemoryCard;
+use self::multitap::Multitap;
 
 pub mod gamepad;
+pub mod memcard;
+pub mod multitap;
 
 pub struct PadMemCard {
     /// Serial clock divider. The LSB is read/write but is not used,
@@ -48,10 +55,8 @@ pub struct PadMemCard {
     response: u8,
     /// True when we the RX FIFO is not empty.
     rx_not_empty: bool,
-    /// Gamepad in slot 1
-    pad1: GamePad,
-    /// Gamepad in slot 2
-    pad2: GamePad,
+    /// Devices connected to the two controller ports
+    ports: [Port; 2],
     /// Bus state machine
     bus: BusState,
 }
@@ -71,8 +76,12 @@ impl PadMemCard {
             dsr_it: false,
             response: 0xff,
             rx_not_empty: false,
-            pad1: GamePad::new(gamepad::Type::Digital),
-            pad2: GamePad::new(gamepad::Type::Disconnected),
+            ports: [
+                Port::Direct(GamePad::new(gamepad::Type::DualShock),
+                             MemoryCard::disconnected()),
+                Port::Direct(GamePad::new(gamepad::Type::Disconnected),
+                             MemoryCard::disconnected()),
+            ],
             bus: BusState::Idle,
         }
     }
@@ -216,9 +225,64 @@ impl PadMemCard {
         }
     }
 
-    /// Return a mutable reference to the gamepad profiles being used.
-    pub fn pad_profiles(&mut self) -> [&mut gamepad::Profile; 2] {
-        [ self.pad1.profile(), self.pad2.profile() ]
+    /// Return the gamepad slots: one per port or four if a multitap
+    /// is connected.
+    pub fn gamepads(&mut self) -> Vec<&mut GamePad> {
+        let (port1, port2) = self.ports.split_at_mut(1);
+
+        let mut pads = port1[0].gamepads();
+
+        pads.extend(port2[0].gamepads());
+
+        pads
+    }
+
+    /// Return a mutable reference to the gamepad profiles being used,
+    /// in the same order as `gamepads`.
+    pub fn pad_profiles(&mut self) -> Vec<&mut gamepad::Profile> {
+        self.gamepads().into_iter().map(|p| p.profile()).collect()
+    }
+
+    /// Return a mutable reference to the memory card slots, in the
+    /// same order as `gamepads`.
+    pub fn memory_cards(&mut self) -> Vec<&mut MemoryCard> {
+        let (port1, port2) = self.ports.split_at_mut(1);
+
+        let mut cards = port1[0].memory_cards();
+
+        cards.extend(port2[0].memory_cards());
+
+        cards
+    }
+
+    /// Return the beam position where a light gun wants the lightpen
+    /// interrupt to be triggered
+    pub fn lightpen_target(&mut self) -> Option<BeamPosition> {
+        self.pad_profiles().iter()
+            .filter_map(|p| p.lightpen_target())
+            .next()
+    }
+
+    /// Connect or disconnect a multitap on `port` (0 or 1). The
+    /// devices directly connected to the port are moved to the slot A
+    /// of the multitap and back.
+    pub fn set_multitap(&mut self, port: usize, connected: bool) {
+        let empty = Port::Direct(GamePad::new(gamepad::Type::Disconnected),
+                                 MemoryCard::disconnected());
+
+        let current = mem::replace(&mut self.ports[port], empty);
+
+        self.ports[port] =
+            match (current, connected) {
+                (Port::Direct(pad, memcard), true) =>
+                    Port::Multitap(Multitap::new(pad, memcard)),
+                (Port::Multitap(mut tap), false) => {
+                    let (pad, memcard) = tap.take_slot_a();
+
+                    Port::Direct(pad, memcard)
+                }
+                (p, _) => p,
+            };
     }
 
     fn send_command(&mut self, tk: &mut TimeKeeper, cmd: u8) {
@@ -235,10 +299,7 @@ impl PadMemCard {
 
         let (response, dsr) =
             if self.select {
-                match self.target {
-                    Target::PadMemCard1 => self.pad1.send_command(cmd),
-                    Target::PadMemCard2 => self.pad2.send_command(cmd),
-                }
+                self.ports[self.target as usize].send_command(cmd)
             } else {
                 // No response
                 (0xff, false)
@@ -358,10 +419,8 @@ impl PadMemCard {
             }
 
             if !prev_select && self.select {
-                // XXX Should probably also check self.target, not
-                // sure how it influences the select line. I assume
-                // only the targeted slot is selected?
-                self.pad1.select();
+                // XXX I assume only the targeted slot is selected?
+                self.ports[self.target as usize].select();
             }
         }
     }
@@ -384,6 +443,58 @@ impl Target {
     }
 }
 
+/// Device connected to a controller port
+enum Port {
+    /// Gamepad and memory card connected directly to the port
+    Direct(GamePad, MemoryCard),
+    /// SCPH-1070 multitap
+    Multitap(Multitap),
+}
+
+impl Port {
+    fn select(&mut self) {
+        match *self {
+            Port::Direct(ref mut pad, ref mut memcard) => {
+                pad.select();
+                memcard.select();
+            }
+            Port::Multitap(ref mut tap) => tap.select(),
+        }
+    }
+
+    fn send_command(&mut self, cmd: u8) -> (u8, bool) {
+        match *self {
+            Port::Direct(ref mut pad, ref mut memcard) => {
+                // The gamepad and memory card share the bus, only
+                // the one addressed by the first byte of the command
+                // will keep responding. The data line is open drain
+                // so the responses are effectively ANDed together.
+                let (pad_response, pad_dsr) = pad.send_command(cmd);
+                let (card_response, card_dsr) = memcard.send_command(cmd);
+
+                (pad_response & card_response, pad_dsr || card_dsr)
+            }
+            Port::Multitap(ref mut tap) => tap.send_command(cmd),
+        }
+    }
+
+    fn gamepads(&mut self) -> Vec<&mut GamePad> {
+        match *self {
+            Port::Direct(ref mut pad, _) => vec![pad],
+            Port::Multitap(ref mut tap) =>
+                tap.gamepads().iter_mut().collect(),
+        }
+    }
+
+    fn memory_cards(&mut self) -> Vec<&mut MemoryCard> {
+        match *self {
+            Port::Direct(_, ref mut memcard) => vec![memcard],
+            Port::Multitap(ref mut tap) =>
+                tap.memory_cards().iter_mut().collect(),
+        }
+    }
+}
+
 /// Controller transaction state machine
 #[derive(Debug)]
 enum BusState {
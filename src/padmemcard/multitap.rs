@@ -0,0 +1,259 @@
+//! SCPH-1070 Multitap emulation.
+//!
+//! The multitap plugs into a controller port and provides 4 slots
+//! (A to D), each one with a gamepad and a memory card connector. The
+//! first byte of a transaction selects the device:
+//!
+//! * 0x01 to 0x04: gamepad in slot A to D
+//! * 0x81 to 0x84: memory card in slot A to D
+//!
+//! The addressed device sees the transaction as if it was directly
+//! connected to the console (the multitap rewrites the address byte
+//! to 0x01/0x81).
+//!
+//! On top of that the multitap can read all 4 gamepads at once: when
+//! the 3rd byte of a 0x42 command sent to slot A (the "TAP" byte) is
+//! 0x01 the next 0x42 command sent to slot A returns the multitap ID
+//! 0x80 followed by 4 8-byte responses, one for each slot. The
+//! command bytes sent by the console for each slot are forwarded to
+//! the corresponding gamepad.
+
+use std::mem;
+
+use super::gamepad::{self, GamePad};
+use super::memcard::MemoryCard;
+
+/// Number of bytes reserved for each gamepad in a multitap read
+const SLOT_RESPONSE_LEN: u32 = 8;
+
+pub struct Multitap {
+    /// Gamepads in slots A to D
+    pads: [GamePad; 4],
+    /// Memory cards in slots A to D
+    memcards: [MemoryCard; 4],
+    /// Position in the current transaction
+    seq: u32,
+    /// Device addressed by the current transaction
+    target: Target,
+    /// Command byte of the current transaction
+    command: u8,
+    /// True if the next read of slot A should return all 4 gamepads.
+    /// Set by the "TAP" byte of the previous read.
+    tap: bool,
+}
+
+impl Multitap {
+    /// Create a multitap with `pad` and `memcard` in slot A and the
+    /// other slots empty
+    pub fn new(pad: GamePad, memcard: MemoryCard) -> Multitap {
+        let disconnected = || GamePad::new(gamepad::Type::Disconnected);
+
+        Multitap {
+            pads: [pad, disconnected(), disconnected(), disconnected()],
+            memcards: [memcard,
+                       MemoryCard::disconnected(),
+                       MemoryCard::disconnected(),
+                       MemoryCard::disconnected()],
+            seq: 0,
+            target: Target::None,
+            command: 0,
+            tap: false,
+        }
+    }
+
+    /// Remove the devices in slot A, used when the multitap is
+    /// unplugged and the devices are connected directly to the port
+    pub fn take_slot_a(&mut self) -> (GamePad, MemoryCard) {
+        let pad = GamePad::new(gamepad::Type::Disconnected);
+        let memcard = MemoryCard::disconnected();
+
+        (mem::replace(&mut self.pads[0], pad),
+         mem::replace(&mut self.memcards[0], memcard))
+    }
+
+    /// Called when the "select" line goes down.
+    pub fn select(&mut self) {
+        self.seq = 0;
+        self.target = Target::None;
+    }
+
+    /// Handle a command byte, returns the response and the DSR state
+    /// like `GamePad::send_command`
+    pub fn send_command(&mut self, cmd: u8) -> (u8, bool) {
+        let seq = self.seq;
+
+        self.seq += 1;
+
+        if seq == 0 {
+            return self.address(cmd);
+        }
+
+        if seq == 1 {
+            self.command = cmd;
+
+            if self.target == Target::Pad(0) && cmd == 0x42 && self.tap {
+                self.target = Target::AllPads;
+                return (0x80, true);
+            }
+        }
+
+        if seq == 2 && self.command == 0x42 && self.target.is_slot_a_pad() {
+            self.tap = cmd == 0x01;
+        }
+
+        match self.target {
+            Target::None => (0xff, false),
+            Target::Pad(n) => self.pads[n].send_command(cmd),
+            Target::MemoryCard(n) => self.memcards[n].send_command(cmd),
+            Target::AllPads => self.read_all_pads(seq, cmd),
+        }
+    }
+
+    /// Return the gamepads in slots A to D
+    pub fn gamepads(&mut self) -> &mut [GamePad; 4] {
+        &mut self.pads
+    }
+
+    /// Return the memory cards in slots A to D
+    pub fn memory_cards(&mut self) -> &mut [MemoryCard; 4] {
+        &mut self.memcards
+    }
+
+    /// Handle the address byte starting the transaction
+    fn address(&mut self, cmd: u8) -> (u8, bool) {
+        self.target =
+            match cmd {
+                0x01...0x04 => Target::Pad((cmd - 0x01) as usize),
+                0x81...0x84 => Target::MemoryCard((cmd - 0x81) as usize),
+                _ => Target::None,
+            };
+
+        match self.target {
+            Target::Pad(n) => {
+                let pad = &mut self.pads[n];
+
+                pad.select();
+                pad.send_command(0x01)
+            }
+            Target::MemoryCard(n) => {
+                let memcard = &mut self.memcards[n];
+
+                memcard.select();
+                memcard.send_command(0x81)
+            }
+            _ => (0xff, false),
+        }
+    }
+
+    /// Multitap read of all 4 gamepads
+    fn read_all_pads(&mut self, seq: u32, cmd: u8) -> (u8, bool) {
+        if seq == 2 {
+            // 2nd multitap ID byte
+            return (0x5a, true);
+        }
+
+        let pos = seq - 3;
+        let slot = (pos / SLOT_RESPONSE_LEN) as usize;
+
+        if slot >= 4 {
+            // Shouldn't be reached
+            return (0xff, false);
+        }
+
+        let pad = &mut self.pads[slot];
+
+        if pos % SLOT_RESPONSE_LEN == 0 {
+            // Start a new transaction with this slot's gamepad
+            pad.select();
+            pad.send_command(0x01);
+        }
+
+        // If the gamepad is missing or has a shorter response the
+        // remaining bytes are 0xff
+        let (response, _) = pad.send_command(cmd);
+
+        // XXX On the real hardware the gamepads are read
+        // simultaneously and the multitap returns the responses
+        // buffered during the previous transaction. We read them
+        // inline instead.
+
+        // DSR is asserted until the last byte
+        (response, pos + 1 < 4 * SLOT_RESPONSE_LEN)
+    }
+}
+
+/// Device addressed by the current transaction
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// Invalid address, the transaction is ignored
+    None,
+    /// Gamepad in the given slot
+    Pad(usize),
+    /// Memory card in the given slot
+    MemoryCard(usize),
+    /// Multitap read of all gamepads
+    AllPads,
+}
+
+impl Target {
+    fn is_slot_a_pad(self) -> bool {
+        self == Target::Pad(0) || self == Target::AllPads
+    }
+}
+
+#[test]
+fn multitap_read_all_pads() {
+    let pad = GamePad::new(gamepad::Type::Digital);
+
+    let mut tap = Multitap::new(pad, MemoryCard::disconnected());
+
+    tap.gamepads()[2] = GamePad::new(gamepad::Type::Digital);
+
+    fn transaction(tap: &mut Multitap, cmd: &[u8]) -> Vec<u8> {
+        let mut reply = Vec::new();
+
+        tap.select();
+
+        for &b in cmd {
+            let (r, dsr) = tap.send_command(b);
+
+            reply.push(r);
+
+            if !dsr {
+                break;
+            }
+        }
+
+        reply
+    }
+
+    // Without TAP the multitap is transparent
+    assert!(transaction(&mut tap, &[0x01, 0x42, 0x01, 0x00, 0x00]) ==
+            [0xff, 0x41, 0x5a, 0xff, 0xff]);
+
+    // Direct access to slot C and D
+    assert!(transaction(&mut tap, &[0x03, 0x42, 0x00, 0x00, 0x00]) ==
+            [0xff, 0x41, 0x5a, 0xff, 0xff]);
+    assert!(transaction(&mut tap, &[0x04, 0x42, 0x00, 0x00, 0x00]) ==
+            [0xff]);
+
+    // The previous TAP byte enabled multitap mode
+    let mut cmd = vec![0x01, 0x42, 0x01];
+
+    for _ in 0..4 {
+        cmd.extend_from_slice(&[0x42, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    let reply = transaction(&mut tap, &cmd);
+
+    assert!(reply.len() == cmd.len());
+    assert!(&reply[0..3] == [0xff, 0x80, 0x5a]);
+    let digital = [0x41, 0x5a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+    // Slot A
+    assert!(&reply[3..11] == digital);
+    // Slot B is empty
+    assert!(&reply[11..19] == [0xff; 8]);
+    // Slot C
+    assert!(&reply[19..27] == digital);
+}
@@ -0,0 +1,376 @@
+//! Devices that can be connected to the serial port.
+//!
+//! The emulated link cable carries the data lines as well as the
+//! DTR/RTS outputs of each side which are seen as DSR/CTS inputs by
+//! the other side.
+
+use std::io::{self, Read, Write};
+use std::collections::VecDeque;
+use std::net::{TcpStream, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::{UnixStream, UnixListener};
+
+/// Interface to the device at the other end of the serial port
+pub trait Link {
+    /// Transmit a byte to the remote device
+    fn send(&mut self, b: u8);
+
+    /// Update the state of our DTR and RTS outputs
+    fn set_lines(&mut self, dtr: bool, rts: bool);
+
+    /// Fetch the next byte received from the remote device if any.
+    /// Must not block.
+    fn receive(&mut self) -> Option<u8>;
+
+    /// Return the state of the remote device's DTR and RTS outputs,
+    /// seen by the console as DSR and CTS
+    fn remote_lines(&self) -> (bool, bool);
+
+    /// Return true if the remote device can send data at any time
+    /// and must be polled
+    fn connected(&self) -> bool;
+
+    /// Called before `receive` and `remote_lines` to give the link
+    /// an opportunity to fetch new data
+    fn poll(&mut self) {
+    }
+}
+
+/// Nothing connected to the serial port
+pub struct Disconnected;
+
+impl Link for Disconnected {
+    fn send(&mut self, _: u8) {
+    }
+
+    fn set_lines(&mut self, _: bool, _: bool) {
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn remote_lines(&self) -> (bool, bool) {
+        (false, false)
+    }
+
+    fn connected(&self) -> bool {
+        false
+    }
+}
+
+/// Loopback plug: TX is wired to RX, DTR to DSR and RTS to CTS
+pub struct Loopback {
+    data: VecDeque<u8>,
+    lines: (bool, bool),
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback {
+            data: VecDeque::new(),
+            lines: (false, false),
+        }
+    }
+}
+
+impl Link for Loopback {
+    fn send(&mut self, b: u8) {
+        self.data.push_back(b);
+    }
+
+    fn set_lines(&mut self, dtr: bool, rts: bool) {
+        self.lines = (dtr, rts);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.data.pop_front()
+    }
+
+    fn remote_lines(&self) -> (bool, bool) {
+        self.lines
+    }
+
+    fn connected(&self) -> bool {
+        // We only receive what we send
+        false
+    }
+}
+
+/// Host terminal: the transmitted data is written to the standard
+/// output, used by homebrew software for debug output.
+///
+/// XXX The terminal doesn't send anything back to the console.
+pub struct Terminal;
+
+impl Link for Terminal {
+    fn send(&mut self, b: u8) {
+        let mut stdout = io::stdout();
+
+        let _ = stdout.write_all(&[b]);
+
+        if b == b'\n' {
+            let _ = stdout.flush();
+        }
+    }
+
+    fn set_lines(&mut self, _: bool, _: bool) {
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn remote_lines(&self) -> (bool, bool) {
+        // The terminal is always ready to receive
+        (true, true)
+    }
+
+    fn connected(&self) -> bool {
+        false
+    }
+}
+
+/// Link cable to another emulator instance through a TCP or Unix
+/// socket. Each side sends two-byte messages: `[0x00, data]` for a
+/// data byte and `[0x01, lines]` when the DTR (bit 0) or RTS (bit 1)
+/// outputs change.
+pub struct StreamLink<S: Read + Write> {
+    /// Connection to the remote, `None` once it's been closed
+    stream: Option<S>,
+    /// Incomplete message received from the remote
+    partial: Option<u8>,
+    /// Data received from the remote and not yet read by the console
+    received: VecDeque<u8>,
+    /// Messages waiting to be sent to the remote
+    outgoing: VecDeque<u8>,
+    /// Remote DTR and RTS outputs
+    remote_lines: (bool, bool),
+}
+
+impl StreamLink<TcpStream> {
+    /// Connect to an emulator listening at `addr`
+    pub fn tcp_connect(addr: &str) -> io::Result<StreamLink<TcpStream>> {
+        let stream = try!(TcpStream::connect(addr));
+
+        try!(stream.set_nonblocking(true));
+
+        Ok(StreamLink::new(stream))
+    }
+
+    /// Wait for an emulator to connect to `addr`
+    pub fn tcp_listen(addr: &str) -> io::Result<StreamLink<TcpStream>> {
+        let listener = try!(TcpListener::bind(addr));
+
+        let (stream, _) = try!(listener.accept());
+
+        try!(stream.set_nonblocking(true));
+
+        Ok(StreamLink::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl StreamLink<UnixStream> {
+    /// Connect to an emulator listening at `path`
+    pub fn unix_connect(path: &str) -> io::Result<StreamLink<UnixStream>> {
+        let stream = try!(UnixStream::connect(path));
+
+        try!(stream.set_nonblocking(true));
+
+        Ok(StreamLink::new(stream))
+    }
+
+    /// Wait for an emulator to connect to `path`
+    pub fn unix_listen(path: &str) -> io::Result<StreamLink<UnixStream>> {
+        let listener = try!(UnixListener::bind(path));
+
+        let (stream, _) = try!(listener.accept());
+
+        try!(stream.set_nonblocking(true));
+
+        Ok(StreamLink::new(stream))
+    }
+}
+
+impl<S: Read + Write> StreamLink<S> {
+    fn new(stream: S) -> StreamLink<S> {
+        StreamLink {
+            stream: Some(stream),
+            partial: None,
+            received: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            remote_lines: (false, false),
+        }
+    }
+
+    fn write_message(&mut self, kind: u8, val: u8) {
+        if self.stream.is_none() {
+            return;
+        }
+
+        self.outgoing.push_back(kind);
+        self.outgoing.push_back(val);
+
+        self.flush();
+    }
+
+    /// Send as much of the outgoing messages as the stream accepts
+    /// without blocking, the rest is sent by a later `poll`
+    fn flush(&mut self) {
+        while !self.outgoing.is_empty() {
+            let res =
+                match self.stream {
+                    Some(ref mut s) => s.write(self.outgoing.as_slices().0),
+                    None => return,
+                };
+
+            match res {
+                Ok(0) => {
+                    let e = io::Error::new(io::ErrorKind::WriteZero,
+                                           "remote doesn't accept data");
+                    return self.close(e);
+                }
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                    return,
+                Err(e) => return self.close(e),
+            }
+        }
+    }
+
+    fn handle_message(&mut self, kind: u8, val: u8) {
+        match kind {
+            0x00 => self.received.push_back(val),
+            0x01 => self.remote_lines = (val & 1 != 0, val & 2 != 0),
+            _ => println!("Invalid serial link message {:02x} {:02x}",
+                          kind, val),
+        }
+    }
+
+    fn close(&mut self, e: io::Error) {
+        println!("Serial link closed: {}", e);
+
+        self.stream = None;
+        self.outgoing.clear();
+        self.remote_lines = (false, false);
+    }
+}
+
+impl<S: Read + Write> Link for StreamLink<S> {
+    fn send(&mut self, b: u8) {
+        self.write_message(0x00, b);
+    }
+
+    fn set_lines(&mut self, dtr: bool, rts: bool) {
+        self.write_message(0x01, (dtr as u8) | ((rts as u8) << 1));
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.received.pop_front()
+    }
+
+    fn remote_lines(&self) -> (bool, bool) {
+        self.remote_lines
+    }
+
+    fn connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn poll(&mut self) {
+        self.flush();
+
+        let mut buf = [0; 256];
+
+        loop {
+            let res =
+                match self.stream {
+                    Some(ref mut s) => s.read(&mut buf),
+                    None => return,
+                };
+
+            let n =
+                match res {
+                    Ok(0) => {
+                        let e = io::Error::new(io::ErrorKind::Other,
+                                               "remote disconnected");
+                        return self.close(e);
+                    }
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                        return,
+                    Err(e) => return self.close(e),
+                };
+
+            for &b in &buf[..n] {
+                match self.partial.take() {
+                    Some(kind) => self.handle_message(kind, b),
+                    None => self.partial = Some(b),
+                }
+            }
+        }
+    }
+}
+
+/// Stream accepting a limited number of bytes before blocking
+#[cfg(test)]
+struct ThrottledStream {
+    written: Vec<u8>,
+    budget: usize,
+}
+
+#[cfg(test)]
+impl Read for ThrottledStream {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"))
+    }
+}
+
+#[cfg(test)]
+impl Write for ThrottledStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.budget == 0 {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "full"));
+        }
+
+        let n = ::std::cmp::min(self.budget, buf.len());
+
+        self.budget -= n;
+        self.written.extend_from_slice(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_link_back_pressure() {
+    let mut link = StreamLink::new(ThrottledStream {
+        written: Vec::new(),
+        budget: 3,
+    });
+
+    link.send(0x42);
+    link.send(0x43);
+
+    // The second message is split, the link stays open
+    assert!(link.connected());
+
+    if let Some(ref mut s) = link.stream {
+        assert!(s.written == [0x00, 0x42, 0x00]);
+        s.budget = 10;
+    }
+
+    link.poll();
+    assert!(link.connected());
+
+    if let Some(ref s) = link.stream {
+        assert!(s.written == [0x00, 0x42, 0x00, 0x43]);
+    }
+}
@@ -0,0 +1,393 @@
+//! Serial port (SIO1) emulation. The port is only accessible through
+//! the link cable connector at the back of the early models but it's
+//! also used by some homebrew software and dev kits for debug output.
+
+use std::cmp;
+use std::collections::VecDeque;
+
+use memory::Addressable;
+use memory::interrupts::{Interrupt, InterruptState};
+use timekeeper::{TimeKeeper, Peripheral, Cycles};
+use self::link::Link;
+
+pub mod link;
+
+/// Number of bytes that can be held in the RX FIFO
+const RX_FIFO_DEPTH: usize = 8;
+
+pub struct Sio1 {
+    /// Device connected to the port
+    link: Box<Link>,
+    /// Baudrate reload value. The bit period is `baud_reload`
+    /// multiplied by the reload factor in `mode`, in CPU cycles.
+    baud_reload: u16,
+    /// Mode register: reload factor (bits [1:0]), character length
+    /// (bits [3:2]), parity (bits [5:4]) and stop bits (bits [7:6])
+    mode: u16,
+    /// Control register, see the accessor methods for the bit
+    /// definitions. The write-only "acknowledge" and "reset" bits are
+    /// not stored.
+    control: u16,
+    /// This register is read/write but doesn't seem to do anything
+    misc: u16,
+    /// TX FIFO, it can only hold a single byte
+    tx_fifo: Option<u8>,
+    /// Byte currently being transmitted and the number of cycles
+    /// remaining until the end of the transmission
+    tx_shift: Option<(u8, Cycles)>,
+    /// Bytes received and not yet read
+    rx_fifo: VecDeque<u8>,
+    /// Set when a byte is received while the RX FIFO is full
+    rx_overrun: bool,
+    /// Data Set Ready input, connected to the remote's DTR
+    dsr: bool,
+    /// Clear To Send input, connected to the remote's RTS
+    cts: bool,
+    /// Current interrupt level
+    interrupt: bool,
+}
+
+impl Sio1 {
+    pub fn new() -> Sio1 {
+        Sio1 {
+            link: Box::new(link::Disconnected),
+            baud_reload: 0,
+            mode: 0,
+            control: 0,
+            misc: 0,
+            tx_fifo: None,
+            tx_shift: None,
+            rx_fifo: VecDeque::new(),
+            rx_overrun: false,
+            dsr: false,
+            cts: false,
+            interrupt: false,
+        }
+    }
+
+    /// Connect a new device to the serial port
+    pub fn set_link(&mut self, link: Box<Link>) {
+        self.link = link;
+
+        let (dtr, rts) = (self.dtr(), self.rts());
+
+        self.link.set_lines(dtr, rts);
+    }
+
+    pub fn store<T: Addressable>(&mut self,
+                                 tk: &mut TimeKeeper,
+                                 irq_state: &mut InterruptState,
+                                 offset: u32,
+                                 val: T) {
+        self.sync(tk, irq_state);
+
+        match offset {
+            0 => {
+                if self.tx_fifo.is_some() {
+                    println!("SIO1 TX while FIFO is full");
+                }
+
+                self.tx_fifo = Some(val.as_u8());
+            }
+            // SIO_STAT is read-only
+            4 | 6 => (),
+            8 => self.mode = val.as_u16(),
+            10 => self.set_control(val.as_u16()),
+            12 => self.misc = val.as_u16(),
+            14 => self.baud_reload = val.as_u16(),
+            _ => panic!("Unhandled write to SIO1 register {} {:04x}",
+                        offset, val.as_u16()),
+        }
+
+        // Start the transmission if possible and update the interrupt
+        // state
+        self.transmit(0);
+        self.update(tk, irq_state);
+    }
+
+    pub fn load<T: Addressable>(&mut self,
+                                tk: &mut TimeKeeper,
+                                irq_state: &mut InterruptState,
+                                offset: u32) -> T {
+        self.sync(tk, irq_state);
+
+        let v =
+            match offset {
+                // XXX Wider reads are supposed to return the next
+                // bytes in the FIFO without removing them
+                0 => self.rx_fifo.pop_front().unwrap_or(0xff) as u32,
+                4 => self.stat(),
+                6 => self.stat() >> 16,
+                8 => self.mode as u32,
+                10 => self.control as u32,
+                12 => self.misc as u32,
+                14 => self.baud_reload as u32,
+                _ => panic!("Unhandled SIO1 read {:?} 0x{:x}",
+                            T::width(), offset),
+            };
+
+        T::from_u32(v)
+    }
+
+    pub fn sync(&mut self,
+                tk: &mut TimeKeeper,
+                irq_state: &mut InterruptState) {
+        let delta = tk.sync(Peripheral::Sio1);
+
+        self.transmit(delta);
+        self.receive();
+        self.update(tk, irq_state);
+    }
+
+    /// Advance the transmission by `delta` cycles and start sending
+    /// the next byte in the FIFO if possible.
+    fn transmit(&mut self, mut delta: Cycles) {
+        loop {
+            if let Some((b, remaining)) = self.tx_shift {
+                if delta < remaining {
+                    self.tx_shift = Some((b, remaining - delta));
+                    return;
+                }
+
+                delta -= remaining;
+
+                let mask = (1u16 << self.char_length()) - 1;
+
+                self.link.send(b & mask as u8);
+                self.tx_shift = None;
+            }
+
+            // XXX I assume that the transmission is paused while CTS
+            // is not asserted.
+            if !self.tx_en() || !self.cts {
+                return;
+            }
+
+            match self.tx_fifo.take() {
+                Some(b) => self.tx_shift = Some((b, self.char_duration())),
+                None => return,
+            }
+        }
+    }
+
+    /// Fetch the data and line state from the remote device
+    fn receive(&mut self) {
+        self.link.poll();
+
+        let (dsr, cts) = self.link.remote_lines();
+
+        self.dsr = dsr;
+        self.cts = cts;
+
+        while let Some(b) = self.link.receive() {
+            if !self.rx_en() {
+                // Data is lost
+                continue;
+            }
+
+            if self.rx_fifo.len() < RX_FIFO_DEPTH {
+                self.rx_fifo.push_back(b);
+            } else {
+                self.rx_overrun = true;
+            }
+        }
+    }
+
+    /// Update the interrupt and schedule the next sync
+    fn update(&mut self,
+              tk: &mut TimeKeeper,
+              irq_state: &mut InterruptState) {
+        let tx_ready = self.tx_fifo.is_none();
+        let rx_ready = self.rx_fifo.len() >= self.rx_irq_threshold();
+
+        let irq =
+            (self.tx_it() && tx_ready) ||
+            (self.rx_it() && rx_ready) ||
+            (self.dsr_it() && self.dsr);
+
+        if irq && !self.interrupt {
+            // Rising edge of the interrupt
+            irq_state.assert(Interrupt::Sio);
+            self.interrupt = true;
+        }
+
+        let mut next_sync = self.tx_shift.map(|(_, remaining)| remaining);
+
+        if self.link.connected() {
+            // The remote can send data at any time, poll it once per
+            // character period
+            let period = self.char_duration();
+
+            next_sync = Some(next_sync.map_or(period,
+                                              |n| cmp::min(n, period)));
+        }
+
+        match next_sync {
+            Some(delta) => tk.set_next_sync_delta(Peripheral::Sio1, delta),
+            None => tk.no_sync_needed(Peripheral::Sio1),
+        }
+    }
+
+    fn stat(&self) -> u32 {
+        let mut stat = 0u32;
+
+        // TX Ready 1: FIFO not full
+        stat |= self.tx_fifo.is_none() as u32;
+        stat |= (!self.rx_fifo.is_empty() as u32) << 1;
+        // TX Ready 2: transmission done
+        stat |= ((self.tx_fifo.is_none() && self.tx_shift.is_none())
+                 as u32) << 2;
+        // RX parity error and bad stop bit can't happen over our
+        // emulated link
+        stat |= (self.rx_overrun as u32) << 4;
+        stat |= (self.dsr as u32) << 7;
+        stat |= (self.cts as u32) << 8;
+        stat |= (self.interrupt as u32) << 9;
+        // XXX needs to add the baudrate counter in bits [25:11];
+        stat |= 0 << 11;
+
+        stat
+    }
+
+    fn set_control(&mut self, ctrl: u16) {
+        if ctrl & 0x40 != 0 {
+            // Soft reset
+            self.baud_reload = 0;
+            self.mode = 0;
+            self.control = 0;
+            self.tx_fifo = None;
+            self.tx_shift = None;
+            self.rx_fifo.clear();
+            self.rx_overrun = false;
+            self.interrupt = false;
+        } else {
+            if ctrl & 0x10 != 0 {
+                // Interrupt acknowledge, also clears the error flags
+                self.interrupt = false;
+                self.rx_overrun = false;
+            }
+
+            // Acknowledge and reset are write-only
+            self.control = ctrl & !0x50;
+        }
+
+        let (dtr, rts) = (self.dtr(), self.rts());
+
+        self.link.set_lines(dtr, rts);
+    }
+
+    /// Number of CPU cycles per bit
+    fn bit_period(&self) -> Cycles {
+        // XXX On SIO0 a factor of 0 stops the clock, I'm not sure
+        // what it does here
+        let factor =
+            match self.mode & 3 {
+                0 | 1 => 1,
+                2 => 16,
+                _ => 64,
+            };
+
+        let period = (self.baud_reload as Cycles * factor) & !1;
+
+        cmp::max(period, factor)
+    }
+
+    /// Number of data bits per character, from 5 to 8
+    fn char_length(&self) -> u16 {
+        5 + ((self.mode >> 2) & 3)
+    }
+
+    /// Number of CPU cycles needed to transfer one character including
+    /// the start, parity and stop bits
+    fn char_duration(&self) -> Cycles {
+        let parity = (self.mode >> 4) & 1;
+
+        // Number of stop bits times two since we can have 1.5 stop
+        // bits. 0 is reserved, I assume it's 1 stop bit.
+        let stop = match (self.mode >> 6) & 3 {
+            2 => 3,
+            3 => 4,
+            _ => 2,
+        };
+
+        let half_bits = 2 * (1 + self.char_length() + parity) + stop;
+
+        self.bit_period() * half_bits as Cycles / 2
+    }
+
+    /// Transmission enabled
+    fn tx_en(&self) -> bool {
+        self.control & 1 != 0
+    }
+
+    /// Data Terminal Ready output
+    fn dtr(&self) -> bool {
+        self.control & 2 != 0
+    }
+
+    /// Reception enabled
+    fn rx_en(&self) -> bool {
+        self.control & 4 != 0
+    }
+
+    /// Request To Send output
+    fn rts(&self) -> bool {
+        self.control & 0x20 != 0
+    }
+
+    /// Number of bytes in the RX FIFO that trigger the RX interrupt:
+    /// 1, 2, 4 or 8
+    fn rx_irq_threshold(&self) -> usize {
+        1 << ((self.control >> 8) & 3)
+    }
+
+    /// TX interrupt enabled
+    fn tx_it(&self) -> bool {
+        self.control & 0x400 != 0
+    }
+
+    /// RX interrupt enabled
+    fn rx_it(&self) -> bool {
+        self.control & 0x800 != 0
+    }
+
+    /// DSR interrupt enabled
+    fn dsr_it(&self) -> bool {
+        self.control & 0x1000 != 0
+    }
+}
+
+#[test]
+fn sio1_loopback() {
+    let mut tk = TimeKeeper::new();
+    let mut irq_state = InterruptState::new();
+    let mut sio = Sio1::new();
+
+    sio.set_link(Box::new(link::Loopback::new()));
+
+    // 8N1 with a x16 reload factor
+    sio.store(&mut tk, &mut irq_state, 14, 0x24u16);
+    sio.store(&mut tk, &mut irq_state, 8, 0x4eu16);
+    // TX, RX, DTR, RTS and the RX interrupt
+    sio.store(&mut tk, &mut irq_state, 10, 0x0827u16);
+
+    // DTR and RTS are looped back
+    let stat = sio.load::<u32>(&mut tk, &mut irq_state, 4);
+    assert!(stat & 0x185 == 0x185);
+
+    sio.store(&mut tk, &mut irq_state, 0, 0x42u8);
+
+    // Transmission in progress
+    let stat = sio.load::<u32>(&mut tk, &mut irq_state, 4);
+    assert!(stat & 6 == 0);
+
+    // 10 bits of 0x24 * 16 cycles
+    tk.tick(5760);
+    sio.sync(&mut tk, &mut irq_state);
+
+    let stat = sio.load::<u32>(&mut tk, &mut irq_state, 4);
+    assert!(stat & 0x206 == 0x206);
+    assert!(irq_state.status() & (1 << 8) != 0);
+
+    assert!(sio.load::<u8>(&mut tk, &mut irq_state, 0) == 0x42);
+}
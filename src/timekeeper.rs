@@ -5,7 +5,7 @@
 
 /// List of all peripherals requiring a TimeSheet. The value of the
 /// enum is used as the index in the timesheet table
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Peripheral {
     /// Graphics Processing Unit
     Gpu,
@@ -17,6 +17,10 @@ pub enum Peripheral {
     PadMemCard,
     /// CD-ROM controller
     CdRom,
+    /// Serial port
+    Sio1,
+    /// DMA controller
+    Dma,
 }
 
 
@@ -28,7 +32,7 @@ pub struct TimeKeeper {
     /// Next time a peripheral needs an update
     next_sync: Cycles,
     /// Time sheets for keeping track of the various peripherals
-    timesheets: [TimeSheet; 6],
+    timesheets: [TimeSheet; 8],
 }
 
 impl TimeKeeper {
@@ -36,7 +40,7 @@ impl TimeKeeper {
         TimeKeeper {
             now: 0,
             next_sync: Cycles::max_value(),
-            timesheets: [TimeSheet::new(); 6],
+            timesheets: [TimeSheet::new(); 8],
         }
     }
 